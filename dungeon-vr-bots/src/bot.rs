@@ -0,0 +1,259 @@
+use std::f32::consts::{PI, TAU};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use dungeon_vr_connection_client::{ConnectionClient, ConnectionConfig, ConnectionStats};
+use dungeon_vr_session_client::headless::HeadlessClient;
+use dungeon_vr_session_client::{Event, Request, SessionClient};
+use dungeon_vr_session_shared::action::Action;
+use dungeon_vr_session_shared::interaction::HandGrabState;
+use dungeon_vr_session_shared::{PlayerId, TICK_INTERVAL};
+use rand::rngs::StdRng;
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use rand_distr::Exp1;
+use rapier3d::prelude::*;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+use crate::voice;
+
+/// How often each bot asks its connection for fresh stats.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Revolutions per second of each hand around its circle.
+const HAND_FREQUENCY: f32 = 0.5;
+
+/// Settings shared by every bot.
+pub struct BotConfig {
+    pub server_addr: SocketAddr,
+    /// The mean time between one bot's grabs and drops.
+    pub grab_interval: Duration,
+    /// Opus packets to loop as each bot's voice, if the bots should talk.
+    pub voice: Option<Vec<Vec<u8>>>,
+}
+
+/// What one bot has measured so far. Shared with the main task for reporting.
+#[derive(Clone, Debug, Default)]
+pub struct BotReport {
+    pub player_id: Option<PlayerId>,
+    /// How many times a session has started, counting restarts after connection outages.
+    pub sessions: u32,
+    /// When the first session started and when the bot stopped, bounding the rates below.
+    pub started_at: Option<Instant>,
+    pub stopped_at: Option<Instant>,
+    pub rtt: Option<Duration>,
+    pub snapshots: u64,
+    /// Grabs attempted. The server refuses any that lose a race for the same object.
+    pub grabs: u64,
+    /// Totals across every connection the bot has made.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_lost: u64,
+    /// The most recent stats, which the next stats are counted from.
+    latest_stats: ConnectionStats,
+}
+
+impl BotReport {
+    /// How long the bot has been in a session, up to `now` if it's still running.
+    pub fn elapsed(&self, now: Instant) -> Option<Duration> {
+        let started_at = self.started_at?;
+        Some(
+            self.stopped_at
+                .unwrap_or(now)
+                .saturating_duration_since(started_at),
+        )
+    }
+
+    fn add_stats(&mut self, stats: ConnectionStats) {
+        // Every connection counts from zero, so counters going backwards mean the bot reconnected.
+        let base = if stats.packets_sent < self.latest_stats.packets_sent {
+            ConnectionStats::default()
+        } else {
+            self.latest_stats
+        };
+        self.rtt = stats.rtt;
+        self.bytes_sent += stats.bytes_sent.saturating_sub(base.bytes_sent);
+        self.bytes_received += stats.bytes_received.saturating_sub(base.bytes_received);
+        self.packets_lost += stats.packets_lost.saturating_sub(base.packets_lost);
+        self.latest_stats = stats;
+    }
+}
+
+/// Runs one bot until `cancel_token` is cancelled. Failing to connect or being turned away by a
+/// full server isn't an error; it shows up in the report as a bot without a player.
+pub async fn run_bot(
+    config: Arc<BotConfig>,
+    rng: StdRng,
+    report: Arc<Mutex<BotReport>>,
+    cancel_token: cancel::Token,
+) -> Result<()> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(config.server_addr).await?;
+    let (_connection_cancel_guard, connection_requests, connection_events) =
+        ConnectionClient::spawn(Box::new(socket), ConnectionConfig::default(), None, None);
+    let mut bot = Bot::new(
+        &config,
+        rng,
+        SessionClient::new(connection_requests, connection_events),
+        Arc::clone(&report),
+    );
+
+    let mut tick_interval = interval(TICK_INTERVAL.try_into().unwrap());
+    tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut stats_interval = interval(STATS_INTERVAL);
+    let mut voice_interval = interval(voice::FRAME_INTERVAL);
+    while !cancel_token.is_cancelled() {
+        select! {
+            biased;
+
+            _ = cancel_token.cancelled() => break,
+
+            _ = tick_interval.tick() => bot.tick(),
+
+            _ = stats_interval.tick() => {
+                let _ = bot.client.session().try_send_request(Request::QueryConnectionStats);
+            }
+
+            _ = voice_interval.tick(), if config.voice.is_some() => {
+                bot.send_voice(config.voice.as_ref().unwrap());
+            }
+        }
+    }
+
+    report.lock().unwrap().stopped_at = Some(Instant::now());
+    Ok(())
+}
+
+/// A client that plays by itself. Its hands sweep around in front of it, now and then grabbing or
+/// dropping something.
+struct Bot {
+    client: HeadlessClient,
+    rng: StdRng,
+    report: Arc<Mutex<BotReport>>,
+    grab_interval: Duration,
+    /// Where the bot stands.
+    position: Vector<f32>,
+    motion_start: Instant,
+    next_grab_time: Instant,
+    next_voice_frame: usize,
+}
+
+impl Bot {
+    fn new(
+        config: &BotConfig,
+        mut rng: StdRng,
+        session: SessionClient,
+        report: Arc<Mutex<BotReport>>,
+    ) -> Self {
+        let position = vector![rng.gen_range(-2.0..2.0), 0.0, rng.gen_range(-2.0..2.0)];
+        let now = Instant::now();
+        let next_grab_time = now + config.grab_interval.mul_f64(rng.sample(Exp1));
+        Self {
+            client: HeadlessClient::new(session),
+            rng,
+            report,
+            grab_interval: config.grab_interval,
+            position,
+            motion_start: now,
+            next_grab_time,
+            next_voice_frame: 0,
+        }
+    }
+
+    fn tick(&mut self) {
+        let report = &self.report;
+        self.client
+            .recv_events(|event| Self::record_event(report, event));
+
+        let now = Instant::now();
+        self.maybe_grab_or_drop(now);
+        self.client.hand_transforms = [self.hand_transform(0, now), self.hand_transform(1, now)];
+        self.client.commit();
+    }
+
+    fn record_event(report: &Mutex<BotReport>, event: &Event) {
+        let mut report = report.lock().unwrap();
+        match *event {
+            Event::Start {
+                local_player_id, ..
+            } => {
+                report.player_id = Some(local_player_id);
+                report.sessions += 1;
+                report.started_at.get_or_insert(Instant::now());
+            }
+            Event::Snapshot { .. } => report.snapshots += 1,
+            Event::Voice(_) => (),
+            Event::ConnectionStats(stats) => report.add_stats(stats),
+        }
+    }
+
+    /// Every so often, drops whatever one hand is holding, or grabs a random free object with it.
+    fn maybe_grab_or_drop(&mut self, now: Instant) {
+        if now < self.next_grab_time {
+            return;
+        }
+        self.next_grab_time = now + self.grab_interval.mul_f64(self.rng.sample(Exp1));
+
+        let player_id = match self.client.player_id() {
+            Some(player_id) => player_id,
+            None => return,
+        };
+        let hand_index = self.rng.gen_range(0..2);
+        let grab_state = match self.client.hand(player_id, hand_index) {
+            Some((_, entity)) => entity.hand.unwrap().grab_state,
+            None => return,
+        };
+        match grab_state {
+            HandGrabState::Grabbing(_) => self.client.queue_action(Action::Drop { hand_index }),
+            HandGrabState::Empty => {
+                let target = self
+                    .client
+                    .snapshot()
+                    .unwrap()
+                    .entities
+                    .iter()
+                    .filter(|(_, entity)| entity.grabbed == Some(false))
+                    .map(|(&net_id, _)| net_id)
+                    .choose(&mut self.rng);
+                if let Some(target) = target {
+                    self.client
+                        .queue_action(Action::Grab { hand_index, target });
+                    self.report.lock().unwrap().grabs += 1;
+                }
+            }
+        }
+    }
+
+    /// Sweeps each hand around a small circle at chest height, half a turn out of phase with the
+    /// other hand, bobbing up and down so the motion isn't planar.
+    fn hand_transform(&self, hand_index: usize, now: Instant) -> Isometry<f32> {
+        let t = now
+            .saturating_duration_since(self.motion_start)
+            .as_secs_f32();
+        let angle = TAU * HAND_FREQUENCY * t + PI * hand_index as f32;
+        let side = if hand_index == 0 { -0.2 } else { 0.2 };
+        let translation = self.position
+            + vector![
+                side + 0.15 * angle.cos(),
+                1.2 + 0.1 * (0.5 * angle).sin(),
+                -0.3 + 0.15 * angle.sin()
+            ];
+        Isometry::new(translation, vector![0.0, angle, 0.0])
+    }
+
+    fn send_voice(&mut self, voice: &[Vec<u8>]) {
+        if self.client.player_id().is_none() {
+            return;
+        }
+        let data = voice[self.next_voice_frame].clone();
+        self.next_voice_frame = (self.next_voice_frame + 1) % voice.len();
+        let _ = self
+            .client
+            .session()
+            .try_send_request(Request::SendVoice(data));
+    }
+}
@@ -0,0 +1,173 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::Parser;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use tokio::select;
+use tokio::time::{interval, interval_at, sleep, Instant};
+
+use crate::bot::{run_bot, BotConfig, BotReport};
+use crate::report::{log_summary, print_report};
+
+mod bot;
+mod report;
+mod voice;
+
+/// Connects many headless players to a server to see how it holds up. Each bot waves its hands
+/// around, grabs and drops objects, and optionally talks. On exit, prints each bot's round-trip
+/// time, snapshot rate and bandwidth.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    /// Connects to a remote server at this ip:port.
+    connect: String,
+
+    /// Number of bots to run. The server must allow this many players for all of them to join.
+    #[clap(long, default_value = "100")]
+    bots: usize,
+
+    /// Milliseconds between starting one bot and the next, up to a minute, so handshakes don't all
+    /// land at once.
+    #[clap(long, default_value = "50", value_parser = clap::value_parser!(u64).range(1..=60_000))]
+    spawn_interval_ms: u64,
+
+    /// Stops after this many seconds. Runs until Ctrl+C otherwise.
+    #[clap(long)]
+    duration_secs: Option<u64>,
+
+    /// Mean seconds between one bot's grabs and drops, up to an hour.
+    #[clap(long, default_value = "5", value_parser = parse_grab_interval)]
+    grab_interval_secs: Duration,
+
+    /// Streams canned Opus voice from every bot.
+    #[clap(long)]
+    voice: bool,
+
+    /// Seeds the bots' random choices so runs can be repeated.
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Seconds between progress summaries, up to a day.
+    #[clap(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..=86_400))]
+    report_interval_secs: u64,
+}
+
+/// The longest mean grab interval. Bots add random multiples of it to the current time, which
+/// must not overflow.
+const MAX_GRAB_INTERVAL: Duration = Duration::from_secs(3600);
+
+fn parse_grab_interval(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    // NaN is not contained in any range.
+    if (0.0..=MAX_GRAB_INTERVAL.as_secs_f64()).contains(&secs) {
+        Ok(Duration::from_secs_f64(secs))
+    } else {
+        Err(format!(
+            "{secs} is not between 0 and {}",
+            MAX_GRAB_INTERVAL.as_secs()
+        ))
+    }
+}
+
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    // Hundreds of connections make the other crates' logs too noisy to read.
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Warn)
+        .filter_module("dungeon_vr_bots", log::LevelFilter::Info)
+        .format_target(false)
+        .format_timestamp_micros()
+        .parse_default_env()
+        .init();
+    let args = Args::parse();
+
+    let config = Arc::new(BotConfig {
+        server_addr: SocketAddr::from_str(&args.connect)?,
+        grab_interval: args.grab_interval_secs,
+        voice: if args.voice {
+            Some(voice::canned_voice()?)
+        } else {
+            None
+        },
+    });
+
+    let cancel_token = set_ctrlc_handler();
+    if let Some(duration_secs) = args.duration_secs {
+        let cancel_token = cancel_token.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(duration_secs)).await;
+            log::info!("Ran for {duration_secs} seconds; shutting down");
+            cancel_token.cancel();
+        });
+    }
+
+    let mut reports = Vec::with_capacity(args.bots);
+    let mut tasks = Vec::with_capacity(args.bots);
+    let mut spawn_interval = interval(Duration::from_millis(args.spawn_interval_ms));
+    let report_interval_duration = Duration::from_secs(args.report_interval_secs);
+    let mut report_interval = interval_at(
+        Instant::now() + report_interval_duration,
+        report_interval_duration,
+    );
+    while !cancel_token.is_cancelled() {
+        select! {
+            biased;
+
+            _ = cancel_token.cancelled() => break,
+
+            _ = spawn_interval.tick(), if tasks.len() < args.bots => {
+                let index = tasks.len();
+                let rng = match args.seed {
+                    Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(index as u64)),
+                    None => StdRng::from_entropy(),
+                };
+                let report = Arc::new(Mutex::new(BotReport::default()));
+                reports.push(Arc::clone(&report));
+                tasks.push(tokio::spawn({
+                    let config = Arc::clone(&config);
+                    let cancel_token = cancel_token.clone();
+                    async move {
+                        if let Err(e) = run_bot(config, rng, report, cancel_token).await {
+                            log::warn!("Bot {index}: Unable to start: {e}");
+                        }
+                    }
+                }));
+                if tasks.len() == args.bots {
+                    log::info!("Started all {} bots", args.bots);
+                }
+            }
+
+            _ = report_interval.tick() => log_summary(&snapshot_reports(&reports), Instant::now()),
+        }
+    }
+
+    for task in tasks {
+        task.await?;
+    }
+    print_report(&snapshot_reports(&reports), Instant::now());
+    Ok(())
+}
+
+fn snapshot_reports(reports: &[Arc<Mutex<BotReport>>]) -> Vec<BotReport> {
+    reports
+        .iter()
+        .map(|report| report.lock().unwrap().clone())
+        .collect()
+}
+
+fn set_ctrlc_handler() -> cancel::Token {
+    let cancel_token = cancel::Token::new();
+    ctrlc::set_handler({
+        let cancel_token = cancel_token.clone();
+        move || {
+            log::info!("Caught Ctrl+C; shutting down");
+            cancel_token.cancel();
+        }
+    })
+    .unwrap();
+    cancel_token
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::bot::BotReport;
+
+/// Rates measured by one bot over its time in a session.
+struct Rates {
+    snapshots_per_sec: f64,
+    kbps_up: f64,
+    kbps_down: f64,
+}
+
+impl Rates {
+    fn new(report: &BotReport, now: Instant) -> Option<Self> {
+        let secs = report.elapsed(now)?.as_secs_f64();
+        if secs <= 0.0 {
+            return None;
+        }
+        Some(Self {
+            snapshots_per_sec: report.snapshots as f64 / secs,
+            kbps_up: report.bytes_sent as f64 * 8.0 / 1000.0 / secs,
+            kbps_down: report.bytes_received as f64 * 8.0 / 1000.0 / secs,
+        })
+    }
+}
+
+/// Aggregates across every bot that has been in a session.
+struct Summary {
+    connected: usize,
+    total: usize,
+    rtt_p50: Option<Duration>,
+    rtt_p95: Option<Duration>,
+    rtt_max: Option<Duration>,
+    mean_snapshots_per_sec: Option<f64>,
+    min_snapshots_per_sec: Option<f64>,
+    kbps_up: f64,
+    kbps_down: f64,
+}
+
+impl Summary {
+    fn new(reports: &[BotReport], now: Instant) -> Self {
+        let mut rtts: Vec<_> = reports.iter().filter_map(|report| report.rtt).collect();
+        rtts.sort();
+        let rates: Vec<_> = reports
+            .iter()
+            .filter_map(|report| Rates::new(report, now))
+            .collect();
+        let snapshot_rates = rates.iter().map(|rates| rates.snapshots_per_sec);
+        Self {
+            connected: reports
+                .iter()
+                .filter(|report| report.player_id.is_some())
+                .count(),
+            total: reports.len(),
+            rtt_p50: percentile(&rtts, 0.5),
+            rtt_p95: percentile(&rtts, 0.95),
+            rtt_max: rtts.last().copied(),
+            mean_snapshots_per_sec: snapshot_rates
+                .clone()
+                .reduce(|a, b| a + b)
+                .map(|sum| sum / rates.len() as f64),
+            min_snapshots_per_sec: snapshot_rates.reduce(f64::min),
+            // Starting from zero rather than using `sum`, which gives -0.0 for no bots.
+            kbps_up: rates.iter().fold(0.0, |sum, rates| sum + rates.kbps_up),
+            kbps_down: rates.iter().fold(0.0, |sum, rates| sum + rates.kbps_down),
+        }
+    }
+}
+
+/// Picks the value `fraction` of the way through `sorted`, which must be sorted.
+fn percentile(sorted: &[Duration], fraction: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    Some(sorted[index])
+}
+
+fn format_ms(duration: Option<Duration>) -> String {
+    format_rate(duration.map(|duration| duration.as_secs_f64() * 1000.0))
+}
+
+fn format_rate(rate: Option<f64>) -> String {
+    match rate {
+        Some(rate) => format!("{rate:.1}"),
+        None => "-".to_string(),
+    }
+}
+
+/// Logs a one-line summary of how the bots are doing.
+pub fn log_summary(reports: &[BotReport], now: Instant) {
+    let summary = Summary::new(reports, now);
+    log::info!(
+        "{}/{} bots in a session; RTT p50 {} ms, p95 {} ms; {} snapshots/s mean, {} min; \
+         {:.0} kbit/s up, {:.0} kbit/s down",
+        summary.connected,
+        summary.total,
+        format_ms(summary.rtt_p50),
+        format_ms(summary.rtt_p95),
+        format_rate(summary.mean_snapshots_per_sec),
+        format_rate(summary.min_snapshots_per_sec),
+        summary.kbps_up,
+        summary.kbps_down,
+    );
+}
+
+/// Prints each bot's measurements as a table, followed by aggregates across all bots. Up and down
+/// are from the bots' side, so the totals are the server's download and upload respectively.
+pub fn print_report(reports: &[BotReport], now: Instant) {
+    println!(
+        "{:>5} {:>6} {:>8} {:>8} {:>11} {:>10} {:>12} {:>6} {:>6}",
+        "Bot",
+        "Player",
+        "Sessions",
+        "RTT (ms)",
+        "Snapshots/s",
+        "Up kbit/s",
+        "Down kbit/s",
+        "Lost",
+        "Grabs",
+    );
+    for (index, report) in reports.iter().enumerate() {
+        let player = match report.player_id {
+            Some(player_id) => player_id.0.to_string(),
+            None => "-".to_string(),
+        };
+        let (snapshots_per_sec, kbps_up, kbps_down) = match Rates::new(report, now) {
+            Some(rates) => (
+                format_rate(Some(rates.snapshots_per_sec)),
+                format_rate(Some(rates.kbps_up)),
+                format_rate(Some(rates.kbps_down)),
+            ),
+            None => ("-".to_string(), "-".to_string(), "-".to_string()),
+        };
+        println!(
+            "{:>5} {:>6} {:>8} {:>8} {:>11} {:>10} {:>12} {:>6} {:>6}",
+            index,
+            player,
+            report.sessions,
+            format_ms(report.rtt),
+            snapshots_per_sec,
+            kbps_up,
+            kbps_down,
+            report.packets_lost,
+            report.grabs,
+        );
+    }
+
+    let summary = Summary::new(reports, now);
+    println!();
+    println!(
+        "Bots in a session: {} of {}",
+        summary.connected, summary.total,
+    );
+    println!(
+        "RTT (ms): p50 {}, p95 {}, max {}",
+        format_ms(summary.rtt_p50),
+        format_ms(summary.rtt_p95),
+        format_ms(summary.rtt_max),
+    );
+    println!(
+        "Snapshots/s: mean {}, min {}",
+        format_rate(summary.mean_snapshots_per_sec),
+        format_rate(summary.min_snapshots_per_sec),
+    );
+    println!(
+        "Total bandwidth (kbit/s): {:.1} up, {:.1} down",
+        summary.kbps_up, summary.kbps_down,
+    );
+}
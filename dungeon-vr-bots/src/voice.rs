@@ -0,0 +1,33 @@
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use anyhow::Result;
+
+const SAMPLE_RATE: u32 = 48000;
+const SAMPLES_PER_FRAME: usize = 960;
+const MAX_PACKET_SIZE: usize = 1024;
+const FRAME_COUNT: usize = 100;
+
+/// How often a talking client sends a voice packet: one 20 ms frame at a time, like the voice chat
+/// client.
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Encodes two seconds of a warbling tone to loop as every bot's voice. A tone whose pitch keeps
+/// moving takes about as many bits to encode as speech, unlike silence or a steady note.
+pub fn canned_voice() -> Result<Vec<Vec<u8>>> {
+    let mut encoder =
+        opus::Encoder::new(SAMPLE_RATE, opus::Channels::Mono, opus::Application::Voip)?;
+    let mut phase = 0.0f32;
+    let mut frames = Vec::with_capacity(FRAME_COUNT);
+    for frame_index in 0..FRAME_COUNT {
+        let mut samples = Vec::with_capacity(SAMPLES_PER_FRAME);
+        for sample_index in 0..SAMPLES_PER_FRAME {
+            let t = (frame_index * SAMPLES_PER_FRAME + sample_index) as f32 / SAMPLE_RATE as f32;
+            let pitch = 220.0 + 80.0 * (TAU * 3.0 * t).sin();
+            phase = (phase + TAU * pitch / SAMPLE_RATE as f32) % TAU;
+            samples.push(0.25 * phase.sin());
+        }
+        frames.push(encoder.encode_vec_float(&samples, MAX_PACKET_SIZE)?);
+    }
+    Ok(frames)
+}
@@ -3,33 +3,52 @@ use std::io;
 use std::pin::Pin;
 
 use dungeon_vr_connection_shared::challenge_token::ChallengeToken;
-use dungeon_vr_connection_shared::connect_challenge_packet::ConnectChallengePacket;
+use dungeon_vr_connection_shared::connect_challenge_packet::{
+    signed_message, ConnectChallengePacket,
+};
 use dungeon_vr_connection_shared::connect_init_packet::ConnectInitPacket;
+use dungeon_vr_connection_shared::connect_response_packet::ConnectResponsePacket;
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::fragment::{FragmentPacket, Fragmenter, Reassembler};
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
+use dungeon_vr_connection_shared::keylog::log_shared_secret;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_connection_shared::sealed::Sealed;
+use dungeon_vr_connection_shared::protocol_version::ProtocolVersions;
+use dungeon_vr_connection_shared::reliable::{AckPacket, GameDataPacket, ReliabilityState};
+use dungeon_vr_connection_shared::sealed::{Role, Sealed, SealingKey};
+use dungeon_vr_connection_shared::stats::StatsTracker;
 use dungeon_vr_connection_shared::{GAME_ID, SAFE_RECV_BUFFER_SIZE};
-use dungeon_vr_cryptography::{KeyExchangeError, PrivateKey, PublicKey, SharedSecret};
+use dungeon_vr_cryptography::{KeyExchangeError, PrivateKey, PublicKey};
 use dungeon_vr_socket::ConnectedSocket;
-use dungeon_vr_stream_codec::{StreamCodec, UnframedByteVec};
+use dungeon_vr_stream_codec::StreamCodec;
 use futures::FutureExt;
 use tokio::select;
 use tokio::sync::mpsc;
-use tokio::time::{interval, sleep, Duration, Instant, Interval, Sleep};
+use tokio::time::{interval, sleep, Instant, Interval, Sleep};
+
+pub use dungeon_vr_connection_shared::config::{
+    ConnectionConfig, ConnectionConfigBuilder, ConnectionConfigError,
+};
+pub use dungeon_vr_connection_shared::protocol_version::ContentHash;
+pub use dungeon_vr_connection_shared::reject_reason::RejectReason;
+pub use dungeon_vr_connection_shared::reliable::Channel;
+pub use dungeon_vr_connection_shared::stats::ConnectionStats;
+pub use dungeon_vr_connection_shared::DEFAULT_MTU;
+pub use dungeon_vr_cryptography::VerifyingKey;
 
 #[cfg(test)]
 mod testing;
 #[cfg(test)]
 mod tests;
 
-const CONNECTING_RESPONDING_SEND_INTERVAL: Duration = Duration::from_millis(250);
-const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
-const SERVER_TIMEOUT_INTERVAL: Duration = Duration::from_secs(5);
-const REQUEST_BUFFER_SIZE: usize = 256;
-const EVENT_BUFFER_SIZE: usize = 256;
-
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Request {
-    SendGameData(Vec<u8>),
+    SendGameData {
+        channel: Channel,
+        data: Vec<u8>,
+    },
+    /// Asks for an [`Event::Stats`] describing the connection.
+    QueryStats,
 }
 
 pub struct ConnectionClient {
@@ -37,7 +56,13 @@ pub struct ConnectionClient {
     requests: Option<mpsc::Receiver<Request>>,
     events: mpsc::Sender<Event>,
     recv_buffer: Pin<Box<[u8; SAFE_RECV_BUFFER_SIZE]>>,
+    config: ConnectionConfig,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    server_key: Option<VerifyingKey>,
+    content_hash: Option<ContentHash>,
     timeout: Option<Pin<Box<Sleep>>>,
+    stats: StatsTracker,
     variant: Variant,
 }
 
@@ -48,11 +73,14 @@ enum InternalEvent {
     ServerTimeout,
     SendIntervalElapsed,
     KeepaliveElapsed,
+    ResendElapsed,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ConnectionState {
     Disconnected,
+    /// Performing the handshake. A connected client that stops hearing from the server comes back
+    /// here to reconnect.
     Connecting,
     Responding,
     Connected,
@@ -61,7 +89,19 @@ pub enum ConnectionState {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Event {
     State(ConnectionState),
+    /// The handshake settled on a protocol version for all game data. Sent before the connection
+    /// reaches [`ConnectionState::Responding`].
+    Negotiated {
+        protocol_version: u16,
+    },
+    /// The server turned the connection away. The connection is now disconnected.
+    Rejected(RejectReason),
+    /// The server explained why it is ending the connection. Followed by
+    /// `State(ConnectionState::Disconnected)`.
+    DisconnectReason(String),
     GameData(Vec<u8>),
+    /// Answers a [`Request::QueryStats`].
+    Stats(ConnectionStats),
     Dropped,
 }
 
@@ -71,14 +111,29 @@ enum ConfirmConnectionResult {
 }
 
 impl ConnectionClient {
+    /// Spawns a client on `socket`, timed and sized according to `config`. If a `server_key` is
+    /// pinned, the client only completes a
+    /// handshake that the server has signed with the matching
+    /// [`SigningKey`](dungeon_vr_cryptography::SigningKey). The `content_hash`, if any, is
+    /// presented to servers that require one.
     pub fn spawn(
         socket: Box<dyn ConnectedSocket>,
+        config: ConnectionConfig,
+        server_key: Option<VerifyingKey>,
+        content_hash: Option<ContentHash>,
     ) -> (cancel::Guard, mpsc::Sender<Request>, mpsc::Receiver<Event>) {
         let cancel_token = cancel::Token::new();
-        let (requests_tx, requests_rx) = mpsc::channel(REQUEST_BUFFER_SIZE);
-        let (events_tx, events_rx) = mpsc::channel(EVENT_BUFFER_SIZE);
+        let (requests_tx, requests_rx) = mpsc::channel(config.request_buffer_size());
+        let (events_tx, events_rx) = mpsc::channel(config.event_buffer_size());
 
-        let connection = Self::new(socket, requests_rx, events_tx);
+        let connection = Self::new(
+            socket,
+            config,
+            server_key,
+            content_hash,
+            requests_rx,
+            events_tx,
+        );
         tokio::spawn(connection.run(cancel_token.clone()));
 
         (cancel_token.guard(), requests_tx, events_rx)
@@ -86,24 +141,26 @@ impl ConnectionClient {
 
     fn new(
         socket: Box<dyn ConnectedSocket>,
+        config: ConnectionConfig,
+        server_key: Option<VerifyingKey>,
+        content_hash: Option<ContentHash>,
         requests: mpsc::Receiver<Request>,
         events: mpsc::Sender<Event>,
     ) -> Self {
         log::debug!("Connection state: connecting");
-        let client_private_key = PrivateKey::gen();
-        let client_public_key = client_private_key.to_public();
-        let connect_state = Variant::Connecting {
-            client_private_key,
-            client_public_key,
-            send_interval: interval(CONNECTING_RESPONDING_SEND_INTERVAL),
-        };
         Self {
             socket,
             requests: Some(requests),
             events,
             recv_buffer: Box::pin([0; SAFE_RECV_BUFFER_SIZE]),
-            timeout: Some(Box::pin(sleep(SERVER_TIMEOUT_INTERVAL))),
-            variant: connect_state,
+            fragmenter: Fragmenter::new(config.mtu()),
+            reassembler: Reassembler::new(config.reassembly_timeout()),
+            server_key,
+            content_hash,
+            timeout: Some(Box::pin(sleep(config.timeout()))),
+            stats: StatsTracker::new(),
+            variant: Variant::connecting(&config),
+            config,
         }
     }
 
@@ -148,6 +205,7 @@ impl ConnectionClient {
                 InternalEvent::ServerTimeout => self.handle_server_timeout().await,
                 InternalEvent::SendIntervalElapsed => self.handle_send_interval_elapsed().await,
                 InternalEvent::KeepaliveElapsed => self.handle_keepalive_elapsed().await,
+                InternalEvent::ResendElapsed => self.handle_resend_elapsed().await,
             }
         }
 
@@ -179,26 +237,66 @@ impl ConnectionClient {
 
     async fn handle_request(&mut self, request: Request) {
         match request {
-            Request::SendGameData(data) => self.handle_send_game_data_request(data).await,
+            Request::SendGameData { channel, data } => {
+                self.handle_send_game_data_request(channel, data).await
+            }
+            Request::QueryStats => self.handle_query_stats_request().await,
         }
     }
 
-    async fn handle_send_game_data_request(&mut self, data: Vec<u8>) {
-        let shared_secret = match &self.variant {
-            Variant::Connected { shared_secret, .. } => shared_secret,
+    async fn handle_send_game_data_request(&mut self, channel: Channel, data: Vec<u8>) {
+        let (key, reliability) = match &mut self.variant {
+            Variant::Connected {
+                key, reliability, ..
+            } => (key, reliability),
             _ => {
                 log::debug!("Dropping outgoing game data: not connected");
                 return;
             }
         };
-        send_packet(
+        let packet = match reliability.send(channel, data) {
+            Ok(Some(packet)) => packet,
+            // Held back until the server acknowledges earlier messages.
+            Ok(None) => return,
+            Err(e) => {
+                // The server has stopped acknowledging, so give up on it rather than queue without
+                // bound.
+                send_packet(
+                    &*self.socket,
+                    &mut self.stats,
+                    Packet::Disconnect(key.seal(DisconnectPacket::default())),
+                )
+                .await;
+                self.timeout = None;
+                self.variant = Variant::Disconnected;
+                log::warn!("Connection state: disconnected ({e})");
+                let _ = self
+                    .events
+                    .send(Event::State(ConnectionState::Disconnected))
+                    .await;
+                return;
+            }
+        };
+        if send_game_data_packet(
             &*self.socket,
-            Packet::GameData(Sealed::seal_ext::<UnframedByteVec>(data, shared_secret)),
+            &mut self.stats,
+            &mut self.fragmenter,
+            packet,
+            key,
         )
-        .await;
+        .await
+        {
+            self.refresh_keepalive();
+        }
+    }
+
+    async fn handle_query_stats_request(&mut self) {
+        let stats = self.stats.snapshot(self.variant.key());
+        let _ = self.events.send(Event::Stats(stats)).await;
     }
 
     async fn handle_socket_recv(&mut self, size: usize) {
+        self.stats.record_received(size);
         let mut r = &self.recv_buffer[..size];
         let packet = match Packet::read_from(&mut r) {
             Ok(packet) => packet,
@@ -217,45 +315,99 @@ impl ConnectionClient {
         }
         match packet {
             Packet::Disconnect(packet) => self.handle_disconnect_packet(packet).await,
+            Packet::Reject(packet) => self.handle_reject_packet(packet).await,
             Packet::ConnectChallenge(packet) => self.handle_connect_challenge_packet(packet).await,
             Packet::Keepalive(packet) => self.handle_keepalive_packet(packet).await,
             Packet::GameData(packet) => self.handle_game_data_packet(packet).await,
+            Packet::Fragment(packet) => self.handle_fragment_packet(packet).await,
+            Packet::Ack(packet) => self.handle_ack_packet(packet),
             _ => {
                 log::debug!("Dropping unsupported {:?} packet", packet.kind());
             }
         }
     }
 
-    async fn handle_disconnect_packet(&mut self, packet: Sealed<()>) {
-        let shared_secret = match self.variant.shared_secret() {
-            Some(shared_secret) => shared_secret,
+    async fn handle_disconnect_packet(&mut self, packet: Sealed<DisconnectPacket>) {
+        let key = match self.variant.key_mut() {
+            Some(key) => key,
             None => {
                 log::debug!("Dropping Disconnect packet: no shared secret");
                 return;
             }
         };
-        if let Err(e) = packet.open(shared_secret) {
-            log::debug!("Dropping Disconnect packet: {e}");
-            return;
-        }
-        log::info!("Connection state: disconnected");
+        let packet = match key.open(&packet) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!("Dropping Disconnect packet: {e}");
+                return;
+            }
+        };
+        self.timeout = None;
         self.variant = Variant::Disconnected;
+        match packet.reason {
+            Some(reason) => {
+                log::info!("Connection state: disconnected ({reason})");
+                let _ = self.events.send(Event::DisconnectReason(reason)).await;
+            }
+            None => log::info!("Connection state: disconnected"),
+        }
         let _ = self
             .events
             .send(Event::State(ConnectionState::Disconnected))
             .await;
     }
 
+    async fn handle_reject_packet(&mut self, packet: Sealed<RejectReason>) {
+        let key = match self.variant.key_mut() {
+            Some(key) => key,
+            None => {
+                log::debug!("Dropping Reject packet: no shared secret");
+                return;
+            }
+        };
+        let reason = match key.open(&packet) {
+            Ok(reason) => reason,
+            Err(e) => {
+                log::debug!("Dropping Reject packet: {e}");
+                return;
+            }
+        };
+        log::info!("Connection state: disconnected (rejected: {reason})");
+        self.timeout = None;
+        self.variant = Variant::Disconnected;
+        let _ = self.events.send(Event::Rejected(reason)).await;
+    }
+
     async fn handle_connect_challenge_packet(&mut self, packet: ConnectChallengePacket) {
-        let client_private_key = match &self.variant {
+        let (client_private_key, client_public_key) = match &self.variant {
             Variant::Connecting {
-                client_private_key, ..
-            } => client_private_key,
+                client_private_key,
+                client_public_key,
+                ..
+            } => (client_private_key, client_public_key),
             _ => {
                 log::debug!("Dropping ConnectChallenge packet: wrong connection state");
                 return;
             }
         };
+        if let Some(server_key) = &self.server_key {
+            let signature = match &packet.signature {
+                Some(signature) => signature,
+                None => {
+                    log::debug!("Dropping ConnectChallenge packet: server did not sign it");
+                    return;
+                }
+            };
+            let message = signed_message(
+                client_public_key,
+                &packet.server_public_key,
+                packet.protocol_versions,
+            );
+            if let Err(e) = server_key.verify(&message, signature) {
+                log::debug!("Dropping ConnectChallenge packet: {e}");
+                return;
+            }
+        }
         let shared_secret = match client_private_key.exchange(&packet.server_public_key) {
             Ok(shared_secret) => shared_secret,
             Err(KeyExchangeError::NonContributory) => {
@@ -263,37 +415,128 @@ impl ConnectionClient {
                 return;
             }
         };
-        let token = match packet.sealed_payload.open(&shared_secret) {
+        // The server seals the challenge outside of the session's sequence numbering because it
+        // keeps no state to continue from, so this is not subject to replay protection.
+        let token = match packet.sealed_payload.open(Role::Server, &shared_secret) {
             Ok(token) => token,
             Err(e) => {
                 log::debug!("Dropping invalid ConnectChallenge packet: {e}");
                 return;
             }
         };
-        log::debug!("Connection state: responding to challenge");
+        // Negotiation is symmetric, so if there is no common version, the server will reject the
+        // connection anyway.
+        let protocol_version = match ProtocolVersions::SUPPORTED.negotiate(packet.protocol_versions)
+        {
+            Some(protocol_version) => protocol_version,
+            None => {
+                log::info!(
+                    "Connection state: disconnected (server speaks protocol versions {}..={})",
+                    packet.protocol_versions.min,
+                    packet.protocol_versions.max,
+                );
+                self.timeout = None;
+                self.variant = Variant::Disconnected;
+                let _ = self
+                    .events
+                    .send(Event::Rejected(RejectReason::VersionMismatch))
+                    .await;
+                return;
+            }
+        };
+        if let Err(e) = log_shared_secret(&shared_secret) {
+            log::warn!("Unable to write key log: {e}");
+        }
+        log::debug!(
+            "Connection state: responding to challenge (protocol version {protocol_version})"
+        );
         self.variant = Variant::Responding {
-            shared_secret,
+            key: SealingKey::new(shared_secret, Role::Client),
             token,
-            send_interval: interval(CONNECTING_RESPONDING_SEND_INTERVAL),
+            send_interval: interval(self.config.send_interval()),
         };
+        let _ = self
+            .events
+            .send(Event::Negotiated { protocol_version })
+            .await;
         let _ = self
             .events
             .send(Event::State(ConnectionState::Responding))
             .await;
     }
 
-    async fn handle_keepalive_packet(&mut self, packet: Sealed<()>) {
-        let shared_secret = match self.variant.shared_secret() {
-            Some(shared_secret) => shared_secret,
+    async fn handle_keepalive_packet(&mut self, packet: Sealed<KeepalivePacket>) {
+        let key = match self.variant.key_mut() {
+            Some(key) => key,
             None => {
                 log::debug!("Dropping Keepalive packet: no shared secret");
                 return;
             }
         };
-        if let Err(e) = packet.open(shared_secret) {
-            eprintln!("Dropping Keepalive packet: {e}");
-            return;
+        let packet = match key.open(&packet) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!("Dropping Keepalive packet: {e}");
+                return;
+            }
+        };
+        self.handle_authenticated_packet().await;
+        match packet {
+            KeepalivePacket::Ping { id } => {
+                let key = self.variant.key_mut().unwrap();
+                let packet = Packet::Keepalive(key.seal(KeepalivePacket::Pong { id }));
+                send_packet(&*self.socket, &mut self.stats, packet).await;
+                self.refresh_keepalive();
+            }
+            KeepalivePacket::Pong { id } => self.stats.pong(id, Instant::now().into_std()),
         }
+    }
+
+    async fn handle_game_data_packet(&mut self, packet: Sealed<GameDataPacket>) {
+        let key = match self.variant.key_mut() {
+            Some(key) => key,
+            None => {
+                log::debug!("Dropping GameData packet: no shared secret");
+                return;
+            }
+        };
+        let packet = match key.open(&packet) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!("Dropping GameData packet: {e}");
+                return;
+            }
+        };
+        self.handle_authenticated_packet().await;
+        self.receive_game_data(packet).await;
+    }
+
+    async fn handle_fragment_packet(&mut self, packet: Sealed<FragmentPacket>) {
+        let key = match self.variant.key_mut() {
+            Some(key) => key,
+            None => {
+                log::debug!("Dropping Fragment packet: no shared secret");
+                return;
+            }
+        };
+        let fragment = match key.open(&packet) {
+            Ok(fragment) => fragment,
+            Err(e) => {
+                log::debug!("Dropping Fragment packet: {e}");
+                return;
+            }
+        };
+        self.handle_authenticated_packet().await;
+        match self.reassembler.insert(fragment, Instant::now().into_std()) {
+            Ok(Some(packet)) => self.receive_game_data(packet).await,
+            Ok(None) => (),
+            Err(e) => log::debug!("Dropping Fragment packet: {e}"),
+        }
+    }
+
+    /// Refreshes the timeout and confirms the connection after opening any sealed packet from the
+    /// server.
+    async fn handle_authenticated_packet(&mut self) {
         self.refresh_timeout();
         if let ConfirmConnectionResult::Connected = self.confirm_connection() {
             let _ = self
@@ -303,32 +546,60 @@ impl ConnectionClient {
         }
     }
 
-    async fn handle_game_data_packet(&mut self, packet: Sealed<Vec<u8>>) {
-        let shared_secret = match self.variant.shared_secret() {
-            Some(shared_secret) => shared_secret,
-            None => {
-                log::debug!("Dropping GameData packet: no shared secret");
+    /// Handles a GameData packet that arrived whole or was reassembled from fragments.
+    async fn receive_game_data(&mut self, packet: GameDataPacket) {
+        let (key, reliability) = match &mut self.variant {
+            Variant::Connected {
+                key, reliability, ..
+            } => (key, reliability),
+            _ => unreachable!(),
+        };
+        let received = reliability.receive(packet);
+        if let Some(ack) = received.ack {
+            send_packet(&*self.socket, &mut self.stats, Packet::Ack(key.seal(ack))).await;
+            self.refresh_keepalive();
+        }
+        for data in received.messages {
+            let _ = self.events.send(Event::GameData(data)).await;
+        }
+    }
+
+    fn handle_ack_packet(&mut self, packet: Sealed<AckPacket>) {
+        let (key, reliability) = match &mut self.variant {
+            Variant::Connected {
+                key, reliability, ..
+            } => (key, reliability),
+            _ => {
+                log::debug!("Dropping Ack packet: not connected");
                 return;
             }
         };
-        let data = match packet.open_ext::<UnframedByteVec>(shared_secret) {
-            Ok(data) => data,
+        let ack = match key.open(&packet) {
+            Ok(ack) => ack,
             Err(e) => {
-                eprintln!("Dropping GameData packet: {e}");
+                log::debug!("Dropping Ack packet: {e}");
                 return;
             }
         };
+        reliability.handle_ack(ack);
         self.refresh_timeout();
-        if let ConfirmConnectionResult::Connected = self.confirm_connection() {
+    }
+
+    async fn handle_server_timeout(&mut self) {
+        let reconnect = !self.config.reconnect_timeout().is_zero();
+        if reconnect && matches!(self.variant, Variant::Connected { .. }) {
+            // The outage may be brief, so start over with a fresh handshake.
+            self.timeout = Some(Box::pin(sleep(self.config.reconnect_timeout())));
+            self.reassembler = Reassembler::new(self.config.reassembly_timeout());
+            self.variant = Variant::connecting(&self.config);
+            log::info!("Connection state: reconnecting (timed out)");
             let _ = self
                 .events
-                .send(Event::State(ConnectionState::Connected))
+                .send(Event::State(ConnectionState::Connecting))
                 .await;
+            return;
         }
-        let _ = self.events.send(Event::GameData(data)).await;
-    }
 
-    async fn handle_server_timeout(&mut self) {
         self.timeout = None;
         self.variant = Variant::Disconnected;
         log::info!("Connection state: disconnected (timed out)");
@@ -339,27 +610,30 @@ impl ConnectionClient {
     }
 
     async fn handle_send_interval_elapsed(&mut self) {
-        match &self.variant {
+        match &mut self.variant {
             Variant::Connecting {
                 client_public_key, ..
             } => {
                 send_packet(
                     &*self.socket,
+                    &mut self.stats,
                     Packet::ConnectInit(ConnectInitPacket {
                         game_id: GAME_ID,
+                        protocol_versions: ProtocolVersions::SUPPORTED,
+                        content_hash: self.content_hash,
                         client_public_key: *client_public_key,
                     }),
                 )
                 .await;
             }
-            Variant::Responding {
-                shared_secret,
-                token,
-                ..
-            } => {
+            Variant::Responding { key, token, .. } => {
                 send_packet(
                     &*self.socket,
-                    Packet::ConnectResponse(Sealed::seal(*token, shared_secret)),
+                    &mut self.stats,
+                    Packet::ConnectResponse(ConnectResponsePacket {
+                        token: *token,
+                        sealed_payload: key.seal(()),
+                    }),
                 )
                 .await;
             }
@@ -368,22 +642,50 @@ impl ConnectionClient {
     }
 
     async fn handle_keepalive_elapsed(&mut self) {
-        let shared_secret = self.variant.shared_secret().unwrap();
+        let key = self.variant.key_mut().unwrap();
+        let ping = self.stats.ping(Instant::now().into_std());
         send_packet(
             &*self.socket,
-            Packet::Keepalive(Sealed::seal((), shared_secret)),
+            &mut self.stats,
+            Packet::Keepalive(key.seal(ping)),
         )
         .await;
         self.refresh_keepalive();
     }
 
+    async fn handle_resend_elapsed(&mut self) {
+        let (key, reliability) = match &mut self.variant {
+            Variant::Connected {
+                key, reliability, ..
+            } => (key, reliability),
+            _ => unreachable!(),
+        };
+        let mut sent = false;
+        for packet in reliability.retransmissions() {
+            sent |= send_game_data_packet(
+                &*self.socket,
+                &mut self.stats,
+                &mut self.fragmenter,
+                packet,
+                key,
+            )
+            .await;
+        }
+        if sent {
+            self.refresh_keepalive();
+        }
+    }
+
     fn confirm_connection(&mut self) -> ConfirmConnectionResult {
-        match self.variant {
-            Variant::Responding { shared_secret, .. } => {
+        match &self.variant {
+            Variant::Responding { key, .. } => {
                 log::info!("Connection state: connected");
+                let key = key.clone();
                 self.variant = Variant::Connected {
-                    shared_secret,
-                    keepalive: Box::pin(sleep(KEEPALIVE_INTERVAL)),
+                    key,
+                    keepalive: Box::pin(sleep(self.config.keepalive_interval())),
+                    reliability: ReliabilityState::new(),
+                    resend_interval: interval(self.config.resend_interval()),
                 };
                 ConfirmConnectionResult::Connected
             }
@@ -396,28 +698,50 @@ impl ConnectionClient {
         if let Some(timeout) = &mut self.timeout {
             timeout
                 .as_mut()
-                .reset(Instant::now() + SERVER_TIMEOUT_INTERVAL);
+                .reset(Instant::now() + self.config.timeout());
         }
     }
 
-    /// Extends the keepalive timer after sending a packet to the server.
+    /// Extends the keepalive timer after sending a sealed packet to the server. Only connected
+    /// clients send keepalives.
     fn refresh_keepalive(&mut self) {
-        match &mut self.variant {
-            Variant::Connected { keepalive, .. } => keepalive
+        if let Variant::Connected { keepalive, .. } = &mut self.variant {
+            keepalive
                 .as_mut()
-                .reset(Instant::now() + KEEPALIVE_INTERVAL),
-            _ => unreachable!(),
+                .reset(Instant::now() + self.config.keepalive_interval());
         }
     }
 }
 
-async fn send_packet(socket: &dyn ConnectedSocket, packet: Packet) {
+async fn send_packet(socket: &dyn ConnectedSocket, stats: &mut StatsTracker, packet: Packet) {
     let mut w = Vec::new();
     packet.write_to(&mut w).unwrap();
     // NOTE: Ignore any error. Windows in particular returns errors based on previous ICMP activity.
     // These are not useful. If connectivity is disrupted, the timeout mechanism will eventually
     // notice.
     _ = socket.send(&w).await;
+    stats.record_sent(w.len());
+}
+
+/// Seals and sends game data, fragmenting it as needed. Returns whether anything was sent.
+async fn send_game_data_packet(
+    socket: &dyn ConnectedSocket,
+    stats: &mut StatsTracker,
+    fragmenter: &mut Fragmenter,
+    packet: GameDataPacket,
+    key: &mut SealingKey,
+) -> bool {
+    let packets = match fragmenter.seal(packet, key) {
+        Some(packets) => packets,
+        None => {
+            log::debug!("Dropping outgoing game data: too large to fragment");
+            return false;
+        }
+    };
+    for packet in packets {
+        send_packet(socket, stats, packet).await;
+    }
+    true
 }
 
 enum Variant {
@@ -429,40 +753,73 @@ enum Variant {
         client_public_key: PublicKey,
         send_interval: Interval,
     },
-    /// Send ConnectResponse packets until receiving a GameData or Keepalive packet.
+    /// Send ConnectResponse packets until receiving a GameData, Fragment, or Keepalive packet.
     Responding {
-        shared_secret: SharedSecret,
+        key: SealingKey,
         token: ChallengeToken,
         send_interval: Interval,
     },
-    /// Connection established. Exchanging GameData and Keepalive packets.
+    /// Connection established. Exchanging GameData, Ack, and Keepalive packets.
     Connected {
-        shared_secret: SharedSecret,
+        key: SealingKey,
         keepalive: Pin<Box<Sleep>>,
+        reliability: ReliabilityState,
+        resend_interval: Interval,
     },
 }
 
 impl Variant {
-    fn shared_secret(&self) -> Option<&SharedSecret> {
+    /// Begins a handshake with a new ephemeral key.
+    fn connecting(config: &ConnectionConfig) -> Self {
+        let client_private_key = PrivateKey::gen();
+        let client_public_key = client_private_key.to_public();
+        Self::Connecting {
+            client_private_key,
+            client_public_key,
+            send_interval: interval(config.send_interval()),
+        }
+    }
+
+    fn key(&self) -> Option<&SealingKey> {
         match self {
-            Variant::Responding { shared_secret, .. }
-            | Variant::Connected { shared_secret, .. } => Some(shared_secret),
+            Variant::Responding { key, .. } | Variant::Connected { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    fn key_mut(&mut self) -> Option<&mut SealingKey> {
+        match self {
+            Variant::Responding { key, .. } | Variant::Connected { key, .. } => Some(key),
             _ => None,
         }
     }
 
     async fn event(&mut self) -> InternalEvent {
-        let (send_interval, keepalive) = match self {
-            Self::Disconnected { .. } => (pending().right_future(), pending().right_future()),
-            Self::Connecting { send_interval, .. } => {
-                (send_interval.tick().left_future(), pending().right_future())
-            }
-            Self::Responding { send_interval, .. } => {
-                (send_interval.tick().left_future(), pending().right_future())
-            }
-            Self::Connected { keepalive, .. } => {
-                (pending().right_future(), keepalive.as_mut().left_future())
-            }
+        let (send_interval, keepalive, resend_interval) = match self {
+            Self::Disconnected { .. } => (
+                pending().right_future(),
+                pending().right_future(),
+                pending().right_future(),
+            ),
+            Self::Connecting { send_interval, .. } => (
+                send_interval.tick().left_future(),
+                pending().right_future(),
+                pending().right_future(),
+            ),
+            Self::Responding { send_interval, .. } => (
+                send_interval.tick().left_future(),
+                pending().right_future(),
+                pending().right_future(),
+            ),
+            Self::Connected {
+                keepalive,
+                resend_interval,
+                ..
+            } => (
+                pending().right_future(),
+                keepalive.as_mut().left_future(),
+                resend_interval.tick().left_future(),
+            ),
         };
 
         select! {
@@ -471,6 +828,8 @@ impl Variant {
             _ = send_interval => InternalEvent::SendIntervalElapsed,
 
             _ = keepalive => InternalEvent::KeepaliveElapsed,
+
+            _ = resend_interval => InternalEvent::ResendElapsed,
         }
     }
 }
@@ -3,10 +3,15 @@ use std::fmt::{self, Debug, Display, Formatter};
 use std::future::Future;
 use std::time::Duration;
 
-use dungeon_vr_connection_shared::challenge_token::ChallengeToken;
+use dungeon_vr_connection_shared::challenge_token::{
+    ChallengeToken, ChallengeTokenContents, ChallengeTokenKey,
+};
 use dungeon_vr_connection_shared::packet::Packet;
+use dungeon_vr_connection_shared::protocol_version::PROTOCOL_VERSION;
+use dungeon_vr_connection_shared::reliable::ReliabilityState;
+use dungeon_vr_connection_shared::sealed::{Role, SealingKey};
 use dungeon_vr_connection_shared::SAFE_RECV_BUFFER_SIZE;
-use dungeon_vr_cryptography::{PrivateKey, PublicKey, SharedSecret};
+use dungeon_vr_cryptography::{PrivateKey, PublicKey, SharedSecret, VerifyingKey};
 use dungeon_vr_socket::testing::FakeNetwork;
 use dungeon_vr_socket::BoundSocket;
 use dungeon_vr_stream_codec::StreamCodec;
@@ -14,10 +19,7 @@ use tokio::sync::mpsc;
 use tokio::time::error::Elapsed;
 use tokio::time::{interval, sleep, timeout};
 
-use crate::{
-    ConnectionClient, Event, Request, Variant, CONNECTING_RESPONDING_SEND_INTERVAL,
-    EVENT_BUFFER_SIZE, KEEPALIVE_INTERVAL, REQUEST_BUFFER_SIZE, SERVER_TIMEOUT_INTERVAL,
-};
+use crate::{ConnectionClient, ConnectionConfig, Event, Request, Variant};
 
 async fn box_deadline_err<T, E>(
     f: impl Future<Output = Result<Result<T, E>, Elapsed>>,
@@ -50,6 +52,19 @@ impl Display for FakeAddr {
     }
 }
 
+/// Issues a challenge token the way a server would. Clients treat tokens as opaque.
+pub fn gen_token() -> ChallengeToken {
+    ChallengeTokenKey::gen().issue(
+        &ChallengeTokenContents {
+            server_private_key: PrivateKey::gen(),
+            client_public_key: PrivateKey::gen().to_public(),
+            protocol_version: Some(PROTOCOL_VERSION),
+        },
+        FakeAddr::Client,
+        Duration::ZERO,
+    )
+}
+
 pub async fn recv_packet(socket: &dyn BoundSocket<FakeAddr>) -> Packet {
     let mut buf = [0; SAFE_RECV_BUFFER_SIZE];
     let (size, addr) = socket.recv_from(&mut buf).await.unwrap();
@@ -70,25 +85,48 @@ pub async fn send_packet(socket: &dyn BoundSocket<FakeAddr>, packet: Packet) {
     socket.send_to(&buf, FakeAddr::Client).await.unwrap();
 }
 
+/// Settings for a test client. The defaults give a client with the default config that trusts any
+/// server.
+#[derive(Default)]
+pub struct InitOptions {
+    pub config: ConnectionConfig,
+    pub server_key: Option<VerifyingKey>,
+}
+
+struct Init {
+    network: FakeNetwork<FakeAddr>,
+    cancel_guard: cancel::Guard,
+    requests: mpsc::Sender<Request>,
+    events: mpsc::Receiver<Event>,
+}
+
 fn make_network_and_connection(
+    options: InitOptions,
     mutate_connection: impl FnOnce(&mut ConnectionClient),
-) -> (
-    FakeNetwork<FakeAddr>,
-    cancel::Guard,
-    mpsc::Sender<Request>,
-    mpsc::Receiver<Event>,
-) {
+) -> Init {
     let network = FakeNetwork::new();
     let socket = network.connect(FakeAddr::Client, FakeAddr::Server);
     let cancel_token = cancel::Token::new();
-    let (request_tx, request_rx) = mpsc::channel(REQUEST_BUFFER_SIZE);
-    let (event_tx, event_rx) = mpsc::channel(EVENT_BUFFER_SIZE);
-
-    let mut connection = ConnectionClient::new(Box::new(socket), request_rx, event_tx);
+    let (request_tx, request_rx) = mpsc::channel(options.config.request_buffer_size());
+    let (event_tx, event_rx) = mpsc::channel(options.config.event_buffer_size());
+
+    let mut connection = ConnectionClient::new(
+        Box::new(socket),
+        options.config,
+        options.server_key,
+        None,
+        request_rx,
+        event_tx,
+    );
     mutate_connection(&mut connection);
     tokio::spawn(connection.run(cancel_token.clone()));
 
-    (network, cancel_token.guard(), request_tx, event_rx)
+    Init {
+        network,
+        cancel_guard: cancel_token.guard(),
+        requests: request_tx,
+        events: event_rx,
+    }
 }
 
 pub struct InitWithConnectingConnection {
@@ -100,14 +138,19 @@ pub struct InitWithConnectingConnection {
     pub client_public_key: PublicKey,
 }
 
-pub fn init_with_connecting_connection() -> InitWithConnectingConnection {
+pub fn init_with_connecting_connection(options: InitOptions) -> InitWithConnectingConnection {
     let client_private_key = PrivateKey::gen();
     let client_public_key = client_private_key.to_public();
-    let (network, cancel_guard, requests, events) = make_network_and_connection(|connection| {
+    let Init {
+        network,
+        cancel_guard,
+        requests,
+        events,
+    } = make_network_and_connection(options, |connection| {
         connection.variant = Variant::Connecting {
             client_private_key: client_private_key.clone(),
             client_public_key,
-            send_interval: interval(CONNECTING_RESPONDING_SEND_INTERVAL),
+            send_interval: interval(connection.config.send_interval()),
         };
     });
     InitWithConnectingConnection {
@@ -125,18 +168,23 @@ pub struct InitWithRespondingConnection {
     pub cancel_guard: cancel::Guard,
     pub requests: mpsc::Sender<Request>,
     pub events: mpsc::Receiver<Event>,
-    pub shared_secret: SharedSecret,
+    pub key: SealingKey,
     pub token: ChallengeToken,
 }
 
-pub fn init_with_responding_connection() -> InitWithRespondingConnection {
+pub fn init_with_responding_connection(options: InitOptions) -> InitWithRespondingConnection {
     let shared_secret = SharedSecret::gen();
-    let token = ChallengeToken::gen();
-    let (network, cancel_guard, requests, events) = make_network_and_connection(|connection| {
+    let token = gen_token();
+    let Init {
+        network,
+        cancel_guard,
+        requests,
+        events,
+    } = make_network_and_connection(options, |connection| {
         connection.variant = Variant::Responding {
-            shared_secret,
+            key: SealingKey::new(shared_secret, Role::Client),
             token,
-            send_interval: interval(CONNECTING_RESPONDING_SEND_INTERVAL),
+            send_interval: interval(connection.config.send_interval()),
         };
     });
     InitWithRespondingConnection {
@@ -144,7 +192,7 @@ pub fn init_with_responding_connection() -> InitWithRespondingConnection {
         cancel_guard,
         requests,
         events,
-        shared_secret,
+        key: SealingKey::new(shared_secret, Role::Server),
         token,
     }
 }
@@ -154,16 +202,23 @@ pub struct InitWithConnectedConnection {
     pub cancel_guard: cancel::Guard,
     pub requests: mpsc::Sender<Request>,
     pub events: mpsc::Receiver<Event>,
-    pub shared_secret: SharedSecret,
+    pub key: SealingKey,
 }
 
-pub fn init_with_connected_connection() -> InitWithConnectedConnection {
+pub fn init_with_connected_connection(options: InitOptions) -> InitWithConnectedConnection {
     let shared_secret = SharedSecret::gen();
-    let (network, cancel_guard, requests, events) = make_network_and_connection(|connection| {
-        connection.timeout = Some(Box::pin(sleep(SERVER_TIMEOUT_INTERVAL)));
+    let Init {
+        network,
+        cancel_guard,
+        requests,
+        events,
+    } = make_network_and_connection(options, |connection| {
+        connection.timeout = Some(Box::pin(sleep(connection.config.timeout())));
         connection.variant = Variant::Connected {
-            shared_secret,
-            keepalive: Box::pin(sleep(KEEPALIVE_INTERVAL)),
+            key: SealingKey::new(shared_secret, Role::Client),
+            keepalive: Box::pin(sleep(connection.config.keepalive_interval())),
+            reliability: ReliabilityState::new(),
+            resend_interval: interval(connection.config.resend_interval()),
         };
     });
     InitWithConnectedConnection {
@@ -171,6 +226,6 @@ pub fn init_with_connected_connection() -> InitWithConnectedConnection {
         cancel_guard,
         requests,
         events,
-        shared_secret,
+        key: SealingKey::new(shared_secret, Role::Server),
     }
 }
@@ -1,21 +1,26 @@
-use dungeon_vr_connection_shared::challenge_token::ChallengeToken;
 use dungeon_vr_connection_shared::connect_challenge_packet::ConnectChallengePacket;
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_connection_shared::sealed::Sealed;
+use dungeon_vr_connection_shared::protocol_version::{ProtocolVersions, PROTOCOL_VERSION};
+use dungeon_vr_connection_shared::sealed::{Role, SealingKey};
 use dungeon_vr_connection_shared::GAME_ID;
 use dungeon_vr_cryptography::PrivateKey;
 use dungeon_vr_socket::testing::FakeNetwork;
 
-use crate::testing::{recv_packet, run_test_with_timeout, send_packet, FakeAddr};
-use crate::{ConnectionClient, ConnectionState, Event};
+use crate::testing::{gen_token, recv_packet, run_test_with_timeout, send_packet, FakeAddr};
+use crate::{ConnectionClient, ConnectionConfig, ConnectionState, Event};
 
 #[tokio::test(start_paused = true)]
 async fn end_to_end() {
     run_test_with_timeout(async move {
         let network = FakeNetwork::new();
-        let (cancel_guard, _requests, mut events) = ConnectionClient::spawn(Box::new(
-            network.connect(FakeAddr::Client, FakeAddr::Server),
-        ));
+        let (cancel_guard, _requests, mut events) = ConnectionClient::spawn(
+            Box::new(network.connect(FakeAddr::Client, FakeAddr::Server)),
+            ConnectionConfig::default(),
+            None,
+            None,
+        );
         let socket = network.bind(FakeAddr::Server);
 
         println!("Waiting for a ConnectInit packet");
@@ -25,21 +30,31 @@ async fn end_to_end() {
             _ => panic!(),
         };
         assert_eq!(GAME_ID, packet.game_id);
+        assert_eq!(ProtocolVersions::SUPPORTED, packet.protocol_versions);
         let client_public_key = packet.client_public_key;
 
         // Send a ConnectChallenge packet.
         let server_private_key = PrivateKey::gen();
         let server_public_key = server_private_key.to_public();
         let shared_secret = server_private_key.exchange(&client_public_key).unwrap();
-        let token = ChallengeToken::gen();
+        let mut key = SealingKey::new(shared_secret, Role::Server);
+        let token = gen_token();
         send_packet(
             &socket,
             Packet::ConnectChallenge(ConnectChallengePacket {
                 server_public_key,
-                sealed_payload: Sealed::seal(token, &shared_secret),
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                signature: None,
+                sealed_payload: key.seal(token),
             }),
         )
         .await;
+        assert_eq!(
+            Event::Negotiated {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            events.recv().await.unwrap()
+        );
         assert_eq!(
             Event::State(ConnectionState::Responding),
             events.recv().await.unwrap()
@@ -47,30 +62,35 @@ async fn end_to_end() {
 
         println!("Waiting for a ConnectResponse packet");
         let packet = recv_packet(&socket).await;
-        let response_token = match packet {
-            Packet::ConnectResponse(packet) => packet.open(&shared_secret).unwrap(),
+        let packet = match packet {
+            Packet::ConnectResponse(packet) => packet,
             _ => panic!(),
         };
-        assert_eq!(token, response_token);
+        assert_eq!(token, packet.token);
+        let () = key.open(&packet.sealed_payload).unwrap();
 
         // Send a Keepalive packet.
-        send_packet(&socket, Packet::Keepalive(Sealed::seal((), &shared_secret))).await;
+        send_packet(
+            &socket,
+            Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 7 })),
+        )
+        .await;
         assert_eq!(
             Event::State(ConnectionState::Connected),
             events.recv().await.unwrap()
         );
 
-        println!("Waiting for a Keepalive packet");
+        println!("Waiting for a Keepalive packet answering ours");
         let packet = match recv_packet(&socket).await {
             Packet::Keepalive(packet) => packet,
             _ => unreachable!(),
         };
-        let () = packet.open(&shared_secret).unwrap();
+        assert_eq!(key.open(&packet).unwrap(), KeepalivePacket::Pong { id: 7 });
 
         // Send a Disconnect packet.
         send_packet(
             &socket,
-            Packet::Disconnect(Sealed::seal((), &shared_secret)),
+            Packet::Disconnect(key.seal(DisconnectPacket::default())),
         )
         .await;
         assert_eq!(
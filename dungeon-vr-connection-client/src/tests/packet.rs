@@ -1,19 +1,26 @@
 use std::time::Duration;
 
-use dungeon_vr_connection_shared::challenge_token::ChallengeToken;
-use dungeon_vr_connection_shared::connect_challenge_packet::ConnectChallengePacket;
+use dungeon_vr_connection_shared::connect_challenge_packet::{
+    signed_message, ConnectChallengePacket,
+};
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::fragment::Fragmenter;
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_connection_shared::sealed::Sealed;
-use dungeon_vr_cryptography::{PrivateKey, SharedSecret};
-use dungeon_vr_stream_codec::UnframedByteVec;
-use tokio::time::sleep;
+use dungeon_vr_connection_shared::protocol_version::{ProtocolVersions, PROTOCOL_VERSION};
+use dungeon_vr_connection_shared::reliable::{AckPacket, Channel, GameDataPacket};
+use dungeon_vr_connection_shared::sealed::{Role, Sealed};
+use dungeon_vr_cryptography::{PrivateKey, SharedSecret, SigningKey};
+use dungeon_vr_stream_codec::StreamCodec;
+use tokio::time::{sleep, Instant};
 
 use crate::testing::{
-    init_with_connected_connection, init_with_connecting_connection,
-    init_with_responding_connection, run_test_with_timeout, send_bytes, send_packet, FakeAddr,
-    InitWithConnectedConnection, InitWithConnectingConnection, InitWithRespondingConnection,
+    gen_token, init_with_connected_connection, init_with_connecting_connection,
+    init_with_responding_connection, recv_packet, run_test_with_timeout, send_bytes, send_packet,
+    FakeAddr, InitOptions, InitWithConnectedConnection, InitWithConnectingConnection,
+    InitWithRespondingConnection,
 };
-use crate::{ConnectionState, Event};
+use crate::{ConnectionConfig, ConnectionState, Event, RejectReason};
 
 #[tokio::test(start_paused = true)]
 async fn connecting_recv_empty_should_ignore() {
@@ -23,7 +30,7 @@ async fn connecting_recv_empty_should_ignore() {
             cancel_guard: _cancel_guard,
             mut events,
             ..
-        } = init_with_connecting_connection();
+        } = init_with_connecting_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
         send_bytes(&socket, b"").await;
@@ -42,7 +49,7 @@ async fn connecting_recv_malformed_should_ignore() {
             cancel_guard: _cancel_guard,
             mut events,
             ..
-        } = init_with_connecting_connection();
+        } = init_with_connecting_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
         send_bytes(&socket, b"\x02ConnectChallenge but too short").await;
@@ -62,22 +69,30 @@ async fn connecting_recv_challenge_should_change_state() {
             mut events,
             client_public_key,
             ..
-        } = init_with_connecting_connection();
+        } = init_with_connecting_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
         let server_private_key = PrivateKey::gen();
         let server_public_key = server_private_key.to_public();
         let shared_secret = server_private_key.exchange(&client_public_key).unwrap();
-        let token = ChallengeToken::gen();
+        let token = gen_token();
         send_packet(
             &socket,
             Packet::ConnectChallenge(ConnectChallengePacket {
                 server_public_key,
-                sealed_payload: Sealed::seal(token, &shared_secret),
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                signature: None,
+                sealed_payload: Sealed::seal(token, Role::Server, 0, &shared_secret),
             }),
         )
         .await;
 
+        assert_eq!(
+            Event::Negotiated {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            events.recv().await.unwrap()
+        );
         assert_eq!(
             Event::State(ConnectionState::Responding),
             events.recv().await.unwrap()
@@ -86,6 +101,43 @@ async fn connecting_recv_challenge_should_change_state() {
     .await;
 }
 
+#[tokio::test(start_paused = true)]
+async fn connecting_recv_incompatible_challenge_should_yield_rejected() {
+    run_test_with_timeout(async move {
+        let InitWithConnectingConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            client_public_key,
+            ..
+        } = init_with_connecting_connection(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Server);
+        let server_private_key = PrivateKey::gen();
+        let server_public_key = server_private_key.to_public();
+        let shared_secret = server_private_key.exchange(&client_public_key).unwrap();
+        send_packet(
+            &socket,
+            Packet::ConnectChallenge(ConnectChallengePacket {
+                server_public_key,
+                protocol_versions: ProtocolVersions {
+                    min: PROTOCOL_VERSION + 1,
+                    max: PROTOCOL_VERSION + 1,
+                },
+                signature: None,
+                sealed_payload: Sealed::seal(gen_token(), Role::Server, 0, &shared_secret),
+            }),
+        )
+        .await;
+
+        assert_eq!(
+            Event::Rejected(RejectReason::VersionMismatch),
+            events.recv().await.unwrap()
+        );
+    })
+    .await;
+}
+
 #[tokio::test(start_paused = true)]
 async fn connecting_recv_bad_signature_challenge_should_ignore() {
     run_test_with_timeout(async move {
@@ -94,14 +146,16 @@ async fn connecting_recv_bad_signature_challenge_should_ignore() {
             cancel_guard: _cancel_guard,
             mut events,
             ..
-        } = init_with_connecting_connection();
+        } = init_with_connecting_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
         send_packet(
             &socket,
             Packet::ConnectChallenge(ConnectChallengePacket {
                 server_public_key: PrivateKey::gen().to_public(),
-                sealed_payload: Sealed::seal(ChallengeToken::gen(), &SharedSecret::gen()),
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                signature: None,
+                sealed_payload: Sealed::seal(gen_token(), Role::Server, 0, &SharedSecret::gen()),
             }),
         )
         .await;
@@ -112,6 +166,99 @@ async fn connecting_recv_bad_signature_challenge_should_ignore() {
     .await;
 }
 
+#[tokio::test(start_paused = true)]
+async fn connecting_with_server_key_recv_signed_challenge_should_change_state() {
+    run_test_with_timeout(async move {
+        let signing_key = SigningKey::gen();
+        let InitWithConnectingConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            client_public_key,
+            ..
+        } = init_with_connecting_connection(InitOptions {
+            server_key: Some(signing_key.verifying_key()),
+            ..Default::default()
+        });
+
+        let socket = network.bind(FakeAddr::Server);
+        let server_private_key = PrivateKey::gen();
+        let server_public_key = server_private_key.to_public();
+        let shared_secret = server_private_key.exchange(&client_public_key).unwrap();
+        send_packet(
+            &socket,
+            Packet::ConnectChallenge(ConnectChallengePacket {
+                server_public_key,
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                signature: Some(signing_key.sign(&signed_message(
+                    &client_public_key,
+                    &server_public_key,
+                    ProtocolVersions::SUPPORTED,
+                ))),
+                sealed_payload: Sealed::seal(gen_token(), Role::Server, 0, &shared_secret),
+            }),
+        )
+        .await;
+
+        assert_eq!(
+            Event::Negotiated {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            events.recv().await.unwrap()
+        );
+        assert_eq!(
+            Event::State(ConnectionState::Responding),
+            events.recv().await.unwrap()
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connecting_with_server_key_recv_unsigned_or_missigned_challenge_should_ignore() {
+    run_test_with_timeout(async move {
+        let InitWithConnectingConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            client_public_key,
+            ..
+        } = init_with_connecting_connection(InitOptions {
+            server_key: Some(SigningKey::gen().verifying_key()),
+            ..Default::default()
+        });
+
+        let socket = network.bind(FakeAddr::Server);
+        let server_private_key = PrivateKey::gen();
+        let server_public_key = server_private_key.to_public();
+        let shared_secret = server_private_key.exchange(&client_public_key).unwrap();
+        let impostor_key = SigningKey::gen();
+        for signature in [
+            None,
+            Some(impostor_key.sign(&signed_message(
+                &client_public_key,
+                &server_public_key,
+                ProtocolVersions::SUPPORTED,
+            ))),
+        ] {
+            send_packet(
+                &socket,
+                Packet::ConnectChallenge(ConnectChallengePacket {
+                    server_public_key,
+                    protocol_versions: ProtocolVersions::SUPPORTED,
+                    signature,
+                    sealed_payload: Sealed::seal(gen_token(), Role::Server, 0, &shared_secret),
+                }),
+            )
+            .await;
+        }
+
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
 #[tokio::test(start_paused = true)]
 async fn responding_recv_keepalive_should_change_state() {
     run_test_with_timeout(async move {
@@ -119,12 +266,16 @@ async fn responding_recv_keepalive_should_change_state() {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             ..
-        } = init_with_responding_connection();
+        } = init_with_responding_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
-        send_packet(&socket, Packet::Keepalive(Sealed::seal((), &shared_secret))).await;
+        send_packet(
+            &socket,
+            Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 0 })),
+        )
+        .await;
 
         assert_eq!(
             Event::State(ConnectionState::Connected),
@@ -135,26 +286,111 @@ async fn responding_recv_keepalive_should_change_state() {
 }
 
 #[tokio::test(start_paused = true)]
-async fn responding_recv_gamedata_should_change_state() {
+async fn responding_recv_reject_should_yield_rejected() {
     run_test_with_timeout(async move {
         let InitWithRespondingConnection {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
+            ..
+        } = init_with_responding_connection(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Server);
+        send_packet(&socket, Packet::Reject(key.seal(RejectReason::ServerFull))).await;
+
+        assert_eq!(
+            Event::Rejected(RejectReason::ServerFull),
+            events.recv().await.unwrap()
+        );
+
+        // The connection is over, so it neither responds further nor times out.
+        sleep(ConnectionConfig::default().timeout()).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_recv_disconnect_with_reason_should_yield_reason() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
             ..
-        } = init_with_responding_connection();
+        } = init_with_connected_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
         send_packet(
             &socket,
-            Packet::GameData(Sealed::seal_ext::<UnframedByteVec>(
-                b"abcdef".to_vec(),
-                &shared_secret,
+            Packet::Disconnect(key.seal(DisconnectPacket::new(Some("Restarting".to_string())))),
+        )
+        .await;
+
+        assert_eq!(
+            Event::DisconnectReason("Restarting".to_string()),
+            events.recv().await.unwrap()
+        );
+        assert_eq!(
+            Event::State(ConnectionState::Disconnected),
+            events.recv().await.unwrap()
+        );
+
+        // The connection is over, so it does not time out.
+        sleep(ConnectionConfig::default().timeout()).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn responding_recv_bad_signature_reject_should_ignore() {
+    run_test_with_timeout(async move {
+        let InitWithRespondingConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            ..
+        } = init_with_responding_connection(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Server);
+        send_packet(
+            &socket,
+            Packet::Reject(Sealed::seal(
+                RejectReason::Banned,
+                Role::Server,
+                0,
+                &SharedSecret::gen(),
             )),
         )
         .await;
 
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn responding_recv_gamedata_should_change_state() {
+    run_test_with_timeout(async move {
+        let InitWithRespondingConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_responding_connection(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Server);
+        send_packet(
+            &socket,
+            Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec()))),
+        )
+        .await;
+
         assert_eq!(
             Event::State(ConnectionState::Connected),
             events.recv().await.unwrap()
@@ -167,6 +403,36 @@ async fn responding_recv_gamedata_should_change_state() {
     .await;
 }
 
+#[tokio::test(start_paused = true)]
+async fn responding_recv_fragments_should_change_state_and_reassemble() {
+    run_test_with_timeout(async move {
+        let InitWithRespondingConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_responding_connection(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Server);
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let packets = Fragmenter::new(1200)
+            .seal(GameDataPacket::Unreliable(data.clone()), &mut key)
+            .unwrap();
+        assert!(packets.len() > 1);
+        for packet in packets.into_iter().rev() {
+            send_packet(&socket, packet).await;
+        }
+
+        assert_eq!(
+            Event::State(ConnectionState::Connected),
+            events.recv().await.unwrap()
+        );
+        assert_eq!(Event::GameData(data), events.recv().await.unwrap());
+    })
+    .await;
+}
+
 #[tokio::test(start_paused = true)]
 async fn connected_recv_keepalive_should_refresh_timeout() {
     run_test_with_timeout(async move {
@@ -174,13 +440,17 @@ async fn connected_recv_keepalive_should_refresh_timeout() {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             ..
-        } = init_with_connected_connection();
+        } = init_with_connected_connection(InitOptions::default());
 
         sleep(Duration::from_millis(4900)).await;
         let socket = network.bind(FakeAddr::Server);
-        send_packet(&socket, Packet::Keepalive(Sealed::seal((), &shared_secret))).await;
+        send_packet(
+            &socket,
+            Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 0 })),
+        )
+        .await;
         sleep(Duration::from_millis(4900)).await;
 
         // If the timeout had not been refreshed, the connection would have timed out, generating an
@@ -197,17 +467,14 @@ async fn connected_recv_gamedata_should_yield_event() {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             ..
-        } = init_with_connected_connection();
+        } = init_with_connected_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
         send_packet(
             &socket,
-            Packet::GameData(Sealed::seal_ext::<UnframedByteVec>(
-                b"abcdef".to_vec(),
-                &shared_secret,
-            )),
+            Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec()))),
         )
         .await;
 
@@ -226,18 +493,15 @@ async fn connected_recv_gamedata_should_refresh_timeout() {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             ..
-        } = init_with_connected_connection();
+        } = init_with_connected_connection(InitOptions::default());
 
         sleep(Duration::from_millis(4900)).await;
         let socket = network.bind(FakeAddr::Server);
         send_packet(
             &socket,
-            Packet::GameData(Sealed::seal_ext::<UnframedByteVec>(
-                b"abcdef".to_vec(),
-                &shared_secret,
-            )),
+            Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec()))),
         )
         .await;
         sleep(Duration::from_millis(4900)).await;
@@ -252,3 +516,137 @@ async fn connected_recv_gamedata_should_refresh_timeout() {
     })
     .await;
 }
+
+#[tokio::test(start_paused = true)]
+async fn connected_recv_reliable_gamedata_should_ack_and_deliver_in_order() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Server);
+        for (sequence, data) in [(1, b"b"), (1, b"b"), (0, b"a")] {
+            send_packet(
+                &socket,
+                Packet::GameData(key.seal(GameDataPacket::ReliableOrdered {
+                    sequence,
+                    data: data.to_vec(),
+                })),
+            )
+            .await;
+        }
+
+        // Every copy is acknowledged, including the duplicate.
+        for sequence in [1, 1, 0] {
+            let sealed = match recv_packet(&socket).await {
+                Packet::Ack(sealed) => sealed,
+                _ => unreachable!(),
+            };
+            assert_eq!(
+                AckPacket {
+                    channel: Channel::ReliableOrdered,
+                    sequence,
+                },
+                key.open(&sealed).unwrap(),
+            );
+        }
+        for data in [b"a", b"b"] {
+            assert_eq!(Event::GameData(data.to_vec()), events.recv().await.unwrap());
+        }
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_send_ack_should_refresh_keepalive() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut key,
+            ..
+        } = init_with_connected_connection(InitOptions::default());
+        let socket = network.bind(FakeAddr::Server);
+
+        sleep(Duration::from_millis(900)).await;
+        send_packet(
+            &socket,
+            Packet::GameData(key.seal(GameDataPacket::ReliableOrdered {
+                sequence: 0,
+                data: b"a".to_vec(),
+            })),
+        )
+        .await;
+        assert!(matches!(recv_packet(&socket).await, Packet::Ack(_)));
+        let acked_at = Instant::now();
+
+        // The Ack counts as traffic, so the next Keepalive waits a full interval after it.
+        assert!(matches!(recv_packet(&socket).await, Packet::Keepalive(_)));
+        assert!(Instant::now() - acked_at >= ConnectionConfig::default().keepalive_interval());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_recv_replayed_gamedata_should_ignore() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Server);
+        let mut buf = Vec::new();
+        Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec())))
+            .write_to(&mut buf)
+            .unwrap();
+        send_bytes(&socket, &buf).await;
+        send_bytes(&socket, &buf).await;
+
+        assert_eq!(
+            Event::GameData(b"abcdef".to_vec()),
+            events.recv().await.unwrap()
+        );
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_recv_replayed_keepalive_should_not_refresh_timeout() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Server);
+        let mut buf = Vec::new();
+        Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 0 }))
+            .write_to(&mut buf)
+            .unwrap();
+        send_bytes(&socket, &buf).await;
+        sleep(Duration::from_millis(4900)).await;
+        send_bytes(&socket, &buf).await;
+        sleep(Duration::from_millis(200)).await;
+
+        // The replayed Keepalive was ignored, so the connection timed out and began reconnecting.
+        assert_eq!(
+            Event::State(ConnectionState::Connecting),
+            events.recv().await.unwrap()
+        );
+    })
+    .await;
+}
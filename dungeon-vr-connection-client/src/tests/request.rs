@@ -1,14 +1,16 @@
 use std::time::Duration;
 
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_stream_codec::UnframedByteVec;
+use dungeon_vr_connection_shared::reliable::{AckPacket, GameDataPacket, SEND_QUEUE_CAPACITY};
 use tokio::time::sleep;
 
 use crate::testing::{
-    init_with_connected_connection, recv_packet, run_test_with_timeout, FakeAddr,
-    InitWithConnectedConnection,
+    init_with_connected_connection, recv_packet, run_test_with_timeout, send_packet, FakeAddr,
+    InitOptions, InitWithConnectedConnection,
 };
-use crate::Request;
+use crate::{Channel, ConnectionState, Event, Request};
 
 #[tokio::test(start_paused = true)]
 async fn connected_request_gamedata_should_send_gamedata() {
@@ -17,13 +19,16 @@ async fn connected_request_gamedata_should_send_gamedata() {
             network,
             cancel_guard: _cancel_guard,
             requests,
-            shared_secret,
+            mut key,
             ..
-        } = init_with_connected_connection();
+        } = init_with_connected_connection(InitOptions::default());
         let socket = network.bind(FakeAddr::Server);
 
         requests
-            .send(Request::SendGameData(b"abcdef".to_vec()))
+            .send(Request::SendGameData {
+                channel: Channel::Unreliable,
+                data: b"abcdef".to_vec(),
+            })
             .await
             .unwrap();
 
@@ -31,8 +36,10 @@ async fn connected_request_gamedata_should_send_gamedata() {
             Packet::GameData(sealed) => sealed,
             _ => unreachable!(),
         };
-        let data = sealed.open_ext::<UnframedByteVec>(&shared_secret).unwrap();
-        assert_eq!(b"abcdef", data.as_slice());
+        assert_eq!(
+            GameDataPacket::Unreliable(b"abcdef".to_vec()),
+            key.open(&sealed).unwrap(),
+        );
     })
     .await;
 }
@@ -44,14 +51,17 @@ async fn connected_request_gamedata_should_refresh_keepalive() {
             network,
             cancel_guard: _cancel_guard,
             requests,
-            shared_secret,
+            mut key,
             ..
-        } = init_with_connected_connection();
+        } = init_with_connected_connection(InitOptions::default());
         let socket = network.bind(FakeAddr::Server);
 
         sleep(Duration::from_millis(900)).await;
         requests
-            .send(Request::SendGameData(b"abcdef".to_vec()))
+            .send(Request::SendGameData {
+                channel: Channel::Unreliable,
+                data: b"abcdef".to_vec(),
+            })
             .await
             .unwrap();
         sleep(Duration::from_millis(900)).await;
@@ -60,8 +70,137 @@ async fn connected_request_gamedata_should_refresh_keepalive() {
             Packet::GameData(sealed) => sealed,
             _ => unreachable!(),
         };
-        let data = sealed.open_ext::<UnframedByteVec>(&shared_secret).unwrap();
-        assert_eq!(b"abcdef", data.as_slice());
+        assert_eq!(
+            GameDataPacket::Unreliable(b"abcdef".to_vec()),
+            key.open(&sealed).unwrap(),
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_reliable_gamedata_should_resend_until_acked() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut key,
+            ..
+        } = init_with_connected_connection(InitOptions::default());
+        let socket = network.bind(FakeAddr::Server);
+
+        requests
+            .send(Request::SendGameData {
+                channel: Channel::ReliableUnordered,
+                data: b"abcdef".to_vec(),
+            })
+            .await
+            .unwrap();
+
+        let expected = GameDataPacket::ReliableUnordered {
+            sequence: 0,
+            data: b"abcdef".to_vec(),
+        };
+        for _ in 0..2 {
+            let sealed = match recv_packet(&socket).await {
+                Packet::GameData(sealed) => sealed,
+                _ => unreachable!(),
+            };
+            assert_eq!(expected, key.open(&sealed).unwrap());
+        }
+
+        send_packet(
+            &socket,
+            Packet::Ack(key.seal(AckPacket {
+                channel: Channel::ReliableUnordered,
+                sequence: 0,
+            })),
+        )
+        .await;
+
+        // Once acknowledged, the next packet is the regularly scheduled Keepalive.
+        assert!(matches!(recv_packet(&socket).await, Packet::Keepalive(_)));
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_gamedata_overflowing_send_queue_should_disconnect() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_connected_connection(InitOptions::default());
+        let socket = network.bind(FakeAddr::Server);
+
+        // The server never acknowledges anything.
+        for _ in 0..=SEND_QUEUE_CAPACITY {
+            requests
+                .send(Request::SendGameData {
+                    channel: Channel::ReliableUnordered,
+                    data: b"abcdef".to_vec(),
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            Event::State(ConnectionState::Disconnected),
+            events.recv().await.unwrap(),
+        );
+        let sealed = loop {
+            if let Packet::Disconnect(sealed) = recv_packet(&socket).await {
+                break sealed;
+            }
+        };
+        assert_eq!(DisconnectPacket::default(), key.open(&sealed).unwrap());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_query_stats_should_report_rtt() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_connected_connection(InitOptions::default());
+        let socket = network.bind(FakeAddr::Server);
+
+        // Answer the client's first Ping after a delay.
+        let id = match recv_packet(&socket).await {
+            Packet::Keepalive(packet) => match key.open(&packet).unwrap() {
+                KeepalivePacket::Ping { id } => id,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        sleep(Duration::from_millis(30)).await;
+        send_packet(
+            &socket,
+            Packet::Keepalive(key.seal(KeepalivePacket::Pong { id })),
+        )
+        .await;
+
+        // Let the Pong arrive before asking.
+        sleep(Duration::from_millis(10)).await;
+        requests.send(Request::QueryStats).await.unwrap();
+        let stats = match events.recv().await.unwrap() {
+            Event::Stats(stats) => stats,
+            _ => unreachable!(),
+        };
+        assert_eq!(stats.rtt, Some(Duration::from_millis(30)));
+        assert_eq!(stats.packets_sent, 1);
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.packets_lost, 0);
+        assert_eq!(stats.decrypt_failures, 0);
     })
     .await;
 }
@@ -1,12 +1,14 @@
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
 use dungeon_vr_connection_shared::packet::Packet;
 use dungeon_vr_connection_shared::GAME_ID;
+use tokio::time::{timeout, Duration, Instant};
 
 use crate::testing::{
     init_with_connected_connection, init_with_connecting_connection,
-    init_with_responding_connection, recv_packet, run_test_with_timeout, FakeAddr,
+    init_with_responding_connection, recv_packet, run_test_with_timeout, FakeAddr, InitOptions,
     InitWithConnectedConnection, InitWithConnectingConnection, InitWithRespondingConnection,
 };
-use crate::{ConnectionState, Event};
+use crate::{ConnectionConfig, ConnectionState, Event};
 
 #[tokio::test(start_paused = true)]
 async fn connecting_should_send_challenges() {
@@ -16,7 +18,7 @@ async fn connecting_should_send_challenges() {
             cancel_guard: _cancel_guard,
             client_public_key,
             ..
-        } = init_with_connecting_connection();
+        } = init_with_connecting_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
         for _ in 0..3 {
@@ -38,7 +40,7 @@ async fn connecting_should_time_out() {
             cancel_guard: _cancel_guard,
             mut events,
             ..
-        } = init_with_connecting_connection();
+        } = init_with_connecting_connection(InitOptions::default());
 
         assert_eq!(
             Event::State(ConnectionState::Disconnected),
@@ -54,10 +56,10 @@ async fn responding_should_send_connectresponses() {
         let InitWithRespondingConnection {
             network,
             cancel_guard: _cancel_guard,
-            shared_secret,
+            mut key,
             token,
             ..
-        } = init_with_responding_connection();
+        } = init_with_responding_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
         for _ in 0..3 {
@@ -65,8 +67,8 @@ async fn responding_should_send_connectresponses() {
                 Packet::ConnectResponse(packet) => packet,
                 _ => unreachable!(),
             };
-            let received_token = packet.open(&shared_secret).unwrap();
-            assert_eq!(token, received_token);
+            let () = key.open(&packet.sealed_payload).unwrap();
+            assert_eq!(token, packet.token);
         }
     })
     .await;
@@ -79,7 +81,7 @@ async fn responding_should_time_out() {
             cancel_guard: _cancel_guard,
             mut events,
             ..
-        } = init_with_responding_connection();
+        } = init_with_responding_connection(InitOptions::default());
 
         assert_eq!(
             Event::State(ConnectionState::Disconnected),
@@ -95,35 +97,88 @@ async fn connected_should_send_keepalives() {
         let InitWithConnectedConnection {
             network,
             cancel_guard: _cancel_guard,
-            shared_secret,
+            mut key,
             ..
-        } = init_with_connected_connection();
+        } = init_with_connected_connection(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Server);
-        for _ in 0..3 {
+        for id in 0..3 {
             let packet = match recv_packet(&socket).await {
                 Packet::Keepalive(packet) => packet,
                 _ => unreachable!(),
             };
-            let () = packet.open(&shared_secret).unwrap();
+            assert_eq!(key.open(&packet).unwrap(), KeepalivePacket::Ping { id });
         }
     })
     .await;
 }
 
 #[tokio::test(start_paused = true)]
-async fn connected_should_time_out() {
+async fn connected_should_time_out_and_reconnect() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            ..
+        } = init_with_connected_connection(InitOptions::default());
+
+        assert_eq!(
+            Event::State(ConnectionState::Connecting),
+            events.recv().await.unwrap()
+        );
+        let start = Instant::now();
+
+        // The client starts a new handshake.
+        let socket = network.bind(FakeAddr::Server);
+        let packet = match recv_packet(&socket).await {
+            Packet::ConnectInit(packet) => packet,
+            _ => unreachable!(),
+        };
+        assert_eq!(GAME_ID, packet.game_id);
+
+        // If the server never answers, the client eventually gives up.
+        assert_eq!(
+            Event::State(ConnectionState::Disconnected),
+            events.recv().await.unwrap()
+        );
+        assert_eq!(
+            start.elapsed(),
+            ConnectionConfig::default().reconnect_timeout()
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_without_reconnect_timeout_should_time_out_and_disconnect() {
     run_test_with_timeout(async move {
+        let config = ConnectionConfig::builder()
+            .timeout(Duration::from_secs(2))
+            .reconnect_timeout(Duration::ZERO)
+            .build()
+            .unwrap();
         let InitWithConnectedConnection {
+            network,
             cancel_guard: _cancel_guard,
             mut events,
             ..
-        } = init_with_connected_connection();
+        } = init_with_connected_connection(InitOptions {
+            config,
+            ..Default::default()
+        });
+        let socket = network.bind(FakeAddr::Server);
+        let start = Instant::now();
 
+        // The client gives up without a reconnect attempt.
         assert_eq!(
             Event::State(ConnectionState::Disconnected),
             events.recv().await.unwrap()
         );
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        while let Ok(packet) = timeout(Duration::from_secs(1), recv_packet(&socket)).await {
+            assert!(matches!(packet, Packet::Keepalive(_)));
+        }
     })
     .await;
 }
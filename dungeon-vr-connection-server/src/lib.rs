@@ -1,39 +1,74 @@
 use std::collections::HashMap;
 use std::future::pending;
 use std::io;
-use std::marker::PhantomData;
 use std::pin::Pin;
 
-use dungeon_vr_connection_shared::challenge_token::ChallengeToken;
-use dungeon_vr_connection_shared::connect_challenge_packet::ConnectChallengePacket;
+use dungeon_vr_connection_shared::challenge_token::{ChallengeTokenContents, ChallengeTokenKey};
+use dungeon_vr_connection_shared::connect_challenge_packet::{
+    signed_message, ConnectChallengePacket,
+};
 use dungeon_vr_connection_shared::connect_init_packet::ConnectInitPacket;
+use dungeon_vr_connection_shared::connect_response_packet::ConnectResponsePacket;
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::fragment::{FragmentPacket, Fragmenter, Reassembler};
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
+use dungeon_vr_connection_shared::keylog::log_shared_secret;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_connection_shared::sealed::Sealed;
+use dungeon_vr_connection_shared::protocol_version::ProtocolVersions;
+use dungeon_vr_connection_shared::reliable::{AckPacket, GameDataPacket, ReliabilityState};
+use dungeon_vr_connection_shared::sealed::{ConnectionId, Role, Sealed, SealingKey};
+use dungeon_vr_connection_shared::stats::StatsTracker;
 use dungeon_vr_connection_shared::{GAME_ID, SAFE_RECV_BUFFER_SIZE};
-use dungeon_vr_cryptography::{KeyExchangeError, PrivateKey, PublicKey, SharedSecret};
+use dungeon_vr_cryptography::{KeyExchangeError, Nonce, PrivateKey};
 use dungeon_vr_socket::{AddrBound, BoundSocket};
-use dungeon_vr_stream_codec::{StreamCodec, UnframedByteVec};
+use dungeon_vr_stream_codec::StreamCodec;
 use futures::stream::FuturesUnordered;
 use futures::{FutureExt, StreamExt};
 use tokio::select;
 use tokio::sync::mpsc;
 use tokio::time::{interval, sleep, Duration, Instant, Interval, Sleep};
 
+pub use dungeon_vr_connection_shared::config::{
+    ConnectionConfig, ConnectionConfigBuilder, ConnectionConfigError,
+};
+pub use dungeon_vr_connection_shared::protocol_version::ContentHash;
+pub use dungeon_vr_connection_shared::reject_reason::RejectReason;
+pub use dungeon_vr_connection_shared::reliable::Channel;
+pub use dungeon_vr_connection_shared::stats::ConnectionStats;
+pub use dungeon_vr_connection_shared::DEFAULT_MTU;
+pub use dungeon_vr_cryptography::SigningKey;
+
 #[cfg(test)]
 mod testing;
 #[cfg(test)]
 mod tests;
 
-const SEND_INTERVAL: Duration = Duration::from_millis(250);
-const DISCONNECT_PACKET_COUNT: usize = 10;
-const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
-const CLIENT_TIMEOUT_INTERVAL: Duration = Duration::from_secs(5);
-const REQUEST_BUFFER_SIZE: usize = 256;
-const EVENT_BUFFER_SIZE: usize = 256;
-
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Request<Addr> {
-    SendGameData { addr: Addr, data: Vec<u8> },
+    /// Admits a connection announced by [`Event::ConnectRequest`].
+    Accept { addr: Addr },
+    /// Turns away a connection announced by [`Event::ConnectRequest`], telling the client why.
+    Reject { addr: Addr, reason: RejectReason },
+    SendGameData {
+        addr: Addr,
+        channel: Channel,
+        data: Vec<u8>,
+    },
+    /// Disconnects a pending or connected client, giving it `reason` if set.
+    Disconnect { addr: Addr, reason: Option<String> },
+    /// Refuses new connections from `addr` until `duration` has passed, or indefinitely if it is
+    /// `None`. Any current connection from `addr` is ended with [`RejectReason::Banned`].
+    Ban {
+        addr: Addr,
+        duration: Option<Duration>,
+    },
+    /// Lifts a ban placed by [`Request::Ban`].
+    Unban { addr: Addr },
+    /// Asks for an [`Event::Stats`] describing the connection with `addr`.
+    QueryStats { addr: Addr },
+    /// Disconnects every client, giving them `reason` if set, and shuts down once they have all
+    /// been told. Dropping the cancel guard does the same without a reason.
+    Shutdown { reason: Option<String> },
 }
 
 pub struct ConnectionServer<Addr> {
@@ -41,7 +76,24 @@ pub struct ConnectionServer<Addr> {
     requests: Option<mpsc::Receiver<Request<Addr>>>,
     events: mpsc::Sender<Event<Addr>>,
     recv_buffer: Pin<Box<[u8; SAFE_RECV_BUFFER_SIZE]>>,
+    config: ConnectionConfig,
+    fragmenter: Fragmenter,
+    signing_key: Option<SigningKey>,
+    content_hash: Option<ContentHash>,
+    challenge_token_key: ChallengeTokenKey,
+    /// The epoch for challenge token timestamps.
+    started_at: Instant,
+    /// The nonces of challenge tokens that have already admitted a connection, each with the time
+    /// the token expires. A token is good for only one connection, even after that one ends.
+    used_challenge_tokens: HashMap<Nonce, Instant>,
     connections: HashMap<Addr, Connection<Addr>>,
+    /// The address of each connection, by the ID in its sealed packets.
+    addrs_by_connection_id: HashMap<ConnectionId, Addr>,
+    /// Banned addresses, each with the time its ban expires, if ever.
+    bans: HashMap<Addr, Option<Instant>>,
+    /// Set once shutdown begins. No new connections are admitted, and the server stops once the
+    /// existing ones finish disconnecting.
+    shutting_down: bool,
 }
 
 #[derive(Debug)]
@@ -51,8 +103,8 @@ enum InternalEvent<Addr> {
     SocketRecv(io::Result<(usize, Addr)>),
     ClientTimeout { addr: Addr },
     DisconnectElapsed { addr: Addr },
-    SendIntervalElapsed { addr: Addr },
     KeepaliveElapsed { addr: Addr },
+    ResendElapsed { addr: Addr },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -66,24 +118,67 @@ pub enum ConnectionState {
 #[must_use]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Event<Addr> {
-    State { addr: Addr, state: ConnectionState },
-    GameData { addr: Addr, data: Vec<u8> },
+    /// A client has completed the handshake and awaits a [`Request::Accept`] or
+    /// [`Request::Reject`]. The connection is in the [`ConnectionState::Pending`] state until then.
+    /// `protocol_version` is the version negotiated with the client for everything that follows.
+    ConnectRequest {
+        addr: Addr,
+        protocol_version: u16,
+    },
+    State {
+        addr: Addr,
+        state: ConnectionState,
+    },
+    GameData {
+        addr: Addr,
+        data: Vec<u8>,
+    },
+    /// Answers a [`Request::QueryStats`].
+    Stats {
+        addr: Addr,
+        stats: ConnectionStats,
+    },
+    /// A connected client's packets started arriving from a new address, and it answered a probe
+    /// sent there. The connection is known by `addr` from now on, and requests naming `old_addr`
+    /// are ignored.
+    Migrated {
+        old_addr: Addr,
+        addr: Addr,
+    },
+    /// The server has stopped. This is the last event, and follows every connection finishing
+    /// its disconnect.
     Dropped,
 }
 
 impl<Addr: AddrBound> ConnectionServer<Addr> {
+    /// Spawns a server on `socket`, timed and sized according to `config`. If a `signing_key` is
+    /// given, the server signs each
+    /// handshake with it so clients that have pinned the matching
+    /// [`VerifyingKey`](dungeon_vr_cryptography::VerifyingKey) can authenticate it. If a
+    /// `content_hash` is given, clients must present the same hash or be rejected with
+    /// [`RejectReason::VersionMismatch`].
     pub fn spawn(
         socket: Box<dyn BoundSocket<Addr>>,
+        config: ConnectionConfig,
+        signing_key: Option<SigningKey>,
+        content_hash: Option<ContentHash>,
     ) -> (
         cancel::Guard,
         mpsc::Sender<Request<Addr>>,
         mpsc::Receiver<Event<Addr>>,
     ) {
         let cancel_token = cancel::Token::new();
-        let (request_tx, request_rx) = mpsc::channel(REQUEST_BUFFER_SIZE);
-        let (event_tx, event_rx) = mpsc::channel(EVENT_BUFFER_SIZE);
+        let (request_tx, request_rx) = mpsc::channel(config.request_buffer_size());
+        let (event_tx, event_rx) = mpsc::channel(config.event_buffer_size());
 
-        let connection = Self::new(socket, request_rx, event_tx);
+        let connection = Self::new(
+            socket,
+            config,
+            signing_key,
+            content_hash,
+            request_rx,
+            event_tx,
+        );
         tokio::spawn(connection.run(cancel_token.clone()));
 
         (cancel_token.guard(), request_tx, event_rx)
@@ -91,6 +186,9 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
 
     fn new(
         socket: Box<dyn BoundSocket<Addr>>,
+        config: ConnectionConfig,
+        signing_key: Option<SigningKey>,
+        content_hash: Option<ContentHash>,
         requests: mpsc::Receiver<Request<Addr>>,
         events: mpsc::Sender<Event<Addr>>,
     ) -> Self {
@@ -99,12 +197,26 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
             requests: Some(requests),
             events,
             recv_buffer: Box::pin([0; SAFE_RECV_BUFFER_SIZE]),
+            fragmenter: Fragmenter::new(config.mtu()),
+            config,
+            signing_key,
+            content_hash,
+            challenge_token_key: ChallengeTokenKey::gen(),
+            started_at: Instant::now(),
+            used_challenge_tokens: HashMap::new(),
             connections: HashMap::new(),
+            addrs_by_connection_id: HashMap::new(),
+            bans: HashMap::new(),
+            shutting_down: false,
         }
     }
 
     async fn run(mut self, cancel_token: cancel::Token) {
-        while !cancel_token.is_cancelled() {
+        while !(self.shutting_down && self.connections.is_empty()) {
+            let cancelled = match self.shutting_down {
+                false => cancel_token.cancelled().left_future(),
+                true => pending().right_future(),
+            };
             let requests = match &mut self.requests {
                 Some(requests) => requests.recv().left_future(),
                 None => pending().right_future(),
@@ -118,7 +230,7 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
             let event = select! {
                 biased;
 
-                _ = cancel_token.cancelled() => InternalEvent::Cancelled,
+                _ = cancelled => InternalEvent::Cancelled,
 
                 result = requests => InternalEvent::Request(result),
 
@@ -129,10 +241,7 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
             drop(dynamic_events);
 
             match event {
-                InternalEvent::Cancelled => {
-                    self.handle_cancelled().await;
-                    break;
-                }
+                InternalEvent::Cancelled => self.handle_shutdown(None).await,
                 InternalEvent::Request(Some(request)) => self.handle_request(request).await,
                 InternalEvent::Request(None) => self.requests = None,
                 InternalEvent::SocketRecv(Ok((size, addr))) => {
@@ -147,12 +256,10 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
                 InternalEvent::DisconnectElapsed { addr } => {
                     self.handle_disconnect_elapsed(addr).await
                 }
-                InternalEvent::SendIntervalElapsed { addr } => {
-                    self.handle_pending_connection_send(addr).await
-                }
                 InternalEvent::KeepaliveElapsed { addr } => {
                     self.handle_keepalive_elapsed(addr).await
                 }
+                InternalEvent::ResendElapsed { addr } => self.handle_resend_elapsed(addr).await,
             }
         }
 
@@ -167,52 +274,240 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
         let _ = self.events.send(Event::Dropped).await;
     }
 
-    async fn handle_cancelled(&mut self) {
-        // TODO: Introduce a Disconnecting variant and put all connections into the Disconnecting
-        // state until they finish shutting down.
+    async fn handle_shutdown(&mut self, reason: Option<String>) {
+        if self.shutting_down {
+            log::debug!("Ignoring redundant shutdown");
+            return;
+        }
+        self.shutting_down = true;
+        log::info!("Shutting down");
+
+        // Put every connection into the Disconnecting state. Connections that are already
+        // disconnecting keep saying what they were saying.
+        let packet = DisconnectPacket::new(reason);
+        let mut events = Vec::new();
+        for (addr, connection) in &mut self.connections {
+            if connection.begin_disconnecting(Goodbye::Disconnect(packet.clone()), &self.config) {
+                events.push(Event::State {
+                    addr: *addr,
+                    state: ConnectionState::Disconnecting,
+                });
+            }
+        }
+        for event in events {
+            let _ = self.events.send(event).await;
+        }
+    }
+
+    async fn handle_request(&mut self, request: Request<Addr>) {
+        match request {
+            Request::Accept { addr } => self.handle_accept_request(addr).await,
+            Request::Reject { addr, reason } => self.handle_reject_request(addr, reason),
+            Request::SendGameData {
+                addr,
+                channel,
+                data,
+            } => {
+                self.handle_send_game_data_request(addr, channel, data)
+                    .await
+            }
+            Request::Disconnect { addr, reason } => {
+                self.handle_disconnect_request(addr, reason).await
+            }
+            Request::Ban { addr, duration } => self.handle_ban_request(addr, duration).await,
+            Request::Unban { addr } => self.handle_unban_request(addr),
+            Request::QueryStats { addr } => self.handle_query_stats_request(addr).await,
+            Request::Shutdown { reason } => self.handle_shutdown(reason).await,
+        }
+    }
+
+    async fn handle_accept_request(&mut self, addr: Addr) {
+        let connection = match self.connections.get_mut(&addr) {
+            Some(connection) => connection,
+            None => {
+                log::debug!("Ignoring Accept request: no connection for addr {addr}");
+                return;
+            }
+        };
+        if !matches!(connection.variant, ConnectionVariant::Pending) {
+            log::debug!("Ignoring Accept request: addr {addr} is not pending");
+            return;
+        }
+
+        // Advance this connection to the Connected state.
+        connection.variant = ConnectionVariant::Connected(Box::new(ConnectedConnection {
+            keepalive: Box::pin(sleep(Duration::ZERO)),
+            reliability: ReliabilityState::new(),
+            resend_interval: interval(self.config.resend_interval()),
+            reassembler: Reassembler::new(self.config.reassembly_timeout()),
+        }));
+        log::info!("Client {addr}: Connected");
+        let _ = self
+            .events
+            .send(Event::State {
+                addr,
+                state: ConnectionState::Connected,
+            })
+            .await;
+    }
+
+    fn handle_reject_request(&mut self, addr: Addr, reason: RejectReason) {
+        let connection = match self.connections.get_mut(&addr) {
+            Some(connection) => connection,
+            None => {
+                log::debug!("Ignoring Reject request: no connection for addr {addr}");
+                return;
+            }
+        };
+        if !matches!(connection.variant, ConnectionVariant::Pending) {
+            log::debug!("Ignoring Reject request: addr {addr} is not pending");
+            return;
+        }
+
+        connection.timeout = None;
+        connection.variant = ConnectionVariant::Disconnecting(DisconnectingConnection::new(
+            Goodbye::Reject(reason),
+            &self.config,
+        ));
+        log::info!("Client {addr}: Rejecting ({reason})");
+    }
+
+    async fn handle_disconnect_request(&mut self, addr: Addr, reason: Option<String>) {
+        let connection = match self.connections.get_mut(&addr) {
+            Some(connection) => connection,
+            None => {
+                log::debug!("Ignoring Disconnect request: no connection for addr {addr}");
+                return;
+            }
+        };
+        let goodbye = Goodbye::Disconnect(DisconnectPacket::new(reason));
+        if !connection.begin_disconnecting(goodbye, &self.config) {
+            log::debug!("Ignoring Disconnect request: addr {addr} is already disconnecting");
+            return;
+        }
+        log::info!("Client {addr}: Disconnecting (requested)");
+        let _ = self
+            .events
+            .send(Event::State {
+                addr,
+                state: ConnectionState::Disconnecting,
+            })
+            .await;
+    }
 
-        // For now, just tell the event reciever that each connected player has disconnected.
+    async fn handle_ban_request(&mut self, addr: Addr, duration: Option<Duration>) {
+        self.bans
+            .insert(addr, duration.map(|duration| Instant::now() + duration));
+        match duration {
+            Some(duration) => log::info!("Client {addr}: Banned for {duration:?}"),
+            None => log::info!("Client {addr}: Banned"),
+        }
 
-        for (addr, _) in self.connections.drain() {
+        let connection = match self.connections.get_mut(&addr) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if connection.begin_disconnecting(Goodbye::Reject(RejectReason::Banned), &self.config) {
+            log::info!("Client {addr}: Disconnecting (banned)");
             let _ = self
                 .events
                 .send(Event::State {
                     addr,
-                    state: ConnectionState::Disconnected,
+                    state: ConnectionState::Disconnecting,
                 })
                 .await;
         }
     }
 
-    async fn handle_request(&mut self, request: Request<Addr>) {
-        match request {
-            Request::SendGameData { addr, data } => {
-                self.handle_send_game_data_request(addr, data).await
-            }
+    fn handle_unban_request(&mut self, addr: Addr) {
+        if self.bans.remove(&addr).is_some() {
+            log::info!("Client {addr}: Unbanned");
+        } else {
+            log::debug!("Ignoring Unban request: addr {addr} is not banned");
         }
     }
 
-    async fn handle_send_game_data_request(&mut self, addr: Addr, data: Vec<u8>) {
-        match self.connections.get(&addr) {
-            Some(connection) => {
-                let socket = &*self.socket;
-                send_packet(
-                    socket,
-                    addr,
-                    Packet::GameData(Sealed::seal_ext::<UnframedByteVec>(
-                        data,
-                        &connection.shared_secret,
-                    )),
-                )
-                .await;
+    async fn handle_query_stats_request(&mut self, addr: Addr) {
+        let connection = match self.connections.get(&addr) {
+            Some(connection) => connection,
+            None => {
+                log::debug!("Ignoring QueryStats request: no connection for addr {addr}");
+                return;
+            }
+        };
+        let stats = connection.stats.snapshot(Some(&connection.key));
+        let _ = self.events.send(Event::Stats { addr, stats }).await;
+    }
+
+    /// Checks whether `addr` is banned, forgetting its ban if it has expired.
+    fn is_banned(&mut self, addr: Addr) -> bool {
+        match self.bans.get(&addr) {
+            None => false,
+            Some(None) => true,
+            Some(Some(expiry)) if Instant::now() < *expiry => true,
+            Some(Some(_)) => {
+                self.bans.remove(&addr);
+                false
             }
+        }
+    }
+
+    async fn handle_send_game_data_request(&mut self, addr: Addr, channel: Channel, data: Vec<u8>) {
+        let connection = match self.connections.get_mut(&addr) {
+            Some(connection) => connection,
             None => {
-                log::debug!("Dropping outgoing game data: no connection for addr {addr}",);
+                log::debug!("Dropping outgoing game data: no connection for addr {addr}");
+                return;
+            }
+        };
+        let connected = match &mut connection.variant {
+            ConnectionVariant::Connected(connected) => connected,
+            _ => {
+                log::debug!("Dropping outgoing game data: addr {addr} is not connected");
+                return;
+            }
+        };
+        let packet = match connected.reliability.send(channel, data) {
+            Ok(Some(packet)) => packet,
+            // Held back until the client acknowledges earlier messages.
+            Ok(None) => return,
+            Err(e) => {
+                // The client has stopped acknowledging, so give up on it rather than queue
+                // without bound.
+                connection.begin_disconnecting(
+                    Goodbye::Disconnect(DisconnectPacket::default()),
+                    &self.config,
+                );
+                log::warn!("Client {addr}: Disconnecting ({e})");
+                let _ = self
+                    .events
+                    .send(Event::State {
+                        addr,
+                        state: ConnectionState::Disconnecting,
+                    })
+                    .await;
+                return;
             }
+        };
+        let socket = &*self.socket;
+        if send_game_data_packet(
+            socket,
+            &mut self.fragmenter,
+            addr,
+            packet,
+            &mut connection.key,
+            &mut connection.stats,
+        )
+        .await
+        {
+            connected.refresh_keepalive(&self.config);
         }
     }
 
     async fn handle_socket_recv(&mut self, size: usize, addr: Addr) {
+        if let Some(connection) = self.connections.get_mut(&addr) {
+            connection.stats.record_received(size);
+        }
         let mut r = &self.recv_buffer[..size];
         let packet = match Packet::read_from(&mut r) {
             Ok(packet) => packet,
@@ -229,14 +524,27 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
             );
             return;
         }
+        if !self.connections.contains_key(&addr) {
+            // A client that is leaving has no reason to move, so a Disconnect never migrates.
+            if let Packet::Keepalive(_)
+            | Packet::GameData(_)
+            | Packet::Fragment(_)
+            | Packet::Ack(_) = packet
+            {
+                self.handle_unfamiliar_packet(size, addr, packet).await;
+                return;
+            }
+        }
         match packet {
             Packet::Disconnect(sealed) => self.handle_disconnect_packet(addr, sealed).await,
             Packet::ConnectInit(packet) => self.handle_connect_init_packet(addr, packet).await,
-            Packet::ConnectResponse(sealed) => {
-                self.handle_connect_response_packet(addr, sealed).await;
+            Packet::ConnectResponse(packet) => {
+                self.handle_connect_response_packet(addr, packet).await;
             }
-            Packet::Keepalive(sealed) => self.handle_keepalive_packet(addr, sealed),
+            Packet::Keepalive(sealed) => self.handle_keepalive_packet(addr, sealed).await,
             Packet::GameData(sealed) => self.handle_game_data_packet(addr, sealed).await,
+            Packet::Fragment(sealed) => self.handle_fragment_packet(addr, sealed).await,
+            Packet::Ack(sealed) => self.handle_ack_packet(addr, sealed),
             _ => {
                 log::debug!(
                     "Client {addr}: Dropping unexpected {:?} packet",
@@ -246,7 +554,118 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
         }
     }
 
-    async fn handle_disconnect_packet(&mut self, addr: Addr, sealed: Sealed<()>) {
+    /// Handles a sealed packet from an address with no connection. If it shows that a connected
+    /// client has moved there, the new address is probed with a Ping, and the connection only
+    /// follows once the matching Pong comes back from it. Until then, packets from the new address
+    /// are dropped and everything is still sent to the old one.
+    async fn handle_unfamiliar_packet(&mut self, size: usize, addr: Addr, packet: Packet) {
+        let old_addr = match &packet {
+            Packet::Keepalive(sealed) => self.find_moved_connection(addr, sealed),
+            Packet::GameData(sealed) => self.find_moved_connection(addr, sealed),
+            Packet::Fragment(sealed) => self.find_moved_connection(addr, sealed),
+            Packet::Ack(sealed) => self.find_moved_connection(addr, sealed),
+            _ => unreachable!(),
+        };
+        let old_addr = match old_addr {
+            Some(old_addr) => old_addr,
+            None => {
+                log::debug!(
+                    "Client {addr}: Dropping {:?} packet: not connected",
+                    packet.kind(),
+                );
+                return;
+            }
+        };
+        if let Packet::Keepalive(sealed) = &packet {
+            if self.try_complete_migration(old_addr, addr, sealed).await {
+                let connection = self.connections.get_mut(&addr).unwrap();
+                connection.stats.record_received(size);
+                return;
+            }
+        }
+        self.probe_path(old_addr, addr).await;
+    }
+
+    /// Returns the address of the connected client that `sealed` shows has moved to `addr`. Only
+    /// an authentic packet from the client that is newer than everything else it has sent counts,
+    /// so a replayed or delayed packet can't lead the connection astray.
+    fn find_moved_connection<P>(&mut self, addr: Addr, sealed: &Sealed<P>) -> Option<Addr> {
+        let old_addr = *self.addrs_by_connection_id.get(&sealed.connection_id())?;
+        if self.is_banned(addr) {
+            log::debug!("Client {old_addr}: Not migrating to {addr}: banned");
+            return None;
+        }
+        let connection = self.connections.get_mut(&old_addr).unwrap();
+        if !matches!(connection.variant, ConnectionVariant::Connected(_)) {
+            log::debug!("Client {old_addr}: Not migrating to {addr}: not connected");
+            return None;
+        }
+        if let Err(e) = connection.key.authenticate_newest(sealed) {
+            log::debug!("Client {old_addr}: Not migrating to {addr}: {e}");
+            return None;
+        }
+        Some(old_addr)
+    }
+
+    /// Moves the connection at `old_addr` to `addr` if `sealed` answers the probe sent there.
+    /// Returns whether the connection moved.
+    async fn try_complete_migration(
+        &mut self,
+        old_addr: Addr,
+        addr: Addr,
+        sealed: &Sealed<KeepalivePacket>,
+    ) -> bool {
+        let connection = self.connections.get_mut(&old_addr).unwrap();
+        let ping_id = match &connection.path_probe {
+            Some(probe) if probe.addr == addr => probe.ping_id,
+            _ => return false,
+        };
+        match connection.key.open(sealed) {
+            Ok(KeepalivePacket::Pong { id }) if id == ping_id => {
+                connection.stats.pong(id, Instant::now().into_std());
+            }
+            _ => return false,
+        }
+        connection.path_probe = None;
+        connection.refresh_timeout(&self.config);
+
+        let connection = self.connections.remove(&old_addr).unwrap();
+        self.addrs_by_connection_id
+            .insert(connection.key.connection_id(), addr);
+        self.connections.insert(addr, connection);
+        log::info!("Client {old_addr}: Migrated to {addr}");
+        let _ = self.events.send(Event::Migrated { old_addr, addr }).await;
+        true
+    }
+
+    /// Sends a Ping to `addr` to check that the client at `old_addr` can be reached there. Probes
+    /// are repeated at most once per resend interval.
+    async fn probe_path(&mut self, old_addr: Addr, addr: Addr) {
+        let now = Instant::now();
+        let connection = self.connections.get_mut(&old_addr).unwrap();
+        if let Some(probe) = &connection.path_probe {
+            if probe.addr == addr && now < probe.sent_at + self.config.resend_interval() {
+                log::debug!("Client {old_addr}: Dropping packet from {addr}: probe outstanding");
+                return;
+            }
+        }
+        let ping = connection.stats.ping(now.into_std());
+        let ping_id = match ping {
+            KeepalivePacket::Ping { id } => id,
+            KeepalivePacket::Pong { .. } => unreachable!(),
+        };
+        let socket = &*self.socket;
+        let size = send_packet(socket, addr, Packet::Keepalive(connection.key.seal(ping))).await;
+        connection.stats.record_sent(size);
+        connection.path_probe = Some(PathProbe {
+            addr,
+            ping_id,
+            sent_at: now,
+        });
+        log::debug!("Client {old_addr}: Probing {addr}");
+    }
+
+    async fn handle_disconnect_packet(&mut self, addr: Addr, sealed: Sealed<DisconnectPacket>) {
         let connection = match self.connections.get_mut(&addr) {
             Some(connection) => connection,
             None => {
@@ -254,22 +673,27 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
                 return;
             }
         };
-        if let Err(e) = sealed.open(&connection.shared_secret) {
-            log::debug!("Client {addr}: Dropping Disconnect packet: {e}");
-            return;
-        }
+        let packet = match connection.key.open(&sealed) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!("Client {addr}: Dropping Disconnect packet: {e}");
+                return;
+            }
+        };
 
         let event = match connection.variant {
             // TODO: Think about this a bit more.
-            ConnectionVariant::Pending(_)
-            | ConnectionVariant::Connected(ConnectedConnection { .. }) => Some(Event::State {
+            ConnectionVariant::Pending | ConnectionVariant::Connected(_) => Some(Event::State {
                 addr,
                 state: ConnectionState::Disconnected,
             }),
             _ => None,
         };
-        self.connections.remove(&addr);
-        log::info!("Client {addr}: Disconnected");
+        self.remove_connection(addr);
+        match packet.reason {
+            Some(reason) => log::info!("Client {addr}: Disconnected ({reason})"),
+            None => log::info!("Client {addr}: Disconnected"),
+        }
         if let Some(event) = event {
             let _ = self.events.send(event).await;
         }
@@ -287,6 +711,14 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
             log::debug!("Client {addr}: Dropping redundant ConnectInit packet");
             return;
         }
+        if self.shutting_down {
+            log::debug!("Client {addr}: Dropping ConnectInit packet: shutting down");
+            return;
+        }
+        if self.is_banned(addr) {
+            log::debug!("Client {addr}: Dropping ConnectInit packet: banned");
+            return;
+        }
 
         // Perform our side of the ECDH key exchange.
         let private_key = PrivateKey::gen();
@@ -301,143 +733,308 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
             }
         };
 
-        // Record the new connection.
-        let token = ChallengeToken::gen();
-        self.connections.insert(
+        // Settle on a protocol version now. An incompatible client still completes the handshake
+        // so that its rejection can be sealed.
+        let content_matches =
+            self.content_hash.is_none() || packet.content_hash == self.content_hash;
+        let protocol_version = ProtocolVersions::SUPPORTED
+            .negotiate(packet.protocol_versions)
+            .filter(|_| content_matches);
+
+        // Vouch for our ephemeral key if we have an identity.
+        let signature = self.signing_key.as_ref().map(|signing_key| {
+            signing_key.sign(&signed_message(
+                &packet.client_public_key,
+                &server_public_key,
+                ProtocolVersions::SUPPORTED,
+            ))
+        });
+
+        // Hand the key exchange back to the client in a token instead of recording anything. The
+        // source address is unverified at this point, so a connection only comes into existence
+        // once the token is echoed from the same address. The client resends ConnectInit until it
+        // is challenged, so there is no need to resend the challenge either.
+        let token = self.challenge_token_key.issue(
+            &ChallengeTokenContents {
+                server_private_key: private_key,
+                client_public_key: packet.client_public_key,
+                protocol_version,
+            },
+            addr,
+            self.started_at.elapsed(),
+        );
+        // There is no SealingKey to number this packet, so it goes outside the session's sequence.
+        let socket = &*self.socket;
+        send_packet(
+            socket,
+            addr,
+            Packet::ConnectChallenge(ConnectChallengePacket {
+                server_public_key,
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                signature,
+                sealed_payload: Sealed::seal(token, Role::Server, 0, &shared_secret),
+            }),
+        )
+        .await;
+    }
+
+    async fn handle_connect_response_packet(&mut self, addr: Addr, packet: ConnectResponsePacket) {
+        if let Some(connection) = self.connections.get(&addr) {
+            match connection.variant {
+                ConnectionVariant::Pending | ConnectionVariant::Connected(_) => {
+                    log::debug!("Client {addr}: Dropping redundant ConnectResponse packet");
+                }
+                ConnectionVariant::Disconnecting(_) => {
+                    log::debug!("Client {addr}: Dropping ConnectResponse packet: disconnecting");
+                }
+            }
+            return;
+        }
+        if self.shutting_down {
+            log::debug!("Client {addr}: Dropping ConnectResponse packet: shutting down");
+            return;
+        }
+        let contents = match self.challenge_token_key.open(
+            &packet.token,
+            addr,
+            self.started_at.elapsed(),
+            self.config.challenge_token_lifetime(),
+        ) {
+            Ok(contents) => contents,
+            Err(e) => {
+                log::debug!("Client {addr}: Dropping ConnectResponse packet: {e}");
+                return;
+            }
+        };
+        let now = Instant::now();
+        let nonce = packet.token.nonce();
+        self.used_challenge_tokens
+            .retain(|_, expires_at| now <= *expires_at);
+        if self.used_challenge_tokens.contains_key(&nonce) {
+            log::debug!("Client {addr}: Dropping ConnectResponse packet: challenge token reused");
+            return;
+        }
+        let shared_secret = match contents
+            .server_private_key
+            .exchange(&contents.client_public_key)
+        {
+            Ok(shared_secret) => shared_secret,
+            // The same exchange already succeeded before the token was issued.
+            Err(KeyExchangeError::NonContributory) => unreachable!(),
+        };
+        let mut key = SealingKey::new(shared_secret, Role::Server);
+        if let Err(e) = key.open(&packet.sealed_payload) {
+            log::debug!("Client {addr}: Dropping ConnectResponse packet: {e}");
+            return;
+        }
+        self.used_challenge_tokens
+            .insert(nonce, now + self.config.challenge_token_lifetime());
+        if let Err(e) = log_shared_secret(key.shared_secret()) {
+            log::warn!("Unable to write key log: {e}");
+        }
+
+        // The client may have been banned since it was challenged.
+        let reject_reason = if self.is_banned(addr) {
+            Some(RejectReason::Banned)
+        } else if contents.protocol_version.is_none() {
+            Some(RejectReason::VersionMismatch)
+        } else {
+            None
+        };
+        if let Some(reason) = reject_reason {
+            // Keep the connection just long enough to tell the client why it was turned away.
+            self.insert_connection(
+                addr,
+                Connection {
+                    key,
+                    timeout: None,
+                    stats: StatsTracker::new(),
+                    variant: ConnectionVariant::Disconnecting(DisconnectingConnection::new(
+                        Goodbye::Reject(reason),
+                        &self.config,
+                    )),
+                    path_probe: None,
+                },
+            );
+            log::info!("Client {addr}: Rejecting ({reason})");
+            return;
+        }
+        let protocol_version = contents.protocol_version.unwrap();
+
+        // Record the new connection and ask whether to admit it.
+        self.insert_connection(
             addr,
             Connection {
-                shared_secret,
-                timeout: Some(Box::pin(sleep(CLIENT_TIMEOUT_INTERVAL))),
-                variant: ConnectionVariant::Pending(PendingConnection {
-                    server_public_key,
-                    token,
-                    send_interval: interval(SEND_INTERVAL),
-                }),
-                _phantom_addr: PhantomData,
+                key,
+                timeout: Some(Box::pin(sleep(self.config.timeout()))),
+                stats: StatsTracker::new(),
+                variant: ConnectionVariant::Pending,
+                path_probe: None,
             },
         );
-        log::info!("Client {addr}: New connection pending");
+        log::info!("Client {addr}: New connection pending (protocol version {protocol_version})");
         let _ = self
             .events
-            .send(Event::State {
+            .send(Event::ConnectRequest {
                 addr,
-                state: ConnectionState::Pending,
+                protocol_version,
             })
             .await;
     }
 
-    async fn handle_connect_response_packet(&mut self, addr: Addr, sealed: Sealed<ChallengeToken>) {
+    async fn handle_keepalive_packet(&mut self, addr: Addr, sealed: Sealed<KeepalivePacket>) {
         let connection = match self.connections.get_mut(&addr) {
             Some(connection) => connection,
             None => {
-                log::debug!("Client {addr}: Dropping ConnectResponse packet: not connected");
+                log::debug!("Client {addr}: Dropping Keepalive packet: not connected");
                 return;
             }
         };
-        let packet_token = match sealed.open(&connection.shared_secret) {
-            Ok(token) => token,
+        let packet = match connection.key.open(&sealed) {
+            Ok(packet) => packet,
             Err(e) => {
-                log::debug!("Client {addr}: Dropping ConnectResponse packet: {e}");
+                log::debug!("Client {addr}: Dropping Keepalive packet: {e}");
                 return;
             }
         };
-        match connection.variant {
-            ConnectionVariant::Pending(PendingConnection { token, .. }) => {
-                if packet_token != token {
-                    log::debug!(
-                        "Client {addr}: Dropping ConnectResponse packet: bad challenge token",
-                    );
-                    return;
+        connection.refresh_timeout(&self.config);
+        match packet {
+            KeepalivePacket::Ping { id } => {
+                if !matches!(connection.variant, ConnectionVariant::Disconnecting(_)) {
+                    let packet =
+                        Packet::Keepalive(connection.key.seal(KeepalivePacket::Pong { id }));
+                    let size = send_packet(&*self.socket, addr, packet).await;
+                    connection.stats.record_sent(size);
+                    connection.refresh_keepalive(&self.config);
                 }
-                // OK to proceed.
             }
-            ConnectionVariant::Connected(_) => {
-                log::debug!("Client {addr}: Dropping redundant ConnectResponse packet");
+            KeepalivePacket::Pong { id } => connection.stats.pong(id, Instant::now().into_std()),
+        }
+    }
+
+    async fn handle_game_data_packet(&mut self, addr: Addr, sealed: Sealed<GameDataPacket>) {
+        let connection = match self.connections.get_mut(&addr) {
+            Some(connection) => connection,
+            None => {
+                log::debug!("Client {addr}: Dropping GameData packet: not connected");
                 return;
             }
-            ConnectionVariant::Disconnecting(_) => {
-                log::debug!("Client {addr}: Dropping ConnectResponse packet: disconnecting");
+        };
+        if !matches!(connection.variant, ConnectionVariant::Connected(_)) {
+            log::debug!("Client {addr}: Dropping GameData packet: not connected");
+            return;
+        }
+        let packet = match connection.key.open(&sealed) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!("Client {addr}: Dropping GameData packet: {e}");
                 return;
             }
-        }
-
-        // Advance this connection to the Connected state.
-        connection.variant = ConnectionVariant::Connected(ConnectedConnection {
-            keepalive: Box::pin(sleep(Duration::ZERO)),
-        });
-        log::info!("Client {addr}: Connected");
-        let _ = self
-            .events
-            .send(Event::State {
-                addr,
-                state: ConnectionState::Connected,
-            })
-            .await;
+        };
+        self.receive_game_data(addr, packet).await;
     }
 
-    fn handle_keepalive_packet(&mut self, addr: Addr, sealed: Sealed<()>) {
+    async fn handle_fragment_packet(&mut self, addr: Addr, sealed: Sealed<FragmentPacket>) {
         let connection = match self.connections.get_mut(&addr) {
             Some(connection) => connection,
             None => {
-                log::debug!("Client {addr}: Dropping Keepalive packet: not connected");
+                log::debug!("Client {addr}: Dropping Fragment packet: not connected");
                 return;
             }
         };
-        match sealed.open(&connection.shared_secret) {
-            Ok(()) => (),
+        let connected = match &mut connection.variant {
+            ConnectionVariant::Connected(connected) => connected,
+            _ => {
+                log::debug!("Client {addr}: Dropping Fragment packet: not connected");
+                return;
+            }
+        };
+        let fragment = match connection.key.open(&sealed) {
+            Ok(fragment) => fragment,
             Err(e) => {
-                log::debug!("Client {addr}: Dropping Keepalive packet: {e}");
+                log::debug!("Client {addr}: Dropping Fragment packet: {e}");
+                return;
+            }
+        };
+        let packet = match connected
+            .reassembler
+            .insert(fragment, Instant::now().into_std())
+        {
+            Ok(Some(packet)) => packet,
+            Ok(None) => {
+                connection.refresh_timeout(&self.config);
                 return;
             }
+            Err(e) => {
+                log::debug!("Client {addr}: Dropping Fragment packet: {e}");
+                return;
+            }
+        };
+        self.receive_game_data(addr, packet).await;
+    }
+
+    /// Handles a GameData packet that arrived whole or was reassembled from fragments.
+    async fn receive_game_data(&mut self, addr: Addr, packet: GameDataPacket) {
+        let connection = self.connections.get_mut(&addr).unwrap();
+        let connected = match &mut connection.variant {
+            ConnectionVariant::Connected(connected) => connected,
+            _ => unreachable!(),
+        };
+        let received = connected.reliability.receive(packet);
+        connection.refresh_timeout(&self.config);
+        if let Some(ack) = received.ack {
+            let socket = &*self.socket;
+            let size = send_packet(socket, addr, Packet::Ack(connection.key.seal(ack))).await;
+            connection.stats.record_sent(size);
+            connection.refresh_keepalive(&self.config);
+        }
+        for data in received.messages {
+            let _ = self.events.send(Event::GameData { addr, data }).await;
         }
-        connection.refresh_timeout();
     }
 
-    async fn handle_game_data_packet(&mut self, addr: Addr, sealed: Sealed<Vec<u8>>) {
+    fn handle_ack_packet(&mut self, addr: Addr, sealed: Sealed<AckPacket>) {
         let connection = match self.connections.get_mut(&addr) {
             Some(connection) => connection,
             None => {
-                log::debug!("Client {addr}: Dropping GameData packet: not connected");
+                log::debug!("Client {addr}: Dropping Ack packet: not connected");
                 return;
             }
         };
-        match connection.variant {
-            ConnectionVariant::Connected(ConnectedConnection { .. }) => (),
+        let connected = match &mut connection.variant {
+            ConnectionVariant::Connected(connected) => connected,
             _ => {
-                log::debug!("Client {addr}: Dropping GameData packet: not connected");
+                log::debug!("Client {addr}: Dropping Ack packet: not connected");
                 return;
             }
-        }
-        let data = match sealed.open_ext::<UnframedByteVec>(&connection.shared_secret) {
-            Ok(game_data) => game_data,
+        };
+        let ack = match connection.key.open(&sealed) {
+            Ok(ack) => ack,
             Err(e) => {
-                log::debug!("Client {addr}: Dropping GameData packet: {e}");
+                log::debug!("Client {addr}: Dropping Ack packet: {e}");
                 return;
             }
         };
-        connection.refresh_timeout();
-        let _ = self.events.send(Event::GameData { addr, data }).await;
+        connected.reliability.handle_ack(ack);
+        connection.refresh_timeout(&self.config);
     }
 
     async fn handle_client_timeout(&mut self, addr: Addr) {
         let connection = self.connections.get_mut(&addr).unwrap();
-        let event = match connection.variant {
-            ConnectionVariant::Pending(_)
-            | ConnectionVariant::Connected(ConnectedConnection { .. }) => Some(Event::State {
+        // Disconnecting connections have no timeout, so this always changes state.
+        let changed = connection.begin_disconnecting(
+            Goodbye::Disconnect(DisconnectPacket::default()),
+            &self.config,
+        );
+        assert!(changed);
+        log::info!("Client {addr}: Disconnecting (timed out)");
+        let _ = self
+            .events
+            .send(Event::State {
                 addr,
                 state: ConnectionState::Disconnecting,
-            }),
-            _ => None,
-        };
-        connection.timeout = None;
-        connection.variant = ConnectionVariant::Disconnecting(DisconnectingConnection {
-            interval: interval(SEND_INTERVAL),
-            packets_to_send: DISCONNECT_PACKET_COUNT,
-        });
-        log::info!("Client {addr}: Disconnecting (timed out)");
-        if let Some(event) = event {
-            let _ = self.events.send(event).await;
-        }
+            })
+            .await;
     }
 
     async fn handle_disconnect_elapsed(&mut self, addr: Addr) {
@@ -447,87 +1044,159 @@ impl<Addr: AddrBound> ConnectionServer<Addr> {
             _ => unreachable!(),
         };
 
+        let packet = match &disconnecting.goodbye {
+            Goodbye::Disconnect(packet) => Packet::Disconnect(connection.key.seal(packet.clone())),
+            Goodbye::Reject(reason) => Packet::Reject(connection.key.seal(*reason)),
+        };
         let socket = &*self.socket;
-        send_packet(
-            socket,
-            addr,
-            Packet::Disconnect(Sealed::seal((), &connection.shared_secret)),
-        )
-        .await;
+        let size = send_packet(socket, addr, packet).await;
+        connection.stats.record_sent(size);
 
         disconnecting.packets_to_send -= 1;
         if disconnecting.packets_to_send == 0 {
-            self.connections.remove(&addr);
+            self.remove_connection(addr);
             log::debug!("Client {addr}: Done sending Disconnect packets")
         }
     }
 
-    async fn handle_pending_connection_send(&mut self, addr: Addr) {
+    /// Inserts a connection, keeping the connection ID index up to date. Connections only come
+    /// into existence after a successful handshake, so IDs never collide in practice.
+    fn insert_connection(&mut self, addr: Addr, connection: Connection<Addr>) {
+        self.addrs_by_connection_id
+            .insert(connection.key.connection_id(), addr);
+        self.connections.insert(addr, connection);
+    }
+
+    fn remove_connection(&mut self, addr: Addr) {
+        let connection = self.connections.remove(&addr).unwrap();
+        self.addrs_by_connection_id
+            .remove(&connection.key.connection_id());
+    }
+
+    async fn handle_keepalive_elapsed(&mut self, addr: Addr) {
         let connection = self.connections.get_mut(&addr).unwrap();
-        let pending = match &connection.variant {
-            ConnectionVariant::Pending(pending) => pending,
-            _ => unreachable!(),
-        };
+        let ping = connection.stats.ping(Instant::now().into_std());
         let socket = &*self.socket;
-        send_packet(
-            socket,
-            addr,
-            Packet::ConnectChallenge(ConnectChallengePacket {
-                server_public_key: pending.server_public_key,
-                sealed_payload: Sealed::seal(pending.token, &connection.shared_secret),
-            }),
-        )
-        .await;
+        let size = send_packet(socket, addr, Packet::Keepalive(connection.key.seal(ping))).await;
+        connection.stats.record_sent(size);
+        connection.refresh_keepalive(&self.config);
     }
 
-    async fn handle_keepalive_elapsed(&mut self, addr: Addr) {
+    async fn handle_resend_elapsed(&mut self, addr: Addr) {
         let connection = self.connections.get_mut(&addr).unwrap();
+        let connected = match &mut connection.variant {
+            ConnectionVariant::Connected(connected) => connected,
+            _ => unreachable!(),
+        };
         let socket = &*self.socket;
-        send_packet(
-            socket,
-            addr,
-            Packet::Keepalive(Sealed::seal((), &connection.shared_secret)),
-        )
-        .await;
-        connection.refresh_keepalive();
+        let mut sent = false;
+        for packet in connected.reliability.retransmissions() {
+            sent |= send_game_data_packet(
+                socket,
+                &mut self.fragmenter,
+                addr,
+                packet,
+                &mut connection.key,
+                &mut connection.stats,
+            )
+            .await;
+        }
+        if sent {
+            connected.refresh_keepalive(&self.config);
+        }
+    }
+}
+
+/// Seals and sends game data, fragmenting it as needed. Returns whether anything was sent.
+async fn send_game_data_packet<Addr: AddrBound>(
+    socket: &dyn BoundSocket<Addr>,
+    fragmenter: &mut Fragmenter,
+    addr: Addr,
+    packet: GameDataPacket,
+    key: &mut SealingKey,
+    stats: &mut StatsTracker,
+) -> bool {
+    let packets = match fragmenter.seal(packet, key) {
+        Some(packets) => packets,
+        None => {
+            log::debug!("Dropping outgoing game data for addr {addr}: too large to fragment");
+            return false;
+        }
+    };
+    for packet in packets {
+        let size = send_packet(socket, addr, packet).await;
+        stats.record_sent(size);
     }
+    true
 }
 
-async fn send_packet<Addr: AddrBound>(socket: &dyn BoundSocket<Addr>, addr: Addr, packet: Packet) {
+/// Encodes and sends a packet, returning its size in bytes.
+async fn send_packet<Addr: AddrBound>(
+    socket: &dyn BoundSocket<Addr>,
+    addr: Addr,
+    packet: Packet,
+) -> usize {
     let mut w = Vec::new();
     packet.write_to(&mut w).unwrap();
     // NOTE: Ignore any error. Windows in particular returns errors based on previous ICMP activity.
     // These are not useful. If connectivity is disrupted, the timeout mechanism will eventually
     // notice.
     _ = socket.send_to(&w, addr).await;
+    w.len()
 }
 
 struct Connection<Addr> {
-    shared_secret: SharedSecret,
+    key: SealingKey,
     timeout: Option<Pin<Box<Sleep>>>,
+    stats: StatsTracker,
     variant: ConnectionVariant,
-    _phantom_addr: PhantomData<Addr>,
+    /// A check that the client can be reached at a new address, before moving the connection
+    /// there.
+    path_probe: Option<PathProbe<Addr>>,
 }
 
-enum ConnectionVariant {
-    Pending(PendingConnection),
-    Connected(ConnectedConnection),
-    Disconnecting(DisconnectingConnection),
+/// A Ping sent to an address a connected client's packets started arriving from.
+struct PathProbe<Addr> {
+    addr: Addr,
+    ping_id: u32,
+    sent_at: Instant,
 }
 
-struct PendingConnection {
-    server_public_key: PublicKey,
-    token: ChallengeToken,
-    send_interval: Interval,
+enum ConnectionVariant {
+    /// Awaiting a [`Request::Accept`] or [`Request::Reject`].
+    Pending,
+    Connected(Box<ConnectedConnection>),
+    Disconnecting(DisconnectingConnection),
 }
 
 struct ConnectedConnection {
     keepalive: Pin<Box<Sleep>>,
+    reliability: ReliabilityState,
+    resend_interval: Interval,
+    reassembler: Reassembler,
 }
 
 struct DisconnectingConnection {
     interval: Interval,
     packets_to_send: usize,
+    goodbye: Goodbye,
+}
+
+/// What a disconnecting connection repeatedly tells the client.
+enum Goodbye {
+    Disconnect(DisconnectPacket),
+    /// The connection was rejected before it was admitted.
+    Reject(RejectReason),
+}
+
+impl DisconnectingConnection {
+    fn new(goodbye: Goodbye, config: &ConnectionConfig) -> Self {
+        Self {
+            interval: interval(config.send_interval()),
+            packets_to_send: config.disconnect_packet_count(),
+            goodbye,
+        }
+    }
 }
 
 impl<Addr> Connection<Addr> {
@@ -536,15 +1205,15 @@ impl<Addr> Connection<Addr> {
             Some(timeout) => timeout.left_future(),
             None => pending().right_future(),
         };
-        let (send_interval, keepalive_elapsed, disconnect_elapsed) = match &mut self.variant {
-            ConnectionVariant::Pending(PendingConnection { send_interval, .. }) => (
-                send_interval.tick().left_future(),
+        let (keepalive_elapsed, resend_elapsed, disconnect_elapsed) = match &mut self.variant {
+            ConnectionVariant::Pending => (
                 pending().right_future(),
                 pending().right_future(),
-            ),
-            ConnectionVariant::Connected(ConnectedConnection { keepalive, .. }) => (
                 pending().right_future(),
-                keepalive.left_future(),
+            ),
+            ConnectionVariant::Connected(connected) => (
+                (&mut connected.keepalive).left_future(),
+                connected.resend_interval.tick().left_future(),
                 pending().right_future(),
             ),
             ConnectionVariant::Disconnecting(DisconnectingConnection { interval, .. }) => (
@@ -557,38 +1226,48 @@ impl<Addr> Connection<Addr> {
         select! {
             biased;
 
-            _ = send_interval => InternalEvent::SendIntervalElapsed { addr },
-
             _ = keepalive_elapsed => InternalEvent::KeepaliveElapsed { addr },
 
+            _ = resend_elapsed => InternalEvent::ResendElapsed { addr },
+
             _ = disconnect_elapsed => InternalEvent::DisconnectElapsed { addr },
 
             _ = timeout => InternalEvent::ClientTimeout { addr },
         }
     }
 
+    /// Moves a pending or connected connection into the Disconnecting state. Returns false and
+    /// leaves the connection alone if it was already disconnecting.
+    fn begin_disconnecting(&mut self, goodbye: Goodbye, config: &ConnectionConfig) -> bool {
+        if let ConnectionVariant::Disconnecting(_) = self.variant {
+            return false;
+        }
+        self.timeout = None;
+        self.variant =
+            ConnectionVariant::Disconnecting(DisconnectingConnection::new(goodbye, config));
+        true
+    }
+
     /// Updates connection state after handling a packet from the client.
-    fn refresh_timeout(&mut self) {
+    fn refresh_timeout(&mut self, config: &ConnectionConfig) {
         if let Some(timeout) = &mut self.timeout {
-            timeout
-                .as_mut()
-                .reset(Instant::now() + CLIENT_TIMEOUT_INTERVAL)
+            timeout.as_mut().reset(Instant::now() + config.timeout())
         }
     }
 
-    /// Updates connection state after sending a packet to the client.
-    fn refresh_keepalive(&mut self) {
-        match &mut self.variant {
-            ConnectionVariant::Connected(connected) => connected.refresh_keepalive(),
-            _ => unreachable!(),
+    /// Updates connection state after sending a sealed packet to the client. Only connected
+    /// connections send keepalives.
+    fn refresh_keepalive(&mut self, config: &ConnectionConfig) {
+        if let ConnectionVariant::Connected(connected) = &mut self.variant {
+            connected.refresh_keepalive(config);
         }
     }
 }
 
 impl ConnectedConnection {
-    fn refresh_keepalive(&mut self) {
+    fn refresh_keepalive(&mut self, config: &ConnectionConfig) {
         self.keepalive
             .as_mut()
-            .reset(Instant::now() + KEEPALIVE_INTERVAL);
+            .reset(Instant::now() + config.keepalive_interval());
     }
 }
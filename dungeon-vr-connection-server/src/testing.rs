@@ -1,14 +1,23 @@
 use std::error::Error;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::future::Future;
-use std::marker::PhantomData;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use dungeon_vr_connection_shared::challenge_token::ChallengeToken;
+use dungeon_vr_connection_shared::connect_init_packet::ConnectInitPacket;
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::fragment::Reassembler;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_connection_shared::SAFE_RECV_BUFFER_SIZE;
-use dungeon_vr_cryptography::{PrivateKey, PublicKey, SharedSecret};
-use dungeon_vr_socket::testing::FakeNetwork;
+use dungeon_vr_connection_shared::protocol_version::{ContentHash, ProtocolVersions};
+use dungeon_vr_connection_shared::reliable::ReliabilityState;
+use dungeon_vr_connection_shared::sealed::{Role, SealingKey};
+use dungeon_vr_connection_shared::stats::StatsTracker;
+use dungeon_vr_connection_shared::{GAME_ID, SAFE_RECV_BUFFER_SIZE};
+use dungeon_vr_cryptography::{PrivateKey, SharedSecret, SigningKey};
+use dungeon_vr_socket::capture::CaptureAddr;
+use dungeon_vr_socket::testing::{FakeBoundSocket, FakeNetwork};
 use dungeon_vr_socket::{AddrBound, BoundSocket};
 use dungeon_vr_stream_codec::StreamCodec;
 use tokio::sync::mpsc;
@@ -16,9 +25,8 @@ use tokio::time::error::Elapsed;
 use tokio::time::{interval, sleep, timeout};
 
 use crate::{
-    ConnectedConnection, Connection, ConnectionServer, ConnectionVariant, DisconnectingConnection,
-    Event, PendingConnection, Request, CLIENT_TIMEOUT_INTERVAL, DISCONNECT_PACKET_COUNT,
-    EVENT_BUFFER_SIZE, KEEPALIVE_INTERVAL, REQUEST_BUFFER_SIZE, SEND_INTERVAL,
+    ConnectedConnection, Connection, ConnectionConfig, ConnectionServer, ConnectionVariant,
+    DisconnectingConnection, Event, Goodbye, Request,
 };
 
 pub async fn box_deadline_err<T, E>(
@@ -44,6 +52,8 @@ pub async fn run_test_with_timeout(f: impl Future<Output = ()> + Send + 'static)
 pub enum FakeAddr {
     Server,
     Client1,
+    Client2,
+    Spoofed(u32),
 }
 
 impl Display for FakeAddr {
@@ -52,6 +62,44 @@ impl Display for FakeAddr {
     }
 }
 
+impl CaptureAddr for FakeAddr {
+    fn write_capture(&self, w: &mut Vec<u8>) {
+        match *self {
+            FakeAddr::Server => w.push(0),
+            FakeAddr::Client1 => w.push(1),
+            FakeAddr::Client2 => w.push(2),
+            FakeAddr::Spoofed(n) => {
+                w.push(3);
+                w.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    }
+
+    fn read_capture(data: &[u8]) -> Option<Self> {
+        match data {
+            [0] => Some(FakeAddr::Server),
+            [1] => Some(FakeAddr::Client1),
+            [2] => Some(FakeAddr::Client2),
+            [3, n @ ..] => Some(FakeAddr::Spoofed(u32::from_be_bytes(n.try_into().ok()?))),
+            _ => None,
+        }
+    }
+}
+
+/// An in-memory capture file that stays readable after a recording socket takes ownership of it.
+#[derive(Clone, Default)]
+pub struct CaptureBuffer(pub Arc<Mutex<Vec<u8>>>);
+
+impl Write for CaptureBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 pub async fn recv_packet(socket: &dyn BoundSocket<impl AddrBound>) -> Packet {
     let mut buf = [0; SAFE_RECV_BUFFER_SIZE];
     let (size, _) = socket.recv_from(&mut buf).await.unwrap();
@@ -79,34 +127,145 @@ pub async fn send_packet_to<Addr: AddrBound>(
     socket.send_to(&buf, addr).await.unwrap();
 }
 
-fn make_network_and_connection(
-    mutate_connection: impl FnOnce(&mut ConnectionServer<FakeAddr>),
-) -> (
-    FakeNetwork<FakeAddr>,
-    cancel::Guard,
-    mpsc::Sender<Request<FakeAddr>>,
-    mpsc::Receiver<Event<FakeAddr>>,
-) {
+/// Settings for a test server. The defaults give a server with the default config, no signing key,
+/// and no content hash.
+#[derive(Default)]
+pub struct InitOptions {
+    pub config: ConnectionConfig,
+    pub signing_key: Option<SigningKey>,
+    pub content_hash: Option<ContentHash>,
+}
+
+pub struct InitUnspawned {
+    pub network: FakeNetwork<FakeAddr>,
+    pub server: ConnectionServer<FakeAddr>,
+    pub requests: mpsc::Sender<Request<FakeAddr>>,
+    pub events: mpsc::Receiver<Event<FakeAddr>>,
+}
+
+/// Creates a server without running it, so a test can drive its handlers directly and inspect its
+/// state.
+pub fn init_unspawned(options: InitOptions) -> InitUnspawned {
     let network = FakeNetwork::new();
     let socket = network.bind(FakeAddr::Server);
+    let (request_tx, request_rx) = mpsc::channel(options.config.request_buffer_size());
+    let (event_tx, event_rx) = mpsc::channel(options.config.event_buffer_size());
+
+    let server = ConnectionServer::new(
+        Box::new(socket),
+        options.config,
+        options.signing_key,
+        options.content_hash,
+        request_rx,
+        event_tx,
+    );
+
+    InitUnspawned {
+        network,
+        server,
+        requests: request_tx,
+        events: event_rx,
+    }
+}
+
+pub struct Init {
+    pub network: FakeNetwork<FakeAddr>,
+    pub cancel_guard: cancel::Guard,
+    pub requests: mpsc::Sender<Request<FakeAddr>>,
+    pub events: mpsc::Receiver<Event<FakeAddr>>,
+}
+
+fn make_network_and_connection(
+    options: InitOptions,
+    mutate_connection: impl FnOnce(&mut ConnectionServer<FakeAddr>),
+) -> Init {
+    let InitUnspawned {
+        network,
+        mut server,
+        requests,
+        events,
+    } = init_unspawned(options);
     let cancel_token = cancel::Token::new();
-    let (request_tx, request_rx) = mpsc::channel(REQUEST_BUFFER_SIZE);
-    let (event_tx, event_rx) = mpsc::channel(EVENT_BUFFER_SIZE);
+    mutate_connection(&mut server);
+    tokio::spawn(server.run(cancel_token.clone()));
 
-    let mut connection = ConnectionServer::new(Box::new(socket), request_rx, event_tx);
-    mutate_connection(&mut connection);
-    tokio::spawn(connection.run(cancel_token.clone()));
+    Init {
+        network,
+        cancel_guard: cancel_token.guard(),
+        requests,
+        events,
+    }
+}
 
-    (network, cancel_token.guard(), request_tx, event_rx)
+/// Runs a server with no connections.
+pub fn init(options: InitOptions) -> Init {
+    make_network_and_connection(options, |_| ())
 }
 
-pub fn init() -> (
-    FakeNetwork<FakeAddr>,
-    cancel::Guard,
-    mpsc::Sender<Request<FakeAddr>>,
-    mpsc::Receiver<Event<FakeAddr>>,
-) {
-    make_network_and_connection(|_| ())
+/// Sends a ConnectInit packet from `socket` and waits for the resulting challenge. Returns the
+/// client's view of the shared secret and the challenge token to echo.
+pub async fn request_challenge(socket: &FakeBoundSocket<FakeAddr>) -> (SealingKey, ChallengeToken) {
+    request_challenge_with(socket, ProtocolVersions::SUPPORTED, None).await
+}
+
+/// Like [`request_challenge`], but offers the given protocol versions and content hash.
+pub async fn request_challenge_with(
+    socket: &FakeBoundSocket<FakeAddr>,
+    protocol_versions: ProtocolVersions,
+    content_hash: Option<ContentHash>,
+) -> (SealingKey, ChallengeToken) {
+    let client_private_key = PrivateKey::gen();
+    send_packet_to(
+        socket,
+        Packet::ConnectInit(ConnectInitPacket {
+            game_id: GAME_ID,
+            protocol_versions,
+            content_hash,
+            client_public_key: client_private_key.to_public(),
+        }),
+        FakeAddr::Server,
+    )
+    .await;
+    let packet = match recv_packet(socket).await {
+        Packet::ConnectChallenge(packet) => packet,
+        _ => unreachable!(),
+    };
+    let shared_secret = client_private_key
+        .exchange(&packet.server_public_key)
+        .unwrap();
+    let token = packet
+        .sealed_payload
+        .open(Role::Server, &shared_secret)
+        .unwrap();
+    (SealingKey::new(shared_secret, Role::Client), token)
+}
+
+pub struct InitWithChallenge {
+    pub network: FakeNetwork<FakeAddr>,
+    pub socket: FakeBoundSocket<FakeAddr>,
+    pub cancel_guard: cancel::Guard,
+    pub events: mpsc::Receiver<Event<FakeAddr>>,
+    pub key: SealingKey,
+    pub token: ChallengeToken,
+}
+
+pub async fn init_with_challenge() -> InitWithChallenge {
+    let Init {
+        network,
+        cancel_guard,
+        events,
+        ..
+    } = init(InitOptions::default());
+    let socket = network.bind(FakeAddr::Client1);
+    let (key, token) = request_challenge(&socket).await;
+    InitWithChallenge {
+        network,
+        socket,
+        cancel_guard,
+        events,
+        key,
+        token,
+    }
 }
 
 pub struct InitWithPendingConnection {
@@ -114,31 +273,25 @@ pub struct InitWithPendingConnection {
     pub cancel_guard: cancel::Guard,
     pub requests: mpsc::Sender<Request<FakeAddr>>,
     pub events: mpsc::Receiver<Event<FakeAddr>>,
-    pub client_private_key: PrivateKey,
-    pub server_public_key: PublicKey,
-    pub shared_secret: SharedSecret,
-    pub token: ChallengeToken,
+    pub key: SealingKey,
 }
 
 pub fn init_with_pending_connection() -> InitWithPendingConnection {
-    let client_private_key = PrivateKey::gen();
-    let client_public_key = client_private_key.to_public();
-    let server_private_key = PrivateKey::gen();
-    let server_public_key = server_private_key.to_public();
-    let shared_secret = server_private_key.exchange(&client_public_key).unwrap();
-    let token = ChallengeToken::gen();
-    let (network, cancel_guard, requests, events) = make_network_and_connection(|connection| {
-        connection.connections.insert(
+    let shared_secret = SharedSecret::gen();
+    let Init {
+        network,
+        cancel_guard,
+        requests,
+        events,
+    } = make_network_and_connection(InitOptions::default(), |connection| {
+        connection.insert_connection(
             FakeAddr::Client1,
             Connection {
-                shared_secret,
-                timeout: Some(Box::pin(sleep(CLIENT_TIMEOUT_INTERVAL))),
-                variant: ConnectionVariant::Pending(PendingConnection {
-                    server_public_key,
-                    token,
-                    send_interval: interval(SEND_INTERVAL),
-                }),
-                _phantom_addr: PhantomData,
+                key: SealingKey::new(shared_secret, Role::Server),
+                timeout: Some(Box::pin(sleep(connection.config.timeout()))),
+                stats: StatsTracker::new(),
+                variant: ConnectionVariant::Pending,
+                path_probe: None,
             },
         );
     });
@@ -147,10 +300,7 @@ pub fn init_with_pending_connection() -> InitWithPendingConnection {
         cancel_guard,
         requests,
         events,
-        client_private_key,
-        server_public_key,
-        shared_secret,
-        token,
+        key: SealingKey::new(shared_secret, Role::Client),
     }
 }
 
@@ -159,30 +309,45 @@ pub struct InitWithConnectedConnection {
     pub cancel_guard: cancel::Guard,
     pub requests: mpsc::Sender<Request<FakeAddr>>,
     pub events: mpsc::Receiver<Event<FakeAddr>>,
-    pub shared_secret: SharedSecret,
+    pub key: SealingKey,
+}
+
+/// Builds the server's side of a connection that has just been accepted.
+pub fn connected_connection(
+    config: &ConnectionConfig,
+    shared_secret: SharedSecret,
+) -> Connection<FakeAddr> {
+    Connection {
+        key: SealingKey::new(shared_secret, Role::Server),
+        timeout: Some(Box::pin(sleep(config.timeout()))),
+        stats: StatsTracker::new(),
+        variant: ConnectionVariant::Connected(Box::new(ConnectedConnection {
+            keepalive: Box::pin(sleep(config.keepalive_interval())),
+            reliability: ReliabilityState::new(),
+            resend_interval: interval(config.resend_interval()),
+            reassembler: Reassembler::new(config.reassembly_timeout()),
+        })),
+        path_probe: None,
+    }
 }
 
 pub fn init_with_connected_connection() -> InitWithConnectedConnection {
     let shared_secret = SharedSecret::gen();
-    let (network, cancel_guard, requests, events) = make_network_and_connection(|connection| {
-        connection.connections.insert(
-            FakeAddr::Client1,
-            Connection {
-                shared_secret,
-                timeout: Some(Box::pin(sleep(CLIENT_TIMEOUT_INTERVAL))),
-                variant: ConnectionVariant::Connected(ConnectedConnection {
-                    keepalive: Box::pin(sleep(KEEPALIVE_INTERVAL)),
-                }),
-                _phantom_addr: PhantomData,
-            },
-        );
+    let Init {
+        network,
+        cancel_guard,
+        requests,
+        events,
+    } = make_network_and_connection(InitOptions::default(), |connection| {
+        let connected = connected_connection(&connection.config, shared_secret);
+        connection.insert_connection(FakeAddr::Client1, connected);
     });
     InitWithConnectedConnection {
         network,
         cancel_guard,
         requests,
         events,
-        shared_secret,
+        key: SealingKey::new(shared_secret, Role::Client),
     }
 }
 
@@ -191,22 +356,28 @@ pub struct InitWithDisconnectingConnection {
     pub cancel_guard: cancel::Guard,
     pub requests: mpsc::Sender<Request<FakeAddr>>,
     pub events: mpsc::Receiver<Event<FakeAddr>>,
-    pub shared_secret: SharedSecret,
+    pub key: SealingKey,
 }
 
 pub fn init_with_disconnecting_connection() -> InitWithDisconnectingConnection {
     let shared_secret = SharedSecret::gen();
-    let (network, cancel_guard, requests, events) = make_network_and_connection(|connection| {
-        connection.connections.insert(
+    let Init {
+        network,
+        cancel_guard,
+        requests,
+        events,
+    } = make_network_and_connection(InitOptions::default(), |connection| {
+        connection.insert_connection(
             FakeAddr::Client1,
             Connection {
-                shared_secret,
-                timeout: Some(Box::pin(sleep(CLIENT_TIMEOUT_INTERVAL))),
-                variant: ConnectionVariant::Disconnecting(DisconnectingConnection {
-                    interval: interval(SEND_INTERVAL),
-                    packets_to_send: DISCONNECT_PACKET_COUNT,
-                }),
-                _phantom_addr: PhantomData,
+                key: SealingKey::new(shared_secret, Role::Server),
+                timeout: Some(Box::pin(sleep(connection.config.timeout()))),
+                stats: StatsTracker::new(),
+                variant: ConnectionVariant::Disconnecting(DisconnectingConnection::new(
+                    Goodbye::Disconnect(DisconnectPacket::default()),
+                    &connection.config,
+                )),
+                path_probe: None,
             },
         );
     });
@@ -215,6 +386,6 @@ pub fn init_with_disconnecting_connection() -> InitWithDisconnectingConnection {
         cancel_guard,
         requests,
         events,
-        shared_secret,
+        key: SealingKey::new(shared_secret, Role::Client),
     }
 }
@@ -1,4 +1,5 @@
 mod end_to_end;
 mod packet;
+mod replay;
 mod request;
 mod state;
@@ -1,19 +1,27 @@
 use dungeon_vr_connection_shared::connect_init_packet::ConnectInitPacket;
+use dungeon_vr_connection_shared::connect_response_packet::ConnectResponsePacket;
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_connection_shared::sealed::Sealed;
+use dungeon_vr_connection_shared::protocol_version::{ProtocolVersions, PROTOCOL_VERSION};
+use dungeon_vr_connection_shared::sealed::{Role, SealingKey};
 use dungeon_vr_connection_shared::GAME_ID;
 use dungeon_vr_cryptography::PrivateKey;
 use dungeon_vr_socket::testing::FakeNetwork;
 
 use crate::testing::{recv_packet, run_test_with_timeout, send_packet_to, FakeAddr};
-use crate::{ConnectionServer, ConnectionState, Event};
+use crate::{ConnectionConfig, ConnectionServer, ConnectionState, Event, Request};
 
 #[tokio::test(start_paused = true)]
 async fn end_to_end() {
     run_test_with_timeout(async move {
         let network = FakeNetwork::new();
-        let (cancel_guard, _requests, mut events) =
-            ConnectionServer::spawn(Box::new(network.bind(FakeAddr::Server)));
+        let (cancel_guard, requests, mut events) = ConnectionServer::spawn(
+            Box::new(network.bind(FakeAddr::Server)),
+            ConnectionConfig::default(),
+            None,
+            None,
+        );
         let socket = network.bind(FakeAddr::Client1);
 
         // Send a ConnectInit packet.
@@ -23,18 +31,13 @@ async fn end_to_end() {
             &socket,
             Packet::ConnectInit(ConnectInitPacket {
                 game_id: GAME_ID,
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                content_hash: None,
                 client_public_key,
             }),
             FakeAddr::Server,
         )
         .await;
-        assert_eq!(
-            Event::State {
-                addr: FakeAddr::Client1,
-                state: ConnectionState::Pending
-            },
-            events.recv().await.unwrap()
-        );
 
         println!("Waiting for a ConnectChallenge packet");
         let packet = match recv_packet(&socket).await {
@@ -44,15 +47,37 @@ async fn end_to_end() {
         let shared_secret = client_private_key
             .exchange(&packet.server_public_key)
             .unwrap();
-        let token = packet.sealed_payload.open(&shared_secret).unwrap();
+        let token = packet
+            .sealed_payload
+            .open(Role::Server, &shared_secret)
+            .unwrap();
+        let mut key = SealingKey::new(shared_secret, Role::Client);
 
         // Send a ConnectResponse packet.
         send_packet_to(
             &socket,
-            Packet::ConnectResponse(Sealed::seal(token, &shared_secret)),
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: key.seal(()),
+            }),
             FakeAddr::Server,
         )
         .await;
+        assert_eq!(
+            Event::ConnectRequest {
+                addr: FakeAddr::Client1,
+                protocol_version: PROTOCOL_VERSION,
+            },
+            events.recv().await.unwrap(),
+        );
+
+        // Admit the client.
+        requests
+            .send(Request::Accept {
+                addr: FakeAddr::Client1,
+            })
+            .await
+            .unwrap();
         assert_eq!(
             Event::State {
                 addr: FakeAddr::Client1,
@@ -66,12 +91,15 @@ async fn end_to_end() {
             Packet::Keepalive(packet) => packet,
             _ => unreachable!(),
         };
-        let () = packet.open(&shared_secret).unwrap();
+        assert!(matches!(
+            key.open(&packet).unwrap(),
+            KeepalivePacket::Ping { .. },
+        ));
 
         // Send a Disconnect packet.
         send_packet_to(
             &socket,
-            Packet::Disconnect(Sealed::seal((), &shared_secret)),
+            Packet::Disconnect(key.seal(DisconnectPacket::default())),
             FakeAddr::Server,
         )
         .await;
@@ -1,25 +1,38 @@
 use std::time::Duration;
 
 use dungeon_vr_connection_shared::challenge_token::ChallengeToken;
+use dungeon_vr_connection_shared::connect_challenge_packet::signed_message;
 use dungeon_vr_connection_shared::connect_init_packet::ConnectInitPacket;
+use dungeon_vr_connection_shared::connect_response_packet::ConnectResponsePacket;
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::fragment::Fragmenter;
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_connection_shared::sealed::Sealed;
+use dungeon_vr_connection_shared::protocol_version::{ProtocolVersions, PROTOCOL_VERSION};
+use dungeon_vr_connection_shared::reliable::{AckPacket, Channel, GameDataPacket};
+use dungeon_vr_connection_shared::sealed::{Role, Sealed};
 use dungeon_vr_connection_shared::GAME_ID;
-use dungeon_vr_cryptography::{PrivateKey, SharedSecret};
-use dungeon_vr_stream_codec::UnframedByteVec;
-use tokio::time::sleep;
+use dungeon_vr_cryptography::{PrivateKey, SharedSecret, SigningKey};
+use dungeon_vr_stream_codec::StreamCodec;
+use tokio::time::{sleep, timeout, Instant};
 
 use crate::testing::{
-    init, init_with_connected_connection, init_with_pending_connection, run_test_with_timeout,
-    send_bytes_to, send_packet_to, FakeAddr, InitWithConnectedConnection,
+    init, init_with_challenge, init_with_connected_connection, init_with_pending_connection,
+    recv_packet, request_challenge_with, run_test_with_timeout, send_bytes_to, send_packet_to,
+    FakeAddr, Init, InitOptions, InitWithChallenge, InitWithConnectedConnection,
     InitWithPendingConnection,
 };
-use crate::{ConnectionState, Event};
+use crate::{ConnectionConfig, ConnectionState, Event, RejectReason, Request};
 
 #[tokio::test(start_paused = true)]
 async fn no_connection_recv_empty_should_ignore() {
     run_test_with_timeout(async move {
-        let (network, _cancel_guard, _requests, mut events) = init();
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests: _requests,
+            mut events,
+        } = init(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Client1);
         send_bytes_to(&socket, b"", FakeAddr::Server).await;
@@ -33,7 +46,12 @@ async fn no_connection_recv_empty_should_ignore() {
 #[tokio::test(start_paused = true)]
 async fn no_connection_recv_malformed_should_ignore() {
     run_test_with_timeout(async move {
-        let (network, _cancel_guard, _requests, mut events) = init();
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests: _requests,
+            mut events,
+        } = init(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Client1);
         send_bytes_to(&socket, b"\x01ConnectInit but too short", FakeAddr::Server).await;
@@ -45,47 +63,151 @@ async fn no_connection_recv_malformed_should_ignore() {
 }
 
 #[tokio::test(start_paused = true)]
-async fn no_connection_recv_connectinit_should_create_pending_connection() {
+async fn no_connection_recv_connectinit_should_send_one_challenge() {
     run_test_with_timeout(async move {
-        let (network, _cancel_guard, _requests, mut events) = init();
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests: _requests,
+            mut events,
+        } = init(InitOptions::default());
 
         let socket = network.bind(FakeAddr::Client1);
+        let client_private_key = PrivateKey::gen();
         send_packet_to(
             &socket,
             Packet::ConnectInit(ConnectInitPacket {
                 game_id: GAME_ID,
-                client_public_key: PrivateKey::gen().to_public(),
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                content_hash: None,
+                client_public_key: client_private_key.to_public(),
             }),
             FakeAddr::Server,
         )
         .await;
 
-        assert_eq!(
-            Event::State {
-                addr: FakeAddr::Client1,
-                state: ConnectionState::Pending,
-            },
-            events.recv().await.unwrap()
-        );
+        let packet = match recv_packet(&socket).await {
+            Packet::ConnectChallenge(packet) => packet,
+            _ => unreachable!(),
+        };
+        packet
+            .sealed_payload
+            .open(
+                Role::Server,
+                &client_private_key
+                    .exchange(&packet.server_public_key)
+                    .unwrap(),
+            )
+            .unwrap();
+
+        // The server holds no state for the client, so it neither resends the challenge nor
+        // reports anything.
+        assert!(timeout(Duration::from_secs(1), recv_packet(&socket))
+            .await
+            .is_err());
+        assert!(events.try_recv().is_err());
     })
     .await;
 }
 
 #[tokio::test(start_paused = true)]
-async fn pending_connection_recv_connectinit_should_ignore() {
+async fn no_connection_recv_unpadded_connectinit_should_ignore() {
     run_test_with_timeout(async move {
-        let InitWithPendingConnection {
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests: _requests,
+            events: _events,
+        } = init(InitOptions::default());
+
+        let socket = network.bind(FakeAddr::Client1);
+        let mut w = Vec::new();
+        Packet::ConnectInit(ConnectInitPacket {
+            game_id: GAME_ID,
+            protocol_versions: ProtocolVersions::SUPPORTED,
+            content_hash: None,
+            client_public_key: PrivateKey::gen().to_public(),
+        })
+        .write_to(&mut w)
+        .unwrap();
+        send_bytes_to(
+            &socket,
+            &w[..w.len() - ConnectInitPacket::PADDING_SIZE],
+            FakeAddr::Server,
+        )
+        .await;
+
+        assert!(timeout(Duration::from_secs(1), recv_packet(&socket))
+            .await
+            .is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn no_connection_recv_connectinit_with_signing_key_should_send_signed_challenge() {
+    run_test_with_timeout(async move {
+        let signing_key = SigningKey::gen();
+        let verifying_key = signing_key.verifying_key();
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests: _requests,
+            events: _events,
+        } = init(InitOptions {
+            signing_key: Some(signing_key),
+            ..Default::default()
+        });
+
+        let socket = network.bind(FakeAddr::Client1);
+        let client_public_key = PrivateKey::gen().to_public();
+        send_packet_to(
+            &socket,
+            Packet::ConnectInit(ConnectInitPacket {
+                game_id: GAME_ID,
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                content_hash: None,
+                client_public_key,
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+
+        let packet = match recv_packet(&socket).await {
+            Packet::ConnectChallenge(packet) => packet,
+            _ => unreachable!(),
+        };
+        verifying_key
+            .verify(
+                &signed_message(
+                    &client_public_key,
+                    &packet.server_public_key,
+                    packet.protocol_versions,
+                ),
+                &packet.signature.unwrap(),
+            )
+            .unwrap();
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_connectinit_should_ignore() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
             network,
             cancel_guard: _cancel_guard,
             mut events,
             ..
-        } = init_with_pending_connection();
+        } = init_with_connected_connection();
 
         let socket = network.bind(FakeAddr::Client1);
         send_packet_to(
             &socket,
             Packet::ConnectInit(ConnectInitPacket {
                 game_id: GAME_ID,
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                content_hash: None,
                 client_public_key: PrivateKey::gen().to_public(),
             }),
             FakeAddr::Server,
@@ -99,29 +221,32 @@ async fn pending_connection_recv_connectinit_should_ignore() {
 }
 
 #[tokio::test(start_paused = true)]
-async fn pending_connection_recv_connectresponse_should_connect() {
+async fn challenged_recv_connectresponse_should_request_approval() {
     run_test_with_timeout(async move {
-        let InitWithPendingConnection {
-            network,
+        let InitWithChallenge {
+            network: _network,
+            socket,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             token,
             ..
-        } = init_with_pending_connection();
+        } = init_with_challenge().await;
 
-        let socket = network.bind(FakeAddr::Client1);
         send_packet_to(
             &socket,
-            Packet::ConnectResponse(Sealed::seal(token, &shared_secret)),
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: key.seal(()),
+            }),
             FakeAddr::Server,
         )
         .await;
 
         assert_eq!(
-            Event::State {
+            Event::ConnectRequest {
                 addr: FakeAddr::Client1,
-                state: ConnectionState::Connected,
+                protocol_version: PROTOCOL_VERSION,
             },
             events.recv().await.unwrap(),
         );
@@ -130,20 +255,115 @@ async fn pending_connection_recv_connectresponse_should_connect() {
 }
 
 #[tokio::test(start_paused = true)]
-async fn pending_connection_recv_bad_signature_connectresponse_should_discard() {
+async fn challenged_recv_bad_signature_connectresponse_should_discard() {
     run_test_with_timeout(async move {
-        let InitWithPendingConnection {
+        let InitWithChallenge {
+            network: _network,
+            socket,
+            cancel_guard: _cancel_guard,
+            mut events,
+            token,
+            ..
+        } = init_with_challenge().await;
+
+        send_packet_to(
+            &socket,
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: Sealed::seal((), Role::Client, 0, &SharedSecret::gen()),
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn challenged_recv_bad_token_connectresponse_should_discard() {
+    run_test_with_timeout(async move {
+        let InitWithChallenge {
+            network: _network,
+            socket,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            token,
+            ..
+        } = init_with_challenge().await;
+
+        let mut w = Vec::new();
+        token.write_to(&mut w).unwrap();
+        *w.last_mut().unwrap() ^= 1;
+        send_packet_to(
+            &socket,
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token: ChallengeToken::read_from(&mut &w[..]).unwrap(),
+                sealed_payload: key.seal(()),
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn challenged_recv_connectresponse_from_other_addr_should_discard() {
+    run_test_with_timeout(async move {
+        let InitWithChallenge {
             network,
             cancel_guard: _cancel_guard,
             mut events,
+            mut key,
             token,
             ..
-        } = init_with_pending_connection();
+        } = init_with_challenge().await;
 
-        let socket = network.bind(FakeAddr::Client1);
+        let socket = network.bind(FakeAddr::Client2);
+        send_packet_to(
+            &socket,
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: key.seal(()),
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn challenged_recv_expired_connectresponse_should_discard() {
+    run_test_with_timeout(async move {
+        let InitWithChallenge {
+            network: _network,
+            socket,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            token,
+            ..
+        } = init_with_challenge().await;
+
+        sleep(ConnectionConfig::default().challenge_token_lifetime() + Duration::from_millis(1))
+            .await;
         send_packet_to(
             &socket,
-            Packet::ConnectResponse(Sealed::seal(token, &SharedSecret::gen())),
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: key.seal(()),
+            }),
             FakeAddr::Server,
         )
         .await;
@@ -155,26 +375,176 @@ async fn pending_connection_recv_bad_signature_connectresponse_should_discard()
 }
 
 #[tokio::test(start_paused = true)]
-async fn pending_connection_recv_bad_token_connectresponse_should_discard() {
+async fn disconnected_recv_replayed_connectresponse_should_discard() {
+    run_test_with_timeout(async move {
+        let InitWithChallenge {
+            network: _network,
+            socket,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            token,
+            ..
+        } = init_with_challenge().await;
+        let mut connect_response = Vec::new();
+        Packet::ConnectResponse(ConnectResponsePacket {
+            token,
+            sealed_payload: key.seal(()),
+        })
+        .write_to(&mut connect_response)
+        .unwrap();
+
+        send_bytes_to(&socket, &connect_response, FakeAddr::Server).await;
+        assert_eq!(
+            Event::ConnectRequest {
+                addr: FakeAddr::Client1,
+                protocol_version: PROTOCOL_VERSION,
+            },
+            events.recv().await.unwrap(),
+        );
+        send_packet_to(
+            &socket,
+            Packet::Disconnect(key.seal(DisconnectPacket::default())),
+            FakeAddr::Server,
+        )
+        .await;
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnected,
+            },
+            events.recv().await.unwrap(),
+        );
+
+        // The token is still fresh, but it already admitted a connection.
+        send_bytes_to(&socket, &connect_response, FakeAddr::Server).await;
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn incompatible_recv_connectresponse_should_send_rejects() {
+    run_test_with_timeout(async move {
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests: _requests,
+            mut events,
+        } = init(InitOptions::default());
+        let socket = network.bind(FakeAddr::Client1);
+        let newer = ProtocolVersions {
+            min: PROTOCOL_VERSION + 1,
+            max: PROTOCOL_VERSION + 1,
+        };
+        let (mut key, token) = request_challenge_with(&socket, newer, None).await;
+
+        send_packet_to(
+            &socket,
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: key.seal(()),
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+
+        for _ in 0..ConnectionConfig::default().disconnect_packet_count() {
+            let packet = match recv_packet(&socket).await {
+                Packet::Reject(packet) => packet,
+                _ => unreachable!(),
+            };
+            assert_eq!(key.open(&packet).unwrap(), RejectReason::VersionMismatch);
+        }
+        sleep(Duration::from_secs(1)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn content_hash_recv_connectresponse_should_require_match() {
+    run_test_with_timeout(async move {
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests: _requests,
+            mut events,
+        } = init(InitOptions {
+            content_hash: Some([1; 32]),
+            ..Default::default()
+        });
+
+        // A client with other content is turned away.
+        let socket = network.bind(FakeAddr::Client1);
+        let (mut key, token) =
+            request_challenge_with(&socket, ProtocolVersions::SUPPORTED, Some([2; 32])).await;
+        send_packet_to(
+            &socket,
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: key.seal(()),
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+        let packet = match recv_packet(&socket).await {
+            Packet::Reject(packet) => packet,
+            _ => unreachable!(),
+        };
+        assert_eq!(key.open(&packet).unwrap(), RejectReason::VersionMismatch);
+
+        // A client with the same content may connect.
+        let socket = network.bind(FakeAddr::Client2);
+        let (mut key, token) =
+            request_challenge_with(&socket, ProtocolVersions::SUPPORTED, Some([1; 32])).await;
+        send_packet_to(
+            &socket,
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: key.seal(()),
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+        assert_eq!(
+            Event::ConnectRequest {
+                addr: FakeAddr::Client2,
+                protocol_version: PROTOCOL_VERSION,
+            },
+            events.recv().await.unwrap(),
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn pending_connection_recv_disconnect_should_disconnect() {
     run_test_with_timeout(async move {
         let InitWithPendingConnection {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             ..
         } = init_with_pending_connection();
 
         let socket = network.bind(FakeAddr::Client1);
         send_packet_to(
             &socket,
-            Packet::ConnectResponse(Sealed::seal(ChallengeToken::gen(), &shared_secret)),
+            Packet::Disconnect(key.seal(DisconnectPacket::default())),
             FakeAddr::Server,
         )
         .await;
 
-        sleep(Duration::from_millis(500)).await;
-        assert!(events.try_recv().is_err());
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnected,
+            },
+            events.recv().await.unwrap(),
+        );
     })
     .await;
 }
@@ -186,7 +556,7 @@ async fn connected_connection_recv_keepalive_should_refresh_timeout() {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             ..
         } = init_with_connected_connection();
 
@@ -194,7 +564,7 @@ async fn connected_connection_recv_keepalive_should_refresh_timeout() {
         let socket = network.bind(FakeAddr::Client1);
         send_packet_to(
             &socket,
-            Packet::Keepalive(Sealed::seal((), &shared_secret)),
+            Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 0 })),
             FakeAddr::Server,
         )
         .await;
@@ -214,16 +584,14 @@ async fn connected_connection_recv_gamedata_should_yield_event() {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             ..
         } = init_with_connected_connection();
 
         let socket = network.bind(FakeAddr::Client1);
         send_packet_to(
             &socket,
-            Packet::GameData(
-                Sealed::seal_ext::<UnframedByteVec>(b"abcdef".to_vec(), &shared_secret).cast(),
-            ),
+            Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec()))),
             FakeAddr::Server,
         )
         .await;
@@ -246,7 +614,7 @@ async fn connected_connection_recv_gamedata_should_refresh_timeout() {
             network,
             cancel_guard: _cancel_guard,
             mut events,
-            shared_secret,
+            mut key,
             ..
         } = init_with_connected_connection();
 
@@ -254,9 +622,7 @@ async fn connected_connection_recv_gamedata_should_refresh_timeout() {
         let socket = network.bind(FakeAddr::Client1);
         send_packet_to(
             &socket,
-            Packet::GameData(
-                Sealed::seal_ext::<UnframedByteVec>(b"abcdef".to_vec(), &shared_secret).cast(),
-            ),
+            Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec()))),
             FakeAddr::Server,
         )
         .await;
@@ -275,3 +641,366 @@ async fn connected_connection_recv_gamedata_should_refresh_timeout() {
     })
     .await;
 }
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_reliable_gamedata_should_ack_and_deliver_in_order() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+
+        let socket = network.bind(FakeAddr::Client1);
+        for (sequence, data) in [(1, b"b"), (1, b"b"), (0, b"a")] {
+            send_packet_to(
+                &socket,
+                Packet::GameData(key.seal(GameDataPacket::ReliableOrdered {
+                    sequence,
+                    data: data.to_vec(),
+                })),
+                FakeAddr::Server,
+            )
+            .await;
+        }
+
+        // Every copy is acknowledged, including the duplicate.
+        for sequence in [1, 1, 0] {
+            let sealed = match recv_packet(&socket).await {
+                Packet::Ack(sealed) => sealed,
+                _ => unreachable!(),
+            };
+            assert_eq!(
+                AckPacket {
+                    channel: Channel::ReliableOrdered,
+                    sequence,
+                },
+                key.open(&sealed).unwrap(),
+            );
+        }
+        for data in [b"a", b"b"] {
+            assert_eq!(
+                Event::GameData {
+                    addr: FakeAddr::Client1,
+                    data: data.to_vec(),
+                },
+                events.recv().await.unwrap()
+            );
+        }
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_send_ack_should_refresh_keepalive() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        sleep(Duration::from_millis(900)).await;
+        send_packet_to(
+            &socket,
+            Packet::GameData(key.seal(GameDataPacket::ReliableOrdered {
+                sequence: 0,
+                data: b"a".to_vec(),
+            })),
+            FakeAddr::Server,
+        )
+        .await;
+        assert!(matches!(recv_packet(&socket).await, Packet::Ack(_)));
+        let acked_at = Instant::now();
+
+        // The Ack counts as traffic, so the next Keepalive waits a full interval after it.
+        assert!(matches!(recv_packet(&socket).await, Packet::Keepalive(_)));
+        assert!(Instant::now() - acked_at >= ConnectionConfig::default().keepalive_interval());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_fragments_should_reassemble_gamedata() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+
+        let socket = network.bind(FakeAddr::Client1);
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let packets = Fragmenter::new(1200)
+            .seal(GameDataPacket::Unreliable(data.clone()), &mut key)
+            .unwrap();
+        assert!(packets.len() > 1);
+        for packet in packets.into_iter().rev() {
+            send_packet_to(&socket, packet, FakeAddr::Server).await;
+        }
+
+        assert_eq!(
+            Event::GameData {
+                addr: FakeAddr::Client1,
+                data,
+            },
+            events.recv().await.unwrap()
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_replayed_gamedata_should_ignore() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+
+        let socket = network.bind(FakeAddr::Client1);
+        let mut buf = Vec::new();
+        Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec())))
+            .write_to(&mut buf)
+            .unwrap();
+        send_bytes_to(&socket, &buf, FakeAddr::Server).await;
+        send_bytes_to(&socket, &buf, FakeAddr::Server).await;
+
+        assert_eq!(
+            Event::GameData {
+                addr: FakeAddr::Client1,
+                data: b"abcdef".to_vec(),
+            },
+            events.recv().await.unwrap()
+        );
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_replayed_keepalive_should_not_refresh_timeout() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+
+        let socket = network.bind(FakeAddr::Client1);
+        let mut buf = Vec::new();
+        Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 0 }))
+            .write_to(&mut buf)
+            .unwrap();
+        send_bytes_to(&socket, &buf, FakeAddr::Server).await;
+        sleep(Duration::from_millis(4900)).await;
+        send_bytes_to(&socket, &buf, FakeAddr::Server).await;
+        sleep(Duration::from_millis(200)).await;
+
+        // The replayed Keepalive was ignored, so the connection timed out.
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnecting,
+            },
+            events.recv().await.unwrap()
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_keepalive_from_new_addr_should_migrate() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+
+        let socket = network.bind(FakeAddr::Client2);
+        send_packet_to(
+            &socket,
+            Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 7 })),
+            FakeAddr::Server,
+        )
+        .await;
+
+        // The server probes the new address before following the client there.
+        let id = match recv_packet(&socket).await {
+            Packet::Keepalive(sealed) => match key.open(&sealed).unwrap() {
+                KeepalivePacket::Ping { id } => id,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert!(events.try_recv().is_err());
+        send_packet_to(
+            &socket,
+            Packet::Keepalive(key.seal(KeepalivePacket::Pong { id })),
+            FakeAddr::Server,
+        )
+        .await;
+
+        assert_eq!(
+            Event::Migrated {
+                old_addr: FakeAddr::Client1,
+                addr: FakeAddr::Client2,
+            },
+            events.recv().await.unwrap(),
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_unconfirmed_packet_from_new_addr_should_not_migrate() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_connected_connection();
+
+        // An attacker relays a fresh packet from the client, but can't answer the probe.
+        let client = network.bind(FakeAddr::Client1);
+        let attacker = network.bind(FakeAddr::Client2);
+        send_packet_to(
+            &attacker,
+            Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec()))),
+            FakeAddr::Server,
+        )
+        .await;
+        assert!(matches!(recv_packet(&attacker).await, Packet::Keepalive(_),));
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+
+        // Traffic still goes to the client's original address.
+        requests
+            .send(Request::SendGameData {
+                addr: FakeAddr::Client1,
+                channel: Channel::Unreliable,
+                data: b"ghijkl".to_vec(),
+            })
+            .await
+            .unwrap();
+        loop {
+            if let Packet::GameData(sealed) = recv_packet(&client).await {
+                assert_eq!(
+                    key.open(&sealed).unwrap(),
+                    GameDataPacket::Unreliable(b"ghijkl".to_vec()),
+                );
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_disconnect_from_new_addr_should_not_migrate() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+
+        let socket = network.bind(FakeAddr::Client2);
+        send_packet_to(
+            &socket,
+            Packet::Disconnect(key.seal(DisconnectPacket::default())),
+            FakeAddr::Server,
+        )
+        .await;
+
+        assert!(timeout(Duration::from_secs(1), recv_packet(&socket))
+            .await
+            .is_err());
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_replayed_packet_from_new_addr_should_not_migrate() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+
+        let socket = network.bind(FakeAddr::Client1);
+        let mut buf = Vec::new();
+        Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 0 }))
+            .write_to(&mut buf)
+            .unwrap();
+        send_bytes_to(&socket, &buf, FakeAddr::Server).await;
+        let attacker = network.bind(FakeAddr::Client2);
+        send_bytes_to(&attacker, &buf, FakeAddr::Server).await;
+        sleep(Duration::from_millis(10)).await;
+
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_connection_recv_reflected_packet_should_ignore() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+
+        // Reflect the server's own keepalive back at it. It must not be answered.
+        let socket = network.bind(FakeAddr::Client1);
+        let packet = recv_packet(&socket).await;
+        assert!(matches!(packet, Packet::Keepalive(_)));
+        send_packet_to(&socket, packet, FakeAddr::Server).await;
+        assert!(timeout(Duration::from_millis(100), recv_packet(&socket))
+            .await
+            .is_err());
+
+        // The client's own packet with the same sequence number is still accepted.
+        send_packet_to(
+            &socket,
+            Packet::GameData(key.seal(GameDataPacket::Unreliable(b"abcdef".to_vec()))),
+            FakeAddr::Server,
+        )
+        .await;
+        assert_eq!(
+            Event::GameData {
+                addr: FakeAddr::Client1,
+                data: b"abcdef".to_vec(),
+            },
+            events.recv().await.unwrap(),
+        );
+    })
+    .await;
+}
@@ -0,0 +1,174 @@
+use std::mem::{discriminant, Discriminant};
+use std::time::Duration;
+
+use dungeon_vr_connection_shared::connect_init_packet::ConnectInitPacket;
+use dungeon_vr_connection_shared::packet::Packet;
+use dungeon_vr_connection_shared::protocol_version::ProtocolVersions;
+use dungeon_vr_connection_shared::reliable::GameDataPacket;
+use dungeon_vr_connection_shared::sealed::{Role, SealingKey};
+use dungeon_vr_connection_shared::GAME_ID;
+use dungeon_vr_cryptography::{PrivateKey, SharedSecret};
+use dungeon_vr_socket::capture::{
+    read_capture, CaptureRecord, CaptureWriter, Direction, RecordingBoundSocket, ReplaySocket,
+};
+use dungeon_vr_socket::testing::FakeNetwork;
+use dungeon_vr_socket::BoundSocket;
+use dungeon_vr_stream_codec::StreamCodec;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Instant};
+
+use crate::testing::{
+    connected_connection, recv_packet, run_test_with_timeout, send_bytes_to, send_packet_to,
+    CaptureBuffer, FakeAddr,
+};
+use crate::{ConnectionConfig, ConnectionServer, Event};
+
+fn kind(data: &[u8]) -> Discriminant<Packet> {
+    discriminant(&Packet::read_from(&mut &data[..]).unwrap())
+}
+
+/// Lists when each recorded send happened relative to the first record, where it went, and what
+/// kind of packet it was.
+fn recorded_sends(
+    records: &[CaptureRecord<FakeAddr>],
+) -> Vec<(Duration, Option<FakeAddr>, Discriminant<Packet>)> {
+    let first_timestamp = records[0].timestamp;
+    records
+        .iter()
+        .filter(|record| record.direction == Direction::Sent)
+        .map(|record| {
+            (
+                record.timestamp - first_timestamp,
+                record.addr,
+                kind(&record.data),
+            )
+        })
+        .collect()
+}
+
+/// Spawns a server on `socket` that is already connected to Client1 through `shared_secret`.
+fn spawn_with_connected_connection(
+    socket: Box<dyn BoundSocket<FakeAddr>>,
+    shared_secret: SharedSecret,
+) -> (cancel::Guard, mpsc::Receiver<Event<FakeAddr>>) {
+    let config = ConnectionConfig::default();
+    let (_request_tx, request_rx) = mpsc::channel(config.request_buffer_size());
+    let (event_tx, event_rx) = mpsc::channel(config.event_buffer_size());
+    let mut server = ConnectionServer::new(socket, config, None, None, request_rx, event_tx);
+    let connected = connected_connection(&server.config, shared_secret);
+    server.insert_connection(FakeAddr::Client1, connected);
+    let cancel_token = cancel::Token::new();
+    tokio::spawn(server.run(cancel_token.clone()));
+    (cancel_token.guard(), event_rx)
+}
+
+#[tokio::test(start_paused = true)]
+async fn replayed_capture_should_get_the_same_responses() {
+    run_test_with_timeout(async move {
+        // Record a server turning away garbage and challenging two clients.
+        let network = FakeNetwork::new();
+        let capture = CaptureBuffer::default();
+        let (cancel_guard, _requests, _events) = ConnectionServer::spawn(
+            Box::new(RecordingBoundSocket::new(
+                network.bind(FakeAddr::Server),
+                CaptureWriter::new(capture.clone()).unwrap(),
+            )),
+            ConnectionConfig::default(),
+            None,
+            None,
+        );
+        let connect_init = || {
+            Packet::ConnectInit(ConnectInitPacket {
+                game_id: GAME_ID,
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                content_hash: None,
+                client_public_key: PrivateKey::gen().to_public(),
+            })
+        };
+        let client1 = network.bind(FakeAddr::Client1);
+        let client2 = network.bind(FakeAddr::Client2);
+        send_packet_to(&client1, connect_init(), FakeAddr::Server).await;
+        recv_packet(&client1).await;
+        sleep(Duration::from_millis(100)).await;
+        send_bytes_to(&client2, b"\x01ConnectInit but too short", FakeAddr::Server).await;
+        sleep(Duration::from_millis(100)).await;
+        send_packet_to(&client2, connect_init(), FakeAddr::Server).await;
+        recv_packet(&client2).await;
+        drop(cancel_guard);
+
+        let records = read_capture::<FakeAddr>(&capture.0.lock().unwrap()[..]).unwrap();
+        let recorded_sends = recorded_sends(&records);
+        assert_eq!(records.len(), 5);
+        assert_eq!(recorded_sends.len(), 2);
+
+        // A fresh server fed the recording responds the same way at the same times.
+        let (socket, mut sent) = ReplaySocket::new(records);
+        let start = Instant::now();
+        let (_cancel_guard, _requests, _events) =
+            ConnectionServer::spawn(Box::new(socket), ConnectionConfig::default(), None, None);
+        for recorded_send in recorded_sends {
+            let (data, addr) = sent.recv().await.unwrap();
+            assert_eq!((start.elapsed(), addr, kind(&data)), recorded_send);
+        }
+        assert!(timeout(Duration::from_secs(1), sent.recv()).await.is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn replayed_capture_of_a_connection_should_get_the_same_responses() {
+    run_test_with_timeout(async move {
+        // Record a connected client sending reliable game data at uneven intervals, so the
+        // server's keepalive and resend timers fire between the datagrams it receives.
+        let shared_secret = SharedSecret::gen();
+        let network = FakeNetwork::new();
+        let capture = CaptureBuffer::default();
+        let (cancel_guard, _events) = spawn_with_connected_connection(
+            Box::new(RecordingBoundSocket::new(
+                network.bind(FakeAddr::Server),
+                CaptureWriter::new(capture.clone()).unwrap(),
+            )),
+            shared_secret,
+        );
+        let client = network.bind(FakeAddr::Client1);
+        let mut key = SealingKey::new(shared_secret, Role::Client);
+        for (sequence, delay_ms) in [0, 250, 250, 1530, 40, 710].into_iter().enumerate() {
+            sleep(Duration::from_millis(delay_ms)).await;
+            send_packet_to(
+                &client,
+                Packet::GameData(key.seal(GameDataPacket::ReliableOrdered {
+                    sequence: sequence as u32,
+                    data: vec![sequence as u8],
+                })),
+                FakeAddr::Server,
+            )
+            .await;
+        }
+        sleep(Duration::from_millis(1450)).await;
+
+        // Read the capture before shutting down, so it holds no goodbyes.
+        let records = read_capture::<FakeAddr>(&capture.0.lock().unwrap()[..]).unwrap();
+        drop(cancel_guard);
+        let sent_count = |f: fn(&Packet) -> bool| {
+            records
+                .iter()
+                .filter(|record| record.direction == Direction::Sent)
+                .filter(|record| f(&Packet::read_from(&mut &record.data[..]).unwrap()))
+                .count()
+        };
+        assert_eq!(sent_count(|packet| matches!(packet, Packet::Ack(_))), 6);
+        assert!(sent_count(|packet| matches!(packet, Packet::Keepalive(_))) > 0);
+        let recorded_sends = recorded_sends(&records);
+
+        // A fresh server that shares the connection replies the same way at the same times.
+        let (socket, mut sent) = ReplaySocket::new(records);
+        let start = Instant::now();
+        let (_cancel_guard, _events) =
+            spawn_with_connected_connection(Box::new(socket), shared_secret);
+        for recorded_send in recorded_sends {
+            let (data, addr) = sent.recv().await.unwrap();
+            assert_eq!((start.elapsed(), addr, kind(&data)), recorded_send);
+        }
+    })
+    .await;
+}
@@ -1,14 +1,115 @@
 use std::time::Duration;
 
+use dungeon_vr_connection_shared::disconnect_packet::DisconnectPacket;
+use dungeon_vr_connection_shared::fragment::Reassembler;
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
 use dungeon_vr_connection_shared::packet::Packet;
-use dungeon_vr_stream_codec::UnframedByteVec;
-use tokio::time::sleep;
+use dungeon_vr_connection_shared::reliable::{AckPacket, GameDataPacket, SEND_QUEUE_CAPACITY};
+use dungeon_vr_stream_codec::StreamCodec;
+use tokio::time::{sleep, timeout, Instant};
 
 use crate::testing::{
-    init_with_connected_connection, recv_packet, run_test_with_timeout, FakeAddr,
-    InitWithConnectedConnection,
+    init, init_with_connected_connection, init_with_pending_connection, recv_packet,
+    request_challenge, run_test_with_timeout, send_packet_to, FakeAddr, Init, InitOptions,
+    InitWithConnectedConnection, InitWithPendingConnection,
 };
-use crate::Request;
+use crate::{
+    Channel, ConnectionConfig, ConnectionState, Event, RejectReason, Request, DEFAULT_MTU,
+};
+
+#[tokio::test(start_paused = true)]
+async fn pending_request_accept_should_connect() {
+    run_test_with_timeout(async move {
+        let InitWithPendingConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_pending_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        requests
+            .send(Request::Accept {
+                addr: FakeAddr::Client1,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Connected,
+            },
+            events.recv().await.unwrap(),
+        );
+        let packet = match recv_packet(&socket).await {
+            Packet::Keepalive(packet) => packet,
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            key.open(&packet).unwrap(),
+            KeepalivePacket::Ping { .. },
+        ));
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn pending_request_reject_should_send_rejects() {
+    run_test_with_timeout(async move {
+        let InitWithPendingConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_pending_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        requests
+            .send(Request::Reject {
+                addr: FakeAddr::Client1,
+                reason: RejectReason::ServerFull,
+            })
+            .await
+            .unwrap();
+
+        for _ in 0..ConnectionConfig::default().disconnect_packet_count() {
+            let packet = match recv_packet(&socket).await {
+                Packet::Reject(packet) => packet,
+                _ => unreachable!(),
+            };
+            assert_eq!(key.open(&packet).unwrap(), RejectReason::ServerFull);
+        }
+        sleep(Duration::from_secs(1)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_accept_should_ignore() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            ..
+        } = init_with_connected_connection();
+
+        requests
+            .send(Request::Accept {
+                addr: FakeAddr::Client1,
+            })
+            .await
+            .unwrap();
+
+        sleep(Duration::from_millis(500)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
 
 #[tokio::test(start_paused = true)]
 async fn connected_request_gamedata_should_send_gamedata() {
@@ -17,7 +118,7 @@ async fn connected_request_gamedata_should_send_gamedata() {
             network,
             cancel_guard: _cancel_guard,
             requests,
-            shared_secret,
+            mut key,
             ..
         } = init_with_connected_connection();
         let socket = network.bind(FakeAddr::Client1);
@@ -25,6 +126,7 @@ async fn connected_request_gamedata_should_send_gamedata() {
         requests
             .send(Request::SendGameData {
                 addr: FakeAddr::Client1,
+                channel: Channel::Unreliable,
                 data: b"abcdef".to_vec(),
             })
             .await
@@ -34,8 +136,10 @@ async fn connected_request_gamedata_should_send_gamedata() {
             Packet::GameData(sealed) => sealed,
             _ => unreachable!(),
         };
-        let data = sealed.open_ext::<UnframedByteVec>(&shared_secret).unwrap();
-        assert_eq!(b"abcdef", data.as_slice());
+        assert_eq!(
+            GameDataPacket::Unreliable(b"abcdef".to_vec()),
+            key.open(&sealed).unwrap(),
+        );
     })
     .await;
 }
@@ -47,7 +151,7 @@ async fn connected_request_gamedata_should_refresh_keepalive() {
             network,
             cancel_guard: _cancel_guard,
             requests,
-            shared_secret,
+            mut key,
             ..
         } = init_with_connected_connection();
         let socket = network.bind(FakeAddr::Client1);
@@ -56,6 +160,7 @@ async fn connected_request_gamedata_should_refresh_keepalive() {
         requests
             .send(Request::SendGameData {
                 addr: FakeAddr::Client1,
+                channel: Channel::Unreliable,
                 data: b"abcdef".to_vec(),
             })
             .await
@@ -66,8 +171,386 @@ async fn connected_request_gamedata_should_refresh_keepalive() {
             Packet::GameData(sealed) => sealed,
             _ => unreachable!(),
         };
-        let data = sealed.open_ext::<UnframedByteVec>(&shared_secret).unwrap();
-        assert_eq!(b"abcdef", data.as_slice());
+        assert_eq!(
+            GameDataPacket::Unreliable(b"abcdef".to_vec()),
+            key.open(&sealed).unwrap(),
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_reliable_gamedata_should_resend_until_acked() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        requests
+            .send(Request::SendGameData {
+                addr: FakeAddr::Client1,
+                channel: Channel::ReliableOrdered,
+                data: b"abcdef".to_vec(),
+            })
+            .await
+            .unwrap();
+
+        let expected = GameDataPacket::ReliableOrdered {
+            sequence: 0,
+            data: b"abcdef".to_vec(),
+        };
+        for _ in 0..2 {
+            let sealed = match recv_packet(&socket).await {
+                Packet::GameData(sealed) => sealed,
+                _ => unreachable!(),
+            };
+            assert_eq!(expected, key.open(&sealed).unwrap());
+        }
+
+        send_packet_to(
+            &socket,
+            Packet::Ack(key.seal(AckPacket {
+                channel: Channel::ReliableOrdered,
+                sequence: 0,
+            })),
+            FakeAddr::Server,
+        )
+        .await;
+
+        // Once acknowledged, the next packet is the regularly scheduled Keepalive.
+        assert!(matches!(recv_packet(&socket).await, Packet::Keepalive(_)));
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_gamedata_overflowing_send_queue_should_disconnect() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        // The client never acknowledges anything.
+        for _ in 0..=SEND_QUEUE_CAPACITY {
+            requests
+                .send(Request::SendGameData {
+                    addr: FakeAddr::Client1,
+                    channel: Channel::ReliableUnordered,
+                    data: b"abcdef".to_vec(),
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnecting,
+            },
+            events.recv().await.unwrap(),
+        );
+        let sealed = loop {
+            if let Packet::Disconnect(sealed) = recv_packet(&socket).await {
+                break sealed;
+            }
+        };
+        assert_eq!(DisconnectPacket::default(), key.open(&sealed).unwrap());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_large_gamedata_should_send_fragments() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        requests
+            .send(Request::SendGameData {
+                addr: FakeAddr::Client1,
+                channel: Channel::Unreliable,
+                data: data.clone(),
+            })
+            .await
+            .unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        let mut received = None;
+        for _ in 0..5 {
+            let packet = recv_packet(&socket).await;
+            let mut w = Vec::new();
+            packet.write_to(&mut w).unwrap();
+            assert!(w.len() <= DEFAULT_MTU);
+            let fragment = match packet {
+                Packet::Fragment(sealed) => key.open(&sealed).unwrap(),
+                _ => unreachable!(),
+            };
+            assert!(received.is_none());
+            received = reassembler
+                .insert(fragment, Instant::now().into_std())
+                .unwrap();
+        }
+        assert_eq!(Some(GameDataPacket::Unreliable(data)), received);
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_shutdown_should_disconnect_then_drop() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        requests
+            .send(Request::Shutdown {
+                reason: Some("Restarting".to_string()),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnecting,
+            },
+            events.recv().await.unwrap(),
+        );
+        let mut disconnects = 0;
+        while disconnects < ConnectionConfig::default().disconnect_packet_count() {
+            match recv_packet(&socket).await {
+                Packet::Disconnect(packet) => {
+                    let packet = key.open(&packet).unwrap();
+                    assert_eq!(packet.reason.as_deref(), Some("Restarting"));
+                    disconnects += 1;
+                }
+                // The connection may get a Keepalive out before shutdown begins.
+                Packet::Keepalive(packet) => {
+                    key.open(&packet).unwrap();
+                }
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(Event::Dropped, events.recv().await.unwrap());
+        assert!(events.recv().await.is_none());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_disconnect_should_send_disconnects() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        requests
+            .send(Request::Disconnect {
+                addr: FakeAddr::Client1,
+                reason: Some("Idle".to_string()),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnecting,
+            },
+            events.recv().await.unwrap(),
+        );
+        let mut disconnects = 0;
+        while disconnects < ConnectionConfig::default().disconnect_packet_count() {
+            match recv_packet(&socket).await {
+                Packet::Disconnect(packet) => {
+                    let packet = key.open(&packet).unwrap();
+                    assert_eq!(packet.reason.as_deref(), Some("Idle"));
+                    disconnects += 1;
+                }
+                // The connection may get a Keepalive out before the request is handled.
+                Packet::Keepalive(packet) => {
+                    key.open(&packet).unwrap();
+                }
+                _ => unreachable!(),
+            }
+        }
+        sleep(Duration::from_secs(1)).await;
+        assert!(events.try_recv().is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_ban_should_reject_and_refuse_reconnect() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        requests
+            .send(Request::Ban {
+                addr: FakeAddr::Client1,
+                duration: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnecting,
+            },
+            events.recv().await.unwrap(),
+        );
+        let mut rejects = 0;
+        while rejects < ConnectionConfig::default().disconnect_packet_count() {
+            match recv_packet(&socket).await {
+                Packet::Reject(packet) => {
+                    assert_eq!(key.open(&packet).unwrap(), RejectReason::Banned);
+                    rejects += 1;
+                }
+                // The connection may get a Keepalive out before the request is handled.
+                Packet::Keepalive(packet) => {
+                    key.open(&packet).unwrap();
+                }
+                _ => unreachable!(),
+            }
+        }
+        sleep(Duration::from_secs(1)).await;
+
+        // Once the old connection is gone, new ConnectInit packets go unanswered.
+        assert!(timeout(Duration::from_secs(1), request_challenge(&socket))
+            .await
+            .is_err());
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn request_ban_should_expire_or_be_lifted() {
+    run_test_with_timeout(async move {
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            events: _events,
+        } = init(InitOptions::default());
+        let socket = network.bind(FakeAddr::Client1);
+
+        requests
+            .send(Request::Ban {
+                addr: FakeAddr::Client1,
+                duration: Some(Duration::from_secs(10)),
+            })
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_secs(1), request_challenge(&socket))
+            .await
+            .is_err());
+        sleep(Duration::from_secs(10)).await;
+        request_challenge(&socket).await;
+
+        requests
+            .send(Request::Ban {
+                addr: FakeAddr::Client1,
+                duration: None,
+            })
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_secs(1), request_challenge(&socket))
+            .await
+            .is_err());
+        requests
+            .send(Request::Unban {
+                addr: FakeAddr::Client1,
+            })
+            .await
+            .unwrap();
+        request_challenge(&socket).await;
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn connected_request_query_stats_should_report_rtt() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard: _cancel_guard,
+            requests,
+            mut events,
+            mut key,
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        // Answer the server's first Ping after a delay.
+        let id = match recv_packet(&socket).await {
+            Packet::Keepalive(packet) => match key.open(&packet).unwrap() {
+                KeepalivePacket::Ping { id } => id,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        sleep(Duration::from_millis(30)).await;
+        send_packet_to(
+            &socket,
+            Packet::Keepalive(key.seal(KeepalivePacket::Pong { id })),
+            FakeAddr::Server,
+        )
+        .await;
+
+        // Let the Pong arrive before asking.
+        sleep(Duration::from_millis(10)).await;
+        requests
+            .send(Request::QueryStats {
+                addr: FakeAddr::Client1,
+            })
+            .await
+            .unwrap();
+        let stats = match events.recv().await.unwrap() {
+            Event::Stats { addr, stats } => {
+                assert_eq!(addr, FakeAddr::Client1);
+                stats
+            }
+            _ => unreachable!(),
+        };
+        assert_eq!(stats.rtt, Some(Duration::from_millis(30)));
+        assert_eq!(stats.packets_sent, 1);
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.packets_lost, 0);
+        assert_eq!(stats.decrypt_failures, 0);
     })
     .await;
 }
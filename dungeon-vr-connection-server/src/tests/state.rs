@@ -1,43 +1,99 @@
+use std::time::Duration;
+
+use dungeon_vr_connection_shared::connect_init_packet::ConnectInitPacket;
+use dungeon_vr_connection_shared::connect_response_packet::ConnectResponsePacket;
+use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
 use dungeon_vr_connection_shared::packet::Packet;
+use dungeon_vr_connection_shared::protocol_version::{ProtocolVersions, PROTOCOL_VERSION};
+use dungeon_vr_connection_shared::sealed::{Role, SealingKey};
+use dungeon_vr_connection_shared::GAME_ID;
+use dungeon_vr_cryptography::PrivateKey;
+use tokio::time::{timeout, Instant};
 
 use crate::testing::{
-    init_with_connected_connection, init_with_disconnecting_connection,
-    init_with_pending_connection, recv_packet, run_test_with_timeout, FakeAddr,
-    InitWithConnectedConnection, InitWithDisconnectingConnection, InitWithPendingConnection,
+    init, init_unspawned, init_with_connected_connection, init_with_disconnecting_connection,
+    init_with_pending_connection, recv_packet, request_challenge, run_test_with_timeout,
+    send_packet_to, FakeAddr, Init, InitOptions, InitUnspawned, InitWithConnectedConnection,
+    InitWithDisconnectingConnection, InitWithPendingConnection,
 };
-use crate::{ConnectionState, Event, DISCONNECT_PACKET_COUNT};
+use crate::{ConnectionConfig, ConnectionState, Event};
+
+const FLOOD_SIZE: u32 = 1_000;
 
 #[tokio::test(start_paused = true)]
-async fn pending_connection_should_send_challenges() {
+async fn connectinit_flood_should_not_allocate_state() {
     run_test_with_timeout(async move {
-        let InitWithPendingConnection {
+        let InitUnspawned {
             network,
-            cancel_guard: _cancel_guard,
-            client_private_key,
-            server_public_key,
-            token,
-            ..
-        } = init_with_pending_connection();
+            mut server,
+            requests: _requests,
+            mut events,
+        } = init_unspawned(InitOptions::default());
 
-        let socket = network.bind(FakeAddr::Client1);
-        for _ in 0..3 {
-            let packet = match recv_packet(&socket).await {
-                Packet::ConnectChallenge(packet) => packet,
-                _ => unreachable!(),
-            };
-            assert_eq!(server_public_key, packet.server_public_key);
-            assert_eq!(
-                token,
-                packet
-                    .sealed_payload
-                    .open(
-                        &client_private_key
-                            .exchange(&packet.server_public_key)
-                            .unwrap(),
-                    )
-                    .unwrap(),
-            );
+        // Flood the server with ConnectInit packets from many spoofed addresses. Each one is
+        // answered, but none of them cost the server any lasting state.
+        for i in 0..FLOOD_SIZE {
+            server
+                .handle_connect_init_packet(
+                    FakeAddr::Spoofed(i),
+                    ConnectInitPacket {
+                        game_id: GAME_ID,
+                        protocol_versions: ProtocolVersions::SUPPORTED,
+                        content_hash: None,
+                        client_public_key: PrivateKey::gen().to_public(),
+                    },
+                )
+                .await;
+            assert!(server.connections.is_empty());
         }
+        assert!(events.try_recv().is_err());
+
+        // A genuine client can still complete the handshake afterward, and only it is recorded.
+        let socket = network.bind(FakeAddr::Client1);
+        let client_private_key = PrivateKey::gen();
+        server
+            .handle_connect_init_packet(
+                FakeAddr::Client1,
+                ConnectInitPacket {
+                    game_id: GAME_ID,
+                    protocol_versions: ProtocolVersions::SUPPORTED,
+                    content_hash: None,
+                    client_public_key: client_private_key.to_public(),
+                },
+            )
+            .await;
+        let packet = match recv_packet(&socket).await {
+            Packet::ConnectChallenge(packet) => packet,
+            _ => unreachable!(),
+        };
+        let mut key = SealingKey::new(
+            client_private_key
+                .exchange(&packet.server_public_key)
+                .unwrap(),
+            Role::Client,
+        );
+        let token = packet
+            .sealed_payload
+            .open(Role::Server, key.shared_secret())
+            .unwrap();
+        server
+            .handle_connect_response_packet(
+                FakeAddr::Client1,
+                ConnectResponsePacket {
+                    token,
+                    sealed_payload: key.seal(()),
+                },
+            )
+            .await;
+        assert_eq!(server.connections.len(), 1);
+        assert!(server.connections.contains_key(&FakeAddr::Client1));
+        assert_eq!(
+            Event::ConnectRequest {
+                addr: FakeAddr::Client1,
+                protocol_version: PROTOCOL_VERSION,
+            },
+            events.recv().await.unwrap(),
+        );
     })
     .await;
 }
@@ -54,10 +110,56 @@ async fn pending_connection_should_time_out() {
         assert_eq!(
             Event::State {
                 addr: FakeAddr::Client1,
-                state: ConnectionState::Disconnecting
+                state: ConnectionState::Disconnecting,
+            },
+            events.recv().await.unwrap(),
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn pending_connection_should_time_out_after_configured_timeout() {
+    run_test_with_timeout(async move {
+        let config = ConnectionConfig::builder()
+            .keepalive_interval(Duration::from_millis(500))
+            .timeout(Duration::from_secs(1))
+            .build()
+            .unwrap();
+        let Init {
+            network,
+            cancel_guard: _cancel_guard,
+            requests: _requests,
+            mut events,
+        } = init(InitOptions {
+            config,
+            ..Default::default()
+        });
+        let socket = network.bind(FakeAddr::Client1);
+        let (mut key, token) = request_challenge(&socket).await;
+        send_packet_to(
+            &socket,
+            Packet::ConnectResponse(ConnectResponsePacket {
+                token,
+                sealed_payload: key.seal(()),
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            Event::ConnectRequest { .. },
+        ));
+
+        let start = Instant::now();
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnecting,
             },
-            events.recv().await.unwrap()
+            events.recv().await.unwrap(),
         );
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
     })
     .await;
 }
@@ -68,7 +170,7 @@ async fn connected_connection_should_send_keepalives() {
         let InitWithConnectedConnection {
             network,
             cancel_guard: _cancel_guard,
-            shared_secret,
+            mut key,
             ..
         } = init_with_connected_connection();
 
@@ -78,7 +180,10 @@ async fn connected_connection_should_send_keepalives() {
                 Packet::Keepalive(packet) => packet,
                 _ => unreachable!(),
             };
-            let () = packet.open(&shared_secret).unwrap();
+            assert!(matches!(
+                key.open(&packet).unwrap(),
+                KeepalivePacket::Ping { .. },
+            ));
         }
     })
     .await;
@@ -110,18 +215,76 @@ async fn disconnecting_connection_should_send_disconnects() {
         let InitWithDisconnectingConnection {
             network,
             cancel_guard: _cancel_guard,
-            shared_secret,
+            mut key,
             ..
         } = init_with_disconnecting_connection();
 
         let socket = network.bind(FakeAddr::Client1);
-        for _ in 0..DISCONNECT_PACKET_COUNT {
+        for _ in 0..ConnectionConfig::default().disconnect_packet_count() {
             let packet = match recv_packet(&socket).await {
                 Packet::Disconnect(packet) => packet,
                 _ => unreachable!(),
             };
-            packet.open(&shared_secret).unwrap();
+            key.open(&packet).unwrap();
+        }
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn cancelled_should_disconnect_then_drop() {
+    run_test_with_timeout(async move {
+        let InitWithConnectedConnection {
+            network,
+            cancel_guard,
+            mut events,
+            mut key,
+            ..
+        } = init_with_connected_connection();
+        let socket = network.bind(FakeAddr::Client1);
+
+        drop(cancel_guard);
+
+        assert_eq!(
+            Event::State {
+                addr: FakeAddr::Client1,
+                state: ConnectionState::Disconnecting,
+            },
+            events.recv().await.unwrap(),
+        );
+
+        // New clients are not challenged while shutting down.
+        let other_socket = network.bind(FakeAddr::Client2);
+        send_packet_to(
+            &other_socket,
+            Packet::ConnectInit(ConnectInitPacket {
+                game_id: GAME_ID,
+                protocol_versions: ProtocolVersions::SUPPORTED,
+                content_hash: None,
+                client_public_key: PrivateKey::gen().to_public(),
+            }),
+            FakeAddr::Server,
+        )
+        .await;
+
+        let mut disconnects = 0;
+        while disconnects < ConnectionConfig::default().disconnect_packet_count() {
+            match recv_packet(&socket).await {
+                Packet::Disconnect(packet) => {
+                    assert_eq!(key.open(&packet).unwrap().reason, None);
+                    disconnects += 1;
+                }
+                Packet::Keepalive(packet) => {
+                    key.open(&packet).unwrap();
+                }
+                _ => unreachable!(),
+            }
         }
+        assert_eq!(Event::Dropped, events.recv().await.unwrap());
+        assert!(events.recv().await.is_none());
+        assert!(timeout(Duration::from_secs(1), recv_packet(&other_socket))
+            .await
+            .is_err());
     })
     .await;
 }
@@ -1,28 +1,36 @@
 use std::convert::Infallible;
+use std::fmt::Display;
+use std::time::Duration;
 
+use dungeon_vr_cryptography::{Nonce, PrivateKey, PublicKey, SharedSecret};
 use dungeon_vr_stream_codec::StreamCodec;
-use rand_core::{OsRng, RngCore};
 
 use crate::packet::ReadPacketError;
 
-/// A challenge token, a block of random data the client must echo to demonstrate its ability to
-/// receive and send packets at its address and its knowledge of the shared secret.
+/// A stateless challenge token, which the client must echo to demonstrate its ability to receive
+/// and send packets at its address.
+///
+/// The token carries the server's half of the handshake encrypted under a key only the server
+/// knows, with the client's address and the time of issue authenticated alongside. This lets the
+/// server recover everything it needs when the token comes back, so it holds no per-client state
+/// until then.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct ChallengeToken {
-    data: [u8; Self::SIZE],
+    /// Milliseconds since an epoch of the issuing server's choosing.
+    issued_at: u64,
+    nonce: Nonce,
+    data: [u8; Self::CIPHERTEXT_SIZE],
 }
 
 impl ChallengeToken {
-    pub const SIZE: usize = 256;
+    const CIPHERTEXT_SIZE: usize = PrivateKey::SIZE + PublicKey::SIZE + 3 + SharedSecret::TAG_SIZE;
 
-    pub fn gen() -> Self {
-        let mut data = [0; Self::SIZE];
-        OsRng.fill_bytes(&mut data[..]);
-        Self { data }
-    }
+    pub const SIZE: usize = 8 + Nonce::SIZE + Self::CIPHERTEXT_SIZE;
 
-    pub fn data(&self) -> &[u8; Self::SIZE] {
-        &self.data
+    /// The nonce the token was sealed with, which is unique to each token and authenticated when
+    /// it is opened.
+    pub fn nonce(&self) -> Nonce {
+        self.nonce
     }
 }
 
@@ -32,11 +40,193 @@ impl StreamCodec for ChallengeToken {
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
         Ok(Self {
-            data: <[u8; Self::SIZE]>::read_from(r)?,
+            issued_at: u64::read_from(r)?,
+            nonce: Nonce::read_from(r)?,
+            data: <[u8; Self::CIPHERTEXT_SIZE]>::read_from(r)?,
         })
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+        self.issued_at.write_to(w)?;
+        self.nonce.write_to(w)?;
         self.data.write_to(w)
     }
 }
+
+/// The handshake state a server recovers from a [`ChallengeToken`].
+#[derive(Debug)]
+pub struct ChallengeTokenContents {
+    /// The server's ephemeral private key for ECDH key exchange.
+    pub server_private_key: PrivateKey,
+    /// The client's public key for ECDH key exchange.
+    pub client_public_key: PublicKey,
+    /// The protocol version negotiated from the client's ConnectInit packet, or `None` if the
+    /// client is incompatible and must be rejected once the handshake completes.
+    pub protocol_version: Option<u16>,
+}
+
+/// A server's secret for issuing and opening [`ChallengeToken`]s. Tokens are only meaningful to
+/// the key that issued them.
+pub struct ChallengeTokenKey(SharedSecret);
+
+impl ChallengeTokenKey {
+    pub fn gen() -> Self {
+        Self(SharedSecret::gen())
+    }
+
+    /// Issues a token for a client at `addr`. `now` is measured from the same epoch that will be
+    /// passed to [`Self::open`].
+    pub fn issue(
+        &self,
+        contents: &ChallengeTokenContents,
+        addr: impl Display,
+        now: Duration,
+    ) -> ChallengeToken {
+        let issued_at = now.as_millis() as u64;
+        let mut plaintext = contents.server_private_key.to_bytes().to_vec();
+        contents.client_public_key.write_to(&mut plaintext).unwrap();
+        // Write a fixed-size encoding so every token is the same size.
+        contents
+            .protocol_version
+            .is_some()
+            .write_to(&mut plaintext)
+            .unwrap();
+        contents
+            .protocol_version
+            .unwrap_or(0)
+            .write_to(&mut plaintext)
+            .unwrap();
+        let nonce = Nonce::gen();
+        let ciphertext = self
+            .0
+            .encrypt(&plaintext, &associated_data(issued_at, addr), &nonce);
+        ChallengeToken {
+            issued_at,
+            nonce,
+            data: ciphertext.try_into().unwrap(),
+        }
+    }
+
+    /// Authenticates a token echoed by a client at `addr` and recovers its contents. Tokens issued
+    /// more than `lifetime` before `now` are rejected.
+    pub fn open(
+        &self,
+        token: &ChallengeToken,
+        addr: impl Display,
+        now: Duration,
+        lifetime: Duration,
+    ) -> Result<ChallengeTokenContents, ReadPacketError> {
+        // Check the age before spending time on decryption. The timestamp is authenticated below.
+        match (now.as_millis() as u64).checked_sub(token.issued_at) {
+            Some(age) if age <= lifetime.as_millis() as u64 => (),
+            _ => return Err(ReadPacketError::ExpiredChallengeToken),
+        }
+        let plaintext = self.0.decrypt(
+            &token.data,
+            &associated_data(token.issued_at, addr),
+            &token.nonce,
+        )?;
+        let mut r = &plaintext[..];
+        let server_private_key =
+            PrivateKey::from_bytes(<[u8; PrivateKey::SIZE]>::read_from(&mut r)?);
+        let client_public_key = PublicKey::read_from(&mut r)?;
+        let has_protocol_version = bool::read_from(&mut r)?;
+        let protocol_version = u16::read_from(&mut r)?;
+        Ok(ChallengeTokenContents {
+            server_private_key,
+            client_public_key,
+            protocol_version: has_protocol_version.then_some(protocol_version),
+        })
+    }
+}
+
+fn associated_data(issued_at: u64, addr: impl Display) -> Vec<u8> {
+    let mut w = Vec::new();
+    issued_at.write_to(&mut w).unwrap();
+    w.extend_from_slice(addr.to_string().as_bytes());
+    w
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use dungeon_vr_cryptography::PrivateKey;
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use crate::packet::ReadPacketError;
+
+    use super::{ChallengeToken, ChallengeTokenContents, ChallengeTokenKey};
+
+    const LIFETIME: Duration = Duration::from_secs(5);
+
+    fn issue(key: &ChallengeTokenKey, addr: &str, now: Duration) -> (ChallengeToken, PrivateKey) {
+        let server_private_key = PrivateKey::gen();
+        let token = key.issue(
+            &ChallengeTokenContents {
+                server_private_key: server_private_key.clone(),
+                client_public_key: PrivateKey::gen().to_public(),
+                protocol_version: Some(7),
+            },
+            addr,
+            now,
+        );
+        (token, server_private_key)
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = ChallengeTokenKey::gen();
+        let (token, server_private_key) = issue(&key, "client", Duration::from_secs(10));
+
+        let mut w = Vec::new();
+        token.write_to(&mut w).unwrap();
+        assert_eq!(w.len(), ChallengeToken::SIZE);
+        let mut r = &w[..];
+        let round_trip = ChallengeToken::read_from(&mut r).unwrap();
+        assert!(r.is_empty());
+        assert_eq!(round_trip, token);
+
+        let contents = key
+            .open(&token, "client", Duration::from_secs(12), LIFETIME)
+            .unwrap();
+        assert_eq!(
+            contents.server_private_key.to_public(),
+            server_private_key.to_public(),
+        );
+        assert_eq!(contents.protocol_version, Some(7));
+    }
+
+    #[test]
+    fn rejects_other_address_or_key() {
+        let key = ChallengeTokenKey::gen();
+        let (token, _) = issue(&key, "client", Duration::ZERO);
+
+        assert!(matches!(
+            key.open(&token, "spoofer", Duration::ZERO, LIFETIME),
+            Err(ReadPacketError::DecryptError(_)),
+        ));
+        assert!(matches!(
+            ChallengeTokenKey::gen().open(&token, "client", Duration::ZERO, LIFETIME),
+            Err(ReadPacketError::DecryptError(_)),
+        ));
+    }
+
+    #[test]
+    fn rejects_expired_or_backdated() {
+        let key = ChallengeTokenKey::gen();
+        let (mut token, _) = issue(&key, "client", Duration::from_secs(10));
+
+        assert!(matches!(
+            key.open(&token, "client", Duration::from_secs(16), LIFETIME),
+            Err(ReadPacketError::ExpiredChallengeToken),
+        ));
+
+        // Moving the timestamp forward keeps the token fresh, but breaks authentication.
+        token.issued_at += 6000;
+        assert!(matches!(
+            key.open(&token, "client", Duration::from_secs(16), LIFETIME),
+            Err(ReadPacketError::DecryptError(_)),
+        ));
+    }
+}
@@ -0,0 +1,285 @@
+//! Tuning for connection clients and servers.
+
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::fragment::min_mtu;
+use crate::{DEFAULT_MTU, SAFE_RECV_BUFFER_SIZE};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConnectionConfigError {
+    #[error("MTU {mtu} is outside the supported range {min}..={max}", min = min_mtu(), max = SAFE_RECV_BUFFER_SIZE)]
+    MtuOutOfRange { mtu: usize },
+
+    #[error("{0} must be nonzero")]
+    Zero(&'static str),
+
+    #[error("keepalive interval {keepalive_interval:?} must be shorter than timeout {timeout:?}")]
+    KeepaliveNotBeforeTimeout {
+        keepalive_interval: Duration,
+        timeout: Duration,
+    },
+}
+
+/// Timeouts, intervals, and sizes for a connection client or server. Each side ignores the
+/// settings that only apply to the other. The defaults suit play over the internet; build a
+/// variant with [`ConnectionConfig::builder`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    mtu: usize,
+    send_interval: Duration,
+    disconnect_packet_count: usize,
+    keepalive_interval: Duration,
+    resend_interval: Duration,
+    reassembly_timeout: Duration,
+    timeout: Duration,
+    reconnect_timeout: Duration,
+    challenge_token_lifetime: Duration,
+    request_buffer_size: usize,
+    event_buffer_size: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            send_interval: Duration::from_millis(250),
+            disconnect_packet_count: 10,
+            keepalive_interval: Duration::from_secs(1),
+            resend_interval: Duration::from_millis(100),
+            reassembly_timeout: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            // This has to outlast the server noticing the timeout and finishing its own
+            // disconnect.
+            reconnect_timeout: Duration::from_secs(30),
+            challenge_token_lifetime: Duration::from_secs(5),
+            request_buffer_size: 256,
+            event_buffer_size: 256,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// Starts from the defaults.
+    pub fn builder() -> ConnectionConfigBuilder {
+        ConnectionConfigBuilder {
+            config: Self::default(),
+        }
+    }
+
+    /// The largest UDP payload to send. Bigger game data is split into fragments.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// How often to repeat handshake and disconnect packets.
+    pub fn send_interval(&self) -> Duration {
+        self.send_interval
+    }
+
+    /// How many times to send the packet that ends a connection.
+    pub fn disconnect_packet_count(&self) -> usize {
+        self.disconnect_packet_count
+    }
+
+    /// How long a connected peer may go without sending anything before a keepalive is sent.
+    pub fn keepalive_interval(&self) -> Duration {
+        self.keepalive_interval
+    }
+
+    /// How often to resend unacknowledged reliable game data.
+    pub fn resend_interval(&self) -> Duration {
+        self.resend_interval
+    }
+
+    /// How long to hold an incomplete fragmented message.
+    pub fn reassembly_timeout(&self) -> Duration {
+        self.reassembly_timeout
+    }
+
+    /// How long to wait without hearing from the peer before giving up on it.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// How long a client keeps trying to reestablish a connection that timed out. Zero disables
+    /// reconnecting.
+    pub fn reconnect_timeout(&self) -> Duration {
+        self.reconnect_timeout
+    }
+
+    /// How long a server's challenge stays valid for the client to answer.
+    pub fn challenge_token_lifetime(&self) -> Duration {
+        self.challenge_token_lifetime
+    }
+
+    /// How many requests may queue up before senders wait.
+    pub fn request_buffer_size(&self) -> usize {
+        self.request_buffer_size
+    }
+
+    /// How many events may queue up before the connection waits for them to be received.
+    pub fn event_buffer_size(&self) -> usize {
+        self.event_buffer_size
+    }
+}
+
+/// Builds a [`ConnectionConfig`], checking the settings for consistency.
+#[derive(Clone, Debug)]
+pub struct ConnectionConfigBuilder {
+    config: ConnectionConfig,
+}
+
+impl ConnectionConfigBuilder {
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.config.mtu = mtu;
+        self
+    }
+
+    pub fn send_interval(mut self, send_interval: Duration) -> Self {
+        self.config.send_interval = send_interval;
+        self
+    }
+
+    pub fn disconnect_packet_count(mut self, disconnect_packet_count: usize) -> Self {
+        self.config.disconnect_packet_count = disconnect_packet_count;
+        self
+    }
+
+    pub fn keepalive_interval(mut self, keepalive_interval: Duration) -> Self {
+        self.config.keepalive_interval = keepalive_interval;
+        self
+    }
+
+    pub fn resend_interval(mut self, resend_interval: Duration) -> Self {
+        self.config.resend_interval = resend_interval;
+        self
+    }
+
+    pub fn reassembly_timeout(mut self, reassembly_timeout: Duration) -> Self {
+        self.config.reassembly_timeout = reassembly_timeout;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    pub fn reconnect_timeout(mut self, reconnect_timeout: Duration) -> Self {
+        self.config.reconnect_timeout = reconnect_timeout;
+        self
+    }
+
+    pub fn challenge_token_lifetime(mut self, challenge_token_lifetime: Duration) -> Self {
+        self.config.challenge_token_lifetime = challenge_token_lifetime;
+        self
+    }
+
+    pub fn request_buffer_size(mut self, request_buffer_size: usize) -> Self {
+        self.config.request_buffer_size = request_buffer_size;
+        self
+    }
+
+    pub fn event_buffer_size(mut self, event_buffer_size: usize) -> Self {
+        self.config.event_buffer_size = event_buffer_size;
+        self
+    }
+
+    pub fn build(self) -> Result<ConnectionConfig, ConnectionConfigError> {
+        let config = self.config;
+        if !(min_mtu()..=SAFE_RECV_BUFFER_SIZE).contains(&config.mtu) {
+            return Err(ConnectionConfigError::MtuOutOfRange { mtu: config.mtu });
+        }
+        for (name, duration) in [
+            ("send interval", config.send_interval),
+            ("keepalive interval", config.keepalive_interval),
+            ("resend interval", config.resend_interval),
+            ("reassembly timeout", config.reassembly_timeout),
+            ("timeout", config.timeout),
+            ("challenge token lifetime", config.challenge_token_lifetime),
+        ] {
+            if duration.is_zero() {
+                return Err(ConnectionConfigError::Zero(name));
+            }
+        }
+        for (name, size) in [
+            ("disconnect packet count", config.disconnect_packet_count),
+            ("request buffer size", config.request_buffer_size),
+            ("event buffer size", config.event_buffer_size),
+        ] {
+            if size == 0 {
+                return Err(ConnectionConfigError::Zero(name));
+            }
+        }
+        // A connection that is otherwise idle only stays alive on keepalives.
+        if config.keepalive_interval >= config.timeout {
+            return Err(ConnectionConfigError::KeepaliveNotBeforeTimeout {
+                keepalive_interval: config.keepalive_interval,
+                timeout: config.timeout,
+            });
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ConnectionConfig, ConnectionConfigError};
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(
+            ConnectionConfig::builder().build(),
+            Ok(ConnectionConfig::default()),
+        );
+    }
+
+    #[test]
+    fn build_rejects_inconsistent_settings() {
+        assert_eq!(
+            ConnectionConfig::builder().mtu(10).build(),
+            Err(ConnectionConfigError::MtuOutOfRange { mtu: 10 }),
+        );
+        assert_eq!(
+            ConnectionConfig::builder()
+                .resend_interval(Duration::ZERO)
+                .build(),
+            Err(ConnectionConfigError::Zero("resend interval")),
+        );
+        assert_eq!(
+            ConnectionConfig::builder().event_buffer_size(0).build(),
+            Err(ConnectionConfigError::Zero("event buffer size")),
+        );
+        assert_eq!(
+            ConnectionConfig::builder()
+                .keepalive_interval(Duration::from_secs(2))
+                .timeout(Duration::from_secs(2))
+                .build(),
+            Err(ConnectionConfigError::KeepaliveNotBeforeTimeout {
+                keepalive_interval: Duration::from_secs(2),
+                timeout: Duration::from_secs(2),
+            }),
+        );
+    }
+
+    #[test]
+    fn build_keeps_settings() {
+        let config = ConnectionConfig::builder()
+            .mtu(1400)
+            .timeout(Duration::from_secs(2))
+            .reconnect_timeout(Duration::ZERO)
+            .build()
+            .unwrap();
+        assert_eq!(config.mtu(), 1400);
+        assert_eq!(config.timeout(), Duration::from_secs(2));
+        assert_eq!(config.reconnect_timeout(), Duration::ZERO);
+        assert_eq!(
+            config.keepalive_interval(),
+            ConnectionConfig::default().keepalive_interval(),
+        );
+    }
+}
@@ -1,10 +1,11 @@
 use std::convert::Infallible;
 
-use dungeon_vr_cryptography::PublicKey;
+use dungeon_vr_cryptography::{PublicKey, Signature};
 use dungeon_vr_stream_codec::StreamCodec;
 
 use crate::challenge_token::ChallengeToken;
 use crate::packet::ReadPacketError;
+use crate::protocol_version::ProtocolVersions;
 use crate::sealed::Sealed;
 
 /// The server's response to a valid
@@ -12,25 +13,57 @@ use crate::sealed::Sealed;
 pub struct ConnectChallengePacket {
     /// The server's public key for ECDH key exchange.
     pub server_public_key: PublicKey,
+    /// The protocol versions the server speaks. The client uses these to arrive at the same
+    /// negotiated version as the server.
+    pub protocol_versions: ProtocolVersions,
+    /// The server's long-term signature over [`signed_message`], if it has a signing key.
+    pub signature: Option<Signature>,
     /// The encrypted part of the packet.
     pub sealed_payload: Sealed<ChallengeToken>,
 }
 
+/// The bytes a server signs to vouch for its ephemeral public key and supported protocol versions.
+/// Covering the client's public key as well ties the signature to a single handshake.
+pub fn signed_message(
+    client_public_key: &PublicKey,
+    server_public_key: &PublicKey,
+    protocol_versions: ProtocolVersions,
+) -> Vec<u8> {
+    let mut w = b"dungeon-vr connect challenge".to_vec();
+    client_public_key.write_to(&mut w).unwrap();
+    server_public_key.write_to(&mut w).unwrap();
+    protocol_versions.write_to(&mut w).unwrap();
+    w
+}
+
 impl StreamCodec for ConnectChallengePacket {
     type ReadError = ReadPacketError;
     type WriteError = Infallible;
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
         let server_public_key = PublicKey::read_from(r)?;
+        let protocol_versions = ProtocolVersions::read_from(r)?;
+        let signature = if bool::read_from(r)? {
+            Some(Signature::read_from(r)?)
+        } else {
+            None
+        };
         let sealed_payload = Sealed::read_from(r)?;
         Ok(Self {
             server_public_key,
+            protocol_versions,
+            signature,
             sealed_payload,
         })
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
         self.server_public_key.write_to(w)?;
+        self.protocol_versions.write_to(w)?;
+        self.signature.is_some().write_to(w)?;
+        if let Some(signature) = &self.signature {
+            signature.write_to(w)?;
+        }
         self.sealed_payload.write_to(w)?;
         Ok(())
     }
@@ -38,14 +71,17 @@ impl StreamCodec for ConnectChallengePacket {
 
 #[cfg(test)]
 mod tests {
-    use dungeon_vr_cryptography::PrivateKey;
+    use std::time::Duration;
+
+    use dungeon_vr_cryptography::{PrivateKey, SigningKey};
     use dungeon_vr_stream_codec::StreamCodec;
 
-    use crate::challenge_token::ChallengeToken;
+    use crate::challenge_token::{ChallengeTokenContents, ChallengeTokenKey};
     use crate::packet::Packet;
-    use crate::sealed::Sealed;
+    use crate::protocol_version::ProtocolVersions;
+    use crate::sealed::{Role, Sealed};
 
-    use super::ConnectChallengePacket;
+    use super::{signed_message, ConnectChallengePacket};
 
     #[test]
     fn round_trip() {
@@ -53,15 +89,29 @@ mod tests {
         let server_private_key = PrivateKey::gen();
         let client_public_key = client_private_key.to_public();
         let server_public_key = server_private_key.to_public();
-        let token = ChallengeToken::gen();
+        let shared_secret = server_private_key.exchange(&client_public_key).unwrap();
+        let token = ChallengeTokenKey::gen().issue(
+            &ChallengeTokenContents {
+                server_private_key,
+                client_public_key,
+                protocol_version: Some(1),
+            },
+            "client",
+            Duration::ZERO,
+        );
+        let signing_key = SigningKey::gen();
+        let protocol_versions = ProtocolVersions { min: 1, max: 2 };
 
         let mut w = Vec::new();
         Packet::ConnectChallenge(ConnectChallengePacket {
             server_public_key,
-            sealed_payload: Sealed::seal(
-                token,
-                &server_private_key.exchange(&client_public_key).unwrap(),
-            ),
+            protocol_versions,
+            signature: Some(signing_key.sign(&signed_message(
+                &client_public_key,
+                &server_public_key,
+                protocol_versions,
+            ))),
+            sealed_payload: Sealed::seal(token, Role::Server, 0, &shared_secret),
         })
         .write_to(&mut w)
         .unwrap();
@@ -74,9 +124,22 @@ mod tests {
             _ => unreachable!(),
         };
         assert_eq!(packet.server_public_key, server_public_key);
+        assert_eq!(packet.protocol_versions, protocol_versions);
+        signing_key
+            .verifying_key()
+            .verify(
+                &signed_message(
+                    &client_public_key,
+                    &packet.server_public_key,
+                    packet.protocol_versions,
+                ),
+                &packet.signature.unwrap(),
+            )
+            .unwrap();
         let roundtrip_token = packet
             .sealed_payload
             .open(
+                Role::Server,
                 &client_private_key
                     .exchange(&packet.server_public_key)
                     .unwrap(),
@@ -1,44 +1,81 @@
 use std::convert::Infallible;
+use std::io::Write;
 
 use dungeon_vr_cryptography::PublicKey;
 use dungeon_vr_stream_codec::StreamCodec;
 
 use crate::packet::ReadPacketError;
+use crate::protocol_version::{ContentHash, ProtocolVersions};
 
 /// The initial packet from a client that wants to connect.
+///
+/// Peers must agree on this packet's encoding before they can negotiate anything else, so changes
+/// to it should only append fields.
 pub struct ConnectInitPacket {
     /// The Game ID, which must be [`GAME_ID`](crate::GAME_ID) to be accepted.
     pub game_id: u64,
+    /// The protocol versions the client speaks.
+    pub protocol_versions: ProtocolVersions,
+    /// The client's content hash, if it has one.
+    pub content_hash: Option<ContentHash>,
     /// The client's public key for ECDH key exchange.
     pub client_public_key: PublicKey,
 }
 
+impl ConnectInitPacket {
+    /// The number of zero bytes that follow the packet's fields on the wire. This makes the packet
+    /// at least as large as the challenge it solicits, so a spoofed ConnectInit cannot be used to
+    /// amplify traffic toward its apparent source.
+    pub const PADDING_SIZE: usize = 240;
+}
+
 impl StreamCodec for ConnectInitPacket {
     type ReadError = ReadPacketError;
     type WriteError = Infallible;
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
         let game_id = u64::read_from(r)?;
+        let protocol_versions = ProtocolVersions::read_from(r)?;
+        let content_hash = if bool::read_from(r)? {
+            Some(ContentHash::read_from(r)?)
+        } else {
+            None
+        };
         let client_public_key = PublicKey::read_from(r)?;
+        <[u8; Self::PADDING_SIZE]>::read_from(r)?;
         Ok(Self {
             game_id,
+            protocol_versions,
+            content_hash,
             client_public_key,
         })
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
         self.game_id.write_to(w)?;
+        self.protocol_versions.write_to(w)?;
+        self.content_hash.is_some().write_to(w)?;
+        if let Some(content_hash) = &self.content_hash {
+            content_hash.write_to(w)?;
+        }
         self.client_public_key.write_to(w)?;
+        w.write_all(&[0; Self::PADDING_SIZE]).unwrap();
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use dungeon_vr_cryptography::PrivateKey;
+    use std::time::Duration;
+
+    use dungeon_vr_cryptography::{PrivateKey, SigningKey};
     use dungeon_vr_stream_codec::StreamCodec;
 
+    use crate::challenge_token::{ChallengeTokenContents, ChallengeTokenKey};
+    use crate::connect_challenge_packet::ConnectChallengePacket;
     use crate::packet::Packet;
+    use crate::protocol_version::ProtocolVersions;
+    use crate::sealed::{Role, Sealed};
 
     use super::ConnectInitPacket;
 
@@ -49,6 +86,8 @@ mod tests {
         let mut w = Vec::new();
         Packet::ConnectInit(ConnectInitPacket {
             game_id: 0x0123456789abcdef,
+            protocol_versions: ProtocolVersions { min: 2, max: 3 },
+            content_hash: Some([0x55; 32]),
             client_public_key,
         })
         .write_to(&mut w)
@@ -62,6 +101,66 @@ mod tests {
             _ => unreachable!(),
         };
         assert_eq!(packet.game_id, 0x0123456789abcdef);
+        assert_eq!(
+            packet.protocol_versions,
+            ProtocolVersions { min: 2, max: 3 },
+        );
+        assert_eq!(packet.content_hash, Some([0x55; 32]));
         assert_eq!(packet.client_public_key, client_public_key);
     }
+
+    #[test]
+    fn unpadded_is_rejected() {
+        let mut w = Vec::new();
+        Packet::ConnectInit(ConnectInitPacket {
+            game_id: 0x0123456789abcdef,
+            protocol_versions: ProtocolVersions::SUPPORTED,
+            content_hash: None,
+            client_public_key: PrivateKey::gen().to_public(),
+        })
+        .write_to(&mut w)
+        .unwrap();
+
+        assert!(Packet::read_from(&mut &w[..w.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn is_no_smaller_than_largest_challenge() {
+        let client_private_key = PrivateKey::gen();
+        let server_private_key = PrivateKey::gen();
+        let server_public_key = server_private_key.to_public();
+        let shared_secret = server_private_key
+            .exchange(&client_private_key.to_public())
+            .unwrap();
+        let token = ChallengeTokenKey::gen().issue(
+            &ChallengeTokenContents {
+                server_private_key,
+                client_public_key: client_private_key.to_public(),
+                protocol_version: Some(1),
+            },
+            "client",
+            Duration::ZERO,
+        );
+
+        let mut init = Vec::new();
+        Packet::ConnectInit(ConnectInitPacket {
+            game_id: 0,
+            protocol_versions: ProtocolVersions::SUPPORTED,
+            content_hash: None,
+            client_public_key: client_private_key.to_public(),
+        })
+        .write_to(&mut init)
+        .unwrap();
+        let mut challenge = Vec::new();
+        Packet::ConnectChallenge(ConnectChallengePacket {
+            server_public_key,
+            protocol_versions: ProtocolVersions::SUPPORTED,
+            signature: Some(SigningKey::gen().sign(b"")),
+            sealed_payload: Sealed::seal(token, Role::Server, 0, &shared_secret),
+        })
+        .write_to(&mut challenge)
+        .unwrap();
+
+        assert!(init.len() >= challenge.len());
+    }
 }
@@ -0,0 +1,69 @@
+use dungeon_vr_stream_codec::StreamCodec;
+
+use crate::challenge_token::ChallengeToken;
+use crate::packet::ReadPacketError;
+use crate::sealed::Sealed;
+
+/// The client's response to a
+/// [`ConnectChallengePacket`](crate::connect_challenge_packet::ConnectChallengePacket).
+#[derive(StreamCodec)]
+#[stream_codec(read_error = "ReadPacketError")]
+pub struct ConnectResponsePacket {
+    /// The challenge token, echoed unchanged.
+    pub token: ChallengeToken,
+    /// The encrypted part of the packet. Opening it proves the client holds the shared secret.
+    pub sealed_payload: Sealed<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use dungeon_vr_cryptography::PrivateKey;
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use crate::challenge_token::{ChallengeTokenContents, ChallengeTokenKey};
+    use crate::packet::Packet;
+    use crate::sealed::{Role, Sealed};
+
+    use super::ConnectResponsePacket;
+
+    #[test]
+    fn round_trip() {
+        let client_private_key = PrivateKey::gen();
+        let server_private_key = PrivateKey::gen();
+        let shared_secret = client_private_key
+            .exchange(&server_private_key.to_public())
+            .unwrap();
+        let token = ChallengeTokenKey::gen().issue(
+            &ChallengeTokenContents {
+                server_private_key,
+                client_public_key: client_private_key.to_public(),
+                protocol_version: Some(1),
+            },
+            "client",
+            Duration::ZERO,
+        );
+
+        let mut w = Vec::new();
+        Packet::ConnectResponse(ConnectResponsePacket {
+            token,
+            sealed_payload: Sealed::seal((), Role::Client, 0, &shared_secret),
+        })
+        .write_to(&mut w)
+        .unwrap();
+
+        let mut r = &w[..];
+        let packet = Packet::read_from(&mut r).unwrap();
+        assert!(r.is_empty());
+        let packet = match packet {
+            Packet::ConnectResponse(packet) => packet,
+            _ => unreachable!(),
+        };
+        assert_eq!(packet.token, token);
+        let () = packet
+            .sealed_payload
+            .open(Role::Client, &shared_secret)
+            .unwrap();
+    }
+}
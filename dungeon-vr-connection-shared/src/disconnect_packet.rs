@@ -0,0 +1,115 @@
+use std::convert::Infallible;
+use std::io::Write;
+
+use dungeon_vr_stream_codec::StreamCodec;
+
+use crate::packet::ReadPacketError;
+
+/// The sealed contents of a Disconnect packet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DisconnectPacket {
+    /// A human-readable explanation for the peer to display, if any. Encoded as the rest of the
+    /// payload, with an empty payload meaning no reason.
+    pub reason: Option<String>,
+}
+
+impl DisconnectPacket {
+    /// The longest reason that will be sent, in bytes. This keeps the packet well under any
+    /// reasonable MTU.
+    pub const MAX_REASON_SIZE: usize = 256;
+
+    /// Creates a packet, truncating `reason` to at most [`Self::MAX_REASON_SIZE`] bytes on a
+    /// character boundary.
+    pub fn new(reason: Option<String>) -> Self {
+        Self {
+            reason: reason
+                .map(|mut reason| {
+                    let mut len = reason.len().min(Self::MAX_REASON_SIZE);
+                    while !reason.is_char_boundary(len) {
+                        len -= 1;
+                    }
+                    reason.truncate(len);
+                    reason
+                })
+                .filter(|reason| !reason.is_empty()),
+        }
+    }
+}
+
+impl StreamCodec for DisconnectPacket {
+    type ReadError = ReadPacketError;
+    type WriteError = Infallible;
+
+    fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
+        let reason = std::str::from_utf8(r)
+            .map_err(|_| ReadPacketError::InvalidDisconnectReason)?
+            .to_string();
+        *r = &[];
+        Ok(Self {
+            reason: (!reason.is_empty()).then_some(reason),
+        })
+    }
+
+    fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+        if let Some(reason) = &self.reason {
+            w.write_all(reason.as_bytes()).unwrap();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dungeon_vr_cryptography::SharedSecret;
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use crate::packet::{Packet, ReadPacketError};
+    use crate::sealed::{Role, Sealed};
+
+    use super::DisconnectPacket;
+
+    fn round_trip(packet: DisconnectPacket) -> DisconnectPacket {
+        let shared_secret = SharedSecret::gen();
+
+        let mut w = Vec::new();
+        Packet::Disconnect(Sealed::seal(packet, Role::Server, 0, &shared_secret))
+            .write_to(&mut w)
+            .unwrap();
+
+        let mut r = &w[..];
+        let packet = Packet::read_from(&mut r).unwrap();
+        assert!(r.is_empty());
+        match packet {
+            Packet::Disconnect(sealed) => sealed.open(Role::Server, &shared_secret).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn round_trip_with_and_without_reason() {
+        let packet = DisconnectPacket::new(Some("Server shutting down".to_string()));
+        assert_eq!(round_trip(packet.clone()), packet);
+
+        let packet = DisconnectPacket::new(None);
+        assert_eq!(round_trip(packet.clone()), packet);
+    }
+
+    #[test]
+    fn new_truncates_on_char_boundary() {
+        // Each 'é' is two bytes, so the limit falls in the middle of one.
+        let packet = DisconnectPacket::new(Some(format!("a{}", "é".repeat(200))));
+        let reason = packet.reason.unwrap();
+        assert_eq!(reason.len(), DisconnectPacket::MAX_REASON_SIZE - 1);
+        assert!(reason.ends_with('é'));
+
+        assert_eq!(DisconnectPacket::new(Some(String::new())).reason, None);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert!(matches!(
+            DisconnectPacket::read_from(&mut &[0xff][..]),
+            Err(ReadPacketError::InvalidDisconnectReason),
+        ));
+    }
+}
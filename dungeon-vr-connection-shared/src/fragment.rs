@@ -0,0 +1,323 @@
+//! Splitting oversized game data across several packets.
+//!
+//! A sealed GameData packet that would exceed the MTU is instead sent as a series of Fragment
+//! packets, each individually sealed and carrying a slice of the GameData plaintext. The receiver
+//! collects the slices by message ID and decodes the GameData packet once all have arrived.
+//! Incomplete messages are discarded after a timeout. Fragments are never retransmitted
+//! individually; a reliable message that loses a fragment is resent whole under a new message ID.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use dungeon_vr_stream_codec::{StreamCodec, UnframedByteVec};
+
+use crate::packet::{Packet, ReadPacketError};
+use crate::reliable::GameDataPacket;
+use crate::sealed::{SealingKey, SEALED_OVERHEAD};
+use crate::SAFE_RECV_BUFFER_SIZE;
+
+/// The size of the packet kind and fragment header.
+const FRAGMENT_HEADER_SIZE: usize = 1 + 4 + 1 + 1;
+
+/// The largest number of fragments a message may be split into.
+pub const MAX_FRAGMENT_COUNT: usize = u8::MAX as usize;
+
+/// How many incomplete messages to hold at once. Starting another evicts the oldest.
+const MAX_PENDING_MESSAGES: usize = 16;
+
+/// The sealed contents of a Fragment packet.
+#[derive(Clone, Debug, PartialEq, Eq, StreamCodec)]
+#[stream_codec(read_error = "ReadPacketError")]
+pub struct FragmentPacket {
+    pub message_id: u32,
+    pub index: u8,
+    pub count: u8,
+    #[stream_codec(with = "UnframedByteVec")]
+    pub data: Vec<u8>,
+}
+
+/// The smallest MTU that leaves room for at least one byte of fragment data.
+pub const fn min_mtu() -> usize {
+    FRAGMENT_HEADER_SIZE + SEALED_OVERHEAD + 1
+}
+
+pub struct Fragmenter {
+    mtu: usize,
+    next_message_id: u32,
+}
+
+impl Fragmenter {
+    /// Creates a fragmenter that keeps every packet at or under `mtu` bytes of UDP payload.
+    pub fn new(mtu: usize) -> Self {
+        assert!(
+            (min_mtu()..=SAFE_RECV_BUFFER_SIZE).contains(&mtu),
+            "MTU {mtu} is outside the supported range {}..={SAFE_RECV_BUFFER_SIZE}",
+            min_mtu(),
+        );
+        Self {
+            mtu,
+            next_message_id: 0,
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Seals a GameData packet, splitting it into Fragment packets if it would not fit in one
+    /// MTU. Returns `None` if the message would need more than [`MAX_FRAGMENT_COUNT`] fragments.
+    pub fn seal(&mut self, packet: GameDataPacket, key: &mut SealingKey) -> Option<Vec<Packet>> {
+        let mut plaintext = Vec::new();
+        packet.write_to(&mut plaintext).unwrap();
+        if 1 + SEALED_OVERHEAD + plaintext.len() <= self.mtu {
+            return Some(vec![Packet::GameData(
+                key.seal_ext::<UnframedByteVec>(plaintext).cast(),
+            )]);
+        }
+
+        let fragment_size = self.mtu - FRAGMENT_HEADER_SIZE - SEALED_OVERHEAD;
+        let count = (plaintext.len() - 1) / fragment_size + 1;
+        if count > MAX_FRAGMENT_COUNT {
+            return None;
+        }
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        Some(
+            plaintext
+                .chunks(fragment_size)
+                .enumerate()
+                .map(|(index, data)| {
+                    Packet::Fragment(key.seal(FragmentPacket {
+                        message_id,
+                        index: index as u8,
+                        count: count as u8,
+                        data: data.to_vec(),
+                    }))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Collects fragments into complete GameData packets.
+pub struct Reassembler {
+    timeout: Duration,
+    pending: HashMap<u32, PendingMessage>,
+}
+
+struct PendingMessage {
+    started: Instant,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Records a fragment. Returns the decoded GameData packet if this fragment completed it.
+    pub fn insert(
+        &mut self,
+        fragment: FragmentPacket,
+        now: Instant,
+    ) -> Result<Option<GameDataPacket>, ReadPacketError> {
+        let timeout = self.timeout;
+        self.pending
+            .retain(|_, message| now.duration_since(message.started) < timeout);
+
+        if fragment.index >= fragment.count {
+            return Err(ReadPacketError::FragmentOutOfRange {
+                index: fragment.index,
+                count: fragment.count,
+            });
+        }
+        if !self.pending.contains_key(&fragment.message_id)
+            && self.pending.len() >= MAX_PENDING_MESSAGES
+        {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, message)| message.started)
+                .map(|(&message_id, _)| message_id)
+                .unwrap();
+            self.pending.remove(&oldest);
+        }
+        let message = self
+            .pending
+            .entry(fragment.message_id)
+            .or_insert_with(|| PendingMessage {
+                started: now,
+                fragments: vec![None; fragment.count as usize],
+                missing: fragment.count as usize,
+            });
+        if message.fragments.len() != fragment.count as usize {
+            return Err(ReadPacketError::FragmentCountMismatch {
+                count: fragment.count,
+                expected: message.fragments.len() as u8,
+            });
+        }
+
+        let slot = &mut message.fragments[fragment.index as usize];
+        if slot.is_some() {
+            // Duplicate.
+            return Ok(None);
+        }
+        *slot = Some(fragment.data);
+        message.missing -= 1;
+        if message.missing > 0 {
+            return Ok(None);
+        }
+
+        let message = self.pending.remove(&fragment.message_id).unwrap();
+        let plaintext: Vec<u8> = message.fragments.into_iter().flatten().flatten().collect();
+        let mut r = plaintext.as_slice();
+        let packet = GameDataPacket::read_from(&mut r)?;
+        if !r.is_empty() {
+            return Err(ReadPacketError::TrailingData);
+        }
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use dungeon_vr_cryptography::SharedSecret;
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use crate::packet::{Packet, ReadPacketError};
+    use crate::reliable::GameDataPacket;
+    use crate::sealed::{Role, SealingKey};
+
+    use super::{min_mtu, FragmentPacket, Fragmenter, Reassembler, MAX_FRAGMENT_COUNT};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn open_fragments(packets: Vec<Packet>, shared_secret: &SharedSecret) -> Vec<FragmentPacket> {
+        packets
+            .into_iter()
+            .map(|packet| match packet {
+                Packet::Fragment(sealed) => sealed.open(Role::Client, shared_secret).unwrap(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_messages_are_not_fragmented() {
+        let shared_secret = SharedSecret::gen();
+        let mut fragmenter = Fragmenter::new(1200);
+        let packet = GameDataPacket::Unreliable(vec![7; 1000]);
+
+        let packets = fragmenter
+            .seal(
+                packet.clone(),
+                &mut SealingKey::new(shared_secret, Role::Client),
+            )
+            .unwrap();
+        assert_eq!(packets.len(), 1);
+        match &packets[0] {
+            Packet::GameData(sealed) => {
+                assert_eq!(sealed.open(Role::Client, &shared_secret).unwrap(), packet)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fragments_fit_in_mtu_and_reassemble_in_any_order() {
+        let shared_secret = SharedSecret::gen();
+        let mut fragmenter = Fragmenter::new(1200);
+        let packet = GameDataPacket::ReliableOrdered {
+            sequence: 3,
+            data: (0..5000).map(|i| i as u8).collect(),
+        };
+
+        let packets = fragmenter
+            .seal(
+                packet.clone(),
+                &mut SealingKey::new(shared_secret, Role::Client),
+            )
+            .unwrap();
+        assert_eq!(packets.len(), 5);
+        for packet in &packets {
+            let mut w = Vec::new();
+            packet.write_to(&mut w).unwrap();
+            assert!(w.len() <= 1200);
+        }
+
+        let mut reassembler = Reassembler::new(TIMEOUT);
+        let now = Instant::now();
+        let mut fragments = open_fragments(packets, &shared_secret);
+        let last = fragments.remove(2);
+        for fragment in fragments.into_iter().rev() {
+            assert_eq!(reassembler.insert(fragment.clone(), now).unwrap(), None);
+            // Duplicates are ignored.
+            assert_eq!(reassembler.insert(fragment, now).unwrap(), None);
+        }
+        assert_eq!(reassembler.insert(last, now).unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn incomplete_messages_time_out() {
+        let shared_secret = SharedSecret::gen();
+        let mut fragmenter = Fragmenter::new(min_mtu());
+        let packets = fragmenter
+            .seal(
+                GameDataPacket::Unreliable(vec![0; 15]),
+                &mut SealingKey::new(shared_secret, Role::Client),
+            )
+            .unwrap();
+        let mut fragments = open_fragments(packets, &shared_secret);
+        assert_eq!(fragments.len(), 16);
+
+        let mut reassembler = Reassembler::new(TIMEOUT);
+        let start = Instant::now();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments {
+            assert_eq!(reassembler.insert(fragment, start).unwrap(), None);
+        }
+        assert_eq!(reassembler.insert(last, start + TIMEOUT).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_inconsistent_fragments() {
+        let mut reassembler = Reassembler::new(TIMEOUT);
+        let now = Instant::now();
+        let fragment = |index, count| FragmentPacket {
+            message_id: 0,
+            index,
+            count,
+            data: vec![0],
+        };
+
+        assert!(matches!(
+            reassembler.insert(fragment(2, 2), now),
+            Err(ReadPacketError::FragmentOutOfRange { index: 2, count: 2 }),
+        ));
+        assert_eq!(reassembler.insert(fragment(0, 2), now).unwrap(), None);
+        assert!(matches!(
+            reassembler.insert(fragment(1, 3), now),
+            Err(ReadPacketError::FragmentCountMismatch {
+                count: 3,
+                expected: 2,
+            }),
+        ));
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let mut fragmenter = Fragmenter::new(min_mtu());
+        assert!(fragmenter
+            .seal(
+                GameDataPacket::Unreliable(vec![0; MAX_FRAGMENT_COUNT]),
+                &mut SealingKey::new(SharedSecret::gen(), Role::Client),
+            )
+            .is_none());
+    }
+}
@@ -0,0 +1,48 @@
+use dungeon_vr_stream_codec::StreamCodec;
+
+use crate::packet::ReadPacketError;
+
+/// The sealed contents of a Keepalive packet. Besides keeping the connection alive, keepalives
+/// measure round-trip time: each peer sends a Ping every keepalive interval and answers each Ping
+/// it receives with a Pong carrying the same ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, StreamCodec)]
+#[stream_codec(
+    read_error = "ReadPacketError",
+    invalid_tag = "ReadPacketError::InvalidKeepalive"
+)]
+pub enum KeepalivePacket {
+    Ping { id: u32 },
+    Pong { id: u32 },
+}
+
+#[cfg(test)]
+mod tests {
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use crate::packet::ReadPacketError;
+
+    use super::KeepalivePacket;
+
+    #[test]
+    fn round_trip() {
+        for packet in [
+            KeepalivePacket::Ping { id: 0x01020304 },
+            KeepalivePacket::Pong { id: 5 },
+        ] {
+            let mut w = Vec::new();
+            packet.write_to(&mut w).unwrap();
+            assert_eq!(w.len(), 5);
+            let mut r = &w[..];
+            assert_eq!(KeepalivePacket::read_from(&mut r).unwrap(), packet);
+            assert!(r.is_empty());
+        }
+    }
+
+    #[test]
+    fn invalid_tag() {
+        assert!(matches!(
+            KeepalivePacket::read_from(&mut &[0x02, 0, 0, 0, 0][..]),
+            Err(ReadPacketError::InvalidKeepalive(0x02)),
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+//! Logging session secrets so that captured traffic can be decrypted later.
+//!
+//! Like `SSLKEYLOGFILE` for TLS, setting the [`KEY_LOG_ENV_VAR`] environment variable makes
+//! connection clients and servers append the shared secret of every session they establish to the
+//! named file. Each line reads `CONNECTION_SECRET <connection ID> <shared secret>`, both in hex.
+//! Anyone holding the file can read and forge the logged sessions' traffic, so only set it while
+//! debugging.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+use dungeon_vr_cryptography::SharedSecret;
+use thiserror::Error;
+
+use crate::sealed::ConnectionId;
+
+pub const KEY_LOG_ENV_VAR: &str = "DUNGEON_VR_KEYLOGFILE";
+
+const LABEL: &str = "CONNECTION_SECRET";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ParseKeyLogError {
+    #[error("line {line}: expected `{LABEL} <connection ID> <shared secret>`")]
+    Malformed { line: usize },
+
+    #[error("line {line}: connection ID does not match the shared secret")]
+    ConnectionIdMismatch { line: usize },
+}
+
+/// Appends `shared_secret` to the key log, if the environment names one.
+pub fn log_shared_secret(shared_secret: &SharedSecret) -> io::Result<()> {
+    let path = match env::var_os(KEY_LOG_ENV_VAR) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(format_line(shared_secret).as_bytes())
+}
+
+fn format_line(shared_secret: &SharedSecret) -> String {
+    let mut line = format!("{LABEL} {:016x} ", ConnectionId::of(shared_secret).0);
+    for byte in shared_secret.to_bytes() {
+        write!(line, "{byte:02x}").unwrap();
+    }
+    line.push('\n');
+    line
+}
+
+/// Parses a hex-encoded shared secret, as found in a key log.
+pub fn parse_shared_secret(s: &str) -> Option<SharedSecret> {
+    if s.len() != 2 * SharedSecret::SIZE || !s.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; SharedSecret::SIZE];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(SharedSecret::from_bytes(bytes))
+}
+
+/// Parses the contents of a key log. Blank lines and lines starting with `#` are skipped.
+pub fn parse_key_log(text: &str) -> Result<Vec<SharedSecret>, ParseKeyLogError> {
+    let mut shared_secrets = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [LABEL, connection_id, shared_secret] => u64::from_str_radix(connection_id, 16)
+                .ok()
+                .zip(parse_shared_secret(shared_secret)),
+            _ => None,
+        };
+        let (connection_id, shared_secret) =
+            parsed.ok_or(ParseKeyLogError::Malformed { line: line_number })?;
+        if ConnectionId::of(&shared_secret) != ConnectionId(connection_id) {
+            return Err(ParseKeyLogError::ConnectionIdMismatch { line: line_number });
+        }
+        shared_secrets.push(shared_secret);
+    }
+    Ok(shared_secrets)
+}
+
+#[cfg(test)]
+mod tests {
+    use dungeon_vr_cryptography::SharedSecret;
+
+    use super::{format_line, parse_key_log, ParseKeyLogError};
+
+    #[test]
+    fn round_trip() {
+        let shared_secrets = [SharedSecret::gen(), SharedSecret::gen()];
+        let text = format!(
+            "# A comment\n{}\n{}",
+            format_line(&shared_secrets[0]),
+            format_line(&shared_secrets[1]),
+        );
+        assert_eq!(parse_key_log(&text), Ok(shared_secrets.to_vec()));
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(
+            parse_key_log("CONNECTION_SECRET 0123"),
+            Err(ParseKeyLogError::Malformed { line: 1 }),
+        );
+
+        let line = format_line(&SharedSecret::gen());
+        let shared_secret = line.split_whitespace().last().unwrap();
+        assert_eq!(
+            parse_key_log(&format!(
+                "\nCONNECTION_SECRET 0000000000000000 {shared_secret}"
+            )),
+            Err(ParseKeyLogError::ConnectionIdMismatch { line: 2 }),
+        );
+    }
+}
@@ -1,9 +1,22 @@
 pub mod challenge_token;
+pub mod config;
 pub mod connect_challenge_packet;
 pub mod connect_init_packet;
+pub mod connect_response_packet;
+pub mod disconnect_packet;
+pub mod fragment;
+pub mod keepalive_packet;
+pub mod keylog;
 pub mod packet;
+pub mod protocol_version;
+pub mod reject_reason;
+pub mod reliable;
+pub mod replay;
 pub mod sealed;
+pub mod stats;
 
 /// A buffer size large enough for any UDP payload carried over IPv4 or IPv6.
 pub const SAFE_RECV_BUFFER_SIZE: usize = 65527;
+/// A conservative UDP payload size that avoids IP fragmentation on nearly every path.
+pub const DEFAULT_MTU: usize = 1200;
 pub const GAME_ID: u64 = 0xd54747a389d9991f;
@@ -1,12 +1,17 @@
 use std::convert::Infallible;
 
 use dungeon_vr_cryptography::DecryptError;
-use dungeon_vr_stream_codec::{ReadError, StreamCodec};
+use dungeon_vr_stream_codec::{ReadBoolError, ReadError, StreamCodec};
 use thiserror::Error;
 
-use crate::challenge_token::ChallengeToken;
 use crate::connect_challenge_packet::ConnectChallengePacket;
 use crate::connect_init_packet::ConnectInitPacket;
+use crate::connect_response_packet::ConnectResponsePacket;
+use crate::disconnect_packet::DisconnectPacket;
+use crate::fragment::FragmentPacket;
+use crate::keepalive_packet::KeepalivePacket;
+use crate::reject_reason::RejectReason;
+use crate::reliable::{AckPacket, GameDataPacket};
 use crate::sealed::Sealed;
 
 #[derive(Debug, Error)]
@@ -14,12 +19,42 @@ pub enum ReadPacketError {
     #[error("{0}")]
     ReadError(#[from] ReadError),
 
+    #[error("{0}")]
+    ReadBoolError(#[from] ReadBoolError),
+
     #[error("{0}")]
     DecryptError(#[from] DecryptError),
 
     #[error("invalid packet type encoding: 0x{0:02x}")]
     InvalidPacketType(u8),
 
+    #[error("invalid channel encoding: 0x{0:02x}")]
+    InvalidChannel(u8),
+
+    #[error("invalid keepalive encoding: 0x{0:02x}")]
+    InvalidKeepalive(u8),
+
+    #[error("invalid reject reason encoding: 0x{0:02x}")]
+    InvalidRejectReason(u8),
+
+    #[error("invalid UTF-8 in disconnect reason")]
+    InvalidDisconnectReason,
+
+    #[error("fragment index {index} out of range for {count} fragment(s)")]
+    FragmentOutOfRange { index: u8, count: u8 },
+
+    #[error("fragment count {count} does not match earlier fragments' count {expected}")]
+    FragmentCountMismatch { count: u8, expected: u8 },
+
+    #[error("replayed sequence number {0}")]
+    ReplayedSequence(u64),
+
+    #[error("sequence number {0} is too old")]
+    StaleSequence(u64),
+
+    #[error("challenge token expired")]
+    ExpiredChallengeToken,
+
     #[error("unexpected trailing data")]
     TrailingData,
 }
@@ -30,7 +65,11 @@ impl From<Infallible> for ReadPacketError {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, StreamCodec)]
+#[stream_codec(
+    read_error = "ReadPacketError",
+    invalid_tag = "ReadPacketError::InvalidPacketType"
+)]
 #[repr(u8)]
 pub enum PacketKind {
     Disconnect,
@@ -39,44 +78,21 @@ pub enum PacketKind {
     ConnectResponse,
     Keepalive,
     GameData,
-}
-
-impl StreamCodec for PacketKind {
-    type ReadError = ReadPacketError;
-    type WriteError = Infallible;
-
-    fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
-        u8::read_from(r)?.try_into()
-    }
-
-    fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
-        (*self as u8).write_to(w)
-    }
-}
-
-impl TryFrom<u8> for PacketKind {
-    type Error = ReadPacketError;
-
-    fn try_from(value: u8) -> Result<Self, ReadPacketError> {
-        match value {
-            x if x == Self::Disconnect as u8 => Ok(Self::Disconnect),
-            x if x == Self::ConnectInit as u8 => Ok(Self::ConnectInit),
-            x if x == Self::ConnectChallenge as u8 => Ok(Self::ConnectChallenge),
-            x if x == Self::ConnectResponse as u8 => Ok(Self::ConnectResponse),
-            x if x == Self::Keepalive as u8 => Ok(Self::Keepalive),
-            x if x == Self::GameData as u8 => Ok(Self::GameData),
-            x => Err(ReadPacketError::InvalidPacketType(x)),
-        }
-    }
+    Ack,
+    Fragment,
+    Reject,
 }
 
 pub enum Packet {
-    Disconnect(Sealed<()>),
+    Disconnect(Sealed<DisconnectPacket>),
     ConnectInit(ConnectInitPacket),
     ConnectChallenge(ConnectChallengePacket),
-    ConnectResponse(Sealed<ChallengeToken>),
-    Keepalive(Sealed<()>),
-    GameData(Sealed<Vec<u8>>),
+    ConnectResponse(ConnectResponsePacket),
+    Keepalive(Sealed<KeepalivePacket>),
+    GameData(Sealed<GameDataPacket>),
+    Ack(Sealed<AckPacket>),
+    Fragment(Sealed<FragmentPacket>),
+    Reject(Sealed<RejectReason>),
 }
 
 impl Packet {
@@ -88,6 +104,9 @@ impl Packet {
             Self::ConnectResponse(_) => PacketKind::ConnectResponse,
             Self::Keepalive(_) => PacketKind::Keepalive,
             Self::GameData(_) => PacketKind::GameData,
+            Self::Ack(_) => PacketKind::Ack,
+            Self::Fragment(_) => PacketKind::Fragment,
+            Self::Reject(_) => PacketKind::Reject,
         }
     }
 }
@@ -103,9 +122,14 @@ impl StreamCodec for Packet {
             PacketKind::ConnectChallenge => Ok(Self::ConnectChallenge(
                 ConnectChallengePacket::read_from(r)?,
             )),
-            PacketKind::ConnectResponse => Ok(Self::ConnectResponse(Sealed::read_from(r)?)),
+            PacketKind::ConnectResponse => {
+                Ok(Self::ConnectResponse(ConnectResponsePacket::read_from(r)?))
+            }
             PacketKind::Keepalive => Ok(Self::Keepalive(Sealed::read_from(r)?)),
             PacketKind::GameData => Ok(Self::GameData(Sealed::read_from(r)?)),
+            PacketKind::Ack => Ok(Self::Ack(Sealed::read_from(r)?)),
+            PacketKind::Fragment => Ok(Self::Fragment(Sealed::read_from(r)?)),
+            PacketKind::Reject => Ok(Self::Reject(Sealed::read_from(r)?)),
         }
     }
 
@@ -118,6 +142,9 @@ impl StreamCodec for Packet {
             Self::ConnectResponse(packet) => packet.write_to(w),
             Self::Keepalive(packet) => packet.write_to(w),
             Self::GameData(packet) => packet.write_to(w),
+            Self::Ack(packet) => packet.write_to(w),
+            Self::Fragment(packet) => packet.write_to(w),
+            Self::Reject(packet) => packet.write_to(w),
         }
     }
 }
@@ -127,28 +154,36 @@ mod tests {
     use dungeon_vr_cryptography::SharedSecret;
     use dungeon_vr_stream_codec::StreamCodec;
 
-    use crate::challenge_token::ChallengeToken;
-    use crate::sealed::Sealed;
+    use crate::keepalive_packet::KeepalivePacket;
+    use crate::sealed::{Role, Sealed};
 
     use super::Packet;
 
     #[test]
     fn round_trip() {
         let shared_secret = SharedSecret::gen();
-        let token = ChallengeToken::gen();
 
         let mut w = Vec::new();
-        Packet::ConnectResponse(Sealed::seal(token, &shared_secret))
-            .write_to(&mut w)
-            .unwrap();
+        Packet::Keepalive(Sealed::seal(
+            KeepalivePacket::Ping { id: 3 },
+            Role::Server,
+            7,
+            &shared_secret,
+        ))
+        .write_to(&mut w)
+        .unwrap();
 
         let mut r = &w[..];
         let packet = Packet::read_from(&mut r).unwrap();
         assert!(r.is_empty());
-        let roundtrip_token = match packet {
-            Packet::ConnectResponse(sealed) => sealed.open(&shared_secret).unwrap(),
+        let sealed = match packet {
+            Packet::Keepalive(sealed) => sealed,
             _ => unreachable!(),
         };
-        assert_eq!(roundtrip_token, token);
+        assert_eq!(sealed.sequence(), 7);
+        assert_eq!(
+            sealed.open(Role::Server, &shared_secret).unwrap(),
+            KeepalivePacket::Ping { id: 3 },
+        );
     }
 }
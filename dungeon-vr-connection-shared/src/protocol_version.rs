@@ -0,0 +1,76 @@
+use dungeon_vr_stream_codec::StreamCodec;
+
+use crate::packet::ReadPacketError;
+
+/// The newest protocol version this build speaks. Bump it whenever the encoding of anything sent
+/// after the handshake changes.
+pub const PROTOCOL_VERSION: u16 = 4;
+/// The oldest protocol version this build still speaks. Version 1 keepalives carried no payload,
+/// version 2 sessions began without a Hello, and version 3 sealed packets carried no connection
+/// ID.
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+
+/// A hash identifying the content (assets, level data, and so on) a peer was built with. Peers
+/// that configure one only talk to peers with the same hash.
+pub type ContentHash = [u8; 32];
+
+/// An inclusive range of protocol versions a peer speaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, StreamCodec)]
+#[stream_codec(read_error = "ReadPacketError")]
+pub struct ProtocolVersions {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl ProtocolVersions {
+    /// The versions this build speaks.
+    pub const SUPPORTED: Self = Self {
+        min: MIN_PROTOCOL_VERSION,
+        max: PROTOCOL_VERSION,
+    };
+
+    /// Returns the newest version both ranges include, if any. Both peers reach the same answer,
+    /// so the result needs no further agreement.
+    pub fn negotiate(self, other: Self) -> Option<u16> {
+        let min = self.min.max(other.min);
+        let max = self.max.min(other.max);
+        if min <= max {
+            Some(max)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use super::ProtocolVersions;
+
+    #[test]
+    fn round_trip() {
+        let versions = ProtocolVersions {
+            min: 3,
+            max: 0x1234,
+        };
+
+        let mut w = Vec::new();
+        versions.write_to(&mut w).unwrap();
+        assert_eq!(w, [0x00, 0x03, 0x12, 0x34]);
+        let mut r = &w[..];
+        assert_eq!(ProtocolVersions::read_from(&mut r).unwrap(), versions);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn negotiate() {
+        let versions = |min, max| ProtocolVersions { min, max };
+
+        assert_eq!(versions(1, 3).negotiate(versions(1, 3)), Some(3));
+        assert_eq!(versions(1, 3).negotiate(versions(2, 5)), Some(3));
+        assert_eq!(versions(2, 5).negotiate(versions(1, 3)), Some(3));
+        assert_eq!(versions(1, 5).negotiate(versions(2, 2)), Some(2));
+        assert_eq!(versions(1, 2).negotiate(versions(3, 4)), None);
+    }
+}
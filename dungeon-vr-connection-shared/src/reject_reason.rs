@@ -0,0 +1,71 @@
+use std::fmt::{self, Display, Formatter};
+
+use dungeon_vr_stream_codec::StreamCodec;
+
+use crate::packet::ReadPacketError;
+
+/// Why a server turned away a connection. Carried by a sealed Reject packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, StreamCodec)]
+#[stream_codec(
+    read_error = "ReadPacketError",
+    invalid_tag = "ReadPacketError::InvalidRejectReason"
+)]
+#[repr(u8)]
+pub enum RejectReason {
+    ServerFull,
+    VersionMismatch,
+    Banned,
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::ServerFull => "server is full",
+            Self::VersionMismatch => "version mismatch",
+            Self::Banned => "banned",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dungeon_vr_cryptography::SharedSecret;
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use crate::packet::{Packet, ReadPacketError};
+    use crate::sealed::{Role, Sealed};
+
+    use super::RejectReason;
+
+    #[test]
+    fn round_trip() {
+        let shared_secret = SharedSecret::gen();
+
+        let mut w = Vec::new();
+        Packet::Reject(Sealed::seal(
+            RejectReason::Banned,
+            Role::Server,
+            0,
+            &shared_secret,
+        ))
+        .write_to(&mut w)
+        .unwrap();
+
+        let mut r = &w[..];
+        let packet = Packet::read_from(&mut r).unwrap();
+        assert!(r.is_empty());
+        let reason = match packet {
+            Packet::Reject(sealed) => sealed.open(Role::Server, &shared_secret).unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(reason, RejectReason::Banned);
+    }
+
+    #[test]
+    fn invalid_reason() {
+        assert!(matches!(
+            RejectReason::read_from(&mut &[0xff][..]),
+            Err(ReadPacketError::InvalidRejectReason(0xff)),
+        ));
+    }
+}
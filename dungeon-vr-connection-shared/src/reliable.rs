@@ -0,0 +1,550 @@
+//! Per-connection delivery guarantees for game data.
+//!
+//! Every GameData packet travels on one of three channels. Unreliable messages are delivered at
+//! most once, in whatever order they arrive. Reliable messages carry a per-channel sequence number
+//! and are retransmitted, backing off exponentially, until the peer acknowledges them with an Ack
+//! packet. The receiver discards duplicates and, on the ordered channel, holds back messages until
+//! every earlier message on that channel has been delivered.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use dungeon_vr_stream_codec::{StreamCodec, UnframedByteVec};
+use thiserror::Error;
+
+use crate::packet::ReadPacketError;
+
+/// How far past the oldest missing message the receiver will accept reliable messages. Anything
+/// further ahead would be dropped, so the sender holds it back until earlier messages are
+/// acknowledged.
+pub const RECEIVE_WINDOW: u32 = 1024;
+
+/// How many unacknowledged reliable messages one channel may hold, including those held back by
+/// the receive window. A peer that falls this far behind is not keeping up and should be dropped.
+pub const SEND_QUEUE_CAPACITY: usize = 4 * RECEIVE_WINDOW as usize;
+
+/// The most resend intervals a message waits between retransmissions.
+const MAX_RESEND_BACKOFF: u32 = 16;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("too many unacknowledged messages on channel {0:?}")]
+pub struct SendQueueFull(pub Channel);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, StreamCodec)]
+#[stream_codec(
+    read_error = "ReadPacketError",
+    invalid_tag = "ReadPacketError::InvalidChannel"
+)]
+#[repr(u8)]
+pub enum Channel {
+    /// Fire and forget. May be lost, duplicated, or reordered.
+    Unreliable,
+    /// Retransmitted until acknowledged. Delivered exactly once, in arrival order.
+    ReliableUnordered,
+    /// Retransmitted until acknowledged. Delivered exactly once, in send order.
+    ReliableOrdered,
+}
+
+/// The sealed contents of a GameData packet.
+#[derive(Clone, Debug, PartialEq, Eq, StreamCodec)]
+#[stream_codec(
+    read_error = "ReadPacketError",
+    invalid_tag = "ReadPacketError::InvalidChannel"
+)]
+pub enum GameDataPacket {
+    Unreliable(#[stream_codec(with = "UnframedByteVec")] Vec<u8>),
+    ReliableUnordered {
+        sequence: u32,
+        #[stream_codec(with = "UnframedByteVec")]
+        data: Vec<u8>,
+    },
+    ReliableOrdered {
+        sequence: u32,
+        #[stream_codec(with = "UnframedByteVec")]
+        data: Vec<u8>,
+    },
+}
+
+impl GameDataPacket {
+    pub fn channel(&self) -> Channel {
+        match self {
+            Self::Unreliable(_) => Channel::Unreliable,
+            Self::ReliableUnordered { .. } => Channel::ReliableUnordered,
+            Self::ReliableOrdered { .. } => Channel::ReliableOrdered,
+        }
+    }
+}
+
+/// The sealed contents of an Ack packet, acknowledging receipt of one reliable message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, StreamCodec)]
+#[stream_codec(read_error = "ReadPacketError")]
+pub struct AckPacket {
+    pub channel: Channel,
+    pub sequence: u32,
+}
+
+/// The result of handling an incoming GameData packet.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Received {
+    /// An ack to send back to the peer, if the packet was reliable.
+    pub ack: Option<AckPacket>,
+    /// Messages ready for delivery, in order.
+    pub messages: Vec<Vec<u8>>,
+}
+
+/// Sequence numbering, retransmission, and duplicate suppression for one connection.
+#[derive(Default)]
+pub struct ReliabilityState {
+    unordered_send: SendQueue,
+    ordered_send: SendQueue,
+    unordered_recv: ReceiveWindow,
+    ordered_recv: ReceiveWindow,
+    ordered_pending: BTreeMap<u32, Vec<u8>>,
+}
+
+#[derive(Default)]
+struct SendQueue {
+    next_sequence: u32,
+    unacked: BTreeMap<u32, UnackedMessage>,
+}
+
+struct UnackedMessage {
+    data: Vec<u8>,
+    /// Resend ticks left until the message is sent again. A message sent immediately starts at two,
+    /// so that it always waits at least one full resend interval before being retransmitted. One
+    /// held back by the receive window starts at one, so it goes out at the first tick it fits.
+    countdown: u32,
+    /// The countdown to restart from after the next send. Doubles with every send, up to
+    /// [`MAX_RESEND_BACKOFF`].
+    backoff: u32,
+}
+
+/// Tracks which sequence numbers have arrived on one reliable channel.
+#[derive(Default)]
+struct ReceiveWindow {
+    /// The lowest sequence number not yet received.
+    next: u32,
+    /// Sequence numbers above `next` that have already been received.
+    received: BTreeSet<u32>,
+}
+
+enum Admit {
+    New,
+    Duplicate,
+    OutOfWindow,
+}
+
+impl SendQueue {
+    /// Queues a message. Returns its sequence number and whether it fits in the receive window and
+    /// should be sent now.
+    fn push(&mut self, channel: Channel, data: &[u8]) -> Result<(u32, bool), SendQueueFull> {
+        if self.unacked.len() >= SEND_QUEUE_CAPACITY {
+            return Err(SendQueueFull(channel));
+        }
+        let sequence = self.next_sequence;
+        let in_window = sequence < self.window_end();
+        self.next_sequence += 1;
+        self.unacked.insert(
+            sequence,
+            UnackedMessage {
+                data: data.to_vec(),
+                countdown: if in_window { 2 } else { 1 },
+                backoff: 2,
+            },
+        );
+        Ok((sequence, in_window))
+    }
+
+    /// One past the highest sequence number the receiver will accept. Every message below the
+    /// oldest unacknowledged one has been received, so the receiver's window starts there or later.
+    fn window_end(&self) -> u32 {
+        let start = match self.unacked.keys().next() {
+            Some(&oldest) => oldest,
+            None => self.next_sequence,
+        };
+        start.saturating_add(RECEIVE_WINDOW)
+    }
+
+    /// Advances every message in the receive window by one resend tick, returning those that are
+    /// due to be sent.
+    fn due(&mut self) -> impl Iterator<Item = (u32, &[u8])> {
+        let window_end = self.window_end();
+        self.unacked
+            .range_mut(..window_end)
+            .filter_map(|(&sequence, message)| {
+                message
+                    .tick()
+                    .then_some((sequence, message.data.as_slice()))
+            })
+    }
+}
+
+impl UnackedMessage {
+    /// Counts down one resend tick. Returns whether the message should be sent now.
+    fn tick(&mut self) -> bool {
+        self.countdown -= 1;
+        if self.countdown > 0 {
+            return false;
+        }
+        self.countdown = self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_RESEND_BACKOFF);
+        true
+    }
+}
+
+impl ReceiveWindow {
+    fn admit(&mut self, sequence: u32) -> Admit {
+        if sequence < self.next || self.received.contains(&sequence) {
+            Admit::Duplicate
+        } else if sequence - self.next >= RECEIVE_WINDOW {
+            Admit::OutOfWindow
+        } else {
+            self.received.insert(sequence);
+            while self.received.remove(&self.next) {
+                self.next += 1;
+            }
+            Admit::New
+        }
+    }
+}
+
+impl ReliabilityState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wraps an outgoing message for the given channel. Reliable messages are retained until
+    /// acknowledged. Returns `None` for a reliable message that is beyond the peer's receive window;
+    /// it is sent from [`Self::retransmissions`] once earlier messages are acknowledged. Fails if
+    /// the channel already holds [`SEND_QUEUE_CAPACITY`] unacknowledged messages.
+    pub fn send(
+        &mut self,
+        channel: Channel,
+        data: Vec<u8>,
+    ) -> Result<Option<GameDataPacket>, SendQueueFull> {
+        Ok(match channel {
+            Channel::Unreliable => Some(GameDataPacket::Unreliable(data)),
+            Channel::ReliableUnordered => {
+                let (sequence, in_window) = self.unordered_send.push(channel, &data)?;
+                in_window.then_some(GameDataPacket::ReliableUnordered { sequence, data })
+            }
+            Channel::ReliableOrdered => {
+                let (sequence, in_window) = self.ordered_send.push(channel, &data)?;
+                in_window.then_some(GameDataPacket::ReliableOrdered { sequence, data })
+            }
+        })
+    }
+
+    /// Returns the reliable messages that should be sent now. Call this once per resend interval.
+    /// A message is retransmitted once it has gone a full interval without an ack, then after
+    /// exponentially longer waits. Messages that were held back go out once they fit in the
+    /// receive window.
+    pub fn retransmissions(&mut self) -> Vec<GameDataPacket> {
+        let unordered =
+            self.unordered_send
+                .due()
+                .map(|(sequence, data)| GameDataPacket::ReliableUnordered {
+                    sequence,
+                    data: data.to_vec(),
+                });
+        let ordered =
+            self.ordered_send
+                .due()
+                .map(|(sequence, data)| GameDataPacket::ReliableOrdered {
+                    sequence,
+                    data: data.to_vec(),
+                });
+        unordered.chain(ordered).collect()
+    }
+
+    /// The number of reliable messages still awaiting an ack.
+    pub fn unacked_len(&self) -> usize {
+        self.unordered_send.unacked.len() + self.ordered_send.unacked.len()
+    }
+
+    pub fn handle_ack(&mut self, ack: AckPacket) {
+        let queue = match ack.channel {
+            Channel::Unreliable => return,
+            Channel::ReliableUnordered => &mut self.unordered_send,
+            Channel::ReliableOrdered => &mut self.ordered_send,
+        };
+        queue.unacked.remove(&ack.sequence);
+    }
+
+    /// Handles an incoming GameData packet, returning the ack to send and any messages that are
+    /// now ready for delivery.
+    pub fn receive(&mut self, packet: GameDataPacket) -> Received {
+        let channel = packet.channel();
+        match packet {
+            GameDataPacket::Unreliable(data) => Received {
+                ack: None,
+                messages: vec![data],
+            },
+            GameDataPacket::ReliableUnordered { sequence, data } => {
+                match self.unordered_recv.admit(sequence) {
+                    Admit::New => Received {
+                        ack: Some(AckPacket { channel, sequence }),
+                        messages: vec![data],
+                    },
+                    // The previous ack may have been lost, so acknowledge duplicates again.
+                    Admit::Duplicate => Received {
+                        ack: Some(AckPacket { channel, sequence }),
+                        messages: Vec::new(),
+                    },
+                    Admit::OutOfWindow => Received::default(),
+                }
+            }
+            GameDataPacket::ReliableOrdered { sequence, data } => {
+                let prev_next = self.ordered_recv.next;
+                match self.ordered_recv.admit(sequence) {
+                    Admit::New => {
+                        // Everything between the old and new low-water marks can now be released.
+                        self.ordered_pending.insert(sequence, data);
+                        let messages = (prev_next..self.ordered_recv.next)
+                            .map(|sequence| self.ordered_pending.remove(&sequence).unwrap())
+                            .collect();
+                        Received {
+                            ack: Some(AckPacket { channel, sequence }),
+                            messages,
+                        }
+                    }
+                    Admit::Duplicate => Received {
+                        ack: Some(AckPacket { channel, sequence }),
+                        messages: Vec::new(),
+                    },
+                    Admit::OutOfWindow => Received::default(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dungeon_vr_cryptography::SharedSecret;
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use crate::packet::Packet;
+    use crate::sealed::{Role, Sealed};
+
+    use super::{
+        AckPacket, Channel, GameDataPacket, Received, ReliabilityState, SendQueueFull,
+        MAX_RESEND_BACKOFF, RECEIVE_WINDOW, SEND_QUEUE_CAPACITY,
+    };
+
+    #[test]
+    fn round_trip() {
+        let shared_secret = SharedSecret::gen();
+        let game_data = GameDataPacket::ReliableOrdered {
+            sequence: 0x01020304,
+            data: b"abcdef".to_vec(),
+        };
+
+        let mut w = Vec::new();
+        Packet::GameData(Sealed::seal(
+            game_data.clone(),
+            Role::Client,
+            0,
+            &shared_secret,
+        ))
+        .write_to(&mut w)
+        .unwrap();
+
+        let mut r = &w[..];
+        let packet = Packet::read_from(&mut r).unwrap();
+        assert!(r.is_empty());
+        let roundtrip_game_data = match packet {
+            Packet::GameData(sealed) => sealed.open(Role::Client, &shared_secret).unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(roundtrip_game_data, game_data);
+    }
+
+    #[test]
+    fn unreliable_is_delivered_without_ack() {
+        let mut sender = ReliabilityState::new();
+        let mut receiver = ReliabilityState::new();
+
+        let packet = sender
+            .send(Channel::Unreliable, b"a".to_vec())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            receiver.receive(packet.clone()),
+            Received {
+                ack: None,
+                messages: vec![b"a".to_vec()],
+            },
+        );
+        // Unreliable messages are not deduplicated.
+        assert_eq!(receiver.receive(packet).messages, vec![b"a".to_vec()]);
+        assert_eq!(sender.unacked_len(), 0);
+    }
+
+    #[test]
+    fn reliable_unordered_suppresses_duplicates() {
+        let mut sender = ReliabilityState::new();
+        let mut receiver = ReliabilityState::new();
+
+        let a = sender
+            .send(Channel::ReliableUnordered, b"a".to_vec())
+            .unwrap()
+            .unwrap();
+        let b = sender
+            .send(Channel::ReliableUnordered, b"b".to_vec())
+            .unwrap()
+            .unwrap();
+        assert_eq!(receiver.receive(b.clone()).messages, vec![b"b".to_vec()]);
+        assert_eq!(receiver.receive(a).messages, vec![b"a".to_vec()]);
+
+        let received = receiver.receive(b);
+        assert!(received.messages.is_empty());
+        assert_eq!(
+            received.ack,
+            Some(AckPacket {
+                channel: Channel::ReliableUnordered,
+                sequence: 1,
+            }),
+        );
+    }
+
+    #[test]
+    fn reliable_ordered_holds_back_until_gap_is_filled() {
+        let mut sender = ReliabilityState::new();
+        let mut receiver = ReliabilityState::new();
+
+        let a = sender
+            .send(Channel::ReliableOrdered, b"a".to_vec())
+            .unwrap()
+            .unwrap();
+        let b = sender
+            .send(Channel::ReliableOrdered, b"b".to_vec())
+            .unwrap()
+            .unwrap();
+        let c = sender
+            .send(Channel::ReliableOrdered, b"c".to_vec())
+            .unwrap()
+            .unwrap();
+
+        let received = receiver.receive(c);
+        assert!(received.messages.is_empty());
+        assert_eq!(
+            received.ack,
+            Some(AckPacket {
+                channel: Channel::ReliableOrdered,
+                sequence: 2,
+            }),
+        );
+        assert!(receiver.receive(b).messages.is_empty());
+        assert_eq!(
+            receiver.receive(a).messages,
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()],
+        );
+    }
+
+    #[test]
+    fn channels_have_independent_sequences() {
+        let mut sender = ReliabilityState::new();
+        let mut receiver = ReliabilityState::new();
+
+        sender
+            .send(Channel::ReliableOrdered, b"lost".to_vec())
+            .unwrap()
+            .unwrap();
+        let unordered = sender
+            .send(Channel::ReliableUnordered, b"a".to_vec())
+            .unwrap()
+            .unwrap();
+        assert_eq!(receiver.receive(unordered).messages, vec![b"a".to_vec()]);
+    }
+
+    #[test]
+    fn retransmits_until_acked() {
+        let mut sender = ReliabilityState::new();
+        let packet = sender
+            .send(Channel::ReliableOrdered, b"a".to_vec())
+            .unwrap()
+            .unwrap();
+
+        // Freshly sent messages wait out one interval.
+        assert!(sender.retransmissions().is_empty());
+        assert_eq!(sender.retransmissions(), vec![packet.clone()]);
+        assert!(sender.retransmissions().is_empty());
+        assert_eq!(sender.retransmissions(), vec![packet]);
+
+        sender.handle_ack(AckPacket {
+            channel: Channel::ReliableOrdered,
+            sequence: 0,
+        });
+        for _ in 0..2 * MAX_RESEND_BACKOFF {
+            assert!(sender.retransmissions().is_empty());
+        }
+        assert_eq!(sender.unacked_len(), 0);
+    }
+
+    #[test]
+    fn retransmissions_back_off() {
+        let mut sender = ReliabilityState::new();
+        sender
+            .send(Channel::ReliableUnordered, b"a".to_vec())
+            .unwrap()
+            .unwrap();
+
+        let ticks: Vec<u32> = (1..=100)
+            .filter(|_| !sender.retransmissions().is_empty())
+            .collect();
+        assert_eq!(ticks, [2, 4, 8, 16, 32, 48, 64, 80, 96]);
+    }
+
+    #[test]
+    fn holds_back_messages_beyond_window() {
+        let mut sender = ReliabilityState::new();
+        for _ in 0..RECEIVE_WINDOW {
+            sender
+                .send(Channel::ReliableOrdered, b"a".to_vec())
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(
+            sender.send(Channel::ReliableOrdered, b"b".to_vec()),
+            Ok(None)
+        );
+        assert!(sender.retransmissions().is_empty());
+
+        // Acknowledging the oldest message makes room for the held-back one.
+        sender.handle_ack(AckPacket {
+            channel: Channel::ReliableOrdered,
+            sequence: 0,
+        });
+        let sent = sender.retransmissions();
+        assert!(sent.contains(&GameDataPacket::ReliableOrdered {
+            sequence: RECEIVE_WINDOW,
+            data: b"b".to_vec(),
+        }));
+        assert_eq!(sent.len(), RECEIVE_WINDOW as usize);
+    }
+
+    #[test]
+    fn send_fails_when_queue_is_full() {
+        let mut sender = ReliabilityState::new();
+        for _ in 0..SEND_QUEUE_CAPACITY {
+            sender.send(Channel::ReliableUnordered, Vec::new()).unwrap();
+        }
+        assert_eq!(
+            sender.send(Channel::ReliableUnordered, Vec::new()),
+            Err(SendQueueFull(Channel::ReliableUnordered)),
+        );
+        // Other channels are unaffected.
+        assert!(sender.send(Channel::ReliableOrdered, Vec::new()).is_ok());
+        assert!(sender.send(Channel::Unreliable, Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn drops_messages_beyond_window() {
+        let mut receiver = ReliabilityState::new();
+        let received = receiver.receive(GameDataPacket::ReliableUnordered {
+            sequence: RECEIVE_WINDOW,
+            data: b"a".to_vec(),
+        });
+        assert_eq!(received, Received::default());
+    }
+}
@@ -0,0 +1,104 @@
+//! Detection of replayed sealed packets.
+//!
+//! Each sealed packet carries a sequence number that is authenticated along with its payload. A
+//! sender numbers its packets from zero upward. A receiver remembers which of the most recent
+//! [`REPLAY_WINDOW_SIZE`] sequence numbers it has accepted and rejects repeats as well as anything
+//! older than the window.
+
+use crate::packet::ReadPacketError;
+
+/// How many sequence numbers behind the highest one seen are still accepted.
+pub const REPLAY_WINDOW_SIZE: u64 = 128;
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplayWindow {
+    /// One past the highest sequence number accepted so far.
+    next: u64,
+    /// Bit `i` is set if sequence number `next - 1 - i` has been accepted.
+    seen: u128,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// One past the highest sequence number accepted so far.
+    pub fn end(&self) -> u64 {
+        self.next
+    }
+
+    /// Returns an error if `sequence` has already been accepted or is too old to tell.
+    pub fn check(&self, sequence: u64) -> Result<(), ReadPacketError> {
+        if sequence >= self.next {
+            return Ok(());
+        }
+        let age = self.next - 1 - sequence;
+        if age >= REPLAY_WINDOW_SIZE {
+            return Err(ReadPacketError::StaleSequence(sequence));
+        }
+        if self.seen & (1 << age) != 0 {
+            return Err(ReadPacketError::ReplayedSequence(sequence));
+        }
+        Ok(())
+    }
+
+    /// Records `sequence` as accepted. Only call this after the packet has been authenticated.
+    pub fn accept(&mut self, sequence: u64) -> Result<(), ReadPacketError> {
+        self.check(sequence)?;
+        if sequence >= self.next {
+            let shift = sequence - self.next + 1;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.next = sequence + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - sequence);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::ReadPacketError;
+
+    use super::{ReplayWindow, REPLAY_WINDOW_SIZE};
+
+    #[test]
+    fn accepts_each_sequence_once_in_any_order() {
+        let mut window = ReplayWindow::new();
+        for sequence in [0, 2, 1, 5, 3] {
+            window.accept(sequence).unwrap();
+        }
+        for sequence in [0, 1, 2, 3, 5] {
+            assert!(matches!(
+                window.accept(sequence),
+                Err(ReadPacketError::ReplayedSequence(s)) if s == sequence,
+            ));
+        }
+        window.accept(4).unwrap();
+    }
+
+    #[test]
+    fn rejects_sequences_older_than_the_window() {
+        let mut window = ReplayWindow::new();
+        window.accept(REPLAY_WINDOW_SIZE).unwrap();
+        assert!(matches!(
+            window.accept(0),
+            Err(ReadPacketError::StaleSequence(0)),
+        ));
+        window.accept(1).unwrap();
+
+        // A large jump forgets everything that came before.
+        window.accept(1000).unwrap();
+        assert!(matches!(
+            window.accept(1000 - REPLAY_WINDOW_SIZE),
+            Err(ReadPacketError::StaleSequence(_)),
+        ));
+        window.accept(1001 - REPLAY_WINDOW_SIZE).unwrap();
+    }
+}
@@ -6,21 +6,114 @@ use dungeon_vr_cryptography::{Nonce, SharedSecret};
 use dungeon_vr_stream_codec::{ExternalStreamCodec, StreamCodec};
 
 use crate::packet::ReadPacketError;
+use crate::replay::ReplayWindow;
+
+/// The number of bytes sealing adds to a plaintext: the connection ID, the sequence number, the
+/// nonce, and the authentication tag.
+pub const SEALED_OVERHEAD: usize = 8 + 8 + Nonce::SIZE + SharedSecret::TAG_SIZE;
+
+/// Identifies the shared secret a sealed packet was sealed under, so a receiver can find the
+/// session it belongs to even if it arrives from an unfamiliar address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, StreamCodec)]
+#[stream_codec(read_error = "ReadPacketError")]
+pub struct ConnectionId(pub u64);
+
+impl ConnectionId {
+    pub fn of(shared_secret: &SharedSecret) -> Self {
+        Self(u64::from_be_bytes(shared_secret.id()))
+    }
+
+    fn associated_data(self, sender: Role, sequence: u64) -> [u8; 17] {
+        let mut associated_data = [0; 17];
+        associated_data[..8].copy_from_slice(&self.0.to_be_bytes());
+        associated_data[8] = sender as u8;
+        associated_data[9..].copy_from_slice(&sequence.to_be_bytes());
+        associated_data
+    }
+}
+
+/// Which end of a connection sealed a packet. Both ends seal under the same shared secret, so the
+/// sender's role is authenticated as associated data to keep a packet from being reflected back at
+/// the endpoint that sent it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    /// The role of the other end of the connection.
+    pub fn peer(self) -> Self {
+        match self {
+            Self::Client => Self::Server,
+            Self::Server => Self::Client,
+        }
+    }
+}
 
 pub struct Sealed<P> {
+    /// The connection this packet belongs to. Authenticated as associated data.
+    connection_id: ConnectionId,
+    /// The sender's sequence number for this packet. Authenticated as associated data.
+    sequence: u64,
     nonce: Nonce,
     data: Vec<u8>,
     _phantom_t: PhantomData<P>,
 }
 
 impl<P> Sealed<P> {
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
     pub fn cast<Q>(self) -> Sealed<Q> {
         Sealed {
+            connection_id: self.connection_id,
+            sequence: self.sequence,
             nonce: self.nonce,
             data: self.data,
             _phantom_t: PhantomData,
         }
     }
+
+    fn seal_plaintext(
+        plaintext: &[u8],
+        connection_id: ConnectionId,
+        sender: Role,
+        sequence: u64,
+        shared_secret: &SharedSecret,
+    ) -> Self {
+        let nonce = Nonce::gen();
+        let data = shared_secret.encrypt(
+            plaintext,
+            &connection_id.associated_data(sender, sequence),
+            &nonce,
+        );
+        Self {
+            connection_id,
+            sequence,
+            nonce,
+            data,
+            _phantom_t: PhantomData,
+        }
+    }
+
+    fn open_plaintext(
+        &self,
+        sender: Role,
+        shared_secret: &SharedSecret,
+    ) -> Result<Vec<u8>, ReadPacketError> {
+        Ok(shared_secret.decrypt(
+            &self.data[..],
+            &self.connection_id.associated_data(sender, self.sequence),
+            &self.nonce,
+        )?)
+    }
 }
 
 impl<P> Sealed<P>
@@ -28,57 +121,86 @@ where
     P: StreamCodec<WriteError = Infallible>,
     <P as StreamCodec>::ReadError: Into<ReadPacketError>,
 {
-    pub fn seal(packet: P, shared_secret: &SharedSecret) -> Self {
+    /// Seals a packet from `sender` under an explicit sequence number. Prefer
+    /// [`SealingKey::seal`], which numbers packets automatically.
+    pub fn seal(packet: P, sender: Role, sequence: u64, shared_secret: &SharedSecret) -> Self {
+        Self::seal_with_id(
+            packet,
+            ConnectionId::of(shared_secret),
+            sender,
+            sequence,
+            shared_secret,
+        )
+    }
+
+    fn seal_with_id(
+        packet: P,
+        connection_id: ConnectionId,
+        sender: Role,
+        sequence: u64,
+        shared_secret: &SharedSecret,
+    ) -> Self {
         let mut plaintext = Vec::new();
         packet.write_to(&mut plaintext).unwrap();
-
-        let nonce = Nonce::gen();
-        let data = shared_secret.encrypt(&plaintext, &nonce);
-        Self {
-            nonce,
-            data,
-            _phantom_t: PhantomData,
-        }
+        Self::seal_plaintext(&plaintext, connection_id, sender, sequence, shared_secret)
     }
 
-    pub fn open(&self, shared_secret: &SharedSecret) -> Result<P, ReadPacketError> {
-        let plaintext = shared_secret.decrypt(&self.data[..], &self.nonce)?;
+    /// Authenticates and decodes a packet from `sender` without checking for replays. Prefer
+    /// [`SealingKey::open`], which rejects sequence numbers it has already seen.
+    pub fn open(&self, sender: Role, shared_secret: &SharedSecret) -> Result<P, ReadPacketError> {
+        let plaintext = self.open_plaintext(sender, shared_secret)?;
         let mut r = &*plaintext;
         let packet = P::read_from(&mut r).map_err(Into::into)?;
         if !r.is_empty() {
-            return Err(ReadPacketError::TrailingData.into());
+            return Err(ReadPacketError::TrailingData);
         }
         Ok(packet)
     }
 }
 
 impl<P> Sealed<P> {
-    pub fn seal_ext<C>(packet: P, shared_secret: &SharedSecret) -> Self
+    pub fn seal_ext<C>(packet: P, sender: Role, sequence: u64, shared_secret: &SharedSecret) -> Self
+    where
+        C: ExternalStreamCodec<Item = P, WriteError = Infallible>,
+    {
+        Self::seal_ext_with_id::<C>(
+            packet,
+            ConnectionId::of(shared_secret),
+            sender,
+            sequence,
+            shared_secret,
+        )
+    }
+
+    fn seal_ext_with_id<C>(
+        packet: P,
+        connection_id: ConnectionId,
+        sender: Role,
+        sequence: u64,
+        shared_secret: &SharedSecret,
+    ) -> Self
     where
         C: ExternalStreamCodec<Item = P, WriteError = Infallible>,
     {
         let mut plaintext = Vec::new();
         C::write_to_ext(&mut plaintext, &packet).unwrap();
-
-        let nonce = Nonce::gen();
-        let data = shared_secret.encrypt(&plaintext, &nonce);
-        Self {
-            nonce,
-            data,
-            _phantom_t: PhantomData,
-        }
+        Self::seal_plaintext(&plaintext, connection_id, sender, sequence, shared_secret)
     }
 
-    pub fn open_ext<C>(&self, shared_secret: &SharedSecret) -> Result<P, ReadPacketError>
+    pub fn open_ext<C>(
+        &self,
+        sender: Role,
+        shared_secret: &SharedSecret,
+    ) -> Result<P, ReadPacketError>
     where
         C: ExternalStreamCodec<Item = P, WriteError = Infallible>,
         <C as ExternalStreamCodec>::ReadError: Into<ReadPacketError>,
     {
-        let plaintext = shared_secret.decrypt(&self.data[..], &self.nonce)?;
+        let plaintext = self.open_plaintext(sender, shared_secret)?;
         let mut r = &*plaintext;
         let packet = C::read_from_ext(&mut r).map_err(Into::into)?;
         if !r.is_empty() {
-            return Err(ReadPacketError::TrailingData.into());
+            return Err(ReadPacketError::TrailingData);
         }
         Ok(packet)
     }
@@ -89,10 +211,14 @@ impl<P> StreamCodec for Sealed<P> {
     type WriteError = Infallible;
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
+        let connection_id = ConnectionId::read_from(r)?;
+        let sequence = u64::read_from(r)?;
         let nonce = Nonce::read_from(r)?;
         let mut data = Vec::new();
         r.read_to_end(&mut data).unwrap();
         Ok(Self {
+            connection_id,
+            sequence,
             nonce,
             data,
             _phantom_t: PhantomData,
@@ -100,8 +226,264 @@ impl<P> StreamCodec for Sealed<P> {
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+        self.connection_id.write_to(w)?;
+        self.sequence.write_to(w)?;
         self.nonce.write_to(w)?;
         w.write_all(&self.data).unwrap();
         Ok(())
     }
 }
+
+/// One endpoint's view of a shared secret. Numbers outgoing sealed packets and rejects incoming
+/// ones whose sequence numbers have already been seen, or that weren't sealed by the peer.
+#[derive(Clone, Debug)]
+pub struct SealingKey {
+    shared_secret: SharedSecret,
+    connection_id: ConnectionId,
+    /// This endpoint's role. Outgoing packets are sealed as this role, and incoming packets must
+    /// have been sealed as its peer.
+    role: Role,
+    next_sequence: u64,
+    replay_window: ReplayWindow,
+    /// The number of incoming packets opened successfully.
+    opened: u64,
+    /// The number of incoming packets that failed authentication.
+    decrypt_failures: u64,
+}
+
+impl SealingKey {
+    pub fn new(shared_secret: SharedSecret, role: Role) -> Self {
+        Self {
+            shared_secret,
+            connection_id: ConnectionId::of(&shared_secret),
+            role,
+            next_sequence: 0,
+            replay_window: ReplayWindow::new(),
+            opened: 0,
+            decrypt_failures: 0,
+        }
+    }
+
+    pub fn shared_secret(&self) -> &SharedSecret {
+        &self.shared_secret
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The number of incoming packets that failed authentication.
+    pub fn decrypt_failures(&self) -> u64 {
+        self.decrypt_failures
+    }
+
+    /// The number of packets the peer sealed that never arrived, inferred from gaps in the
+    /// sequence numbers opened so far. Packets still in flight count until they arrive.
+    pub fn packets_lost(&self) -> u64 {
+        self.replay_window.end().saturating_sub(self.opened)
+    }
+
+    fn take_sequence(&mut self) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        sequence
+    }
+
+    pub fn seal<P>(&mut self, packet: P) -> Sealed<P>
+    where
+        P: StreamCodec<WriteError = Infallible>,
+        <P as StreamCodec>::ReadError: Into<ReadPacketError>,
+    {
+        let sequence = self.take_sequence();
+        Sealed::seal_with_id(
+            packet,
+            self.connection_id,
+            self.role,
+            sequence,
+            &self.shared_secret,
+        )
+    }
+
+    pub fn seal_ext<C>(&mut self, packet: C::Item) -> Sealed<C::Item>
+    where
+        C: ExternalStreamCodec<WriteError = Infallible>,
+    {
+        let sequence = self.take_sequence();
+        Sealed::seal_ext_with_id::<C>(
+            packet,
+            self.connection_id,
+            self.role,
+            sequence,
+            &self.shared_secret,
+        )
+    }
+
+    pub fn open<P>(&mut self, sealed: &Sealed<P>) -> Result<P, ReadPacketError>
+    where
+        P: StreamCodec<WriteError = Infallible>,
+        <P as StreamCodec>::ReadError: Into<ReadPacketError>,
+    {
+        // Check the window before spending time on decryption, but only record the sequence
+        // number once the packet is known to be authentic.
+        self.replay_window.check(sealed.sequence)?;
+        let packet = match sealed.open(self.role.peer(), &self.shared_secret) {
+            Ok(packet) => packet,
+            Err(e) => {
+                if let ReadPacketError::DecryptError(_) = e {
+                    self.decrypt_failures += 1;
+                }
+                return Err(e);
+            }
+        };
+        self.replay_window.accept(sealed.sequence)?;
+        self.opened += 1;
+        Ok(packet)
+    }
+
+    /// Checks that `sealed` is authentic and newer than everything opened so far, without
+    /// recording it. A connection should only follow its peer to a new address on a packet that
+    /// passes this check, so that a replayed or delayed packet can't lead it astray.
+    pub fn authenticate_newest<P>(&mut self, sealed: &Sealed<P>) -> Result<(), ReadPacketError> {
+        if sealed.sequence < self.replay_window.end() {
+            return Err(ReadPacketError::StaleSequence(sealed.sequence));
+        }
+        if let Err(e) = sealed.open_plaintext(self.role.peer(), &self.shared_secret) {
+            if let ReadPacketError::DecryptError(_) = e {
+                self.decrypt_failures += 1;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dungeon_vr_cryptography::SharedSecret;
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use crate::packet::ReadPacketError;
+
+    use super::{ConnectionId, Role, Sealed, SealingKey};
+
+    #[test]
+    fn sealing_key_rejects_replays() {
+        let shared_secret = SharedSecret::gen();
+        let mut sender = SealingKey::new(shared_secret, Role::Client);
+        let mut receiver = SealingKey::new(shared_secret, Role::Server);
+
+        let first = sender.seal(1u8);
+        let second = sender.seal(2u8);
+        assert_eq!(second.sequence(), 1);
+        assert_eq!(receiver.open(&second).unwrap(), 2);
+        assert_eq!(receiver.open(&first).unwrap(), 1);
+        assert!(matches!(
+            receiver.open(&first),
+            Err(ReadPacketError::ReplayedSequence(0)),
+        ));
+    }
+
+    #[test]
+    fn sealing_key_counts_losses_and_failures() {
+        let shared_secret = SharedSecret::gen();
+        let mut sender = SealingKey::new(shared_secret, Role::Client);
+        let mut receiver = SealingKey::new(shared_secret, Role::Server);
+
+        let packets: Vec<_> = (0..5u8).map(|i| sender.seal(i)).collect();
+        receiver.open(&packets[0]).unwrap();
+        receiver.open(&packets[3]).unwrap();
+        assert_eq!(receiver.packets_lost(), 2);
+        receiver.open(&packets[1]).unwrap();
+        assert_eq!(receiver.packets_lost(), 1);
+
+        let forged = Sealed::seal(9u8, Role::Client, 9, &SharedSecret::gen());
+        assert!(matches!(
+            receiver.open(&forged),
+            Err(ReadPacketError::DecryptError(_)),
+        ));
+        assert_eq!(receiver.decrypt_failures(), 1);
+        assert_eq!(receiver.packets_lost(), 1);
+    }
+
+    #[test]
+    fn sequence_is_authenticated() {
+        let shared_secret = SharedSecret::gen();
+        let sealed = Sealed::seal(1u8, Role::Client, 5, &shared_secret);
+
+        let mut w = Vec::new();
+        sealed.write_to(&mut w).unwrap();
+        w[15] ^= 1;
+        let tampered = Sealed::<u8>::read_from(&mut &w[..]).unwrap();
+        assert_eq!(tampered.sequence(), 4);
+        assert!(matches!(
+            tampered.open(Role::Client, &shared_secret),
+            Err(ReadPacketError::DecryptError(_)),
+        ));
+    }
+
+    #[test]
+    fn connection_id_is_authenticated() {
+        let shared_secret = SharedSecret::gen();
+        let sealed = SealingKey::new(shared_secret, Role::Client).seal(1u8);
+        assert_eq!(sealed.connection_id(), ConnectionId::of(&shared_secret));
+
+        let mut w = Vec::new();
+        sealed.write_to(&mut w).unwrap();
+        w[0] ^= 1;
+        let tampered = Sealed::<u8>::read_from(&mut &w[..]).unwrap();
+        assert_ne!(tampered.connection_id(), sealed.connection_id());
+        assert!(matches!(
+            tampered.open(Role::Client, &shared_secret),
+            Err(ReadPacketError::DecryptError(_)),
+        ));
+    }
+
+    #[test]
+    fn authenticate_newest_requires_a_new_sequence() {
+        let shared_secret = SharedSecret::gen();
+        let mut sender = SealingKey::new(shared_secret, Role::Client);
+        let mut receiver = SealingKey::new(shared_secret, Role::Server);
+
+        let first = sender.seal(1u8);
+        let second = sender.seal(2u8);
+        receiver.open(&second).unwrap();
+        assert!(matches!(
+            receiver.authenticate_newest(&first),
+            Err(ReadPacketError::StaleSequence(0)),
+        ));
+
+        // Authenticating doesn't consume the packet.
+        let third = sender.seal(3u8);
+        receiver.authenticate_newest(&third).unwrap();
+        assert_eq!(receiver.open(&third).unwrap(), 3);
+    }
+
+    #[test]
+    fn reflected_packets_are_rejected() {
+        let shared_secret = SharedSecret::gen();
+        let mut client = SealingKey::new(shared_secret, Role::Client);
+        let mut server = SealingKey::new(shared_secret, Role::Server);
+
+        // A packet the server sent, reflected back at it, neither opens nor advances its window.
+        let reflected = server.seal(1u8);
+        assert!(matches!(
+            server.authenticate_newest(&reflected),
+            Err(ReadPacketError::DecryptError(_)),
+        ));
+        assert!(matches!(
+            server.open(&reflected),
+            Err(ReadPacketError::DecryptError(_)),
+        ));
+        assert_eq!(server.decrypt_failures(), 2);
+
+        // The client's packet with the same sequence number still opens.
+        let genuine = client.seal(2u8);
+        assert_eq!(genuine.sequence(), reflected.sequence());
+        assert_eq!(server.open(&genuine).unwrap(), 2);
+        assert_eq!(client.open(&reflected).unwrap(), 1);
+    }
+}
@@ -0,0 +1,141 @@
+//! Link-quality measurements for one connection.
+//!
+//! Round-trip time comes from the Ping and Pong keepalives each peer exchanges once per keepalive
+//! interval. Loss and authentication failures are counted by the connection's [`SealingKey`].
+
+use std::time::{Duration, Instant};
+
+use crate::keepalive_packet::KeepalivePacket;
+use crate::sealed::SealingKey;
+
+/// A snapshot of one connection's counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The smoothed round-trip time, once at least one Ping has been answered.
+    pub rtt: Option<Duration>,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Packets the peer sent that never arrived, inferred from gaps in sequence numbers.
+    pub packets_lost: u64,
+    /// Packets that claimed to be from the peer but failed authentication.
+    pub decrypt_failures: u64,
+}
+
+/// Accumulates [`ConnectionStats`] for one connection.
+#[derive(Default)]
+pub struct StatsTracker {
+    rtt: Option<Duration>,
+    packets_sent: u64,
+    bytes_sent: u64,
+    packets_received: u64,
+    bytes_received: u64,
+    next_ping_id: u32,
+    /// The ID and send time of the most recent Ping, until its Pong arrives.
+    outstanding_ping: Option<(u32, Instant)>,
+}
+
+impl StatsTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a datagram sent to the peer.
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.packets_sent += 1;
+        self.bytes_sent += bytes as u64;
+    }
+
+    /// Counts a datagram received from the peer, whether or not it turns out to be valid.
+    pub fn record_received(&mut self, bytes: usize) {
+        self.packets_received += 1;
+        self.bytes_received += bytes as u64;
+    }
+
+    /// Starts a round-trip measurement and returns the Ping to send. A Ping still awaiting its
+    /// Pong is abandoned.
+    pub fn ping(&mut self, now: Instant) -> KeepalivePacket {
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.outstanding_ping = Some((id, now));
+        KeepalivePacket::Ping { id }
+    }
+
+    /// Completes a round-trip measurement if `id` answers the outstanding Ping.
+    pub fn pong(&mut self, id: u32, now: Instant) {
+        let sent_at = match self.outstanding_ping {
+            Some((outstanding_id, sent_at)) if outstanding_id == id => sent_at,
+            _ => return,
+        };
+        self.outstanding_ping = None;
+        let sample = now.saturating_duration_since(sent_at);
+        // Each sample gets a weight of 1/8, as in TCP's smoothed RTT.
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    /// Returns the current counters. `key` is the connection's key, if it has one yet.
+    pub fn snapshot(&self, key: Option<&SealingKey>) -> ConnectionStats {
+        ConnectionStats {
+            rtt: self.rtt,
+            packets_sent: self.packets_sent,
+            bytes_sent: self.bytes_sent,
+            packets_received: self.packets_received,
+            bytes_received: self.bytes_received,
+            packets_lost: key.map_or(0, SealingKey::packets_lost),
+            decrypt_failures: key.map_or(0, SealingKey::decrypt_failures),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::keepalive_packet::KeepalivePacket;
+
+    use super::StatsTracker;
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut tracker = StatsTracker::new();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+
+        let id = match tracker.ping(start) {
+            KeepalivePacket::Ping { id } => id,
+            _ => unreachable!(),
+        };
+        tracker.pong(id, start + ms(80));
+        assert_eq!(tracker.snapshot(None).rtt, Some(ms(80)));
+
+        // Stale and repeated Pongs are ignored.
+        tracker.pong(id, start + ms(500));
+        let id = match tracker.ping(start + ms(1000)) {
+            KeepalivePacket::Ping { id } => id,
+            _ => unreachable!(),
+        };
+        tracker.pong(id.wrapping_sub(1), start + ms(1010));
+        assert_eq!(tracker.snapshot(None).rtt, Some(ms(80)));
+
+        tracker.pong(id, start + ms(1160));
+        assert_eq!(tracker.snapshot(None).rtt, Some(ms(90)));
+    }
+
+    #[test]
+    fn counts_traffic() {
+        let mut tracker = StatsTracker::new();
+        tracker.record_sent(100);
+        tracker.record_sent(20);
+        tracker.record_received(7);
+
+        let stats = tracker.snapshot(None);
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.bytes_sent, 120);
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.bytes_received, 7);
+    }
+}
@@ -1,10 +1,12 @@
 use std::convert::Infallible;
-use std::fmt::{self, Debug, Formatter};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::io::Write;
+use std::str::FromStr;
 
 use chacha20poly1305::aead::rand_core::{OsRng, RngCore};
-use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
 use dungeon_vr_stream_codec::{ReadError, StreamCodec};
+use ed25519_dalek::Signer;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,8 +19,16 @@ pub enum KeyExchangeError {
 #[error("error in authenticated decryption")]
 pub struct DecryptError;
 
+#[derive(Error, Debug)]
+#[error("invalid key encoding")]
+pub struct InvalidKeyError;
+
+#[derive(Error, Debug)]
+#[error("signature verification failed")]
+pub struct SignatureError;
+
 #[derive(Clone)]
-pub struct PrivateKey(x25519_dalek::ReusableSecret);
+pub struct PrivateKey(x25519_dalek::StaticSecret);
 
 impl Debug for PrivateKey {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -27,8 +37,18 @@ impl Debug for PrivateKey {
 }
 
 impl PrivateKey {
+    pub const SIZE: usize = 32;
+
     pub fn gen() -> Self {
-        Self(x25519_dalek::ReusableSecret::new(rand_core::OsRng))
+        Self(x25519_dalek::StaticSecret::new(rand_core::OsRng))
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes.into())
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        self.0.to_bytes()
     }
 
     pub fn to_public(&self) -> PublicKey {
@@ -53,12 +73,16 @@ impl Debug for PublicKey {
     }
 }
 
+impl PublicKey {
+    pub const SIZE: usize = 32;
+}
+
 impl StreamCodec for PublicKey {
     type ReadError = ReadError;
     type WriteError = Infallible;
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadError> {
-        Ok(Self(<[u8; 32]>::read_from(r)?.into()))
+        Ok(Self(<[u8; Self::SIZE]>::read_from(r)?.into()))
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
@@ -66,6 +90,13 @@ impl StreamCodec for PublicKey {
     }
 }
 
+/// Returns `N` bytes from the operating system's secure random number generator.
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut buf = [0; N];
+    OsRng.fill_bytes(&mut buf);
+    buf
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct SharedSecret(chacha20poly1305::Key);
 
@@ -76,29 +107,72 @@ impl Debug for SharedSecret {
 }
 
 impl SharedSecret {
+    pub const SIZE: usize = 32;
+    /// The number of bytes encryption adds to the plaintext for the authentication tag.
+    pub const TAG_SIZE: usize = 16;
+
     pub fn gen() -> Self {
         Self(chacha20poly1305::XChaCha20Poly1305::generate_key(OsRng))
     }
 
-    pub fn encrypt(&self, plaintext: &[u8], nonce: &Nonce) -> Vec<u8> {
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes.into())
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        self.0.into()
+    }
+
+    /// Encrypts `plaintext`. The ciphertext also authenticates `associated_data`, which must be
+    /// presented again unchanged to decrypt it.
+    pub fn encrypt(&self, plaintext: &[u8], associated_data: &[u8], nonce: &Nonce) -> Vec<u8> {
         chacha20poly1305::XChaCha20Poly1305::new(&self.0)
-            .encrypt(&nonce.0, plaintext)
+            .encrypt(
+                &nonce.0,
+                Payload {
+                    msg: plaintext,
+                    aad: associated_data,
+                },
+            )
             .unwrap()
     }
 
-    pub fn decrypt(&self, ciphertext: &[u8], nonce: &Nonce) -> Result<Vec<u8>, DecryptError> {
+    /// Derives an identifier for this secret that is safe to send in the clear. Only holders of
+    /// the secret can compute it or tell which secret it belongs to.
+    pub fn id(&self) -> [u8; 8] {
+        // Sealing draws random nonces, so the all-zero nonce is left for this.
+        let keystream = chacha20poly1305::XChaCha20Poly1305::new(&self.0)
+            .encrypt(&Default::default(), &[0; 8][..])
+            .unwrap();
+        keystream[..8].try_into().unwrap()
+    }
+
+    pub fn decrypt(
+        &self,
+        ciphertext: &[u8],
+        associated_data: &[u8],
+        nonce: &Nonce,
+    ) -> Result<Vec<u8>, DecryptError> {
         chacha20poly1305::XChaCha20Poly1305::new(&self.0)
-            .decrypt(&nonce.0, ciphertext)
+            .decrypt(
+                &nonce.0,
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
             .map_err(|_| DecryptError)
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Nonce(chacha20poly1305::XNonce);
 
 impl Nonce {
+    pub const SIZE: usize = 24;
+
     pub fn gen() -> Self {
-        let mut buf = [0; 24];
+        let mut buf = [0; Self::SIZE];
         OsRng.fill_bytes(&mut buf);
         Self(buf.into())
     }
@@ -115,7 +189,7 @@ impl StreamCodec for Nonce {
     type WriteError = Infallible;
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadError> {
-        Ok(Self(<[u8; 24]>::read_from(r)?.into()))
+        Ok(Self(<[u8; Self::SIZE]>::read_from(r)?.into()))
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
@@ -123,11 +197,123 @@ impl StreamCodec for Nonce {
     }
 }
 
+/// A long-term Ed25519 key that a server uses to prove its identity.
+pub struct SigningKey(ed25519_dalek::Keypair);
+
+impl Debug for SigningKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "SigningKey(_)")
+    }
+}
+
+impl SigningKey {
+    pub const SIZE: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+
+    pub fn gen() -> Self {
+        Self(ed25519_dalek::Keypair::generate(&mut rand_core::OsRng))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, InvalidKeyError> {
+        let secret = ed25519_dalek::SecretKey::from_bytes(bytes).map_err(|_| InvalidKeyError)?;
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        Ok(Self(ed25519_dalek::Keypair { secret, public }))
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        self.0.secret.to_bytes()
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.public)
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        Signature(self.0.sign(message).to_bytes())
+    }
+}
+
+/// The public half of a [`SigningKey`]. Displays and parses as hexadecimal so it can be passed
+/// around as text.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VerifyingKey(ed25519_dalek::PublicKey);
+
+impl Debug for VerifyingKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "VerifyingKey({self})")
+    }
+}
+
+impl VerifyingKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, InvalidKeyError> {
+        Ok(Self(
+            ed25519_dalek::PublicKey::from_bytes(bytes).map_err(|_| InvalidKeyError)?,
+        ))
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> Result<(), SignatureError> {
+        let signature =
+            ed25519_dalek::Signature::from_bytes(&signature.0).map_err(|_| SignatureError)?;
+        self.0
+            .verify_strict(message, &signature)
+            .map_err(|_| SignatureError)
+    }
+}
+
+impl Display for VerifyingKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for byte in self.0.as_bytes() {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for VerifyingKey {
+    type Err = InvalidKeyError;
+
+    fn from_str(s: &str) -> Result<Self, InvalidKeyError> {
+        if s.len() != 2 * ed25519_dalek::PUBLIC_KEY_LENGTH || !s.is_ascii() {
+            return Err(InvalidKeyError);
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| InvalidKeyError))
+            .collect::<Result<Vec<u8>, InvalidKeyError>>()?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature([u8; Self::SIZE]);
+
+impl Signature {
+    pub const SIZE: usize = ed25519_dalek::SIGNATURE_LENGTH;
+}
+
+impl Debug for Signature {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Signature(_)")
+    }
+}
+
+impl StreamCodec for Signature {
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from(r: &mut &[u8]) -> Result<Self, ReadError> {
+        Ok(Self(<[u8; Self::SIZE]>::read_from(r)?))
+    }
+
+    fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+        self.0.write_to(w)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use chacha20poly1305::aead::rand_core::{OsRng, RngCore};
 
-    use super::{Nonce, PrivateKey, SharedSecret};
+    use super::{Nonce, PrivateKey, SharedSecret, SigningKey, VerifyingKey};
 
     #[test]
     fn key_exchange() {
@@ -142,6 +328,13 @@ mod tests {
         assert_eq!(shared_key_a.0, shared_key_b.0);
     }
 
+    #[test]
+    fn private_key_round_trip() {
+        let private_key = PrivateKey::gen();
+        let round_trip = PrivateKey::from_bytes(private_key.to_bytes());
+        assert_eq!(round_trip.to_public(), private_key.to_public());
+    }
+
     #[test]
     fn authenticated_encryption() {
         let plaintext = {
@@ -152,9 +345,55 @@ mod tests {
         let nonce = Nonce::gen();
         let key = SharedSecret::gen();
 
-        let ciphertext = key.encrypt(&plaintext, &nonce);
-        let round_trip_plaintext = key.decrypt(&ciphertext, &nonce).unwrap();
+        let ciphertext = key.encrypt(&plaintext, b"header", &nonce);
+        assert_eq!(ciphertext.len(), plaintext.len() + SharedSecret::TAG_SIZE);
+        let round_trip_plaintext = key.decrypt(&ciphertext, b"header", &nonce).unwrap();
 
         assert_eq!(&plaintext[..], &round_trip_plaintext);
     }
+
+    #[test]
+    fn associated_data_is_authenticated() {
+        let nonce = Nonce::gen();
+        let key = SharedSecret::gen();
+
+        let ciphertext = key.encrypt(b"plaintext", b"header", &nonce);
+        assert!(key.decrypt(&ciphertext, b"HEADER", &nonce).is_err());
+    }
+
+    #[test]
+    fn shared_secret_ids() {
+        let key = SharedSecret::gen();
+        assert_eq!(key.id(), key.id());
+        assert_ne!(key.id(), SharedSecret::gen().id());
+        assert_eq!(SharedSecret::from_bytes(key.to_bytes()).id(), key.id());
+    }
+
+    #[test]
+    fn signatures() {
+        let signing_key = SigningKey::gen();
+        let verifying_key = signing_key.verifying_key();
+        let signature = signing_key.sign(b"message");
+
+        verifying_key.verify(b"message", &signature).unwrap();
+        assert!(verifying_key.verify(b"MESSAGE", &signature).is_err());
+        assert!(SigningKey::gen()
+            .verifying_key()
+            .verify(b"message", &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn key_encodings_round_trip() {
+        let signing_key = SigningKey::gen();
+        let verifying_key = signing_key.verifying_key();
+
+        let round_trip = SigningKey::from_bytes(&signing_key.to_bytes()).unwrap();
+        assert_eq!(round_trip.verifying_key(), verifying_key);
+        assert_eq!(
+            verifying_key.to_string().parse::<VerifyingKey>().unwrap(),
+            verifying_key,
+        );
+        assert!("not hex".parse::<VerifyingKey>().is_err());
+    }
 }
@@ -1,10 +1,15 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use dungeon_vr_connection_server::ConnectionServer;
+use dungeon_vr_connection_server::{ConnectionConfig, ConnectionServer, SigningKey};
 use dungeon_vr_session_server::SessionServer;
+use dungeon_vr_socket::capture::{CaptureWriter, RecordingBoundSocket};
+use dungeon_vr_socket::BoundSocket;
 use tokio::net::UdpSocket;
 
 #[derive(Parser, Debug)]
@@ -17,6 +22,28 @@ pub struct Args {
     /// Server UDP port.
     #[clap(long, default_value = "7777")]
     port: u16,
+
+    /// Largest UDP payload to send, in bytes. Larger game data is split into fragments.
+    #[clap(long, default_value = "1200")]
+    mtu: usize,
+
+    /// File holding the server's Ed25519 signing key. Clients that pin the matching public key
+    /// (logged at startup) can verify they reached this server.
+    #[clap(long)]
+    signing_key: Option<PathBuf>,
+
+    /// Writes a new signing key to the --signing-key path and exits. Fails rather than replace an
+    /// existing file.
+    #[clap(long)]
+    generate_signing_key: bool,
+
+    /// Most players allowed in the session at once.
+    #[clap(long, default_value = "4")]
+    max_players: u8,
+
+    /// Records every datagram the server sends or receives to this pcap file.
+    #[clap(long)]
+    capture: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -28,16 +55,65 @@ pub async fn main() -> Result<()> {
         .init();
     let args = Args::parse();
 
+    if args.generate_signing_key {
+        let path = match &args.signing_key {
+            Some(path) => path,
+            None => bail!("--generate-signing-key requires --signing-key"),
+        };
+        let signing_key = SigningKey::gen();
+        write_signing_key(path, &signing_key)?;
+        log::info!(
+            "Wrote signing key to {}. Public key: {}",
+            path.display(),
+            signing_key.verifying_key(),
+        );
+        return Ok(());
+    }
+    let signing_key = match &args.signing_key {
+        Some(path) => {
+            let signing_key = SigningKey::from_bytes(&fs::read(path)?)?;
+            log::info!("Server public key: {}", signing_key.verifying_key());
+            Some(signing_key)
+        }
+        None => None,
+    };
+
     let ip = match &args.ip {
         Some(addr) => Ipv4Addr::from_str(addr)?,
         None => Ipv4Addr::UNSPECIFIED,
     };
-    let socket = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(ip, args.port))).await?;
-    log::info!("Listening on {}", socket.local_addr()?);
-    let (cancel_guard, requests, events) = ConnectionServer::spawn(Box::new(socket));
-    let _session_server = SessionServer::new(requests, events, 4);
+    let udp_socket = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(ip, args.port))).await?;
+    log::info!("Listening on {}", udp_socket.local_addr()?);
+    let mut socket: Box<dyn BoundSocket<SocketAddr>> = Box::new(udp_socket);
+    if let Some(path) = &args.capture {
+        socket = Box::new(RecordingBoundSocket::new(
+            socket,
+            CaptureWriter::create(path)?,
+        ));
+        log::info!("Recording packets to {}", path.display());
+    }
+    let config = ConnectionConfig::builder().mtu(args.mtu).build()?;
+    let (cancel_guard, requests, events) =
+        ConnectionServer::spawn(socket, config, signing_key, None);
+    let _session_server = SessionServer::new(requests, events, args.max_players.into());
 
     cancel_guard.cancelled().await;
 
     Ok(())
 }
+
+/// Writes `signing_key` to a new file at `path`, readable only by its owner where supported.
+fn write_signing_key(path: &Path, signing_key: &SigningKey) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Creating signing key file {}", path.display()))?;
+    file.write_all(&signing_key.to_bytes())?;
+    Ok(())
+}
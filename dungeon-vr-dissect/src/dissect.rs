@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use dungeon_vr_connection_shared::fragment::{FragmentPacket, Reassembler};
+use dungeon_vr_connection_shared::packet::{Packet, ReadPacketError};
+use dungeon_vr_connection_shared::reliable::GameDataPacket;
+use dungeon_vr_connection_shared::sealed::{ConnectionId, Role, Sealed};
+use dungeon_vr_cryptography::SharedSecret;
+use dungeon_vr_session_shared::core::NetId;
+use dungeon_vr_session_shared::packet::Packet as SessionPacket;
+use dungeon_vr_session_shared::snapshot::{
+    read_snapshot, EntitySnapshot, Snapshot, SnapshotHistory,
+};
+use dungeon_vr_socket::capture::{CaptureRecord, Direction};
+use dungeon_vr_stream_codec::StreamCodec;
+
+/// Fragments of a message are expected to arrive together. Incomplete messages older than this are
+/// abandoned, as a peer would.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Decodes captured datagrams, following each connection's state across the capture.
+pub struct Dissector {
+    shared_secrets: HashMap<ConnectionId, SharedSecret>,
+    connections: HashMap<ConnectionId, ConnectionState>,
+    start: Instant,
+    first_timestamp: Option<Duration>,
+}
+
+struct ConnectionState {
+    received_reassembler: Reassembler,
+    sent_reassembler: Reassembler,
+    snapshots: SnapshotHistory,
+}
+
+impl ConnectionState {
+    fn new() -> Self {
+        Self {
+            received_reassembler: Reassembler::new(REASSEMBLY_TIMEOUT),
+            sent_reassembler: Reassembler::new(REASSEMBLY_TIMEOUT),
+            snapshots: SnapshotHistory::new(),
+        }
+    }
+}
+
+impl Dissector {
+    pub fn new(shared_secrets: impl IntoIterator<Item = SharedSecret>) -> Self {
+        Self {
+            shared_secrets: shared_secrets
+                .into_iter()
+                .map(|shared_secret| (ConnectionId::of(&shared_secret), shared_secret))
+                .collect(),
+            connections: HashMap::new(),
+            start: Instant::now(),
+            first_timestamp: None,
+        }
+    }
+
+    /// Describes one datagram. The first line summarizes the connection-layer packet. Any further
+    /// lines, indented to show nesting, describe its contents.
+    pub fn dissect(&mut self, record: &CaptureRecord<SocketAddr>) -> Vec<String> {
+        let first_timestamp = *self.first_timestamp.get_or_insert(record.timestamp);
+        let now = self.start + record.timestamp.saturating_sub(first_timestamp);
+
+        let packet = match Packet::read_from(&mut &record.data[..]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                return vec![format!(
+                    "Malformed packet ({} bytes): {e}",
+                    record.data.len(),
+                )]
+            }
+        };
+        match packet {
+            Packet::ConnectInit(packet) => vec![format!(
+                "ConnectInit protocol versions {}..={}{}",
+                packet.protocol_versions.min,
+                packet.protocol_versions.max,
+                if packet.content_hash.is_some() {
+                    ", with content hash"
+                } else {
+                    ""
+                },
+            )],
+            Packet::ConnectChallenge(packet) => vec![format!(
+                "ConnectChallenge {} protocol versions {}..={}{}",
+                describe_sealed(&packet.sealed_payload),
+                packet.protocol_versions.min,
+                packet.protocol_versions.max,
+                if packet.signature.is_some() {
+                    ", signed"
+                } else {
+                    ""
+                },
+            )],
+            Packet::ConnectResponse(packet) => vec![format!(
+                "ConnectResponse {}",
+                describe_sealed(&packet.sealed_payload),
+            )],
+            Packet::Disconnect(sealed) => vec![match self.open(&sealed) {
+                Ok(packet) => format!(
+                    "Disconnect {}: {}",
+                    describe_sealed(&sealed),
+                    packet.reason.as_deref().unwrap_or("no reason given"),
+                ),
+                Err(e) => format!("Disconnect {} {e}", describe_sealed(&sealed)),
+            }],
+            Packet::Keepalive(sealed) => vec![match self.open(&sealed) {
+                Ok(packet) => format!("Keepalive {}: {packet:?}", describe_sealed(&sealed)),
+                Err(e) => format!("Keepalive {} {e}", describe_sealed(&sealed)),
+            }],
+            Packet::Ack(sealed) => vec![match self.open(&sealed) {
+                Ok(packet) => format!(
+                    "Ack {}: {:?} #{}",
+                    describe_sealed(&sealed),
+                    packet.channel,
+                    packet.sequence,
+                ),
+                Err(e) => format!("Ack {} {e}", describe_sealed(&sealed)),
+            }],
+            Packet::Reject(sealed) => vec![match self.open(&sealed) {
+                Ok(reason) => format!("Reject {}: {reason:?}", describe_sealed(&sealed)),
+                Err(e) => format!("Reject {} {e}", describe_sealed(&sealed)),
+            }],
+            Packet::GameData(sealed) => match self.open(&sealed) {
+                Ok(packet) => {
+                    let (summary, details) =
+                        self.describe_game_data(sealed.connection_id(), packet);
+                    let mut lines =
+                        vec![format!("GameData {}: {summary}", describe_sealed(&sealed),)];
+                    lines.extend(details);
+                    lines
+                }
+                Err(e) => vec![format!("GameData {} {e}", describe_sealed(&sealed))],
+            },
+            Packet::Fragment(sealed) => match self.open(&sealed) {
+                Ok(fragment) => {
+                    let mut lines = vec![format!(
+                        "Fragment {}: {} of {} in message {}",
+                        describe_sealed(&sealed),
+                        fragment.index + 1,
+                        fragment.count,
+                        fragment.message_id,
+                    )];
+                    lines.extend(self.reassemble(
+                        sealed.connection_id(),
+                        record.direction,
+                        fragment,
+                        now,
+                    ));
+                    lines
+                }
+                Err(e) => vec![format!("Fragment {} {e}", describe_sealed(&sealed))],
+            },
+        }
+    }
+
+    fn open<P>(&self, sealed: &Sealed<P>) -> Result<P, String>
+    where
+        P: StreamCodec<WriteError = Infallible>,
+        <P as StreamCodec>::ReadError: Into<ReadPacketError>,
+    {
+        let shared_secret = self
+            .shared_secrets
+            .get(&sealed.connection_id())
+            .ok_or_else(|| "(no shared secret)".to_string())?;
+        // Either end may have recorded the capture, so accept a packet sealed by either role.
+        sealed
+            .open(Role::Client, shared_secret)
+            .or_else(|_| sealed.open(Role::Server, shared_secret))
+            .map_err(|e| format!("(unable to open: {e})"))
+    }
+
+    fn connection(&mut self, connection_id: ConnectionId) -> &mut ConnectionState {
+        self.connections
+            .entry(connection_id)
+            .or_insert_with(ConnectionState::new)
+    }
+
+    fn reassemble(
+        &mut self,
+        connection_id: ConnectionId,
+        direction: Direction,
+        fragment: FragmentPacket,
+        now: Instant,
+    ) -> Vec<String> {
+        let connection = self.connection(connection_id);
+        let reassembler = match direction {
+            Direction::Received => &mut connection.received_reassembler,
+            Direction::Sent => &mut connection.sent_reassembler,
+        };
+        match reassembler.insert(fragment, now) {
+            Ok(Some(packet)) => {
+                let (summary, details) = self.describe_game_data(connection_id, packet);
+                let mut lines = vec![format!("  Reassembled GameData: {summary}")];
+                lines.extend(details.into_iter().map(|line| format!("  {line}")));
+                lines
+            }
+            Ok(None) => Vec::new(),
+            Err(e) => vec![format!("  Unable to reassemble: {e}")],
+        }
+    }
+
+    /// Returns a summary of a GameData packet and lines describing the session packet it carries.
+    fn describe_game_data(
+        &mut self,
+        connection_id: ConnectionId,
+        packet: GameDataPacket,
+    ) -> (String, Vec<String>) {
+        let (summary, data) = match packet {
+            GameDataPacket::Unreliable(data) => ("Unreliable".to_string(), data),
+            GameDataPacket::ReliableUnordered { sequence, data } => {
+                (format!("ReliableUnordered #{sequence}"), data)
+            }
+            GameDataPacket::ReliableOrdered { sequence, data } => {
+                (format!("ReliableOrdered #{sequence}"), data)
+            }
+        };
+        let details = self
+            .describe_session_packet(connection_id, &data)
+            .into_iter()
+            .map(|line| format!("  {line}"))
+            .collect();
+        (summary, details)
+    }
+
+    fn describe_session_packet(&mut self, connection_id: ConnectionId, data: &[u8]) -> Vec<String> {
+        let mut r = data;
+        let packet = match SessionPacket::read_from(&mut r) {
+            Ok(packet) => packet,
+            Err(e) => return vec![format!("Malformed session packet: {e}")],
+        };
+        match packet {
+            SessionPacket::Ping(packet) => vec![format!(
+                "Ping client time {}",
+                packet.client_time.as_nanos_since_epoch(),
+            )],
+            SessionPacket::Pong(packet) => vec![format!(
+                "Pong client time {}, server time {}, last completed tick {}, tick interval {}s",
+                packet.client_time.as_nanos_since_epoch(),
+                packet.server_time.as_nanos_since_epoch(),
+                packet.server_last_completed_tick.0,
+                packet.server_tick_interval.as_secs_f64(),
+            )],
+            SessionPacket::GameState(packet) => {
+                let connection = self.connection(connection_id);
+                let empty = Snapshot::default();
+                let baseline = match packet.baseline_tick_id {
+                    Some(tick_id) => connection.snapshots.get(tick_id),
+                    None => Some(&empty),
+                };
+                let mut lines = vec![format!(
+                    "GameState tick {}{}",
+                    packet.tick_id.0,
+                    match packet.baseline_tick_id {
+                        Some(tick_id) => format!(", delta from tick {}", tick_id.0),
+                        None => String::new(),
+                    },
+                )];
+                let baseline = match baseline {
+                    Some(baseline) => baseline,
+                    None => {
+                        lines.push("  Baseline not captured".to_string());
+                        return lines;
+                    }
+                };
+                match read_snapshot(&mut &packet.serialized_game_state[..], baseline) {
+                    Ok(snapshot) => {
+                        lines.extend(snapshot.entities.iter().map(|(&net_id, entity)| {
+                            format!("  {}", describe_entity(net_id, entity))
+                        }));
+                        connection.snapshots.insert(packet.tick_id, snapshot);
+                    }
+                    Err(e) => lines.push(format!("  Malformed snapshot: {e}")),
+                }
+                lines
+            }
+            SessionPacket::Voice(packet) => vec![format!("Voice {} bytes", packet.data.len())],
+            SessionPacket::PlayerAssignment(packet) => {
+                vec![format!("PlayerAssignment {}", packet.player_id)]
+            }
+            SessionPacket::CommitActions(packet) => {
+                let mut lines = vec!["CommitActions".to_string()];
+                for (tick_id, actions) in &packet.actions_by_tick_id {
+                    lines.push(format!("  Tick {}: {actions:?}", tick_id.0));
+                }
+                lines
+            }
+            SessionPacket::UpdateOwnedTransforms(packet) => {
+                let mut lines = vec![format!(
+                    "UpdateOwnedTransforms after tick {}",
+                    packet.after_tick_id.0,
+                )];
+                let mut transforms: Vec<_> = packet.transforms_by_net_id.iter().collect();
+                transforms.sort_by_key(|&(&net_id, _)| net_id);
+                for (net_id, transform) in transforms {
+                    let v = transform.translation.vector;
+                    lines.push(format!(
+                        "  Entity {} at ({:.3}, {:.3}, {:.3})",
+                        net_id.0, v.x, v.y, v.z,
+                    ));
+                }
+                lines
+            }
+            SessionPacket::AckGameState(packet) => {
+                vec![format!("AckGameState tick {}", packet.tick_id.0)]
+            }
+            SessionPacket::Hello(packet) => vec![if packet.resumption_token.is_some() {
+                "Hello, resuming a session".to_string()
+            } else {
+                "Hello".to_string()
+            }],
+        }
+    }
+}
+
+fn describe_sealed<P>(sealed: &Sealed<P>) -> String {
+    format!(
+        "connection {:016x} #{}",
+        sealed.connection_id().0,
+        sealed.sequence(),
+    )
+}
+
+fn describe_entity(net_id: NetId, entity: &EntitySnapshot) -> String {
+    let mut description = format!("Entity {}: {:?}", net_id.0, entity.authority);
+    if let Some(transform) = &entity.transform {
+        let v = transform.translation.vector;
+        description += &format!(", at ({:.3}, {:.3}, {:.3})", v.x, v.y, v.z);
+    }
+    if let Some(model_name) = &entity.model_name {
+        description += &format!(", model {model_name}");
+    }
+    if let Some(physics) = &entity.physics {
+        description += &format!(", collider {} {:?}", physics.collider_name, physics.mode);
+    }
+    if let Some(hand) = &entity.hand {
+        description += &format!(", hand {} {:?}", hand.index, hand.grab_state);
+    }
+    if entity.grabbed == Some(true) {
+        description += ", grabbed";
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::num::NonZeroU8;
+    use std::time::Duration;
+
+    use dungeon_vr_connection_shared::fragment::Fragmenter;
+    use dungeon_vr_connection_shared::keepalive_packet::KeepalivePacket;
+    use dungeon_vr_connection_shared::packet::Packet;
+    use dungeon_vr_connection_shared::reliable::GameDataPacket;
+    use dungeon_vr_connection_shared::sealed::{Role, SealingKey};
+    use dungeon_vr_cryptography::SharedSecret;
+    use dungeon_vr_session_shared::packet::player_assignment_packet::PlayerAssignmentPacket;
+    use dungeon_vr_session_shared::packet::voice_packet::VoicePacket;
+    use dungeon_vr_session_shared::packet::Packet as SessionPacket;
+    use dungeon_vr_session_shared::{PlayerId, ResumptionToken};
+    use dungeon_vr_socket::capture::{CaptureRecord, Direction};
+    use dungeon_vr_stream_codec::StreamCodec;
+
+    use super::Dissector;
+
+    fn record(packet: Packet) -> CaptureRecord<SocketAddr> {
+        let mut data = Vec::new();
+        packet.write_to(&mut data).unwrap();
+        CaptureRecord {
+            timestamp: Duration::ZERO,
+            direction: Direction::Received,
+            addr: Some("127.0.0.1:7777".parse().unwrap()),
+            data,
+        }
+    }
+
+    fn game_data(packet: SessionPacket) -> GameDataPacket {
+        let mut data = Vec::new();
+        packet.write_to(&mut data).unwrap();
+        GameDataPacket::ReliableOrdered { sequence: 0, data }
+    }
+
+    #[test]
+    fn sealed_packets_need_the_shared_secret() {
+        let mut key = SealingKey::new(SharedSecret::gen(), Role::Client);
+        let keepalive = record(Packet::Keepalive(key.seal(KeepalivePacket::Ping { id: 7 })));
+        let connection_id = key.connection_id().0;
+
+        assert_eq!(
+            Dissector::new([]).dissect(&keepalive),
+            [format!(
+                "Keepalive connection {connection_id:016x} #0 (no shared secret)"
+            )],
+        );
+        assert_eq!(
+            Dissector::new([*key.shared_secret()]).dissect(&keepalive),
+            [format!(
+                "Keepalive connection {connection_id:016x} #0: Ping {{ id: 7 }}"
+            )],
+        );
+    }
+
+    #[test]
+    fn game_data_shows_session_packets() {
+        let mut key = SealingKey::new(SharedSecret::gen(), Role::Client);
+        let mut dissector = Dissector::new([*key.shared_secret()]);
+        let packets = Fragmenter::new(1200)
+            .seal(
+                game_data(SessionPacket::PlayerAssignment(PlayerAssignmentPacket {
+                    player_id: PlayerId(NonZeroU8::new(3).unwrap()),
+                    resumption_token: ResumptionToken([0; 16]),
+                })),
+                &mut key,
+            )
+            .unwrap();
+        let connection_id = key.connection_id().0;
+
+        assert_eq!(
+            dissector.dissect(&record(packets.into_iter().next().unwrap())),
+            [
+                format!("GameData connection {connection_id:016x} #0: ReliableOrdered #0"),
+                "  PlayerAssignment Player 3".to_string(),
+            ],
+        );
+    }
+
+    #[test]
+    fn fragments_are_reassembled() {
+        let mut key = SealingKey::new(SharedSecret::gen(), Role::Client);
+        let mut dissector = Dissector::new([*key.shared_secret()]);
+        let packets = Fragmenter::new(200)
+            .seal(
+                game_data(SessionPacket::Voice(VoicePacket { data: vec![0; 500] })),
+                &mut key,
+            )
+            .unwrap();
+        assert_eq!(packets.len(), 4);
+
+        let lines: Vec<_> = packets
+            .into_iter()
+            .flat_map(|packet| dissector.dissect(&record(packet)))
+            .collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[3].ends_with(": 4 of 4 in message 0"));
+        assert_eq!(
+            lines[4..],
+            [
+                "  Reassembled GameData: ReliableOrdered #0".to_string(),
+                "    Voice 500 bytes".to_string(),
+            ],
+        );
+    }
+}
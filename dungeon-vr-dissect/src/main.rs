@@ -0,0 +1,78 @@
+use std::fs::{self, File};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+use dungeon_vr_connection_shared::keylog::{parse_key_log, parse_shared_secret, KEY_LOG_ENV_VAR};
+use dungeon_vr_cryptography::SharedSecret;
+use dungeon_vr_socket::capture::{read_capture, Direction};
+
+use crate::dissect::Dissector;
+
+mod dissect;
+
+/// Decodes a packet capture written with --capture, opening sealed packets for any session whose
+/// shared secret is known.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    /// The pcap file to decode.
+    capture: PathBuf,
+
+    /// A hex-encoded shared secret to open sealed packets with. May be repeated.
+    #[clap(long, value_parser = parse_shared_secret_arg)]
+    shared_secret: Vec<SharedSecret>,
+
+    /// A key log written by a client or server run with the DUNGEON_VR_KEYLOGFILE environment
+    /// variable set.
+    #[clap(long)]
+    key_log: Option<PathBuf>,
+}
+
+fn parse_shared_secret_arg(s: &str) -> Result<SharedSecret, String> {
+    parse_shared_secret(s).ok_or_else(|| {
+        format!(
+            "expected {} hex digits, as found in a {KEY_LOG_ENV_VAR} key log",
+            2 * SharedSecret::SIZE,
+        )
+    })
+}
+
+pub fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut shared_secrets = args.shared_secret;
+    if let Some(path) = &args.key_log {
+        shared_secrets.extend(parse_key_log(&fs::read_to_string(path)?)?);
+    }
+    let records = read_capture::<SocketAddr>(File::open(&args.capture)?)?;
+
+    let mut dissector = Dissector::new(shared_secrets);
+    let first_timestamp = records
+        .first()
+        .map(|record| record.timestamp)
+        .unwrap_or_default();
+    for record in &records {
+        let lines = dissector.dissect(record);
+        let time = record.timestamp.saturating_sub(first_timestamp);
+        let direction = match record.direction {
+            Direction::Received => "<-",
+            Direction::Sent => "->",
+        };
+        let peer = match record.addr {
+            Some(addr) => addr.to_string(),
+            None => "peer".to_string(),
+        };
+        println!(
+            "{:12.6} {direction} {peer} {}",
+            time.as_secs_f64(),
+            lines[0],
+        );
+        for line in &lines[1..] {
+            println!("    {line}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! A client that plays without VR input, for tests and bots. Its owner decides where the hands go
+//! and which actions to take; the client keeps pace with the session's ticks and reports
+//! transforms for the entities it has authority over.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem::take;
+use std::time::Duration;
+
+use dungeon_vr_session_shared::action::Action;
+use dungeon_vr_session_shared::core::{Authority, NetId};
+use dungeon_vr_session_shared::interaction::HandGrabState;
+use dungeon_vr_session_shared::snapshot::{EntitySnapshot, Snapshot};
+use dungeon_vr_session_shared::{PlayerId, TickId, TICK_INTERVAL};
+use rapier3d::prelude::*;
+use tokio::time::Instant;
+
+use crate::{Event, Request, SessionClient};
+
+/// Each tick, commits queued actions and reports transforms for the player's hands, which are
+/// wherever the owner puts them, and for anything the hands hold.
+pub struct HeadlessClient {
+    session: SessionClient,
+    player_id: Option<PlayerId>,
+    /// The next tick to commit actions for, and when to commit them, once a session has started.
+    next_tick: Option<(TickId, Instant)>,
+    queued_actions: Vec<Action>,
+    /// Where each hand is.
+    pub hand_transforms: [Isometry<f32>; 2],
+    latest: Option<(TickId, Snapshot)>,
+}
+
+impl HeadlessClient {
+    pub fn new(session: SessionClient) -> Self {
+        Self {
+            session,
+            player_id: None,
+            next_tick: None,
+            queued_actions: Vec::new(),
+            hand_transforms: [Isometry::identity(); 2],
+            latest: None,
+        }
+    }
+
+    pub fn session(&self) -> &SessionClient {
+        &self.session
+    }
+
+    /// The local player, once a session has started.
+    pub fn player_id(&self) -> Option<PlayerId> {
+        self.player_id
+    }
+
+    /// The most recent snapshot received from the server.
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.latest.as_ref().map(|(_, snapshot)| snapshot)
+    }
+
+    /// Finds a player's hand in the latest snapshot.
+    pub fn hand(&self, player_id: PlayerId, index: usize) -> Option<(NetId, &EntitySnapshot)> {
+        self.snapshot()?
+            .entities
+            .iter()
+            .find(|(_, entity)| {
+                entity.authority == Authority::Player(player_id)
+                    && entity.hand.map(|hand| hand.index) == Some(index)
+            })
+            .map(|(&net_id, entity)| (net_id, entity))
+    }
+
+    /// Commits `action` with the next tick that comes due.
+    pub fn queue_action(&mut self, action: Action) {
+        self.queued_actions.push(action);
+    }
+
+    /// Waits for a session to start, applying events along the way.
+    pub async fn wait_for_start(&mut self) -> PlayerId {
+        loop {
+            let event = self.session.recv_event().await;
+            let started = matches!(event, Event::Start { .. });
+            self.handle_event(event);
+            if started {
+                return self.player_id.unwrap();
+            }
+        }
+    }
+
+    /// Applies every event the session has delivered, showing each to `observe` first.
+    pub fn recv_events(&mut self, mut observe: impl FnMut(&Event)) {
+        while let Some(event) = self.session.try_recv_event() {
+            observe(&event);
+            self.handle_event(event);
+        }
+    }
+
+    /// Commits every tick that has come due and reports owned transforms. Does nothing until a
+    /// session has started.
+    pub fn commit(&mut self) {
+        let (mut next_tick_id, mut next_tick_time) = match self.next_tick {
+            Some(next_tick) => next_tick,
+            None => return,
+        };
+
+        // Like the game, commit every tick that has come due, catching up if this client hasn't
+        // been ticked for a while.
+        let mut actions_by_tick_id = BTreeMap::new();
+        let now = Instant::now();
+        while next_tick_time <= now {
+            actions_by_tick_id.insert(next_tick_id, take(&mut self.queued_actions));
+            next_tick_id = next_tick_id.next();
+            next_tick_time += Duration::try_from(TICK_INTERVAL).unwrap();
+        }
+        self.next_tick = Some((next_tick_id, next_tick_time));
+        // The session may have ended, so sends are best effort.
+        if !actions_by_tick_id.is_empty() {
+            let _ = self
+                .session
+                .try_send_request(Request::CommitActions(actions_by_tick_id));
+        }
+
+        let owned_transforms = self.owned_transforms();
+        if !owned_transforms.is_empty() {
+            let _ = self
+                .session
+                .try_send_request(Request::UpdateOwnedTransforms(owned_transforms));
+        }
+    }
+
+    /// Applies pending events, then commits.
+    pub fn tick(&mut self) {
+        self.recv_events(|_| ());
+        self.commit();
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Start {
+                local_player_id,
+                tick_id,
+                ..
+            } => {
+                self.player_id = Some(local_player_id);
+                self.next_tick = Some((tick_id.next(), Instant::now()));
+                self.queued_actions.clear();
+                self.latest = None;
+            }
+            Event::Snapshot {
+                tick_id, snapshot, ..
+            } => {
+                if !matches!(self.latest, Some((latest, _)) if tick_id <= latest) {
+                    self.latest = Some((tick_id, snapshot));
+                }
+            }
+            Event::Voice(_) | Event::ConnectionStats(_) => (),
+        }
+    }
+
+    fn owned_transforms(&self) -> HashMap<NetId, Isometry<f32>> {
+        let mut transforms = HashMap::new();
+        let (player_id, snapshot) = match (self.player_id, &self.latest) {
+            (Some(player_id), Some((_, snapshot))) => (player_id, snapshot),
+            _ => return transforms,
+        };
+        for (&net_id, entity) in &snapshot.entities {
+            if entity.authority != Authority::Player(player_id) {
+                continue;
+            }
+            if let Some(hand) = entity.hand {
+                let transform = self.hand_transforms[hand.index];
+                transforms.insert(net_id, transform);
+                if let HandGrabState::Grabbing(target) = hand.grab_state {
+                    transforms.insert(target, transform);
+                }
+            }
+        }
+        transforms
+    }
+}
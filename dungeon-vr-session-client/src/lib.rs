@@ -2,26 +2,34 @@ use std::collections::{BTreeMap, HashMap};
 use std::future::pending;
 
 use dungeon_vr_connection_client::{
-    ConnectionState, Event as ConnectionEvent, Request as ConnectionRequest,
+    Channel, ConnectionState, ConnectionStats, Event as ConnectionEvent,
+    Request as ConnectionRequest,
 };
 use dungeon_vr_session_shared::action::Action;
 use dungeon_vr_session_shared::core::NetId;
+use dungeon_vr_session_shared::packet::ack_game_state_packet::AckGameStatePacket;
 use dungeon_vr_session_shared::packet::commit_actions_packet::CommitActionsPacket;
 use dungeon_vr_session_shared::packet::game_state_packet::GameStatePacket;
+use dungeon_vr_session_shared::packet::hello_packet::HelloPacket;
 use dungeon_vr_session_shared::packet::ping_packet::PingPacket;
 use dungeon_vr_session_shared::packet::player_assignment_packet::PlayerAssignmentPacket;
 use dungeon_vr_session_shared::packet::pong_packet::PongPacket;
 use dungeon_vr_session_shared::packet::update_owned_transforms_packet::UpdateOwnedTransformsPacket;
 use dungeon_vr_session_shared::packet::voice_packet::VoicePacket;
 use dungeon_vr_session_shared::packet::Packet;
+use dungeon_vr_session_shared::snapshot::{read_snapshot, Snapshot, SnapshotHistory};
 use dungeon_vr_session_shared::time::{ClientTime, ClientTokioEpoch, NanoDuration, TokioEpoch};
-use dungeon_vr_session_shared::{PlayerId, TickId};
+use dungeon_vr_session_shared::{PlayerId, ResumptionToken, TickId};
 use dungeon_vr_stream_codec::StreamCodec;
 use rapier3d::prelude::*;
 use tokio::select;
 use tokio::sync::mpsc;
 use tokio::time::sleep_until;
 
+pub mod headless;
+#[cfg(test)]
+mod tests;
+
 const EVENT_BUFFER_SIZE: usize = 256;
 const REQUEST_BUFFER_SIZE: usize = 256;
 const PING_INTERVAL: NanoDuration = NanoDuration::from_nanos(100_000_000);
@@ -85,7 +93,15 @@ struct InnerClient {
     events: mpsc::Sender<Event>,
     requests: mpsc::Receiver<Request>,
     epoch: ClientTokioEpoch,
+    /// The protocol version negotiated by the connection layer, once known.
+    protocol_version: Option<u16>,
+    /// The token from the most recent player assignment, presented to resume the same player
+    /// after reconnecting.
+    resumption_token: Option<ResumptionToken>,
+    /// Whether the connection has already signaled Dropped.
+    connection_dropped: bool,
     state: State,
+    snapshots: SnapshotHistory,
 }
 
 #[derive(Debug)]
@@ -126,22 +142,30 @@ impl State {
 }
 
 pub enum Event {
+    /// The session is running. Sent again after the connection recovers from an outage, possibly
+    /// with a different player ID if the server could not hold the old one.
     Start {
         local_player_id: PlayerId,
         tick_id: TickId,
+        /// The protocol version negotiated with the server.
+        protocol_version: u16,
     },
     Snapshot {
         tick_id: TickId,
         tick_interval: NanoDuration,
-        data: Vec<u8>,
+        snapshot: Snapshot,
     },
     Voice(Vec<u8>),
+    /// Answers a [`Request::QueryConnectionStats`].
+    ConnectionStats(ConnectionStats),
 }
 
 pub enum Request {
     SendVoice(Vec<u8>),
     CommitActions(BTreeMap<TickId, Vec<Action>>),
     UpdateOwnedTransforms(HashMap<NetId, Isometry<f32>>),
+    /// Asks for an [`Event::ConnectionStats`] describing the link to the server.
+    QueryConnectionStats,
 }
 
 impl InnerClient {
@@ -160,7 +184,11 @@ impl InnerClient {
             events,
             requests,
             epoch: TokioEpoch::new(),
+            protocol_version: None,
+            resumption_token: None,
+            connection_dropped: false,
             state: State::AwaitingConnection,
+            snapshots: SnapshotHistory::new(),
         }
     }
 
@@ -188,12 +216,13 @@ impl InnerClient {
         }
 
         // Wait for the connection to drop.
-        loop {
+        while !self.connection_dropped {
             match self.connection_events.recv().await {
                 Some(ConnectionEvent::Dropped) => break,
                 Some(_) => (),
                 None => {
                     log::error!("Connection closed its event channel before signaling Dropped");
+                    break;
                 }
             }
         }
@@ -201,21 +230,51 @@ impl InnerClient {
 
     async fn handle_connection_event(&mut self, event: Option<ConnectionEvent>) {
         match event.unwrap() {
-            ConnectionEvent::State(state) => self.handle_connection_state(state),
+            ConnectionEvent::State(state) => self.handle_connection_state(state).await,
+            ConnectionEvent::Negotiated { protocol_version } => {
+                log::info!("Negotiated protocol version {protocol_version}");
+                self.protocol_version = Some(protocol_version);
+            }
+            ConnectionEvent::Rejected(reason) => {
+                log::error!("Server rejected the connection: {reason}");
+                self.cancel_token.cancel();
+            }
+            ConnectionEvent::DisconnectReason(reason) => {
+                log::info!("Server disconnected: {reason}");
+            }
             ConnectionEvent::GameData(data) => self.handle_connection_game_data(data).await,
+            ConnectionEvent::Stats(stats) => {
+                let _ = self.events.send(Event::ConnectionStats(stats)).await;
+            }
             ConnectionEvent::Dropped => self.handle_connection_dropped(),
         }
     }
 
-    fn handle_connection_state(&mut self, state: ConnectionState) {
+    async fn handle_connection_state(&mut self, state: ConnectionState) {
         match state {
             ConnectionState::Disconnected => {
                 self.cancel_token.cancel();
             }
-            ConnectionState::Connecting => (),
+            ConnectionState::Connecting => {
+                // The connection falls back to Connecting when it times out and tries to
+                // reconnect. Start over and resume once it's back.
+                if !matches!(self.state, State::AwaitingConnection) {
+                    log::info!("Session state: reconnecting");
+                    self.state = State::AwaitingConnection;
+                    self.snapshots = SnapshotHistory::new();
+                }
+            }
             ConnectionState::Responding => (),
             ConnectionState::Connected => {
                 assert!(matches!(self.state, State::AwaitingConnection));
+                send_packet(
+                    &self.connection_requests,
+                    Channel::ReliableOrdered,
+                    Packet::Hello(HelloPacket {
+                        resumption_token: self.resumption_token,
+                    }),
+                )
+                .await;
                 log::info!("Session state: measuring ping");
                 self.state = State::MeasuringPing {
                     local_player_id: None,
@@ -276,19 +335,61 @@ impl InnerClient {
     }
 
     async fn handle_game_state_packet(&mut self, packet: GameStatePacket) {
-        if matches!(self.state, State::Running) {
-            send_event(
-                &self.events,
-                Event::Snapshot {
-                    tick_id: packet.tick_id,
-                    tick_interval: packet.tick_interval,
-                    data: packet.serialized_game_state,
-                },
-            )
-            .await;
-        } else {
+        if !matches!(self.state, State::Running) {
             log::warn!("Dropping unexpected game state packet");
+            return;
         }
+
+        let empty_baseline = Snapshot::default();
+        let baseline = match packet.baseline_tick_id {
+            Some(baseline_tick_id) => match self.snapshots.get(baseline_tick_id) {
+                Some(baseline) => baseline,
+                None => {
+                    log::warn!(
+                        "Dropping game state packet for tick {}: unknown baseline tick {}",
+                        packet.tick_id.0,
+                        baseline_tick_id.0,
+                    );
+                    return;
+                }
+            },
+            None => &empty_baseline,
+        };
+        let mut r = packet.serialized_game_state.as_slice();
+        let snapshot = match read_snapshot(&mut r, baseline) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                log::error!("Error decoding snapshot for tick {}: {e}", packet.tick_id.0);
+                return;
+            }
+        };
+        if !r.is_empty() {
+            log::error!(
+                "Dropping snapshot for tick {}: {} unexpected trailing byte(s)",
+                packet.tick_id.0,
+                r.len(),
+            );
+            return;
+        }
+        self.snapshots.insert(packet.tick_id, snapshot.clone());
+
+        send_packet(
+            &self.connection_requests,
+            Channel::Unreliable,
+            Packet::AckGameState(AckGameStatePacket {
+                tick_id: packet.tick_id,
+            }),
+        )
+        .await;
+        send_event(
+            &self.events,
+            Event::Snapshot {
+                tick_id: packet.tick_id,
+                tick_interval: packet.tick_interval,
+                snapshot,
+            },
+        )
+        .await;
     }
 
     async fn handle_voice_packet(&mut self, packet: VoicePacket) {
@@ -303,11 +404,14 @@ impl InnerClient {
         {
             log::info!("Accepted player assignment: {}", packet.player_id);
             *local_player_id = Some(packet.player_id);
+            self.resumption_token = Some(packet.resumption_token);
         }
     }
 
     fn handle_connection_dropped(&mut self) {
-        todo!()
+        log::info!("Connection dropped");
+        self.connection_dropped = true;
+        self.cancel_token.cancel();
     }
 
     async fn handle_state_event(&mut self, event: StateEvent) {
@@ -320,8 +424,10 @@ impl InnerClient {
                 server_last_completed_tick,
                 server_tick_interval,
             } => match event {
-                StateEvent::PingElapsed => {
-                    if rtt_samples.len() >= PING_SAMPLES {
+                StateEvent::PingElapsed => match *local_player_id {
+                    // The player assignment travels on a reliable channel and may be held up by
+                    // retransmissions, so keep pinging until it arrives.
+                    Some(local_player_id) if rtt_samples.len() >= PING_SAMPLES => {
                         let server_tick_interval = server_tick_interval.unwrap();
 
                         // Compute an RTT estimate, add a fixed amount of slack, and convert that to
@@ -346,27 +452,30 @@ impl InnerClient {
                         send_event(
                             &self.events,
                             Event::Start {
-                                local_player_id: local_player_id.unwrap(),
+                                local_player_id,
                                 tick_id,
+                                protocol_version: self.protocol_version.unwrap(),
                             },
                         )
                         .await;
 
                         log::info!("Session state: running");
                         self.state = State::Running;
-                    } else {
-                        // Need more samples. There may already be enough packets in flight, but
-                        // send another ping.
+                    }
+                    _ => {
+                        // Need more samples or the player assignment. There may already be enough
+                        // packets in flight, but send another ping.
                         *next_ping_time += PING_INTERVAL;
                         send_packet(
                             &self.connection_requests,
+                            Channel::Unreliable,
                             Packet::Ping(PingPacket {
                                 client_time: self.epoch.now(),
                             }),
                         )
                         .await;
                     }
-                }
+                },
             },
             State::Running => unreachable!(),
         }
@@ -377,16 +486,17 @@ impl InnerClient {
             Request::SendVoice(data) => {
                 send_packet(
                     &self.connection_requests,
+                    Channel::Unreliable,
                     Packet::Voice(VoicePacket { data }),
                 )
                 .await;
             }
             Request::CommitActions(actions_by_tick_id) => {
-                // TODO: Record committed actions and send them redundantly until acknowledged by
-                // the server. This is the simplest thing that can work, but it drops inputs on a
-                // single lost packet.
+                // Each commit is keyed by tick, so the server doesn't care what order they arrive
+                // in, but losing one would drop input.
                 send_packet(
                     &self.connection_requests,
+                    Channel::ReliableUnordered,
                     Packet::CommitActions(CommitActionsPacket { actions_by_tick_id }),
                 )
                 .await;
@@ -394,6 +504,7 @@ impl InnerClient {
             Request::UpdateOwnedTransforms(transforms_by_net_id) => {
                 send_packet(
                     &self.connection_requests,
+                    Channel::Unreliable,
                     Packet::UpdateOwnedTransforms(UpdateOwnedTransformsPacket {
                         after_tick_id: TickId(0), // TODO
                         transforms_by_net_id,
@@ -401,6 +512,12 @@ impl InnerClient {
                 )
                 .await;
             }
+            Request::QueryConnectionStats => {
+                let _ = self
+                    .connection_requests
+                    .send(ConnectionRequest::QueryStats)
+                    .await;
+            }
         }
     }
 }
@@ -409,8 +526,10 @@ async fn send_event(events: &mpsc::Sender<Event>, event: Event) {
     let _ = events.send(event).await;
 }
 
-async fn send_packet(requests: &mpsc::Sender<ConnectionRequest>, packet: Packet) {
+async fn send_packet(requests: &mpsc::Sender<ConnectionRequest>, channel: Channel, packet: Packet) {
     let mut data = Vec::new();
     packet.write_to(&mut data).unwrap();
-    let _ = requests.send(ConnectionRequest::SendGameData(data)).await;
+    let _ = requests
+        .send(ConnectionRequest::SendGameData { channel, data })
+        .await;
 }
@@ -0,0 +1,94 @@
+use std::num::NonZeroU8;
+
+use dungeon_vr_connection_client::{
+    Channel, ConnectionState, Event as ConnectionEvent, Request as ConnectionRequest,
+};
+use dungeon_vr_session_shared::packet::player_assignment_packet::PlayerAssignmentPacket;
+use dungeon_vr_session_shared::packet::pong_packet::PongPacket;
+use dungeon_vr_session_shared::packet::Packet;
+use dungeon_vr_session_shared::time::{NanoDuration, ServerTime};
+use dungeon_vr_session_shared::{PlayerId, ResumptionToken, TickId};
+use dungeon_vr_stream_codec::StreamCodec;
+use tokio::sync::mpsc;
+
+use crate::{Event, SessionClient, PING_SAMPLES};
+
+/// Stands in for the connection layer and the server behind it.
+struct FakeServer {
+    connection_requests: mpsc::Receiver<ConnectionRequest>,
+    connection_events: mpsc::Sender<ConnectionEvent>,
+}
+
+impl FakeServer {
+    async fn send(&self, event: ConnectionEvent) {
+        self.connection_events.send(event).await.unwrap();
+    }
+
+    async fn send_packet(&self, packet: Packet) {
+        let mut data = Vec::new();
+        packet.write_to(&mut data).unwrap();
+        self.send(ConnectionEvent::GameData(data)).await;
+    }
+
+    /// Answers the client's next ping.
+    async fn pong(&mut self) {
+        loop {
+            let data = match self.connection_requests.recv().await.unwrap() {
+                ConnectionRequest::SendGameData {
+                    channel: Channel::Unreliable,
+                    data,
+                } => data,
+                _ => continue,
+            };
+            if let Packet::Ping(ping) = Packet::read_from(&mut data.as_slice()).unwrap() {
+                self.send_packet(Packet::Pong(PongPacket {
+                    client_time: ping.client_time,
+                    server_time: ServerTime::from_nanos_since_epoch(0),
+                    server_last_completed_tick: TickId(0),
+                    server_tick_interval: NanoDuration::from_nanos(50_000_000),
+                }))
+                .await;
+                return;
+            }
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn start_should_wait_for_a_delayed_player_assignment() {
+    let (connection_requests_tx, connection_requests_rx) = mpsc::channel(256);
+    let (connection_events_tx, connection_events_rx) = mpsc::channel(256);
+    let mut client = SessionClient::new(connection_requests_tx, connection_events_rx);
+    let mut server = FakeServer {
+        connection_requests: connection_requests_rx,
+        connection_events: connection_events_tx,
+    };
+    server
+        .send(ConnectionEvent::Negotiated {
+            protocol_version: 1,
+        })
+        .await;
+    server
+        .send(ConnectionEvent::State(ConnectionState::Connected))
+        .await;
+
+    // Answer well past the usual number of pings with the assignment still in flight.
+    for _ in 0..2 * PING_SAMPLES {
+        server.pong().await;
+    }
+    assert!(client.try_recv_event().is_none());
+
+    let player_id = PlayerId(NonZeroU8::new(3).unwrap());
+    server
+        .send_packet(Packet::PlayerAssignment(PlayerAssignmentPacket {
+            player_id,
+            resumption_token: ResumptionToken([0; 16]),
+        }))
+        .await;
+    match client.recv_event().await {
+        Event::Start {
+            local_player_id, ..
+        } => assert_eq!(local_player_id, player_id),
+        _ => panic!("expected the session to start"),
+    }
+}
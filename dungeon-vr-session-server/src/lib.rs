@@ -1,14 +1,13 @@
-use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::collections::{btree_map, BTreeMap, HashMap};
 use std::f32::consts::FRAC_PI_2;
-use std::future::pending;
 use std::iter::repeat_with;
 use std::num::NonZeroU32;
-use std::pin::Pin;
 use std::time::Duration;
 
 use bevy_ecs::prelude::*;
 use dungeon_vr_connection_server::{
-    ConnectionState, Event as ConnectionEvent, Request as ConnectionRequest,
+    Channel, ConnectionState, ConnectionStats, Event as ConnectionEvent, RejectReason,
+    Request as ConnectionRequest,
 };
 use dungeon_vr_session_shared::action::{apply_actions, Action};
 use dungeon_vr_session_shared::collider_cache::ColliderCache;
@@ -18,8 +17,10 @@ use dungeon_vr_session_shared::core::{
 use dungeon_vr_session_shared::fly_around::fly_around;
 use dungeon_vr_session_shared::fly_around::FlyAroundComponent;
 use dungeon_vr_session_shared::interaction::{GrabbableComponent, HandComponent, HandGrabState};
+use dungeon_vr_session_shared::packet::ack_game_state_packet::AckGameStatePacket;
 use dungeon_vr_session_shared::packet::commit_actions_packet::CommitActionsPacket;
 use dungeon_vr_session_shared::packet::game_state_packet::GameStatePacket;
+use dungeon_vr_session_shared::packet::hello_packet::HelloPacket;
 use dungeon_vr_session_shared::packet::ping_packet::PingPacket;
 use dungeon_vr_session_shared::packet::player_assignment_packet::PlayerAssignmentPacket;
 use dungeon_vr_session_shared::packet::pong_packet::PongPacket;
@@ -32,19 +33,36 @@ use dungeon_vr_session_shared::physics::{
 };
 use dungeon_vr_session_shared::render::RenderComponent;
 use dungeon_vr_session_shared::resources::{AllActionsResource, EntitiesByNetIdResource};
-use dungeon_vr_session_shared::snapshot::write_snapshot;
+use dungeon_vr_session_shared::snapshot::{
+    capture_snapshot, write_snapshot, Snapshot, SnapshotHistory,
+};
 use dungeon_vr_session_shared::time::{NanoDuration, ServerTime, ServerTokioEpoch, TokioEpoch};
-use dungeon_vr_session_shared::{PlayerId, TickId, TICK_INTERVAL};
+use dungeon_vr_session_shared::{PlayerId, ResumptionToken, TickId, TICK_INTERVAL};
 use dungeon_vr_socket::AddrBound;
 use dungeon_vr_stream_codec::StreamCodec;
-use futures::stream::FuturesUnordered;
-use futures::{FutureExt, StreamExt};
 use rapier3d::prelude::*;
 use tokio::select;
 use tokio::sync::mpsc;
-use tokio::time::{interval, sleep_until, Interval};
+use tokio::time::sleep_until;
+
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+mod tests;
+
+/// How many consecutive ticks a player may go without committing actions before being
+/// disconnected. This is ten seconds.
+const IDLE_TICK_LIMIT: u32 = 200;
+
+/// How often to log each player's connection statistics.
+const STATS_LOG_INTERVAL: NanoDuration = NanoDuration::from_nanos(10_000_000_000);
+
+/// How long a client is banned for after sending game data that doesn't decode.
+const MALFORMED_DATA_BAN_DURATION: Duration = Duration::from_secs(60);
 
-const SEND_ASSIGNMENT_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a player whose connection dropped keeps their slot, hands, and held objects while
+/// waiting for them to resume.
+const RESUMPTION_GRACE_PERIOD: NanoDuration = NanoDuration::from_nanos(30_000_000_000);
 
 trait PlayerIdExt {
     fn index(self) -> usize;
@@ -86,7 +104,6 @@ pub struct SessionServer {
 
 enum Event<Addr> {
     Connection(Option<ConnectionEvent<Addr>>),
-    PlayerEvent(PlayerEvent),
     Tick,
 }
 
@@ -95,6 +112,22 @@ impl SessionServer {
         connection_requests: mpsc::Sender<ConnectionRequest<Addr>>,
         connection_events: mpsc::Receiver<ConnectionEvent<Addr>>,
         max_players: usize,
+    ) -> Self {
+        Self::with_collider_cache(
+            connection_requests,
+            connection_events,
+            max_players,
+            ColliderCache::new(),
+        )
+    }
+
+    /// Like [`Self::new`], but builds the world's colliders from `collider_cache`. Pass a cache
+    /// with a placeholder to run without assets.
+    pub fn with_collider_cache<Addr: AddrBound>(
+        connection_requests: mpsc::Sender<ConnectionRequest<Addr>>,
+        connection_events: mpsc::Receiver<ConnectionEvent<Addr>>,
+        max_players: usize,
+        collider_cache: ColliderCache,
     ) -> Self {
         let cancel_token = cancel::Token::new();
         tokio::spawn(
@@ -103,6 +136,7 @@ impl SessionServer {
                 connection_requests,
                 connection_events,
                 max_players,
+                collider_cache,
             )
             .run(),
         );
@@ -118,6 +152,9 @@ struct InnerServer<Addr> {
     connection_events: mpsc::Receiver<ConnectionEvent<Addr>>,
     clients: HashMap<Addr, ClientState>,
     players: Vec<Option<PlayerState<Addr>>>,
+    /// Players whose connections dropped, held until they resume or the grace period runs out.
+    /// Their slots stay reserved and their entities stay in the world.
+    suspended_players: HashMap<PlayerId, SuspendedPlayer>,
     epoch: ServerTokioEpoch,
     world: World,
     tick_schedule: Schedule,
@@ -126,17 +163,31 @@ struct InnerServer<Addr> {
     last_completed_tick_id: TickId,
     /// When the next tick is scheduled.
     next_tick_time: ServerTime,
+    /// When to next log connection statistics.
+    next_stats_time: ServerTime,
+    /// Recently sent snapshots, retained as baselines for delta encoding.
+    snapshots: SnapshotHistory,
 }
 
 struct ClientState {
     player_id: Option<PlayerId>,
+    /// The protocol version negotiated by the connection layer.
+    protocol_version: u16,
+    /// Whether the server asked for this client to be disconnected. Kicked players are not held
+    /// for resumption.
+    kicked: bool,
 }
 
 struct PlayerState<Addr> {
     addr: Addr,
-    send_assignment: Option<Pin<Box<Interval>>>,
+    /// The token the player can present in a Hello to resume this slot after reconnecting.
+    resumption_token: ResumptionToken,
     committed_actions_by_tick_id: BTreeMap<TickId, CommittedActions>,
     slack_estimate_nanoseconds: f64,
+    /// The most recent tick whose snapshot this player has acknowledged.
+    acked_tick_id: Option<TickId>,
+    /// How many ticks in a row have passed without actions from this player.
+    missed_ticks: u32,
 }
 
 impl<Addr> PlayerState<Addr> {
@@ -146,6 +197,11 @@ impl<Addr> PlayerState<Addr> {
     }
 }
 
+struct SuspendedPlayer {
+    resumption_token: ResumptionToken,
+    expires_at: ServerTime,
+}
+
 struct CommittedActions {
     slack: NanoDuration,
     actions: Vec<Action>,
@@ -171,6 +227,7 @@ impl<Addr: AddrBound> InnerServer<Addr> {
         connection_requests: mpsc::Sender<ConnectionRequest<Addr>>,
         connection_events: mpsc::Receiver<ConnectionEvent<Addr>>,
         max_players: usize,
+        collider_cache: ColliderCache,
     ) -> Self {
         let mut world = World::new();
         let mut net_ids = NetIdAllocator::new();
@@ -283,7 +340,7 @@ impl<Addr: AddrBound> InnerServer<Addr> {
         world.insert_resource(PhysicsResource::new(
             RigidBodySet::new(),
             ColliderSet::new(),
-            ColliderCache::new(),
+            collider_cache,
             TICK_INTERVAL.as_secs_f32(),
         ));
         world.insert_resource(entities_by_net_id);
@@ -296,6 +353,7 @@ impl<Addr: AddrBound> InnerServer<Addr> {
             connection_events,
             clients: HashMap::new(),
             players: repeat_with(|| None).take(max_players).collect(),
+            suspended_players: HashMap::new(),
             epoch,
             world,
             tick_schedule: Schedule::default().with_stage(
@@ -335,16 +393,13 @@ impl<Addr: AddrBound> InnerServer<Addr> {
             net_ids,
             last_completed_tick_id: TickId(0),
             next_tick_time: epoch.now() + TICK_INTERVAL,
+            next_stats_time: epoch.now() + STATS_LOG_INTERVAL,
+            snapshots: SnapshotHistory::new(),
         }
     }
 
     async fn run(mut self) {
         while !self.cancel_token.is_cancelled() {
-            let mut dynamic_events = FuturesUnordered::from_iter(
-                iter_players_mut(&mut self.players).map(|(player_id, player)| async move {
-                    Event::PlayerEvent(player.wait_for_event(player_id).await)
-                }),
-            );
             let tick = sleep_until(self.epoch.instant_at(self.next_tick_time));
 
             let event = select! {
@@ -352,15 +407,11 @@ impl<Addr: AddrBound> InnerServer<Addr> {
 
                 event = self.connection_events.recv() => Event::Connection(event),
 
-                Some(event) = dynamic_events.next() => event,
-
                 _ = tick => Event::Tick,
             };
-            drop(dynamic_events);
 
             match event {
                 Event::Connection(event) => self.handle_connection_event(event.unwrap()).await,
-                Event::PlayerEvent(event) => self.handle_player_event(event).await,
                 Event::Tick => self.handle_tick().await,
             }
         }
@@ -368,88 +419,103 @@ impl<Addr: AddrBound> InnerServer<Addr> {
 
     async fn handle_connection_event(&mut self, event: ConnectionEvent<Addr>) {
         match event {
-            ConnectionEvent::State { addr, state } => self.handle_connection_state(addr, state),
+            ConnectionEvent::ConnectRequest {
+                addr,
+                protocol_version,
+            } => {
+                self.handle_connection_connect_request(addr, protocol_version)
+                    .await
+            }
+            ConnectionEvent::State { addr, state } => {
+                self.handle_connection_state(addr, state).await
+            }
             ConnectionEvent::GameData { addr, data } => {
                 self.handle_connection_game_data(addr, data).await
             }
+            ConnectionEvent::Stats { addr, stats } => self.handle_connection_stats(addr, stats),
+            ConnectionEvent::Migrated { old_addr, addr } => {
+                self.handle_connection_migrated(old_addr, addr)
+            }
             ConnectionEvent::Dropped => self.handle_connection_dropped(),
         }
     }
 
-    async fn handle_player_event(&mut self, event: PlayerEvent) {
-        match event {
-            PlayerEvent::SendAssignment { player_id } => {
-                let player = self.players[player_id.index()].as_mut().unwrap();
-                send_game_data(
-                    &self.connection_requests,
-                    player.addr,
-                    Packet::PlayerAssignment(PlayerAssignmentPacket { player_id }),
-                )
-                .await;
+    async fn handle_connection_connect_request(&mut self, addr: Addr, protocol_version: u16) {
+        // Every admitted client is on its way to a player slot, so count them all against the
+        // limit.
+        let request = if self.clients.len() < self.players.len() {
+            let prev = self.clients.insert(
+                addr,
+                ClientState {
+                    player_id: None,
+                    protocol_version,
+                    kicked: false,
+                },
+            );
+            assert!(prev.is_none());
+            ConnectionRequest::Accept { addr }
+        } else {
+            log::info!("Peer {addr} rejected: the server is full");
+            ConnectionRequest::Reject {
+                addr,
+                reason: RejectReason::ServerFull,
             }
-        }
+        };
+        let _ = self.connection_requests.send(request).await;
     }
 
-    fn handle_connection_state(&mut self, addr: Addr, state: ConnectionState) {
+    async fn handle_connection_state(&mut self, addr: Addr, state: ConnectionState) {
         match state {
-            ConnectionState::Disconnected => {
-                let client_entry = match self.clients.entry(addr) {
-                    hash_map::Entry::Occupied(entry) => entry,
-                    _ => unreachable!(),
-                };
+            ConnectionState::Disconnected | ConnectionState::Disconnecting => {
                 // Connections may or may not pass through the Disconnecting state on their way to
-                // Disconnected, so there might still be a player mapping.
-                let player_id = client_entry.get().player_id;
-                if let Some(player_id) = player_id {
+                // Disconnected, so the client might already be gone.
+                let client = match self.clients.remove(&addr) {
+                    Some(client) => client,
+                    None => return,
+                };
+                let player_id = match client.player_id {
+                    Some(player_id) => player_id,
+                    None => return,
+                };
+                let player = self.players[player_id.index()].take().unwrap();
+                if client.kicked {
                     log::info!("{player_id} disconnected");
-                    self.players[player_id.index()] = None;
-                }
-                client_entry.remove();
-                if let Some(player_id) = player_id {
                     self.despawn_player(player_id);
+                } else {
+                    log::info!("{player_id} disconnected; holding their slot for resumption");
+                    self.suspended_players.insert(
+                        player_id,
+                        SuspendedPlayer {
+                            resumption_token: player.resumption_token,
+                            expires_at: self.epoch.now() + RESUMPTION_GRACE_PERIOD,
+                        },
+                    );
                 }
             }
-            ConnectionState::Pending => {
-                let prev = self.clients.insert(addr, ClientState { player_id: None });
-                assert!(prev.is_none());
-            }
+            ConnectionState::Pending => (),
             ConnectionState::Connected => {
-                let client = self.clients.get_mut(&addr).unwrap();
-                match self.players.iter().position(Option::is_none) {
-                    Some(index) => {
-                        let player_id = PlayerId::from_index(index);
-                        log::info!("Peer {addr} connected as {player_id}");
-                        self.players[index] = Some(PlayerState {
-                            addr,
-                            // Prepare to tell this player their player ID assignment repeatedly
-                            // until we process a session packet indicating they got the message.
-                            send_assignment: Some(Box::pin(interval(SEND_ASSIGNMENT_INTERVAL))),
-                            committed_actions_by_tick_id: BTreeMap::new(),
-                            slack_estimate_nanoseconds: 0.0,
-                        });
-                        *client = ClientState {
-                            player_id: Some(player_id),
-                        };
-
-                        self.spawn_player(player_id);
-                    }
-                    None => {
-                        log::info!("Peer {addr} connected, but the server is full");
-                        // TODO: Kick the player? Approve connections before they are made? Just let
-                        // them spectate?
-                    }
-                }
-            }
-            ConnectionState::Disconnecting => {
-                if let Some(player_id) = self.clients[&addr].player_id {
-                    log::info!("{player_id} disconnected");
-                    self.players[player_id.index()] = None;
-                    self.despawn_player(player_id);
-                }
+                // The player is assigned once the client says hello, which is when it gets the
+                // chance to resume an earlier session.
+                log::info!(
+                    "Peer {addr} connected (protocol version {})",
+                    self.clients[&addr].protocol_version,
+                );
             }
         }
     }
 
+    fn handle_connection_migrated(&mut self, old_addr: Addr, addr: Addr) {
+        let client = match self.clients.remove(&old_addr) {
+            Some(client) => client,
+            None => return,
+        };
+        if let Some(player_id) = client.player_id {
+            log::info!("{player_id} moved from {old_addr} to {addr}");
+            self.players[player_id.index()].as_mut().unwrap().addr = addr;
+        }
+        self.clients.insert(addr, client);
+    }
+
     fn spawn_player(&mut self, player_id: PlayerId) {
         log::info!("Spawning hands for {player_id}");
         for index in 0..2 {
@@ -498,15 +564,19 @@ impl<Addr: AddrBound> InnerServer<Addr> {
                 .remove(&net_id);
         }
 
-        // Transfer any other entities owned by the player back to server authority.
+        // Transfer any other entities owned by the player back to server authority. Anything they
+        // were holding is released, since the hands holding it are gone.
         let mut count = 0usize;
-        for mut synchronized in self
+        for (mut synchronized, grabbable) in self
             .world
-            .query::<&mut SynchronizedComponent>()
+            .query::<(&mut SynchronizedComponent, Option<&mut GrabbableComponent>)>()
             .iter_mut(&mut self.world)
         {
             if synchronized.authority == Authority::Player(player_id) {
                 synchronized.authority = Authority::Server;
+                if let Some(mut grabbable) = grabbable {
+                    grabbable.grabbed = false;
+                }
                 count += 1;
             }
         }
@@ -519,6 +589,7 @@ impl<Addr: AddrBound> InnerServer<Addr> {
             Ok(packet) => packet,
             Err(e) => {
                 log::error!("Error decoding game data packet from client {addr}: {e}");
+                self.ban_misbehaving_client(addr).await;
                 return;
             }
         };
@@ -528,9 +599,11 @@ impl<Addr: AddrBound> InnerServer<Addr> {
                 packet.kind(),
                 r.len(),
             );
+            self.ban_misbehaving_client(addr).await;
             return;
         }
         match packet {
+            Packet::Hello(packet) => self.handle_hello_packet(addr, packet).await,
             Packet::Ping(packet) => self.handle_ping_packet(addr, packet).await,
             Packet::Voice(packet) => self.handle_voice_packet(addr, packet).await,
             Packet::CommitActions(packet) => self.handle_commit_actions_packet(addr, packet).await,
@@ -538,16 +611,125 @@ impl<Addr: AddrBound> InnerServer<Addr> {
                 self.handle_update_owned_transforms_packet(addr, packet)
                     .await
             }
+            Packet::AckGameState(packet) => self.handle_ack_game_state_packet(addr, packet),
             _ => {
                 log::error!("Unexpected game data packet: {:?}", packet.kind());
             }
         }
     }
 
+    async fn handle_hello_packet(&mut self, addr: Addr, packet: HelloPacket) {
+        match self.clients.get(&addr) {
+            Some(ClientState {
+                player_id: None, ..
+            }) => (),
+            Some(_) => {
+                log::warn!("Client {addr}: Dropping hello packet: player ID already assigned");
+                return;
+            }
+            None => return,
+        }
+
+        let resumed_player_id = match packet.resumption_token {
+            Some(token) => self.take_resumable_player(token).await,
+            None => None,
+        };
+        let player_id = match resumed_player_id {
+            Some(player_id) => {
+                log::info!("Peer {addr} resumed as {player_id}");
+                player_id
+            }
+            None => {
+                let player_id = self.allocate_player_id();
+                log::info!("Peer {addr} joined as {player_id}");
+                self.spawn_player(player_id);
+                player_id
+            }
+        };
+        // Tokens are single use, so a leaked token can't be replayed after its owner resumes.
+        let resumption_token = ResumptionToken(dungeon_vr_cryptography::random_bytes());
+        self.players[player_id.index()] = Some(PlayerState {
+            addr,
+            resumption_token,
+            committed_actions_by_tick_id: BTreeMap::new(),
+            slack_estimate_nanoseconds: 0.0,
+            acked_tick_id: None,
+            missed_ticks: 0,
+        });
+        self.clients.get_mut(&addr).unwrap().player_id = Some(player_id);
+
+        send_game_data(
+            &self.connection_requests,
+            addr,
+            Channel::ReliableOrdered,
+            Packet::PlayerAssignment(PlayerAssignmentPacket {
+                player_id,
+                resumption_token,
+            }),
+        )
+        .await;
+    }
+
+    /// Finds the player holding `token` and detaches it from any previous connection, leaving its
+    /// slot empty for the caller to fill.
+    async fn take_resumable_player(&mut self, token: ResumptionToken) -> Option<PlayerId> {
+        if let Some(&player_id) = self
+            .suspended_players
+            .iter()
+            .find(|(_, suspended)| suspended.resumption_token == token)
+            .map(|(player_id, _)| player_id)
+        {
+            self.suspended_players.remove(&player_id);
+            return Some(player_id);
+        }
+
+        // The player may come back from a new address before the server notices the old connection
+        // is gone. Take the player over and let the old connection go.
+        let (player_id, old_addr) = iter_players(&self.players)
+            .find(|(_, player)| player.resumption_token == token)
+            .map(|(player_id, player)| (player_id, player.addr))?;
+        self.players[player_id.index()] = None;
+        self.clients.get_mut(&old_addr).unwrap().player_id = None;
+        let _ = self
+            .connection_requests
+            .send(ConnectionRequest::Disconnect {
+                addr: old_addr,
+                reason: Some("Resumed from another address".to_string()),
+            })
+            .await;
+        Some(player_id)
+    }
+
+    /// Picks a slot for a new player. Admission guarantees a slot that is either free or held for
+    /// a suspended player, so if none are free, the suspended player closest to expiring loses
+    /// theirs.
+    fn allocate_player_id(&mut self) -> PlayerId {
+        if let Some(index) = (0..self.players.len()).find(|&index| {
+            self.players[index].is_none()
+                && !self
+                    .suspended_players
+                    .contains_key(&PlayerId::from_index(index))
+        }) {
+            return PlayerId::from_index(index);
+        }
+
+        let player_id = *self
+            .suspended_players
+            .iter()
+            .min_by_key(|(_, suspended)| suspended.expires_at)
+            .unwrap()
+            .0;
+        log::info!("Giving up on {player_id} to make room");
+        self.suspended_players.remove(&player_id);
+        self.despawn_player(player_id);
+        player_id
+    }
+
     async fn handle_ping_packet(&mut self, addr: Addr, packet: PingPacket) {
         send_game_data(
             &self.connection_requests,
             addr,
+            Channel::Unreliable,
             Packet::Pong(PongPacket {
                 client_time: packet.client_time,
                 server_time: self.epoch.now(),
@@ -567,6 +749,7 @@ impl<Addr: AddrBound> InnerServer<Addr> {
                 send_game_data(
                     &self.connection_requests,
                     player.addr,
+                    Channel::Unreliable,
                     Packet::Voice(VoicePacket {
                         data: packet.data.clone(),
                     }),
@@ -643,8 +826,66 @@ impl<Addr: AddrBound> InnerServer<Addr> {
         }
     }
 
+    fn handle_ack_game_state_packet(&mut self, addr: Addr, packet: AckGameStatePacket) {
+        let player_id = match self.clients[&addr].player_id {
+            Some(player_id) => player_id,
+            None => {
+                log::warn!("Client {addr}: Dropping ack game state packet: player ID not assigned");
+                return;
+            }
+        };
+        if packet.tick_id > self.last_completed_tick_id {
+            log::warn!(
+                "Client {addr}: Dropping ack game state packet: tick {} has not happened yet",
+                packet.tick_id.0,
+            );
+            return;
+        }
+        let player = self.players[player_id.index()].as_mut().unwrap();
+        // Acks may arrive out of order. Only ever move the baseline forward.
+        if player.acked_tick_id < Some(packet.tick_id) {
+            player.acked_tick_id = Some(packet.tick_id);
+        }
+    }
+
+    fn handle_connection_stats(&mut self, addr: Addr, stats: ConnectionStats) {
+        let player_id = match self.clients.get(&addr).and_then(|client| client.player_id) {
+            Some(player_id) => player_id,
+            None => return,
+        };
+        log::info!(
+            "{player_id}: RTT {:?}, {} sent ({} B), {} received ({} B), {} lost, {} failed decryption",
+            stats.rtt,
+            stats.packets_sent,
+            stats.bytes_sent,
+            stats.packets_received,
+            stats.bytes_received,
+            stats.packets_lost,
+            stats.decrypt_failures,
+        );
+    }
+
     fn handle_connection_dropped(&mut self) {
-        todo!()
+        // The connection server only drops after every connection has finished disconnecting, so
+        // there is nobody left to serve.
+        log::info!("Connection server dropped");
+        self.cancel_token.cancel();
+    }
+
+    /// Asks the connection server to drop and temporarily ban a client that sent something the
+    /// session layer can't make sense of.
+    async fn ban_misbehaving_client(&mut self, addr: Addr) {
+        log::info!("Banning client {addr} for {MALFORMED_DATA_BAN_DURATION:?}");
+        if let Some(client) = self.clients.get_mut(&addr) {
+            client.kicked = true;
+        }
+        let _ = self
+            .connection_requests
+            .send(ConnectionRequest::Ban {
+                addr,
+                duration: Some(MALFORMED_DATA_BAN_DURATION),
+            })
+            .await;
     }
 
     async fn handle_tick(&mut self) {
@@ -652,24 +893,53 @@ impl<Addr: AddrBound> InnerServer<Addr> {
         let tick_time = self.next_tick_time;
 
         // Gather the current committed actions for this tick from each player.
-        self.world.insert_resource(AllActionsResource({
-            let mut all_actions = HashMap::new();
-            for (player_id, player) in iter_players_mut(&mut self.players) {
-                if let Some(committed_actions) = player.committed_actions_by_tick_id.get(&tick_id) {
-                    all_actions.insert(player_id, committed_actions.actions.clone());
-                    player.record_slack_observation(committed_actions.slack);
-                } else {
-                    log::warn!("No actions from {player_id} by {tick_id:?} deadline");
-                    // TODO: Use this as the timeout criterion.
+        let mut all_actions = HashMap::new();
+        let mut idle_addrs = Vec::new();
+        for (player_id, player) in iter_players_mut(&mut self.players) {
+            if let Some(committed_actions) = player.committed_actions_by_tick_id.get(&tick_id) {
+                all_actions.insert(player_id, committed_actions.actions.clone());
+                player.record_slack_observation(committed_actions.slack);
+                player.missed_ticks = 0;
+            } else {
+                log::warn!("No actions from {player_id} by {tick_id:?} deadline");
+                player.missed_ticks += 1;
+                if player.missed_ticks == IDLE_TICK_LIMIT {
+                    log::info!("Disconnecting idle {player_id}");
+                    idle_addrs.push(player.addr);
                 }
             }
-            all_actions
-        }));
+        }
+        self.world.insert_resource(AllActionsResource(all_actions));
         self.tick_schedule.run(&mut self.world);
 
+        // The player's slot is freed once the connection server reports the disconnect.
+        for addr in idle_addrs {
+            self.clients.get_mut(&addr).unwrap().kicked = true;
+            let _ = self
+                .connection_requests
+                .send(ConnectionRequest::Disconnect {
+                    addr,
+                    reason: Some("Idle for too long".to_string()),
+                })
+                .await;
+        }
+
         self.last_completed_tick_id = tick_id;
         self.next_tick_time += TICK_INTERVAL;
 
+        // Give up on suspended players who haven't come back in time.
+        let expired_player_ids = Vec::from_iter(
+            self.suspended_players
+                .iter()
+                .filter(|(_, suspended)| suspended.expires_at <= tick_time)
+                .map(|(&player_id, _)| player_id),
+        );
+        for player_id in expired_player_ids {
+            log::info!("{player_id} did not resume in time");
+            self.suspended_players.remove(&player_id);
+            self.despawn_player(player_id);
+        }
+
         // Discard obsolete committed actions.
         // TODO: Keep some window of history to use for tuning client send rates.
         // TODO: Record a -WINDOW_SIZE slack observation when a vacant slot goes out of the window.
@@ -679,12 +949,10 @@ impl<Addr: AddrBound> InnerServer<Addr> {
                 .retain(|&action_tick_id, _| action_tick_id > tick_id);
         }
 
-        // Send updates to all players.
-        let snapshot = {
-            let mut w = Vec::new();
-            write_snapshot(&mut w, &mut self.world).unwrap();
-            w
-        };
+        // Send updates to all players, each encoded against the most recent snapshot that player
+        // has acknowledged. Players sharing a baseline share an encoding.
+        let snapshot = capture_snapshot(&mut self.world);
+        let mut serialized_by_baseline = BTreeMap::new();
         for player in self.players.iter().flatten() {
             const GOAL_SLACK_NS: f64 = 100_000_000.0;
 
@@ -701,17 +969,48 @@ impl<Addr: AddrBound> InnerServer<Addr> {
                 tick_interval.as_nanos(),
             );
 
+            // Fall back to a full snapshot if the acknowledged baseline has aged out of history.
+            let baseline_tick_id = player
+                .acked_tick_id
+                .filter(|&tick_id| self.snapshots.get(tick_id).is_some());
+            let serialized_game_state = serialized_by_baseline
+                .entry(baseline_tick_id)
+                .or_insert_with(|| {
+                    let empty_baseline = Snapshot::default();
+                    let baseline = match baseline_tick_id {
+                        Some(tick_id) => self.snapshots.get(tick_id).unwrap(),
+                        None => &empty_baseline,
+                    };
+                    let mut w = Vec::new();
+                    write_snapshot(&mut w, baseline, &snapshot).unwrap();
+                    w
+                })
+                .clone();
+
             send_game_data(
                 &self.connection_requests,
                 player.addr,
+                Channel::Unreliable,
                 Packet::GameState(GameStatePacket {
                     tick_id,
+                    baseline_tick_id,
                     tick_interval,
-                    serialized_game_state: snapshot.clone(),
+                    serialized_game_state,
                 }),
             )
             .await;
         }
+        self.snapshots.insert(tick_id, snapshot);
+
+        if tick_time >= self.next_stats_time {
+            self.next_stats_time = tick_time + STATS_LOG_INTERVAL;
+            for (_, player) in iter_players(&self.players) {
+                let _ = self
+                    .connection_requests
+                    .send(ConnectionRequest::QueryStats { addr: player.addr })
+                    .await;
+            }
+        }
     }
 }
 
@@ -741,12 +1040,17 @@ fn iter_players_mut<Addr>(
 async fn send_game_data<Addr: AddrBound>(
     connection_requests: &mpsc::Sender<ConnectionRequest<Addr>>,
     addr: Addr,
+    channel: Channel,
     packet: Packet,
 ) {
     let mut data = Vec::new();
     packet.write_to(&mut data).unwrap();
     let _ = connection_requests
-        .send(ConnectionRequest::SendGameData { addr, data })
+        .send(ConnectionRequest::SendGameData {
+            addr,
+            channel,
+            data,
+        })
         .await;
 }
 
@@ -774,22 +1078,3 @@ impl<'a> SpawnContext<'a> {
         );
     }
 }
-
-enum PlayerEvent {
-    SendAssignment { player_id: PlayerId },
-}
-
-impl<Addr> PlayerState<Addr> {
-    async fn wait_for_event(&mut self, player_id: PlayerId) -> PlayerEvent {
-        let send_assignment = match &mut self.send_assignment {
-            Some(send_assignment) => send_assignment.tick().left_future(),
-            None => pending().right_future(),
-        };
-
-        select! {
-            biased;
-
-            _ = send_assignment => PlayerEvent::SendAssignment { player_id },
-        }
-    }
-}
@@ -0,0 +1,141 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+use dungeon_vr_connection_client::ConnectionClient;
+use dungeon_vr_connection_server::{ConnectionConfig, ConnectionServer};
+use dungeon_vr_session_client::headless::HeadlessClient;
+use dungeon_vr_session_client::SessionClient;
+use dungeon_vr_session_shared::collider_cache::ColliderCache;
+use dungeon_vr_session_shared::core::NetId;
+use dungeon_vr_session_shared::snapshot::{EntitySnapshot, Snapshot};
+use dungeon_vr_session_shared::{PlayerId, TICK_INTERVAL};
+use dungeon_vr_socket::testing::FakeNetwork;
+use rapier3d::prelude::*;
+use tokio::time::error::Elapsed;
+use tokio::time::{sleep, timeout};
+
+use crate::SessionServer;
+
+pub async fn box_deadline_err<T, E>(
+    f: impl Future<Output = Result<Result<T, E>, Elapsed>>,
+) -> Result<T, Box<dyn Error>>
+where
+    E: Error + 'static,
+{
+    match f.await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(Box::new(e) as Box<dyn Error>),
+        Err(e) => Err(Box::new(e) as Box<dyn Error>),
+    }
+}
+
+pub async fn run_test_with_timeout(f: impl Future<Output = ()> + Send + 'static) {
+    box_deadline_err(timeout(Duration::from_secs(120), tokio::spawn(f)))
+        .await
+        .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FakeAddr {
+    Server,
+    Client(u8),
+}
+
+impl Display for FakeAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Server => write!(f, "Server"),
+            Self::Client(index) => write!(f, "Client{index}"),
+        }
+    }
+}
+
+/// A session server and its connection server, listening on [`FakeAddr::Server`].
+pub struct TestServer {
+    _connection_cancel_guard: cancel::Guard,
+    _session_server: SessionServer,
+}
+
+impl TestServer {
+    pub fn spawn(network: &FakeNetwork<FakeAddr>, max_players: usize) -> Self {
+        let (connection_cancel_guard, requests, events) = ConnectionServer::spawn(
+            Box::new(network.bind(FakeAddr::Server)),
+            ConnectionConfig::default(),
+            None,
+            None,
+        );
+        Self {
+            _connection_cancel_guard: connection_cancel_guard,
+            _session_server: SessionServer::with_collider_cache(
+                requests,
+                events,
+                max_players,
+                ColliderCache::with_placeholder(ColliderBuilder::cuboid(0.1, 0.1, 0.1)),
+            ),
+        }
+    }
+}
+
+/// A headless client on the fake network. Tests script it through [`HeadlessClient`]'s methods.
+pub struct TestClient {
+    _connection_cancel_guard: cancel::Guard,
+    client: HeadlessClient,
+}
+
+impl TestClient {
+    /// Connects from `addr` and waits for the session to start.
+    pub async fn connect(network: &FakeNetwork<FakeAddr>, addr: FakeAddr) -> Self {
+        let (connection_cancel_guard, requests, events) = ConnectionClient::spawn(
+            Box::new(network.connect(addr, FakeAddr::Server)),
+            ConnectionConfig::default(),
+            None,
+            None,
+        );
+        let mut client = HeadlessClient::new(SessionClient::new(requests, events));
+        client.wait_for_start().await;
+        Self {
+            _connection_cancel_guard: connection_cancel_guard,
+            client,
+        }
+    }
+
+    pub fn player_id(&self) -> PlayerId {
+        self.client.player_id().unwrap()
+    }
+
+    /// The most recent snapshot received from the server.
+    pub fn snapshot(&self) -> &Snapshot {
+        self.client.snapshot().expect("no snapshot received")
+    }
+
+    pub fn entity(&self, net_id: NetId) -> &EntitySnapshot {
+        &self.snapshot().entities[&net_id]
+    }
+}
+
+impl Deref for TestClient {
+    type Target = HeadlessClient;
+
+    fn deref(&self) -> &HeadlessClient {
+        &self.client
+    }
+}
+
+impl DerefMut for TestClient {
+    fn deref_mut(&mut self) -> &mut HeadlessClient {
+        &mut self.client
+    }
+}
+
+/// Advances time by `ticks` ticks, ticking every client along the way.
+pub async fn run_ticks(clients: &mut [&mut TestClient], ticks: u32) {
+    for _ in 0..ticks {
+        sleep(TICK_INTERVAL.try_into().unwrap()).await;
+        for client in clients.iter_mut() {
+            client.tick();
+        }
+    }
+}
@@ -0,0 +1,2 @@
+mod end_to_end;
+mod player;
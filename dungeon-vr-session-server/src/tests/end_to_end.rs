@@ -0,0 +1,159 @@
+use dungeon_vr_session_shared::action::Action;
+use dungeon_vr_session_shared::core::{Authority, NetId};
+use dungeon_vr_session_shared::interaction::HandGrabState;
+use dungeon_vr_socket::testing::FakeNetwork;
+use rapier3d::prelude::*;
+
+use crate::testing::{run_test_with_timeout, run_ticks, FakeAddr, TestClient, TestServer};
+
+fn assert_near(transform: Option<Isometry<f32>>, translation: Vector<f32>) {
+    let actual = transform.unwrap().translation.vector;
+    assert!(
+        (actual - translation).norm() < 1e-3,
+        "expected {translation:?} but got {actual:?}",
+    );
+}
+
+/// Finds a grabbable object that nobody is holding.
+fn free_grabbable(client: &TestClient) -> NetId {
+    *client
+        .snapshot()
+        .entities
+        .iter()
+        .find(|(_, entity)| entity.grabbed == Some(false))
+        .unwrap()
+        .0
+}
+
+#[tokio::test(start_paused = true)]
+async fn players_see_each_others_hands() {
+    run_test_with_timeout(async move {
+        let network = FakeNetwork::new();
+        let _server = TestServer::spawn(&network, 4);
+        let mut client1 = TestClient::connect(&network, FakeAddr::Client(1)).await;
+        let mut client2 = TestClient::connect(&network, FakeAddr::Client(2)).await;
+        assert_ne!(client1.player_id(), client2.player_id());
+
+        client1.hand_transforms[0] = Isometry::translation(1.0, 1.5, -0.5);
+        client2.hand_transforms[1] = Isometry::translation(-1.0, 1.0, 0.5);
+        run_ticks(&mut [&mut client1, &mut client2], 20).await;
+
+        for client in [&client1, &client2] {
+            let (_, hand) = client.hand(client1.player_id(), 0).unwrap();
+            assert_near(hand.transform, vector![1.0, 1.5, -0.5]);
+            let (_, hand) = client.hand(client2.player_id(), 1).unwrap();
+            assert_near(hand.transform, vector![-1.0, 1.0, 0.5]);
+        }
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn grab_and_drop_should_transfer_authority() {
+    run_test_with_timeout(async move {
+        let network = FakeNetwork::new();
+        let _server = TestServer::spawn(&network, 4);
+        let mut client1 = TestClient::connect(&network, FakeAddr::Client(1)).await;
+        let mut client2 = TestClient::connect(&network, FakeAddr::Client(2)).await;
+        run_ticks(&mut [&mut client1, &mut client2], 10).await;
+        let target = free_grabbable(&client1);
+
+        // The grabbing player takes authority.
+        client1.queue_action(Action::Grab {
+            hand_index: 1,
+            target,
+        });
+        run_ticks(&mut [&mut client1, &mut client2], 10).await;
+        for client in [&client1, &client2] {
+            let entity = client.entity(target);
+            assert_eq!(entity.authority, Authority::Player(client1.player_id()));
+            assert_eq!(entity.grabbed, Some(true));
+            let (_, hand) = client.hand(client1.player_id(), 1).unwrap();
+            assert_eq!(
+                hand.hand.unwrap().grab_state,
+                HandGrabState::Grabbing(target),
+            );
+        }
+
+        // The held object follows the holder's hand, and can't be taken away.
+        client1.hand_transforms[1] = Isometry::translation(0.25, 1.25, -0.75);
+        client2.queue_action(Action::Grab {
+            hand_index: 0,
+            target,
+        });
+        run_ticks(&mut [&mut client1, &mut client2], 10).await;
+        let entity = client2.entity(target);
+        assert_eq!(entity.authority, Authority::Player(client1.player_id()));
+        assert_near(entity.transform, vector![0.25, 1.25, -0.75]);
+        let (_, hand) = client2.hand(client2.player_id(), 0).unwrap();
+        assert_eq!(hand.hand.unwrap().grab_state, HandGrabState::Empty);
+
+        // Dropping hands authority back to the server, after which anyone can grab it.
+        client1.queue_action(Action::Drop { hand_index: 1 });
+        run_ticks(&mut [&mut client1, &mut client2], 10).await;
+        for client in [&client1, &client2] {
+            let entity = client.entity(target);
+            assert_eq!(entity.authority, Authority::Server);
+            assert_eq!(entity.grabbed, Some(false));
+        }
+        client2.queue_action(Action::Grab {
+            hand_index: 0,
+            target,
+        });
+        run_ticks(&mut [&mut client1, &mut client2], 10).await;
+        assert_eq!(
+            client1.entity(target).authority,
+            Authority::Player(client2.player_id()),
+        );
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn lost_player_should_release_held_objects_after_grace_period() {
+    run_test_with_timeout(async move {
+        let network = FakeNetwork::new();
+        let _server = TestServer::spawn(&network, 4);
+        let mut client1 = TestClient::connect(&network, FakeAddr::Client(1)).await;
+        let mut client2 = TestClient::connect(&network, FakeAddr::Client(2)).await;
+        run_ticks(&mut [&mut client1, &mut client2], 10).await;
+        let target = free_grabbable(&client1);
+        client1.queue_action(Action::Grab {
+            hand_index: 0,
+            target,
+        });
+        run_ticks(&mut [&mut client1, &mut client2], 10).await;
+        assert_eq!(
+            client2.entity(target).authority,
+            Authority::Player(client1.player_id()),
+        );
+
+        // Cut the holder off. Their hands and held object stay put while the server waits for them
+        // to come back.
+        network.isolate(FakeAddr::Client(1));
+        run_ticks(&mut [&mut client1, &mut client2], 20 * 20).await;
+        assert_eq!(
+            client2.entity(target).authority,
+            Authority::Player(client1.player_id()),
+        );
+        assert!(client2.hand(client1.player_id(), 0).is_some());
+
+        // Once the grace period runs out, the object goes back to the server, free to grab.
+        run_ticks(&mut [&mut client1, &mut client2], 20 * 20).await;
+        let entity = client2.entity(target);
+        assert_eq!(entity.authority, Authority::Server);
+        assert_eq!(entity.grabbed, Some(false));
+        assert!(client2.hand(client1.player_id(), 0).is_none());
+
+        client2.queue_action(Action::Grab {
+            hand_index: 0,
+            target,
+        });
+        run_ticks(&mut [&mut client2], 10).await;
+        assert_eq!(
+            client2.entity(target).authority,
+            Authority::Player(client2.player_id()),
+        );
+    })
+    .await;
+}
@@ -0,0 +1,77 @@
+use std::net::SocketAddr;
+use std::num::NonZeroU8;
+
+use bevy_ecs::prelude::*;
+use dungeon_vr_session_shared::collider_cache::ColliderCache;
+use dungeon_vr_session_shared::core::{Authority, SynchronizedComponent};
+use dungeon_vr_session_shared::interaction::{GrabbableComponent, HandComponent, HandGrabState};
+use dungeon_vr_session_shared::PlayerId;
+use tokio::sync::mpsc;
+
+use crate::InnerServer;
+
+fn new_server() -> InnerServer<SocketAddr> {
+    let (connection_requests, _) = mpsc::channel(1);
+    let (_, connection_events) = mpsc::channel(1);
+    InnerServer::new(
+        cancel::Token::new(),
+        connection_requests,
+        connection_events,
+        4,
+        ColliderCache::new(),
+    )
+}
+
+#[tokio::test]
+async fn despawn_player_should_release_held_objects() {
+    let mut server = new_server();
+    let player_id = PlayerId(NonZeroU8::new(1).unwrap());
+    server.spawn_player(player_id);
+
+    // Put a grabbable object in the player's first hand, as a grab action would.
+    let (target, target_net_id) = server
+        .world
+        .query_filtered::<(Entity, &SynchronizedComponent), With<GrabbableComponent>>()
+        .iter(&server.world)
+        .map(|(entity, synchronized)| (entity, synchronized.net_id))
+        .next()
+        .unwrap();
+    for mut hand in server
+        .world
+        .query::<&mut HandComponent>()
+        .iter_mut(&mut server.world)
+    {
+        if hand.index == 0 {
+            hand.grab_state = HandGrabState::Grabbing(target_net_id);
+        }
+    }
+    let mut target_entity = server.world.entity_mut(target);
+    target_entity
+        .get_mut::<SynchronizedComponent>()
+        .unwrap()
+        .authority = Authority::Player(player_id);
+    target_entity
+        .get_mut::<GrabbableComponent>()
+        .unwrap()
+        .grabbed = true;
+
+    server.despawn_player(player_id);
+
+    let target_entity = server.world.entity(target);
+    assert_eq!(
+        target_entity
+            .get::<SynchronizedComponent>()
+            .unwrap()
+            .authority,
+        Authority::Server,
+    );
+    assert!(!target_entity.get::<GrabbableComponent>().unwrap().grabbed);
+    assert_eq!(
+        server
+            .world
+            .query::<&HandComponent>()
+            .iter(&server.world)
+            .count(),
+        0,
+    );
+}
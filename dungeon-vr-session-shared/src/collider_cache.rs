@@ -7,6 +7,7 @@ use rapier3d::prelude::*;
 
 pub struct ColliderCache {
     cache: HashMap<OwnedColliderCacheKey, ColliderBuilder>,
+    placeholder: Option<ColliderBuilder>,
 }
 
 pub trait ColliderCacheKey {
@@ -85,10 +86,23 @@ impl ColliderCache {
     pub fn new() -> Self {
         Self {
             cache: HashMap::new(),
+            placeholder: None,
+        }
+    }
+
+    /// Creates a cache that hands out `placeholder` for every collider instead of loading assets.
+    /// This lets headless tests and tools run a world without the asset directory.
+    pub fn with_placeholder(placeholder: ColliderBuilder) -> Self {
+        Self {
+            cache: HashMap::new(),
+            placeholder: Some(placeholder),
         }
     }
 
     pub fn get(&mut self, key: BorrowedColliderCacheKey) -> ColliderBuilder {
+        if let Some(placeholder) = &self.placeholder {
+            return placeholder.clone();
+        }
         if let Some(collider) = self.cache.get(&key as &dyn ColliderCacheKey) {
             return collider.clone();
         }
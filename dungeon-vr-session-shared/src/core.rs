@@ -2,7 +2,9 @@ use std::convert::Infallible;
 use std::num::{NonZeroU32, NonZeroU8};
 
 use bevy_ecs::prelude::*;
-use dungeon_vr_stream_codec::{ReadError, StreamCodec};
+use dungeon_vr_stream_codec::{
+    PositionBounds, QuantizedIsometry3, ReadError, ReadVarIntError, StreamCodec, VarU32,
+};
 use rapier3d::na::Vector4;
 use rapier3d::prelude::*;
 use thiserror::Error;
@@ -12,7 +14,7 @@ use crate::{NetComponent, PlayerId};
 #[derive(Error, Debug)]
 pub enum ReadNetIdError {
     #[error("{0}")]
-    ReadError(#[from] ReadError),
+    ReadVarIntError(#[from] ReadVarIntError),
 
     #[error("invalid zero net ID")]
     InvalidNetId,
@@ -27,14 +29,14 @@ impl StreamCodec for NetId {
     type WriteError = Infallible;
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadNetIdError> {
-        match NonZeroU32::new(u32::read_from(r)?) {
+        match NonZeroU32::new(VarU32::read_from(r)?.0) {
             None => Err(ReadNetIdError::InvalidNetId),
             Some(id) => Ok(Self(id)),
         }
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
-        self.0.get().write_to(w)
+        VarU32(self.0.get()).write_to(w)
     }
 }
 
@@ -106,3 +108,17 @@ impl LocalAuthorityResource {
 pub struct TransformComponent(pub Isometry<f32>);
 
 impl NetComponent for TransformComponent {}
+
+/// The region replicated positions are expected to stay within. The dungeon room spans ±4 m, which
+/// leaves plenty of margin for objects that are thrown or fall.
+pub enum NetPositionBounds {}
+
+impl PositionBounds for NetPositionBounds {
+    const MIN: [f32; 3] = [-16.0, -16.0, -16.0];
+    const MAX: [f32; 3] = [16.0, 16.0, 16.0];
+    const BITS: u32 = 16;
+}
+
+/// How transforms are encoded for replication: positions to within 0.25 mm and rotations to within
+/// about a tenth of a degree, in 10 bytes.
+pub type NetTransformCodec = QuantizedIsometry3<NetPositionBounds, 10>;
@@ -11,7 +11,7 @@ pub struct HandComponent {
 
 impl NetComponent for HandComponent {}
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HandGrabState {
     Empty,
     Grabbing(NetId),
@@ -5,7 +5,7 @@ use std::fmt::{self, Display, Formatter};
 use std::num::NonZeroU8;
 
 use bevy_ecs::prelude::Component;
-use dungeon_vr_stream_codec::{ReadError, StreamCodec};
+use dungeon_vr_stream_codec::{ReadError, ReadVarIntError, StreamCodec, VarU32};
 use thiserror::Error;
 
 use crate::physics::PhysicsResource;
@@ -60,6 +60,12 @@ impl StreamCodec for PlayerId {
     }
 }
 
+/// A secret issued along with a [`PlayerId`]. A client that loses its connection presents it when
+/// reconnecting to take back the same player.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, StreamCodec)]
+#[stream_codec(read_error = "ReadError")]
+pub struct ResumptionToken(pub [u8; 16]);
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TickId(pub u32);
 
@@ -69,6 +75,19 @@ impl TickId {
     }
 }
 
+impl StreamCodec for TickId {
+    type ReadError = ReadVarIntError;
+    type WriteError = Infallible;
+
+    fn read_from(r: &mut &[u8]) -> Result<Self, ReadVarIntError> {
+        Ok(Self(VarU32::read_from(r)?.0))
+    }
+
+    fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+        VarU32(self.0).write_to(w)
+    }
+}
+
 pub struct NetComponentDestroyContext<'a> {
     pub physics: &'a mut PhysicsResource,
 }
@@ -1,12 +1,14 @@
 use std::convert::Infallible;
 
-use dungeon_vr_stream_codec::{ReadError, StreamCodec};
+use dungeon_vr_stream_codec::{ReadError, ReadVarIntError, StreamCodec};
 use thiserror::Error;
 
 use crate::action::ReadActionError;
 use crate::core::ReadNetIdError;
+use crate::packet::ack_game_state_packet::AckGameStatePacket;
 use crate::packet::commit_actions_packet::CommitActionsPacket;
 use crate::packet::game_state_packet::GameStatePacket;
+use crate::packet::hello_packet::HelloPacket;
 use crate::packet::ping_packet::PingPacket;
 use crate::packet::player_assignment_packet::PlayerAssignmentPacket;
 use crate::packet::pong_packet::PongPacket;
@@ -14,8 +16,10 @@ use crate::packet::update_owned_transforms_packet::UpdateOwnedTransformsPacket;
 use crate::packet::voice_packet::VoicePacket;
 use crate::ReadPlayerIdError;
 
+pub mod ack_game_state_packet;
 pub mod commit_actions_packet;
 pub mod game_state_packet;
+pub mod hello_packet;
 pub mod ping_packet;
 pub mod player_assignment_packet;
 pub mod pong_packet;
@@ -27,6 +31,9 @@ pub enum ReadPacketError {
     #[error("{0}")]
     ReadError(#[from] ReadError),
 
+    #[error("{0}")]
+    ReadVarIntError(#[from] ReadVarIntError),
+
     #[error("{0}")]
     ReadPlayerIdError(#[from] ReadPlayerIdError),
 
@@ -49,7 +56,11 @@ impl From<Infallible> for ReadPacketError {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, StreamCodec)]
+#[stream_codec(
+    read_error = "ReadPacketError",
+    invalid_tag = "ReadPacketError::InvalidPacketType"
+)]
 #[repr(u8)]
 pub enum PacketKind {
     Ping,
@@ -59,36 +70,8 @@ pub enum PacketKind {
     PlayerAssignment,
     CommitActions,
     UpdateOwnedTransforms,
-}
-
-impl StreamCodec for PacketKind {
-    type ReadError = ReadPacketError;
-    type WriteError = Infallible;
-
-    fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
-        u8::read_from(r)?.try_into()
-    }
-
-    fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
-        (*self as u8).write_to(w)
-    }
-}
-
-impl TryFrom<u8> for PacketKind {
-    type Error = ReadPacketError;
-
-    fn try_from(value: u8) -> Result<Self, ReadPacketError> {
-        match value {
-            x if x == Self::Ping as u8 => Ok(Self::Ping),
-            x if x == Self::Pong as u8 => Ok(Self::Pong),
-            x if x == Self::GameState as u8 => Ok(Self::GameState),
-            x if x == Self::Voice as u8 => Ok(Self::Voice),
-            x if x == Self::PlayerAssignment as u8 => Ok(Self::PlayerAssignment),
-            x if x == Self::CommitActions as u8 => Ok(Self::CommitActions),
-            x if x == Self::UpdateOwnedTransforms as u8 => Ok(Self::UpdateOwnedTransforms),
-            x => Err(ReadPacketError::InvalidPacketType(x)),
-        }
-    }
+    AckGameState,
+    Hello,
 }
 
 pub enum Packet {
@@ -99,6 +82,8 @@ pub enum Packet {
     PlayerAssignment(PlayerAssignmentPacket),
     CommitActions(CommitActionsPacket),
     UpdateOwnedTransforms(UpdateOwnedTransformsPacket),
+    AckGameState(AckGameStatePacket),
+    Hello(HelloPacket),
 }
 
 impl Packet {
@@ -111,6 +96,8 @@ impl Packet {
             Self::PlayerAssignment(_) => PacketKind::PlayerAssignment,
             Self::CommitActions(_) => PacketKind::CommitActions,
             Self::UpdateOwnedTransforms(_) => PacketKind::UpdateOwnedTransforms,
+            Self::AckGameState(_) => PacketKind::AckGameState,
+            Self::Hello(_) => PacketKind::Hello,
         }
     }
 }
@@ -134,6 +121,8 @@ impl StreamCodec for Packet {
             PacketKind::UpdateOwnedTransforms => Ok(Self::UpdateOwnedTransforms(
                 UpdateOwnedTransformsPacket::read_from(r)?,
             )),
+            PacketKind::AckGameState => Ok(Self::AckGameState(AckGameStatePacket::read_from(r)?)),
+            PacketKind::Hello => Ok(Self::Hello(HelloPacket::read_from(r)?)),
         }
     }
 
@@ -147,6 +136,8 @@ impl StreamCodec for Packet {
             Self::PlayerAssignment(packet) => packet.write_to(w),
             Self::CommitActions(packet) => packet.write_to(w),
             Self::UpdateOwnedTransforms(packet) => packet.write_to(w),
+            Self::AckGameState(packet) => packet.write_to(w),
+            Self::Hello(packet) => packet.write_to(w),
         }
     }
 }
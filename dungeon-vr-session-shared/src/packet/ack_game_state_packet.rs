@@ -0,0 +1,27 @@
+use std::convert::Infallible;
+
+use dungeon_vr_stream_codec::StreamCodec;
+
+use crate::packet::ReadPacketError;
+use crate::TickId;
+
+/// Sent by a client to acknowledge receipt of a game state snapshot. The server uses the most
+/// recently acknowledged snapshot as the baseline for delta encoding.
+pub struct AckGameStatePacket {
+    pub tick_id: TickId,
+}
+
+impl StreamCodec for AckGameStatePacket {
+    type ReadError = ReadPacketError;
+    type WriteError = Infallible;
+
+    fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
+        let tick_id = TickId::read_from(r)?;
+        Ok(Self { tick_id })
+    }
+
+    fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+        self.tick_id.write_to(w)?;
+        Ok(())
+    }
+}
@@ -19,7 +19,7 @@ impl StreamCodec for CommitActionsPacket {
         let count = u8::read_from(r)?;
         let mut actions_by_tick_id = BTreeMap::new();
         for _ in 0..count {
-            let tick_id = TickId(u32::read_from(r)?);
+            let tick_id = TickId::read_from(r)?;
             let mut actions = Vec::new();
             let count = u8::read_from(r)?;
             for _ in 0..count {
@@ -35,7 +35,7 @@ impl StreamCodec for CommitActionsPacket {
             .unwrap()
             .write_to(w)?;
         for (tick_id, actions) in &self.actions_by_tick_id {
-            tick_id.0.write_to(w)?;
+            tick_id.write_to(w)?;
             u8::try_from(actions.len()).unwrap().write_to(w)?;
             for action in actions {
                 action.write_to(w)?;
@@ -1,6 +1,6 @@
 use std::convert::Infallible;
 
-use dungeon_vr_stream_codec::{ExternalStreamCodec, StreamCodec, UnframedByteVec};
+use dungeon_vr_stream_codec::{ExternalStreamCodec, StreamCodec, UnframedByteVec, VarU32};
 
 use crate::packet::ReadPacketError;
 use crate::time::NanoDuration;
@@ -8,6 +8,9 @@ use crate::TickId;
 
 pub struct GameStatePacket {
     pub tick_id: TickId,
+    /// The tick whose snapshot `serialized_game_state` is a delta against, or `None` if it is a
+    /// full snapshot.
+    pub baseline_tick_id: Option<TickId>,
     pub tick_interval: NanoDuration,
     pub serialized_game_state: Vec<u8>,
 }
@@ -17,18 +20,24 @@ impl StreamCodec for GameStatePacket {
     type WriteError = Infallible;
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
-        let tick_id = TickId(u32::read_from(r)?);
+        let tick_id = TickId::read_from(r)?;
+        let baseline_tick_id = match VarU32::read_from(r)?.0 {
+            0 => None,
+            x => Some(TickId(x)),
+        };
         let tick_interval = NanoDuration::from_nanos(i64::read_from(r)?);
         let serialized_game_state = UnframedByteVec::read_from_ext(r)?;
         Ok(Self {
             tick_id,
+            baseline_tick_id,
             tick_interval,
             serialized_game_state,
         })
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
-        self.tick_id.0.write_to(w)?;
+        self.tick_id.write_to(w)?;
+        VarU32(self.baseline_tick_id.map_or(0, |tick_id| tick_id.0)).write_to(w)?;
         self.tick_interval.as_nanos().write_to(w)?;
         UnframedByteVec::write_to_ext(w, &self.serialized_game_state)?;
         Ok(())
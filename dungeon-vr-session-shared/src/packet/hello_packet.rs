@@ -0,0 +1,35 @@
+use std::convert::Infallible;
+
+use dungeon_vr_stream_codec::StreamCodec;
+
+use crate::packet::ReadPacketError;
+use crate::ResumptionToken;
+
+/// The first packet a client sends once connected. The server answers with a
+/// [`PlayerAssignmentPacket`](crate::packet::player_assignment_packet::PlayerAssignmentPacket).
+pub struct HelloPacket {
+    /// The token from an earlier assignment, if the client is reconnecting. Encoded as the rest of
+    /// the packet, with an empty remainder meaning no token.
+    pub resumption_token: Option<ResumptionToken>,
+}
+
+impl StreamCodec for HelloPacket {
+    type ReadError = ReadPacketError;
+    type WriteError = Infallible;
+
+    fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
+        let resumption_token = if r.is_empty() {
+            None
+        } else {
+            Some(ResumptionToken::read_from(r)?)
+        };
+        Ok(Self { resumption_token })
+    }
+
+    fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+        if let Some(resumption_token) = &self.resumption_token {
+            resumption_token.write_to(w)?;
+        }
+        Ok(())
+    }
+}
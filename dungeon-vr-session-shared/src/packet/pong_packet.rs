@@ -20,7 +20,7 @@ impl StreamCodec for PongPacket {
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
         let client_time = ClientTime::from_nanos_since_epoch(i64::read_from(r)?);
         let server_time = ServerTime::from_nanos_since_epoch(i64::read_from(r)?);
-        let server_last_completed_tick = TickId(u32::read_from(r)?);
+        let server_last_completed_tick = TickId::read_from(r)?;
         let server_tick_interval = NanoDuration::from_nanos(i64::read_from(r)?);
         Ok(Self {
             client_time,
@@ -33,7 +33,7 @@ impl StreamCodec for PongPacket {
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
         self.client_time.as_nanos_since_epoch().write_to(w)?;
         self.server_time.as_nanos_since_epoch().write_to(w)?;
-        self.server_last_completed_tick.0.write_to(w)?;
+        self.server_last_completed_tick.write_to(w)?;
         self.server_tick_interval.as_nanos().write_to(w)?;
         Ok(())
     }
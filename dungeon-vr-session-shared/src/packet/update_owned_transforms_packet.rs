@@ -1,10 +1,10 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
 
-use dungeon_vr_stream_codec::StreamCodec;
+use dungeon_vr_stream_codec::{ExternalStreamCodec, StreamCodec, VarU32};
 use rapier3d::prelude::*;
 
-use crate::core::NetId;
+use crate::core::{NetId, NetTransformCodec};
 use crate::packet::ReadPacketError;
 use crate::TickId;
 
@@ -18,12 +18,12 @@ impl StreamCodec for UpdateOwnedTransformsPacket {
     type WriteError = Infallible;
 
     fn read_from(r: &mut &[u8]) -> Result<Self, ReadPacketError> {
-        let after_tick_id = TickId(u32::read_from(r)?);
-        let count = u32::read_from(r)?;
+        let after_tick_id = TickId::read_from(r)?;
+        let count = VarU32::read_from(r)?.0;
         let mut transforms_by_net_id = HashMap::new();
         for _ in 0..count {
             let net_id = NetId::read_from(r)?;
-            let transform = Isometry::read_from(r)?;
+            let transform = NetTransformCodec::read_from_ext(r)?;
             transforms_by_net_id.insert(net_id, transform);
         }
         Ok(Self {
@@ -33,13 +33,11 @@ impl StreamCodec for UpdateOwnedTransformsPacket {
     }
 
     fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
-        self.after_tick_id.0.write_to(w)?;
-        u32::try_from(self.transforms_by_net_id.len())
-            .unwrap()
-            .write_to(w)?;
+        self.after_tick_id.write_to(w)?;
+        VarU32(u32::try_from(self.transforms_by_net_id.len()).unwrap()).write_to(w)?;
         for (net_id, transform) in &self.transforms_by_net_id {
             net_id.write_to(w)?;
-            transform.write_to(w)?;
+            NetTransformCodec::write_to_ext(w, transform)?;
         }
         Ok(())
     }
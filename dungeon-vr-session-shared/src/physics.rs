@@ -13,7 +13,7 @@ pub struct PhysicsComponent {
     pub rigid_body: Option<RigidBodyHandle>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum NetPhysicsMode {
     Static,
     Dynamic { ccd_enabled: bool },
@@ -1,35 +1,43 @@
 use std::borrow::BorrowMut;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::num::NonZeroU32;
 
 use bevy_ecs::prelude::*;
 use bevy_ecs::world::EntityMut;
-use dungeon_vr_stream_codec::{ReadBoolError, ReadError, ReadStringError, StreamCodec};
+use dungeon_vr_stream_codec::{
+    ExternalStreamCodec, ReadBoolError, ReadError, ReadVarIntError, ReadVarintFramedError,
+    StreamCodec, VarU32, VarintFramedString,
+};
 use rapier3d::prelude::*;
 
 use slotmap::Key;
 use thiserror::Error;
 
-use crate::core::{Authority, NetId, ReadNetIdError, SynchronizedComponent, TransformComponent};
+use crate::core::{
+    Authority, NetId, NetTransformCodec, ReadNetIdError, SynchronizedComponent, TransformComponent,
+};
 use crate::fly_around::FlyAroundComponent;
 use crate::interaction::{GrabbableComponent, HandComponent, HandGrabState};
 use crate::physics::PhysicsComponent;
 use crate::physics::{NetPhysicsMode, PhysicsResource};
 use crate::render::{ModelHandle, RenderComponent};
 use crate::resources::EntitiesByNetIdResource;
-use crate::{NetComponent, NetComponentDestroyContext};
+use crate::{NetComponent, NetComponentDestroyContext, TickId};
 
 #[derive(Error, Debug)]
 pub enum ReadSnapshotError {
     #[error("{0}")]
     ReadError(#[from] ReadError),
 
+    #[error("{0}")]
+    ReadVarIntError(#[from] ReadVarIntError),
+
     #[error("{0}")]
     ReadBoolError(#[from] ReadBoolError),
 
     #[error("{0}")]
-    ReadStringError(#[from] ReadStringError),
+    ReadVarintFramedError(#[from] ReadVarintFramedError),
 
     #[error("{0}")]
     ReadNetIdError(#[from] ReadNetIdError),
@@ -42,71 +50,329 @@ pub enum ReadSnapshotError {
 
     #[error("invalid hand grab mode: 0x{0:02x}")]
     InvalidHandGrabMode(u8),
+
+    #[error("removed entity {0:?} is not present in the baseline")]
+    RemovedEntityNotInBaseline(NetId),
 }
 
-pub fn write_snapshot(w: &mut Vec<u8>, world: &mut World) -> Result<(), Infallible> {
-    // Gather a sorted list entities by net ID.
-    let mut entities_by_net_id = world
-        .query::<(&SynchronizedComponent, Entity)>()
-        .iter(world)
-        .map(|(synchronized, entity)| (synchronized, entity))
-        .collect::<Vec<_>>();
-    entities_by_net_id.sort_unstable_by_key(|(synchronized, _)| synchronized.net_id);
-
-    (entities_by_net_id.len() as u32).write_to(w)?;
-    for (synchronized, entity) in entities_by_net_id {
-        let entity = world.entity(entity);
-        synchronized.net_id.write_to(w)?;
-        synchronized.authority.write_to(w)?;
-        if let Some(transform) = entity.get::<TransformComponent>() {
-            1u8.write_to(w)?;
-            transform.0.write_to(w)?;
+const TRANSFORM_TOKEN: u8 = 1;
+const RENDER_TOKEN: u8 = 2;
+const PHYSICS_TOKEN: u8 = 3;
+const HAND_TOKEN: u8 = 4;
+const GRABBABLE_TOKEN: u8 = 5;
+/// Set on a component token to indicate the component was removed since the baseline.
+const REMOVED_FLAG: u8 = 0x80;
+
+/// The synchronized state of every entity in a world as of some tick.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub entities: BTreeMap<NetId, EntitySnapshot>,
+}
+
+/// The synchronized state of a single entity. Each optional field corresponds to a
+/// [`NetComponent`] that the entity may or may not have.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntitySnapshot {
+    pub authority: Authority,
+    pub transform: Option<Isometry<f32>>,
+    pub model_name: Option<String>,
+    pub physics: Option<PhysicsSnapshot>,
+    pub hand: Option<HandSnapshot>,
+    pub grabbed: Option<bool>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhysicsSnapshot {
+    pub collider_name: String,
+    pub mode: NetPhysicsMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandSnapshot {
+    pub index: usize,
+    pub grab_state: HandGrabState,
+}
+
+impl EntitySnapshot {
+    fn new(authority: Authority) -> Self {
+        Self {
+            authority,
+            transform: None,
+            model_name: None,
+            physics: None,
+            hand: None,
+            grabbed: None,
         }
-        if let Some(render) = entity.get::<RenderComponent>() {
-            2u8.write_to(w)?;
-            render.model_name.write_to(w)?;
+    }
+}
+
+/// A bounded history of recent snapshots by tick, used as baselines for delta encoding.
+#[derive(Default)]
+pub struct SnapshotHistory {
+    snapshots: BTreeMap<TickId, Snapshot>,
+}
+
+impl SnapshotHistory {
+    /// The number of snapshots to retain. At 20 Hz this covers a little over 1.5 seconds.
+    pub const CAPACITY: usize = 32;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a snapshot, evicting the oldest snapshot if the history is full.
+    pub fn insert(&mut self, tick_id: TickId, snapshot: Snapshot) {
+        self.snapshots.insert(tick_id, snapshot);
+        while self.snapshots.len() > Self::CAPACITY {
+            let oldest = *self.snapshots.keys().next().unwrap();
+            self.snapshots.remove(&oldest);
         }
-        if let Some(physics) = entity.get::<PhysicsComponent>() {
-            3u8.write_to(w)?;
-            physics.collider_name.write_to(w)?;
+    }
+
+    pub fn get(&self, tick_id: TickId) -> Option<&Snapshot> {
+        self.snapshots.get(&tick_id)
+    }
+}
+
+/// Gathers the synchronized state of every entity in the world.
+pub fn capture_snapshot(world: &mut World) -> Snapshot {
+    let entities = world
+        .query::<(
+            &SynchronizedComponent,
+            Option<&TransformComponent>,
+            Option<&RenderComponent>,
+            Option<&PhysicsComponent>,
+            Option<&HandComponent>,
+            Option<&GrabbableComponent>,
+        )>()
+        .iter(world)
+        .map(
+            |(synchronized, transform, render, physics, hand, grabbable)| {
+                (
+                    synchronized.net_id,
+                    EntitySnapshot {
+                        authority: synchronized.authority,
+                        transform: transform.map(|transform| transform.0),
+                        model_name: render.map(|render| render.model_name.clone()),
+                        physics: physics.map(|physics| PhysicsSnapshot {
+                            collider_name: physics.collider_name.clone(),
+                            mode: physics.mode,
+                        }),
+                        hand: hand.map(|hand| HandSnapshot {
+                            index: hand.index,
+                            grab_state: hand.grab_state,
+                        }),
+                        grabbed: grabbable.map(|grabbable| grabbable.grabbed),
+                    },
+                )
+            },
+        )
+        .collect();
+    Snapshot { entities }
+}
+
+/// Encodes `snapshot` as a delta against `baseline`. Only entities and components that differ from
+/// the baseline are written, along with explicit removals for entities and components that are no
+/// longer present. A full snapshot is a delta against the empty [`Snapshot::default()`].
+pub fn write_snapshot(
+    w: &mut Vec<u8>,
+    baseline: &Snapshot,
+    snapshot: &Snapshot,
+) -> Result<(), Infallible> {
+    let removed = baseline
+        .entities
+        .keys()
+        .filter(|net_id| !snapshot.entities.contains_key(net_id))
+        .collect::<Vec<_>>();
+    VarU32(removed.len() as u32).write_to(w)?;
+    for net_id in removed {
+        net_id.write_to(w)?;
+    }
+
+    let changed = snapshot
+        .entities
+        .iter()
+        .filter_map(|(net_id, entity)| {
+            let baseline = baseline.entities.get(net_id);
+            if baseline == Some(entity) {
+                None
+            } else {
+                Some((net_id, baseline, entity))
+            }
+        })
+        .collect::<Vec<_>>();
+    VarU32(changed.len() as u32).write_to(w)?;
+    for (net_id, baseline, entity) in changed {
+        net_id.write_to(w)?;
+        write_entity(w, baseline, entity)?;
+    }
+    Ok(())
+}
+
+fn write_entity(
+    w: &mut Vec<u8>,
+    baseline: Option<&EntitySnapshot>,
+    entity: &EntitySnapshot,
+) -> Result<(), Infallible> {
+    entity.authority.write_to(w)?;
+    write_component(
+        w,
+        TRANSFORM_TOKEN,
+        baseline.and_then(|baseline| baseline.transform.as_ref()),
+        entity.transform.as_ref(),
+        NetTransformCodec::write_to_ext,
+    )?;
+    write_component(
+        w,
+        RENDER_TOKEN,
+        baseline.and_then(|baseline| baseline.model_name.as_ref()),
+        entity.model_name.as_ref(),
+        VarintFramedString::write_to_ext,
+    )?;
+    write_component(
+        w,
+        PHYSICS_TOKEN,
+        baseline.and_then(|baseline| baseline.physics.as_ref()),
+        entity.physics.as_ref(),
+        |w, physics| {
+            VarintFramedString::write_to_ext(w, &physics.collider_name)?;
             match physics.mode {
                 NetPhysicsMode::Static => 0u8,
                 NetPhysicsMode::Dynamic { ccd_enabled: false } => 1u8,
                 NetPhysicsMode::Dynamic { ccd_enabled: true } => 2u8,
             }
-            .write_to(w)?;
-        }
-        if let Some(hand) = entity.get::<HandComponent>() {
-            4u8.write_to(w)?;
+            .write_to(w)
+        },
+    )?;
+    write_component(
+        w,
+        HAND_TOKEN,
+        baseline.and_then(|baseline| baseline.hand.as_ref()),
+        entity.hand.as_ref(),
+        |w, hand| {
             u8::try_from(hand.index).unwrap().write_to(w)?;
-            match hand.grab_state {
+            VarU32(match hand.grab_state {
                 HandGrabState::Empty => 0,
                 HandGrabState::Grabbing(net_id) => net_id.0.get(),
-            }
-            .write_to(w)?;
+            })
+            .write_to(w)
+        },
+    )?;
+    write_component(
+        w,
+        GRABBABLE_TOKEN,
+        baseline.and_then(|baseline| baseline.grabbed.as_ref()),
+        entity.grabbed.as_ref(),
+        |w, grabbed| grabbed.write_to(w),
+    )?;
+    0u8.write_to(w)?;
+    Ok(())
+}
+
+fn write_component<T: PartialEq>(
+    w: &mut Vec<u8>,
+    token: u8,
+    baseline: Option<&T>,
+    value: Option<&T>,
+    write_value: impl FnOnce(&mut Vec<u8>, &T) -> Result<(), Infallible>,
+) -> Result<(), Infallible> {
+    match (baseline, value) {
+        (baseline, Some(value)) if baseline != Some(value) => {
+            token.write_to(w)?;
+            write_value(w, value)
         }
-        if let Some(grabbable) = entity.get::<GrabbableComponent>() {
-            5u8.write_to(w)?;
-            grabbable.grabbed.write_to(w)?;
+        (Some(_), None) => (token | REMOVED_FLAG).write_to(w),
+        _ => Ok(()),
+    }
+}
+
+/// Decodes a delta written by [`write_snapshot`] and applies it to `baseline`, producing the full
+/// snapshot it describes.
+pub fn read_snapshot(r: &mut &[u8], baseline: &Snapshot) -> Result<Snapshot, ReadSnapshotError> {
+    let mut snapshot = baseline.clone();
+
+    let removed_count = VarU32::read_from(r)?.0;
+    for _ in 0..removed_count {
+        let net_id = NetId::read_from(r)?;
+        if snapshot.entities.remove(&net_id).is_none() {
+            return Err(ReadSnapshotError::RemovedEntityNotInBaseline(net_id));
+        }
+    }
+
+    let changed_count = VarU32::read_from(r)?.0;
+    for _ in 0..changed_count {
+        let net_id = NetId::read_from(r)?;
+        let authority = Authority::read_from(r)?;
+        let entity = snapshot
+            .entities
+            .entry(net_id)
+            .or_insert_with(|| EntitySnapshot::new(authority));
+        entity.authority = authority;
+        read_entity_components(r, entity)?;
+    }
+    Ok(snapshot)
+}
+
+fn read_entity_components(
+    r: &mut &[u8],
+    entity: &mut EntitySnapshot,
+) -> Result<(), ReadSnapshotError> {
+    loop {
+        match u8::read_from(r)? {
+            0 => break,
+            TRANSFORM_TOKEN => {
+                entity.transform = Some(NetTransformCodec::read_from_ext(r)?);
+            }
+            RENDER_TOKEN => {
+                entity.model_name = Some(VarintFramedString::read_from_ext(r)?);
+            }
+            PHYSICS_TOKEN => {
+                let collider_name = VarintFramedString::read_from_ext(r)?;
+                let mode = match u8::read_from(r)? {
+                    0 => NetPhysicsMode::Static,
+                    1 => NetPhysicsMode::Dynamic { ccd_enabled: false },
+                    2 => NetPhysicsMode::Dynamic { ccd_enabled: true },
+                    x => return Err(ReadSnapshotError::InvalidNetPhysicsMode(x)),
+                };
+                entity.physics = Some(PhysicsSnapshot {
+                    collider_name,
+                    mode,
+                });
+            }
+            HAND_TOKEN => {
+                let index = u8::read_from(r)? as usize;
+                let grab_state = match NonZeroU32::new(VarU32::read_from(r)?.0) {
+                    Some(net_id) => HandGrabState::Grabbing(NetId(net_id)),
+                    None => HandGrabState::Empty,
+                };
+                entity.hand = Some(HandSnapshot { index, grab_state });
+            }
+            GRABBABLE_TOKEN => {
+                entity.grabbed = Some(bool::read_from(r)?);
+            }
+            token if token == TRANSFORM_TOKEN | REMOVED_FLAG => entity.transform = None,
+            token if token == RENDER_TOKEN | REMOVED_FLAG => entity.model_name = None,
+            token if token == PHYSICS_TOKEN | REMOVED_FLAG => entity.physics = None,
+            token if token == HAND_TOKEN | REMOVED_FLAG => entity.hand = None,
+            token if token == GRABBABLE_TOKEN | REMOVED_FLAG => entity.grabbed = None,
+            token => return Err(ReadSnapshotError::InvalidGameStateToken(token)),
         }
-        0u8.write_to(w)?;
     }
     Ok(())
 }
 
-pub fn apply_snapshot(r: &mut &[u8], world: &mut World) -> Result<(), ReadSnapshotError> {
+/// Updates the world to match a full snapshot.
+pub fn apply_snapshot(snapshot: &Snapshot, world: &mut World) {
     world.resource_scope(|world, mut physics_resource: Mut<PhysicsResource>| {
-        let count = u32::read_from(r)?;
         let entities_by_net_id = world
             .query::<(&SynchronizedComponent, Entity)>()
             .iter(world)
             .map(|(synchronized, entity)| (synchronized.net_id, entity))
             .collect::<HashMap<_, _>>();
 
-        for _ in 0..count {
+        for (&net_id, entity_snapshot) in &snapshot.entities {
             // Get or create the referenced entity.
-            let net_id = NetId::read_from(r)?;
-            let authority = Authority::read_from(r)?;
+            let authority = entity_snapshot.authority;
             let mut entity = match entities_by_net_id.get(&net_id).copied() {
                 Some(entity) => {
                     let mut entity = world.entity_mut(entity);
@@ -126,56 +392,31 @@ pub fn apply_snapshot(r: &mut &[u8], world: &mut World) -> Result<(), ReadSnapsh
                 }
             };
 
-            // Decode and update this entity's other components.
-            let mut transform = None;
-            let mut render = None;
-            let mut physics = None;
-            let mut hand = None;
-            let mut grabbable = None;
-            loop {
-                match u8::read_from(r)? {
-                    0 => break,
-                    1 => {
-                        let isometry = Isometry::<f32>::read_from(r)?;
-                        transform = Some(TransformComponent(isometry));
-                    }
-                    2 => {
-                        let name = String::read_from(r)?;
-                        render = Some(RenderComponent {
-                            model_name: name,
-                            model_handle: ModelHandle::null(),
-                        });
-                    }
-                    3 => {
-                        let collider_name = String::read_from(r)?;
-                        let mode = match u8::read_from(r)? {
-                            0 => NetPhysicsMode::Static,
-                            1 => NetPhysicsMode::Dynamic { ccd_enabled: false },
-                            2 => NetPhysicsMode::Dynamic { ccd_enabled: true },
-                            x => return Err(ReadSnapshotError::InvalidNetPhysicsMode(x)),
-                        };
-                        physics = Some(PhysicsComponent {
-                            collider_name,
-                            mode,
-                            collider: None,
-                            rigid_body: None,
-                        });
-                    }
-                    4 => {
-                        let index = u8::read_from(r)? as usize;
-                        let grab_state = match NonZeroU32::new(u32::read_from(r)?) {
-                            Some(net_id) => HandGrabState::Grabbing(NetId(net_id)),
-                            None => HandGrabState::Empty,
-                        };
-                        hand = Some(HandComponent { index, grab_state });
-                    }
-                    5 => {
-                        let grabbed = bool::read_from(r)?;
-                        grabbable = Some(GrabbableComponent { grabbed });
-                    }
-                    token => return Err(ReadSnapshotError::InvalidGameStateToken(token)),
-                }
-            }
+            // Update this entity's other components.
+            let transform = entity_snapshot.transform.map(TransformComponent);
+            let render = entity_snapshot
+                .model_name
+                .clone()
+                .map(|model_name| RenderComponent {
+                    model_name,
+                    model_handle: ModelHandle::null(),
+                });
+            let physics = entity_snapshot
+                .physics
+                .clone()
+                .map(|physics| PhysicsComponent {
+                    collider_name: physics.collider_name,
+                    mode: physics.mode,
+                    collider: None,
+                    rigid_body: None,
+                });
+            let hand = entity_snapshot.hand.map(|hand| HandComponent {
+                index: hand.index,
+                grab_state: hand.grab_state,
+            });
+            let grabbable = entity_snapshot
+                .grabbed
+                .map(|grabbed| GrabbableComponent { grabbed });
             let mut ctx = NetComponentDestroyContext {
                 physics: &mut physics_resource,
             };
@@ -185,7 +426,28 @@ pub fn apply_snapshot(r: &mut &[u8], world: &mut World) -> Result<(), ReadSnapsh
             update_component(entity.borrow_mut(), hand, ctx.borrow_mut());
             update_component(entity.borrow_mut(), grabbable, ctx.borrow_mut());
         }
-        Ok(())
+
+        // Despawn any entities that are absent from the snapshot.
+        for (net_id, entity) in entities_by_net_id {
+            if snapshot.entities.contains_key(&net_id) {
+                continue;
+            }
+            let mut ctx = NetComponentDestroyContext {
+                physics: &mut physics_resource,
+            };
+            let mut entity = world.entity_mut(entity);
+            update_component::<TransformComponent>(entity.borrow_mut(), None, ctx.borrow_mut());
+            update_component::<RenderComponent>(entity.borrow_mut(), None, ctx.borrow_mut());
+            update_component::<PhysicsComponent>(entity.borrow_mut(), None, ctx.borrow_mut());
+            update_component::<HandComponent>(entity.borrow_mut(), None, ctx.borrow_mut());
+            update_component::<GrabbableComponent>(entity.borrow_mut(), None, ctx.borrow_mut());
+            update_component::<FlyAroundComponent>(entity.borrow_mut(), None, ctx.borrow_mut());
+            entity.despawn();
+            world
+                .resource_mut::<EntitiesByNetIdResource>()
+                .0
+                .remove(&net_id);
+        }
     })
 }
 
@@ -207,3 +469,214 @@ fn update_component<T: NetComponent>(
         (None, None) => (),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use bevy_ecs::prelude::*;
+    use rapier3d::prelude::*;
+
+    use crate::collider_cache::ColliderCache;
+    use dungeon_vr_stream_codec::ExternalStreamCodec;
+
+    use crate::core::{Authority, NetId, NetTransformCodec};
+    use crate::interaction::HandGrabState;
+    use crate::physics::{NetPhysicsMode, PhysicsComponent, PhysicsResource};
+    use crate::resources::EntitiesByNetIdResource;
+
+    use super::{
+        apply_snapshot, read_snapshot, write_snapshot, EntitySnapshot, HandSnapshot,
+        PhysicsSnapshot, Snapshot,
+    };
+
+    fn net_id(id: u32) -> NetId {
+        NetId(NonZeroU32::new(id).unwrap())
+    }
+
+    fn wall(x: f32) -> EntitySnapshot {
+        EntitySnapshot {
+            authority: Authority::Server,
+            transform: Some(vector![x, 0.0, 0.0].into()),
+            model_name: Some("LowPolyDungeon/Dungeon_Wall_Var1".to_string()),
+            physics: Some(PhysicsSnapshot {
+                collider_name: "LowPolyDungeon/Dungeon_Wall_Var1_col".to_string(),
+                mode: NetPhysicsMode::Static,
+            }),
+            hand: None,
+            grabbed: None,
+        }
+    }
+
+    fn key(y: f32) -> EntitySnapshot {
+        EntitySnapshot {
+            authority: Authority::Server,
+            transform: Some(vector![0.0, y, 0.0].into()),
+            model_name: Some("LowPolyDungeon/Key_Silver".to_string()),
+            physics: Some(PhysicsSnapshot {
+                collider_name: "LowPolyDungeon/Key_Silver".to_string(),
+                mode: NetPhysicsMode::Dynamic { ccd_enabled: true },
+            }),
+            hand: None,
+            grabbed: Some(false),
+        }
+    }
+
+    /// Passes every transform through the lossy network encoding, as a client would see it.
+    fn quantized(snapshot: &Snapshot) -> Snapshot {
+        let mut snapshot = snapshot.clone();
+        for transform in snapshot
+            .entities
+            .values_mut()
+            .filter_map(|entity| entity.transform.as_mut())
+        {
+            let mut w = Vec::new();
+            NetTransformCodec::write_to_ext(&mut w, transform).unwrap();
+            *transform = NetTransformCodec::read_from_ext(&mut &w[..]).unwrap();
+        }
+        snapshot
+    }
+
+    fn round_trip(baseline: &Snapshot, snapshot: &Snapshot) -> Vec<u8> {
+        let mut w = Vec::new();
+        write_snapshot(&mut w, baseline, snapshot).unwrap();
+        let mut r = &w[..];
+        assert_eq!(
+            read_snapshot(&mut r, &quantized(baseline)).unwrap(),
+            quantized(snapshot),
+        );
+        assert!(r.is_empty());
+        w
+    }
+
+    #[test]
+    fn full_round_trip() {
+        let snapshot = Snapshot {
+            entities: [(net_id(1), wall(0.0)), (net_id(2), key(1.0))]
+                .into_iter()
+                .collect(),
+        };
+        round_trip(&Snapshot::default(), &snapshot);
+    }
+
+    #[test]
+    fn unchanged_entities_are_omitted() {
+        let baseline = Snapshot {
+            entities: (1..=100)
+                .map(|id| (net_id(id), wall(id as f32 * 0.1)))
+                .collect(),
+        };
+        let mut snapshot = baseline.clone();
+        snapshot.entities.insert(net_id(101), key(0.5));
+
+        let full = round_trip(&Snapshot::default(), &snapshot);
+        let delta = round_trip(&baseline, &snapshot);
+        assert!(delta.len() * 20 < full.len());
+    }
+
+    #[test]
+    fn changed_components_round_trip() {
+        let baseline = Snapshot {
+            entities: [(net_id(1), key(1.0)), (net_id(2), key(2.0))]
+                .into_iter()
+                .collect(),
+        };
+        let mut snapshot = baseline.clone();
+        let entity = snapshot.entities.get_mut(&net_id(1)).unwrap();
+        entity.transform = Some(vector![0.0, 0.5, 0.0].into());
+        entity.grabbed = Some(true);
+        let entity = snapshot.entities.get_mut(&net_id(2)).unwrap();
+        entity.model_name = None;
+        entity.physics = None;
+        round_trip(&baseline, &snapshot);
+    }
+
+    #[test]
+    fn hands_round_trip() {
+        let hand = |grab_state| EntitySnapshot {
+            authority: Authority::Server,
+            transform: None,
+            model_name: None,
+            physics: None,
+            hand: Some(HandSnapshot {
+                index: 1,
+                grab_state,
+            }),
+            grabbed: None,
+        };
+        let snapshot = Snapshot {
+            entities: [
+                (net_id(1), hand(HandGrabState::Empty)),
+                (net_id(2), hand(HandGrabState::Grabbing(net_id(1_000_000)))),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        round_trip(&Snapshot::default(), &snapshot);
+    }
+
+    #[test]
+    fn small_ids_are_compact() {
+        // One entity with no components: two counts, a net ID, the authority, and the end token.
+        let snapshot = Snapshot {
+            entities: [(net_id(1), EntitySnapshot::new(Authority::Server))]
+                .into_iter()
+                .collect(),
+        };
+        let w = round_trip(&Snapshot::default(), &snapshot);
+        assert_eq!(w.len(), 5);
+    }
+
+    #[test]
+    fn removed_entities_round_trip() {
+        let baseline = Snapshot {
+            entities: [(net_id(1), wall(0.0)), (net_id(2), key(1.0))]
+                .into_iter()
+                .collect(),
+        };
+        let mut snapshot = baseline.clone();
+        snapshot.entities.remove(&net_id(2));
+        round_trip(&baseline, &snapshot);
+    }
+
+    #[test]
+    fn absent_entities_are_despawned() {
+        let mut world = World::new();
+        world.insert_resource(PhysicsResource::new(
+            RigidBodySet::new(),
+            ColliderSet::new(),
+            ColliderCache::new(),
+            0.05,
+        ));
+        world.insert_resource(EntitiesByNetIdResource::default());
+
+        let mut snapshot = Snapshot {
+            entities: [(net_id(1), wall(0.0)), (net_id(2), key(1.0))]
+                .into_iter()
+                .collect(),
+        };
+        apply_snapshot(&snapshot, &mut world);
+        assert_eq!(world.entities().len(), 2);
+
+        // Give the key a rigid body, as the physics sync system would.
+        let key_entity = world.resource::<EntitiesByNetIdResource>().0[&net_id(2)];
+        let rigid_body = world
+            .resource_mut::<PhysicsResource>()
+            .bodies
+            .insert(RigidBodyBuilder::dynamic());
+        world
+            .get_mut::<PhysicsComponent>(key_entity)
+            .unwrap()
+            .rigid_body = Some(rigid_body);
+
+        snapshot.entities.remove(&net_id(2));
+        apply_snapshot(&snapshot, &mut world);
+        assert_eq!(world.entities().len(), 1);
+        assert!(world.get_entity(key_entity).is_none());
+        assert!(!world
+            .resource::<EntitiesByNetIdResource>()
+            .0
+            .contains_key(&net_id(2)));
+        assert!(world.resource::<PhysicsResource>().bodies.is_empty());
+    }
+}
@@ -0,0 +1,560 @@
+//! Packet captures in the pcap format. Recording sockets write every datagram they send or receive
+//! to a capture, and a replay socket feeds the datagrams a recording received back in.
+//!
+//! Captures use the `LINKTYPE_USER0` link type. Each record begins with a direction byte (0 for
+//! received, 1 for sent), then the peer address as a big-endian `u16` length followed by that many
+//! bytes, then the datagram. Connected sockets record an empty address.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::future::pending;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+use crate::{AddrBound, BoundSocket, ConnectedSocket};
+
+/// `LINKTYPE_USER0`, reserved for private use.
+pub const LINKTYPE: u32 = 147;
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const SNAPLEN: u32 = 262144;
+
+/// An address that can be stored in a capture.
+pub trait CaptureAddr: AddrBound {
+    fn write_capture(&self, w: &mut Vec<u8>);
+
+    /// Returns `None` if `data` is not an address written by `write_capture`.
+    fn read_capture(data: &[u8]) -> Option<Self>;
+}
+
+impl CaptureAddr for SocketAddr {
+    fn write_capture(&self, w: &mut Vec<u8>) {
+        match self.ip() {
+            IpAddr::V4(ip) => {
+                w.push(4);
+                w.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                w.push(6);
+                w.extend_from_slice(&ip.octets());
+            }
+        }
+        w.extend_from_slice(&self.port().to_be_bytes());
+    }
+
+    fn read_capture(data: &[u8]) -> Option<Self> {
+        let (ip, port) = match data {
+            [4, rest @ ..] if rest.len() == 6 => {
+                let octets: [u8; 4] = rest[..4].try_into().unwrap();
+                (IpAddr::V4(Ipv4Addr::from(octets)), &rest[4..])
+            }
+            [6, rest @ ..] if rest.len() == 18 => {
+                let octets: [u8; 16] = rest[..16].try_into().unwrap();
+                (IpAddr::V6(Ipv6Addr::from(octets)), &rest[16..])
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(
+            ip,
+            u16::from_be_bytes(port.try_into().unwrap()),
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// One datagram read back from a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureRecord<Addr> {
+    /// When the datagram was recorded, relative to the Unix epoch.
+    pub timestamp: Duration,
+    pub direction: Direction,
+    /// The peer's address, or `None` if a connected socket recorded the datagram.
+    pub addr: Option<Addr>,
+    pub data: Vec<u8>,
+}
+
+/// Writes a capture. Timestamps advance with Tokio's clock, so a test with paused time records
+/// repeatable captures.
+pub struct CaptureWriter {
+    w: std::sync::Mutex<Box<dyn Write + Send>>,
+    start_timestamp: Duration,
+    start: Instant,
+}
+
+impl CaptureWriter {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn new(mut w: impl Write + Send + 'static) -> io::Result<Self> {
+        w.write_all(&MAGIC_NANOS.to_le_bytes())?;
+        w.write_all(&2u16.to_le_bytes())?;
+        w.write_all(&4u16.to_le_bytes())?;
+        w.write_all(&0i32.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&SNAPLEN.to_le_bytes())?;
+        w.write_all(&LINKTYPE.to_le_bytes())?;
+        w.flush()?;
+
+        Ok(Self {
+            w: std::sync::Mutex::new(Box::new(w)),
+            start_timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            start: Instant::now(),
+        })
+    }
+
+    fn record<Addr: CaptureAddr>(&self, direction: Direction, addr: Option<Addr>, data: &[u8]) {
+        let mut record = vec![match direction {
+            Direction::Received => 0,
+            Direction::Sent => 1,
+        }];
+        let mut addr_data = Vec::new();
+        if let Some(addr) = addr {
+            addr.write_capture(&mut addr_data);
+        }
+        record.extend_from_slice(&(addr_data.len() as u16).to_be_bytes());
+        record.extend_from_slice(&addr_data);
+        record.extend_from_slice(data);
+
+        // Flush every record so that a capture survives a crash.
+        let timestamp = self.start_timestamp + self.start.elapsed();
+        let mut w = self.w.lock().unwrap();
+        let result = (|| {
+            w.write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+            w.write_all(&timestamp.subsec_nanos().to_le_bytes())?;
+            w.write_all(&(record.len() as u32).to_le_bytes())?;
+            w.write_all(&(record.len() as u32).to_le_bytes())?;
+            w.write_all(&record)?;
+            w.flush()
+        })();
+        if let Err(e) = result {
+            log::warn!("Unable to write packet capture: {e}");
+        }
+    }
+}
+
+/// Reads every record of a capture.
+pub fn read_capture<Addr: CaptureAddr>(r: impl Read) -> io::Result<Vec<CaptureRecord<Addr>>> {
+    let mut r = BufReader::new(r);
+    let mut header = [0; 24];
+    r.read_exact(&mut header)?;
+
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let (big_endian, nanos) = match magic {
+        MAGIC_MICROS => (false, false),
+        MAGIC_NANOS => (false, true),
+        _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+        _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+        _ => return Err(invalid_data("not a pcap file")),
+    };
+    let read_u32 = |data: &[u8]| {
+        let bytes = data[..4].try_into().unwrap();
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let linktype = read_u32(&header[20..]);
+    if linktype != LINKTYPE {
+        return Err(invalid_data(format!("unexpected link type {linktype}")));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let mut record_header = [0; 16];
+        match r.read_exact(&mut record_header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let secs = read_u32(&record_header[..]);
+        let fraction = read_u32(&record_header[4..]);
+        let subsec_nanos = match (nanos, fraction) {
+            (true, 0..=999_999_999) => fraction,
+            (false, 0..=999_999) => fraction * 1000,
+            _ => return Err(invalid_data("bad timestamp fraction")),
+        };
+        let timestamp = Duration::new(secs.into(), subsec_nanos);
+        let len = read_u32(&record_header[8..]);
+        if len > SNAPLEN {
+            return Err(invalid_data(format!("record length {len} exceeds snaplen")));
+        }
+        let mut record = vec![0; len as usize];
+        r.read_exact(&mut record)?;
+
+        let direction = match record.first() {
+            Some(0) => Direction::Received,
+            Some(1) => Direction::Sent,
+            _ => return Err(invalid_data("bad direction")),
+        };
+        if record.len() < 3 {
+            return Err(invalid_data("truncated record"));
+        }
+        let addr_len = u16::from_be_bytes([record[1], record[2]]) as usize;
+        let addr_data = record
+            .get(3..3 + addr_len)
+            .ok_or_else(|| invalid_data("truncated address"))?;
+        let addr = if addr_data.is_empty() {
+            None
+        } else {
+            Some(Addr::read_capture(addr_data).ok_or_else(|| invalid_data("bad address"))?)
+        };
+        records.push(CaptureRecord {
+            timestamp,
+            direction,
+            addr,
+            data: record[3 + addr_len..].to_vec(),
+        });
+    }
+    Ok(records)
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+pub struct RecordingBoundSocket<T, Addr> {
+    inner: T,
+    writer: CaptureWriter,
+    _phantom_addr: PhantomData<Addr>,
+}
+
+impl<T, Addr> RecordingBoundSocket<T, Addr>
+where
+    T: BoundSocket<Addr>,
+    Addr: CaptureAddr,
+{
+    pub fn new(inner: T, writer: CaptureWriter) -> Self {
+        Self {
+            inner,
+            writer,
+            _phantom_addr: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<T, Addr> BoundSocket<Addr> for RecordingBoundSocket<T, Addr>
+where
+    T: BoundSocket<Addr>,
+    Addr: CaptureAddr,
+{
+    async fn recv_from(&'_ self, buf: &'_ mut [u8]) -> io::Result<(usize, Addr)> {
+        let (size, addr) = self.inner.recv_from(buf).await?;
+        self.writer
+            .record(Direction::Received, Some(addr), &buf[..size]);
+        Ok((size, addr))
+    }
+
+    async fn send_to(&'_ self, buf: &'_ [u8], addr: Addr) -> io::Result<()> {
+        self.inner.send_to(buf, addr).await?;
+        self.writer.record(Direction::Sent, Some(addr), buf);
+        Ok(())
+    }
+}
+
+pub struct RecordingConnectedSocket<T> {
+    inner: T,
+    writer: CaptureWriter,
+}
+
+impl<T> RecordingConnectedSocket<T>
+where
+    T: ConnectedSocket,
+{
+    pub fn new(inner: T, writer: CaptureWriter) -> Self {
+        Self { inner, writer }
+    }
+}
+
+#[async_trait]
+impl<T> ConnectedSocket for RecordingConnectedSocket<T>
+where
+    T: ConnectedSocket,
+{
+    async fn recv(&'_ self, buf: &'_ mut [u8]) -> io::Result<usize> {
+        let size = self.inner.recv(buf).await?;
+        self.writer
+            .record::<SocketAddr>(Direction::Received, None, &buf[..size]);
+        Ok(size)
+    }
+
+    async fn send(&'_ self, buf: &'_ [u8]) -> io::Result<()> {
+        self.inner.send(buf).await?;
+        self.writer.record::<SocketAddr>(Direction::Sent, None, buf);
+        Ok(())
+    }
+}
+
+/// A datagram sent to a [`ReplaySocket`], with its destination if sent from a bound socket.
+pub type SentDatagram<Addr> = (Vec<u8>, Option<Addr>);
+
+/// Receives the datagrams a recording socket received, at the same times relative to the first
+/// record. The datagrams it sent are skipped. Whatever is sent to the replay socket comes out of
+/// the receiver returned by [`ReplaySocket::new`] instead.
+///
+/// Under paused Tokio time, a replay unfolds the same way on every run.
+pub struct ReplaySocket<Addr> {
+    start: Instant,
+    first_timestamp: Duration,
+    received: tokio::sync::Mutex<VecDeque<CaptureRecord<Addr>>>,
+    sent_tx: mpsc::UnboundedSender<SentDatagram<Addr>>,
+}
+
+impl<Addr> ReplaySocket<Addr>
+where
+    Addr: AddrBound,
+{
+    pub fn new(
+        records: Vec<CaptureRecord<Addr>>,
+    ) -> (Self, mpsc::UnboundedReceiver<SentDatagram<Addr>>) {
+        let first_timestamp = records
+            .first()
+            .map(|record| record.timestamp)
+            .unwrap_or_default();
+        let (sent_tx, sent_rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                start: Instant::now(),
+                first_timestamp,
+                received: tokio::sync::Mutex::new(
+                    records
+                        .into_iter()
+                        .filter(|record| record.direction == Direction::Received)
+                        .collect(),
+                ),
+                sent_tx,
+            },
+            sent_rx,
+        )
+    }
+
+    /// Waits for the next received datagram's time to come. Never returns once the recording is
+    /// exhausted.
+    ///
+    /// The record stays queued until its time has come, so a caller that gives up waiting (as a
+    /// `select!` does whenever a timer fires first) loses nothing.
+    async fn next_received(&self) -> CaptureRecord<Addr> {
+        let mut received = self.received.lock().await;
+        let timestamp = match received.front() {
+            Some(record) => record.timestamp,
+            None => pending().await,
+        };
+        sleep_until(self.start + timestamp.saturating_sub(self.first_timestamp)).await;
+        received.pop_front().unwrap()
+    }
+}
+
+#[async_trait]
+impl<Addr> BoundSocket<Addr> for ReplaySocket<Addr>
+where
+    Addr: AddrBound,
+{
+    async fn recv_from(&'_ self, buf: &'_ mut [u8]) -> io::Result<(usize, Addr)> {
+        let record = self.next_received().await;
+        let addr = record
+            .addr
+            .ok_or_else(|| invalid_data("recorded by a connected socket"))?;
+        buf[..record.data.len()].copy_from_slice(&record.data);
+        Ok((record.data.len(), addr))
+    }
+
+    async fn send_to(&'_ self, buf: &'_ [u8], addr: Addr) -> io::Result<()> {
+        drop(self.sent_tx.send((buf.to_vec(), Some(addr))));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<Addr> ConnectedSocket for ReplaySocket<Addr>
+where
+    Addr: AddrBound,
+{
+    async fn recv(&'_ self, buf: &'_ mut [u8]) -> io::Result<usize> {
+        let record = self.next_received().await;
+        buf[..record.data.len()].copy_from_slice(&record.data);
+        Ok(record.data.len())
+    }
+
+    async fn send(&'_ self, buf: &'_ [u8]) -> io::Result<()> {
+        drop(self.sent_tx.send((buf.to_vec(), None)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::time::{sleep, timeout, Instant};
+
+    use super::{
+        read_capture, CaptureAddr, CaptureRecord, CaptureWriter, Direction, RecordingBoundSocket,
+        ReplaySocket, LINKTYPE, MAGIC_MICROS, MAGIC_NANOS, SNAPLEN,
+    };
+    use crate::testing::FakeNetwork;
+    use crate::{BoundSocket, ConnectedSocket};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn socket_addr_round_trip() {
+        for addr in ["1.2.3.4:5678", "[::1]:9"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let mut w = Vec::new();
+            addr.write_capture(&mut w);
+            assert_eq!(SocketAddr::read_capture(&w), Some(addr));
+        }
+        assert_eq!(SocketAddr::read_capture(&[4, 1, 2, 3]), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recording_round_trip() {
+        let a: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        let network = FakeNetwork::new();
+        let buf = SharedBuf::default();
+        let socket_a =
+            RecordingBoundSocket::new(network.bind(a), CaptureWriter::new(buf.clone()).unwrap());
+        let socket_b = network.bind(b);
+
+        socket_a.send_to(b"ping", b).await.unwrap();
+        let mut data = [0; 16];
+        assert_eq!(socket_b.recv_from(&mut data).await.unwrap(), (4, a));
+        sleep(Duration::from_millis(250)).await;
+        socket_b.send_to(b"pong!", a).await.unwrap();
+        assert_eq!(socket_a.recv_from(&mut data).await.unwrap(), (5, b));
+
+        let records = read_capture::<SocketAddr>(&buf.0.lock().unwrap()[..]).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].direction, records[0].addr, &records[0].data[..]),
+            (Direction::Sent, Some(b), &b"ping"[..]),
+        );
+        assert_eq!(
+            (records[1].direction, records[1].addr, &records[1].data[..]),
+            (Direction::Received, Some(b), &b"pong!"[..]),
+        );
+        assert_eq!(
+            records[1].timestamp - records[0].timestamp,
+            Duration::from_millis(250),
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_receives_at_recorded_times() {
+        let record = |millis, direction, data: &[u8]| CaptureRecord {
+            timestamp: Duration::from_secs(1_000_000) + Duration::from_millis(millis),
+            direction,
+            addr: None,
+            data: data.to_vec(),
+        };
+        let (socket, mut sent) = ReplaySocket::<SocketAddr>::new(vec![
+            record(0, Direction::Sent, b"hello"),
+            record(100, Direction::Received, b"a"),
+            record(300, Direction::Received, b"b"),
+        ]);
+        let start = Instant::now();
+
+        let mut data = [0; 16];
+        assert_eq!(socket.recv(&mut data).await.unwrap(), 1);
+        assert_eq!(
+            (&data[..1], start.elapsed()),
+            (&b"a"[..], Duration::from_millis(100))
+        );
+        assert_eq!(socket.recv(&mut data).await.unwrap(), 1);
+        assert_eq!(
+            (&data[..1], start.elapsed()),
+            (&b"b"[..], Duration::from_millis(300))
+        );
+
+        socket.send(b"reply").await.unwrap();
+        assert_eq!(sent.recv().await.unwrap(), (b"reply".to_vec(), None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_keeps_records_when_a_receive_is_cancelled() {
+        let record = |millis, data: &[u8]| CaptureRecord {
+            timestamp: Duration::from_millis(millis),
+            direction: Direction::Received,
+            addr: None,
+            data: data.to_vec(),
+        };
+        let (socket, _sent) =
+            ReplaySocket::<SocketAddr>::new(vec![record(0, b"a"), record(300, b"b")]);
+        let start = Instant::now();
+
+        let mut data = [0; 16];
+        assert_eq!(socket.recv(&mut data).await.unwrap(), 1);
+        assert!(timeout(Duration::from_millis(100), socket.recv(&mut data))
+            .await
+            .is_err());
+        assert_eq!(socket.recv(&mut data).await.unwrap(), 1);
+        assert_eq!(
+            (&data[..1], start.elapsed()),
+            (&b"b"[..], Duration::from_millis(300))
+        );
+    }
+
+    #[test]
+    fn read_capture_rejects_bad_record_headers() {
+        let capture = |magic: u32, fraction: u32, len: u32| {
+            let mut w = Vec::new();
+            w.extend_from_slice(&magic.to_le_bytes());
+            w.extend_from_slice(&[2, 0, 4, 0]);
+            w.extend_from_slice(&[0; 8]);
+            w.extend_from_slice(&SNAPLEN.to_le_bytes());
+            w.extend_from_slice(&LINKTYPE.to_le_bytes());
+            w.extend_from_slice(&0u32.to_le_bytes());
+            w.extend_from_slice(&fraction.to_le_bytes());
+            w.extend_from_slice(&len.to_le_bytes());
+            w.extend_from_slice(&len.to_le_bytes());
+            w.extend_from_slice(&[0, 0, 0]);
+            read_capture::<SocketAddr>(&w[..]).map(|records| records.len())
+        };
+
+        assert_eq!(capture(MAGIC_MICROS, 999_999, 3).unwrap(), 1);
+        assert_eq!(capture(MAGIC_NANOS, 999_999_999, 3).unwrap(), 1);
+        for (magic, fraction, len) in [
+            (MAGIC_MICROS, 1_000_000, 3),
+            (MAGIC_MICROS, u32::MAX, 3),
+            (MAGIC_NANOS, 1_000_000_000, 3),
+            (MAGIC_NANOS, 0, SNAPLEN + 1),
+        ] {
+            assert_eq!(
+                capture(magic, fraction, len).unwrap_err().kind(),
+                io::ErrorKind::InvalidData,
+            );
+        }
+    }
+}
@@ -3,19 +3,262 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use rand::{thread_rng, Rng};
-use rand_distr::Exp;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Exp1, StandardNormal};
+use thiserror::Error;
 use tokio::select;
 use tokio::sync::{mpsc, Mutex};
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 
 use crate::{AddrBound, BoundSocket, ConnectedSocket};
 
+/// The longest mean delay, spread, or standard deviation a [`DelayDistribution`] may have. This
+/// keeps samples from the tails of the unbounded distributions well within [`Duration`]'s range.
+pub const MAX_DELAY: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FakeLagConfigError {
+    #[error("{0} must be nonzero")]
+    Zero(&'static str),
+
+    #[error("{name} {delay:?} must be at most {MAX_DELAY:?}")]
+    DelayTooLong { name: &'static str, delay: Duration },
+
+    #[error("{0} must be between 0 and 1")]
+    ProbabilityOutOfRange(&'static str),
+}
+
+/// How a fake lag socket mistreats packets. Incoming and outgoing packets are conditioned
+/// independently, each with the same settings.
+#[derive(Clone, Debug)]
+pub struct FakeLagConfig {
+    /// The delay added to each packet.
+    pub delay: DelayDistribution,
+    /// Which packets never arrive.
+    pub loss: Loss,
+    /// The probability that a packet arrives twice. Each copy is delayed independently.
+    pub duplicate_probability: f64,
+    /// The probability that a packet skips the delay, overtaking packets sent before it.
+    pub reorder_probability: f64,
+    /// Limits each direction to this many bytes per second. Packets wait in a queue for their turn
+    /// to be sent.
+    pub bandwidth: Option<u64>,
+    /// The most bytes the bandwidth queue holds. Packets that would overflow it are dropped.
+    pub queue_limit: usize,
+    /// Seeds the random number generators so that a run can be reproduced.
+    pub seed: Option<u64>,
+}
+
+impl FakeLagConfig {
+    /// Checks that every setting is one the sockets can act on. Sockets and fake network links
+    /// check this before they accept a config.
+    pub fn validate(&self) -> Result<(), FakeLagConfigError> {
+        for (name, delay) in self.delay.parameters() {
+            if delay > MAX_DELAY {
+                return Err(FakeLagConfigError::DelayTooLong { name, delay });
+            }
+        }
+        let mut probabilities = vec![
+            ("duplicate probability", self.duplicate_probability),
+            ("reorder probability", self.reorder_probability),
+        ];
+        match self.loss {
+            Loss::None => (),
+            Loss::Random { probability } => probabilities.push(("loss probability", probability)),
+            Loss::GilbertElliott {
+                good_to_bad,
+                bad_to_good,
+                good_loss,
+                bad_loss,
+            } => probabilities.extend([
+                ("good to bad probability", good_to_bad),
+                ("bad to good probability", bad_to_good),
+                ("good loss probability", good_loss),
+                ("bad loss probability", bad_loss),
+            ]),
+        }
+        for (name, probability) in probabilities {
+            // NaN is not contained in any range.
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(FakeLagConfigError::ProbabilityOutOfRange(name));
+            }
+        }
+        if self.bandwidth == Some(0) {
+            return Err(FakeLagConfigError::Zero("bandwidth"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for FakeLagConfig {
+    fn default() -> Self {
+        Self {
+            delay: DelayDistribution::Constant(Duration::ZERO),
+            loss: Loss::None,
+            duplicate_probability: 0.0,
+            reorder_probability: 0.0,
+            bandwidth: None,
+            queue_limit: 64 * 1024,
+            seed: None,
+        }
+    }
+}
+
+/// The distribution each packet's delay is drawn from. Samples that would be negative are clamped
+/// to zero.
+#[derive(Clone, Copy, Debug)]
+pub enum DelayDistribution {
+    Constant(Duration),
+    Exponential {
+        mean: Duration,
+    },
+    /// Uniform between `mean - spread` and `mean + spread`.
+    Uniform {
+        mean: Duration,
+        spread: Duration,
+    },
+    Normal {
+        mean: Duration,
+        std_dev: Duration,
+    },
+}
+
+impl DelayDistribution {
+    fn parameters(&self) -> Vec<(&'static str, Duration)> {
+        match *self {
+            Self::Constant(delay) => vec![("delay", delay)],
+            Self::Exponential { mean } => vec![("mean delay", mean)],
+            Self::Uniform { mean, spread } => vec![("mean delay", mean), ("delay spread", spread)],
+            Self::Normal { mean, std_dev } => {
+                vec![("mean delay", mean), ("delay standard deviation", std_dev)]
+            }
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> Duration {
+        let secs = match *self {
+            Self::Constant(delay) => return delay,
+            Self::Exponential { mean } => mean.as_secs_f64() * rng.sample::<f64, _>(Exp1),
+            Self::Uniform { mean, spread } => {
+                mean.as_secs_f64() + spread.as_secs_f64() * rng.gen_range(-1.0..=1.0)
+            }
+            Self::Normal { mean, std_dev } => {
+                mean.as_secs_f64() + std_dev.as_secs_f64() * rng.sample::<f64, _>(StandardNormal)
+            }
+        };
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Loss {
+    None,
+    /// Each packet is lost independently with the given probability.
+    Random {
+        probability: f64,
+    },
+    /// The Gilbert-Elliott model of bursty loss. The link flips between a good and a bad state
+    /// before each packet and then loses it with that state's probability.
+    GilbertElliott {
+        good_to_bad: f64,
+        bad_to_good: f64,
+        good_loss: f64,
+        bad_loss: f64,
+    },
+}
+
+/// Decides the fate of the packets travelling in one direction.
+pub(crate) struct Conditioner {
+    config: FakeLagConfig,
+    rng: StdRng,
+    bad_state: bool,
+    queue_drained_at: Instant,
+}
+
+impl Conditioner {
+    /// `config` must be valid.
+    pub(crate) fn new(config: FakeLagConfig, stream: u64) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(stream)),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            config,
+            rng,
+            bad_state: false,
+            queue_drained_at: Instant::now(),
+        }
+    }
+
+    /// Returns how long after `now` each copy of a `len` byte packet is delivered. Lost packets
+    /// have no copies.
+    pub(crate) fn schedule(&mut self, len: usize, now: Instant) -> Vec<Duration> {
+        if self.lose() {
+            return Vec::new();
+        }
+
+        let mut queue_delay = Duration::ZERO;
+        if let Some(bandwidth) = self.config.bandwidth {
+            let backlog = self.queue_drained_at.saturating_duration_since(now);
+            let queued_bytes = (backlog.as_secs_f64() * bandwidth as f64) as usize;
+            if queued_bytes + len > self.config.queue_limit {
+                return Vec::new();
+            }
+            self.queue_drained_at = self.queue_drained_at.max(now)
+                + Duration::from_secs_f64(len as f64 / bandwidth as f64);
+            queue_delay = self.queue_drained_at - now;
+        }
+
+        let copies = if self.rng.gen::<f64>() < self.config.duplicate_probability {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                if self.rng.gen::<f64>() < self.config.reorder_probability {
+                    queue_delay
+                } else {
+                    queue_delay + self.config.delay.sample(&mut self.rng)
+                }
+            })
+            .collect()
+    }
+
+    fn lose(&mut self) -> bool {
+        match self.config.loss {
+            Loss::None => false,
+            Loss::Random { probability } => self.rng.gen::<f64>() < probability,
+            Loss::GilbertElliott {
+                good_to_bad,
+                bad_to_good,
+                good_loss,
+                bad_loss,
+            } => {
+                let flip = if self.bad_state {
+                    bad_to_good
+                } else {
+                    good_to_bad
+                };
+                if self.rng.gen::<f64>() < flip {
+                    self.bad_state = !self.bad_state;
+                }
+                let loss = if self.bad_state { bad_loss } else { good_loss };
+                self.rng.gen::<f64>() < loss
+            }
+        }
+    }
+}
+
+pub(crate) const SEND_STREAM: u64 = 0;
+pub(crate) const RECV_STREAM: u64 = 1;
+
 pub struct FakeLagBoundSocket<T, Addr> {
     _cancel_guard: cancel::Guard,
     inner: Arc<T>,
     packet_rx: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, Addr)>>,
-    delay_sec: Exp<f64>,
+    send_conditioner: std::sync::Mutex<Conditioner>,
 }
 
 impl<T, Addr> FakeLagBoundSocket<T, Addr>
@@ -23,31 +266,43 @@ where
     T: BoundSocket<Addr>,
     Addr: AddrBound,
 {
-    pub fn new(inner: T, mean_delay: Duration) -> Self {
+    /// Adds exponentially distributed delay with the given mean to all incoming and outgoing
+    /// packets.
+    pub fn new(inner: T, mean_delay: Duration) -> Result<Self, FakeLagConfigError> {
+        Self::with_config(
+            inner,
+            FakeLagConfig {
+                delay: DelayDistribution::Exponential { mean: mean_delay },
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_config(inner: T, config: FakeLagConfig) -> Result<Self, FakeLagConfigError> {
+        config.validate()?;
         let cancel_token = cancel::Token::new();
         let inner = Arc::new(inner);
         let (packet_tx, packet_rx) = mpsc::unbounded_channel();
-        let delay_sec = Exp::new(1.0 / mean_delay.as_secs_f64()).unwrap();
 
         tokio::spawn(Self::run(
             cancel_token.clone(),
             Arc::clone(&inner),
-            delay_sec,
+            Conditioner::new(config.clone(), RECV_STREAM),
             packet_tx,
         ));
 
-        Self {
+        Ok(Self {
             _cancel_guard: cancel_token.guard(),
             inner,
             packet_rx: Mutex::new(packet_rx),
-            delay_sec,
-        }
+            send_conditioner: std::sync::Mutex::new(Conditioner::new(config, SEND_STREAM)),
+        })
     }
 
     async fn run(
         cancel_token: cancel::Token,
         inner: Arc<T>,
-        delay_sec: Exp<f64>,
+        mut conditioner: Conditioner,
         packet_tx: tokio::sync::mpsc::UnboundedSender<(Vec<u8>, Addr)>,
     ) {
         let mut buf = [0; 65536];
@@ -59,12 +314,14 @@ where
             };
             match result {
                 Ok((size, addr)) => {
-                    tokio::spawn(Self::delay_recv(
-                        Duration::from_secs_f64(thread_rng().sample(delay_sec)),
-                        packet_tx.clone(),
-                        buf[..size].to_vec(),
-                        addr,
-                    ));
+                    for delay in conditioner.schedule(size, Instant::now()) {
+                        tokio::spawn(Self::delay_recv(
+                            delay,
+                            packet_tx.clone(),
+                            buf[..size].to_vec(),
+                            addr,
+                        ));
+                    }
                 }
                 Err(e) => {
                     log::warn!("Unexpected socket error: {e}");
@@ -107,12 +364,19 @@ where
     }
 
     async fn send_to(&'_ self, buf: &'_ [u8], addr: Addr) -> io::Result<()> {
-        tokio::spawn(Self::delay_send(
-            Duration::from_secs_f64(thread_rng().sample(self.delay_sec)),
-            Arc::clone(&self.inner),
-            buf.to_vec(),
-            addr,
-        ));
+        let delays = self
+            .send_conditioner
+            .lock()
+            .unwrap()
+            .schedule(buf.len(), Instant::now());
+        for delay in delays {
+            tokio::spawn(Self::delay_send(
+                delay,
+                Arc::clone(&self.inner),
+                buf.to_vec(),
+                addr,
+            ));
+        }
         Ok(())
     }
 }
@@ -121,38 +385,50 @@ pub struct FakeLagConnectedSocket<T> {
     _cancel_guard: cancel::Guard,
     inner: Arc<T>,
     packet_rx: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
-    delay_sec: Exp<f64>,
+    send_conditioner: std::sync::Mutex<Conditioner>,
 }
 
 impl<T> FakeLagConnectedSocket<T>
 where
     T: ConnectedSocket,
 {
-    pub fn new(inner: T, mean_delay: Duration) -> Self {
+    /// Adds exponentially distributed delay with the given mean to all incoming and outgoing
+    /// packets.
+    pub fn new(inner: T, mean_delay: Duration) -> Result<Self, FakeLagConfigError> {
+        Self::with_config(
+            inner,
+            FakeLagConfig {
+                delay: DelayDistribution::Exponential { mean: mean_delay },
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_config(inner: T, config: FakeLagConfig) -> Result<Self, FakeLagConfigError> {
+        config.validate()?;
         let cancel_token = cancel::Token::new();
         let inner = Arc::new(inner);
         let (packet_tx, packet_rx) = mpsc::unbounded_channel();
-        let delay_sec = Exp::new(1.0 / mean_delay.as_secs_f64()).unwrap();
 
         tokio::spawn(Self::run(
             cancel_token.clone(),
             Arc::clone(&inner),
-            delay_sec,
+            Conditioner::new(config.clone(), RECV_STREAM),
             packet_tx,
         ));
 
-        Self {
+        Ok(Self {
             _cancel_guard: cancel_token.guard(),
             inner,
             packet_rx: Mutex::new(packet_rx),
-            delay_sec,
-        }
+            send_conditioner: std::sync::Mutex::new(Conditioner::new(config, SEND_STREAM)),
+        })
     }
 
     async fn run(
         cancel_token: cancel::Token,
         inner: Arc<T>,
-        delay_sec: Exp<f64>,
+        mut conditioner: Conditioner,
         packet_tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
     ) {
         let mut buf = [0; 65536];
@@ -164,11 +440,13 @@ where
             };
             match result {
                 Ok(size) => {
-                    tokio::spawn(Self::delay_recv(
-                        Duration::from_secs_f64(thread_rng().sample(delay_sec)),
-                        packet_tx.clone(),
-                        buf[..size].to_vec(),
-                    ));
+                    for delay in conditioner.schedule(size, Instant::now()) {
+                        tokio::spawn(Self::delay_recv(
+                            delay,
+                            packet_tx.clone(),
+                            buf[..size].to_vec(),
+                        ));
+                    }
                 }
                 Err(e) => {
                     log::warn!("Unexpected socket error: {e}");
@@ -209,11 +487,176 @@ where
     }
 
     async fn send(&'_ self, buf: &'_ [u8]) -> io::Result<()> {
-        tokio::spawn(Self::delay_send(
-            Duration::from_secs_f64(thread_rng().sample(self.delay_sec)),
-            Arc::clone(&self.inner),
-            buf.to_vec(),
-        ));
+        let delays = self
+            .send_conditioner
+            .lock()
+            .unwrap()
+            .schedule(buf.len(), Instant::now());
+        for delay in delays {
+            tokio::spawn(Self::delay_send(
+                delay,
+                Arc::clone(&self.inner),
+                buf.to_vec(),
+            ));
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{Conditioner, DelayDistribution, FakeLagConfig, FakeLagConfigError, Loss};
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(FakeLagConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_unusable_settings() {
+        assert_eq!(
+            FakeLagConfig {
+                bandwidth: Some(0),
+                ..Default::default()
+            }
+            .validate(),
+            Err(FakeLagConfigError::Zero("bandwidth")),
+        );
+        assert_eq!(
+            FakeLagConfig {
+                delay: DelayDistribution::Normal {
+                    mean: Duration::from_millis(50),
+                    std_dev: Duration::MAX,
+                },
+                ..Default::default()
+            }
+            .validate(),
+            Err(FakeLagConfigError::DelayTooLong {
+                name: "delay standard deviation",
+                delay: Duration::MAX,
+            }),
+        );
+        assert_eq!(
+            FakeLagConfig {
+                duplicate_probability: 1.5,
+                ..Default::default()
+            }
+            .validate(),
+            Err(FakeLagConfigError::ProbabilityOutOfRange(
+                "duplicate probability"
+            )),
+        );
+        assert_eq!(
+            FakeLagConfig {
+                loss: Loss::GilbertElliott {
+                    good_to_bad: 0.01,
+                    bad_to_good: f64::NAN,
+                    good_loss: 0.0,
+                    bad_loss: 1.0,
+                },
+                ..Default::default()
+            }
+            .validate(),
+            Err(FakeLagConfigError::ProbabilityOutOfRange(
+                "bad to good probability"
+            )),
+        );
+    }
+
+    #[test]
+    fn seeded_schedules_repeat() {
+        let config = FakeLagConfig {
+            delay: DelayDistribution::Normal {
+                mean: Duration::from_millis(50),
+                std_dev: Duration::from_millis(10),
+            },
+            loss: Loss::Random { probability: 0.1 },
+            duplicate_probability: 0.1,
+            reorder_probability: 0.1,
+            seed: Some(1234),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let schedules = |stream| {
+            let mut conditioner = Conditioner::new(config.clone(), stream);
+            (0..1000)
+                .map(|_| conditioner.schedule(100, now))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(schedules(0), schedules(0));
+        assert_ne!(schedules(0), schedules(1));
+    }
+
+    #[test]
+    fn gilbert_elliott_loss_comes_in_bursts() {
+        let mut conditioner = Conditioner::new(
+            FakeLagConfig {
+                loss: Loss::GilbertElliott {
+                    good_to_bad: 0.01,
+                    bad_to_good: 0.25,
+                    good_loss: 0.0,
+                    bad_loss: 1.0,
+                },
+                seed: Some(1234),
+                ..Default::default()
+            },
+            0,
+        );
+        let now = Instant::now();
+        let lost: Vec<bool> = (0..100_000)
+            .map(|_| conditioner.schedule(100, now).is_empty())
+            .collect();
+
+        let losses = lost.iter().filter(|&&lost| lost).count();
+        let bursts = lost.windows(2).filter(|w| !w[0] && w[1]).count();
+        // The bad state is entered about 1% of the time and lasts four packets on average.
+        assert!((2_000..6_000).contains(&losses), "{losses} losses");
+        assert!(losses as f64 / bursts as f64 > 3.0, "{bursts} bursts");
+    }
+
+    #[test]
+    fn bandwidth_limit_queues_then_drops() {
+        let mut conditioner = Conditioner::new(
+            FakeLagConfig {
+                bandwidth: Some(1000),
+                queue_limit: 2500,
+                seed: Some(1234),
+                ..Default::default()
+            },
+            0,
+        );
+        let now = Instant::now();
+
+        assert_eq!(conditioner.schedule(1000, now), [Duration::from_secs(1)]);
+        assert_eq!(conditioner.schedule(1000, now), [Duration::from_secs(2)]);
+        assert_eq!(conditioner.schedule(1000, now), []);
+        assert_eq!(
+            conditioner.schedule(1000, now + Duration::from_millis(1500)),
+            [Duration::from_millis(1500)],
+        );
+    }
+
+    #[test]
+    fn duplicated_and_reordered_packets() {
+        let mut conditioner = Conditioner::new(
+            FakeLagConfig {
+                delay: DelayDistribution::Constant(Duration::from_millis(100)),
+                duplicate_probability: 1.0,
+                reorder_probability: 1.0,
+                seed: Some(1234),
+                ..Default::default()
+            },
+            0,
+        );
+
+        assert_eq!(
+            conditioner.schedule(100, Instant::now()),
+            [Duration::ZERO, Duration::ZERO],
+        );
+    }
+}
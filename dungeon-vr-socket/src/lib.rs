@@ -7,6 +7,7 @@ use std::io;
 
 use async_trait::async_trait;
 
+pub mod capture;
 pub mod fakelag;
 mod std_impls;
 pub mod testing;
@@ -5,7 +5,7 @@ use async_trait::async_trait;
 use futures::TryFutureExt;
 use tokio::net::UdpSocket;
 
-use crate::{BoundSocket, ConnectedSocket};
+use crate::{AddrBound, BoundSocket, ConnectedSocket};
 
 #[async_trait]
 impl BoundSocket<SocketAddr> for UdpSocket {
@@ -28,3 +28,28 @@ impl ConnectedSocket for UdpSocket {
         self.send(buf).map_ok(|_| ()).await
     }
 }
+
+#[async_trait]
+impl<Addr> BoundSocket<Addr> for Box<dyn BoundSocket<Addr>>
+where
+    Addr: AddrBound,
+{
+    async fn recv_from(&'_ self, buf: &'_ mut [u8]) -> io::Result<(usize, Addr)> {
+        (**self).recv_from(buf).await
+    }
+
+    async fn send_to(&'_ self, buf: &'_ [u8], addr: Addr) -> io::Result<()> {
+        (**self).send_to(buf, addr).await
+    }
+}
+
+#[async_trait]
+impl ConnectedSocket for Box<dyn ConnectedSocket> {
+    async fn recv(&'_ self, buf: &'_ mut [u8]) -> io::Result<usize> {
+        (**self).recv(buf).await
+    }
+
+    async fn send(&'_ self, buf: &'_ [u8]) -> io::Result<()> {
+        (**self).send(buf).await
+    }
+}
@@ -1,11 +1,21 @@
-use std::collections::HashMap;
+//! An in-memory network for tests.
+//!
+//! By default every pair of addresses is joined by a perfect link that delivers packets
+//! immediately. Links can be given a [`FakeLagConfig`] to add latency, loss and bandwidth limits,
+//! and can be partitioned and healed while a test runs. [`FakeNetwork::rebind`] moves a socket to a
+//! new address, as when a NAT forgets a client's mapping. Delays are measured with tokio's clock,
+//! so tests run deterministically under `tokio::time::pause` when links are seeded.
+
+use std::collections::{HashMap, HashSet};
 use std::future::pending;
 use std::io;
 use std::sync::{Arc, Mutex, Weak};
 
 use async_trait::async_trait;
 use tokio::sync::mpsc;
+use tokio::time::{sleep, Instant};
 
+use crate::fakelag::{Conditioner, FakeLagConfig, RECV_STREAM, SEND_STREAM};
 use crate::{AddrBound, BoundSocket, ConnectedSocket};
 
 #[derive(Clone)]
@@ -14,7 +24,19 @@ pub struct FakeNetwork<Addr> {
 }
 
 struct InnerFakeNetwork<Addr> {
-    bindings: HashMap<Addr, mpsc::UnboundedSender<(Vec<u8>, Addr)>>,
+    bindings: HashMap<Addr, Binding<Addr>>,
+    /// Conditioners for links with a profile, keyed by `(from, to)`.
+    links: HashMap<(Addr, Addr), Conditioner>,
+    /// Severed links, keyed by `(from, to)`.
+    partitions: HashSet<(Addr, Addr)>,
+    isolated: HashSet<Addr>,
+}
+
+type PacketReceiver<Addr> = mpsc::UnboundedReceiver<(Vec<u8>, Addr)>;
+
+struct Binding<Addr> {
+    tx: mpsc::UnboundedSender<(Vec<u8>, Addr)>,
+    local_addr: Arc<Mutex<Addr>>,
 }
 
 impl<Addr> FakeNetwork<Addr>
@@ -25,31 +47,24 @@ where
         Self {
             inner: Arc::new(Mutex::new(InnerFakeNetwork {
                 bindings: HashMap::new(),
+                links: HashMap::new(),
+                partitions: HashSet::new(),
+                isolated: HashSet::new(),
             })),
         }
     }
 
     pub fn bind(&self, addr: Addr) -> FakeBoundSocket<Addr> {
-        let mut inner = self.inner.lock().unwrap();
-        assert!(!inner.bindings.contains_key(&addr));
-
-        let (tx, rx) = mpsc::unbounded_channel();
-        inner.bindings.insert(addr, tx);
-
+        let (local_addr, rx) = self.inner.lock().unwrap().add_binding(addr);
         FakeBoundSocket {
             network: Arc::downgrade(&self.inner),
-            local_addr: addr,
+            local_addr,
             rx: tokio::sync::Mutex::new(rx),
         }
     }
 
     pub fn connect(&self, local_addr: Addr, remote_addr: Addr) -> FakeConnectedSocket<Addr> {
-        let mut inner = self.inner.lock().unwrap();
-        assert!(!inner.bindings.contains_key(&local_addr));
-
-        let (tx, rx) = mpsc::unbounded_channel();
-        inner.bindings.insert(local_addr, tx);
-
+        let (local_addr, rx) = self.inner.lock().unwrap().add_binding(local_addr);
         FakeConnectedSocket {
             network: Arc::downgrade(&self.inner),
             local_addr,
@@ -57,12 +72,171 @@ where
             rx: tokio::sync::Mutex::new(rx),
         }
     }
+
+    /// Conditions packets travelling between `a` and `b` in both directions. Each direction draws
+    /// from its own random number generator. Panics if `config` is invalid.
+    pub fn set_link(&self, a: Addr, b: Addr, config: FakeLagConfig) {
+        config.validate().unwrap();
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .links
+            .insert((a, b), Conditioner::new(config.clone(), SEND_STREAM));
+        inner
+            .links
+            .insert((b, a), Conditioner::new(config, RECV_STREAM));
+    }
+
+    /// Conditions packets travelling from `from` to `to`, leaving the reverse direction alone.
+    /// Panics if `config` is invalid.
+    pub fn set_one_way_link(&self, from: Addr, to: Addr, config: FakeLagConfig) {
+        config.validate().unwrap();
+        self.inner
+            .lock()
+            .unwrap()
+            .links
+            .insert((from, to), Conditioner::new(config, SEND_STREAM));
+    }
+
+    /// Restores the perfect link between `a` and `b`.
+    pub fn clear_link(&self, a: Addr, b: Addr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.links.remove(&(a, b));
+        inner.links.remove(&(b, a));
+    }
+
+    /// Drops all packets between `a` and `b`, including those already in flight, until the link is
+    /// healed.
+    pub fn partition(&self, a: Addr, b: Addr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.partitions.insert((a, b));
+        inner.partitions.insert((b, a));
+    }
+
+    pub fn heal(&self, a: Addr, b: Addr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.partitions.remove(&(a, b));
+        inner.partitions.remove(&(b, a));
+    }
+
+    /// Drops all packets to or from `addr` until [`Self::heal_all`] is called.
+    pub fn isolate(&self, addr: Addr) {
+        self.inner.lock().unwrap().isolated.insert(addr);
+    }
+
+    /// Heals every partition and isolation.
+    pub fn heal_all(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.partitions.clear();
+        inner.isolated.clear();
+    }
+
+    /// Moves the socket bound to `old_addr` to `new_addr`, as a NAT does when its mapping expires.
+    /// The socket's later packets come from `new_addr`, and packets sent to `old_addr` are dropped.
+    /// Link profiles and partitions follow the socket, since its path through the network is
+    /// unchanged.
+    pub fn rebind(&self, old_addr: Addr, new_addr: Addr) {
+        let mut inner = self.inner.lock().unwrap();
+        assert!(!inner.bindings.contains_key(&new_addr));
+        let binding = inner.bindings.remove(&old_addr).unwrap();
+        *binding.local_addr.lock().unwrap() = new_addr;
+        inner.bindings.insert(new_addr, binding);
+
+        let rename = |addr: Addr| if addr == old_addr { new_addr } else { addr };
+        inner.links = inner
+            .links
+            .drain()
+            .map(|((from, to), conditioner)| ((rename(from), rename(to)), conditioner))
+            .collect();
+        inner.partitions = inner
+            .partitions
+            .drain()
+            .map(|(from, to)| (rename(from), rename(to)))
+            .collect();
+        if inner.isolated.remove(&old_addr) {
+            inner.isolated.insert(new_addr);
+        }
+    }
+}
+
+impl<Addr> InnerFakeNetwork<Addr>
+where
+    Addr: AddrBound,
+{
+    fn add_binding(&mut self, addr: Addr) -> (Arc<Mutex<Addr>>, PacketReceiver<Addr>) {
+        assert!(!self.bindings.contains_key(&addr));
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let local_addr = Arc::new(Mutex::new(addr));
+        self.bindings.insert(
+            addr,
+            Binding {
+                tx,
+                local_addr: Arc::clone(&local_addr),
+            },
+        );
+        (local_addr, rx)
+    }
+
+    fn is_connected(&self, from: Addr, to: Addr) -> bool {
+        !self.partitions.contains(&(from, to))
+            && !self.isolated.contains(&from)
+            && !self.isolated.contains(&to)
+    }
+
+    fn deliver(&self, data: Vec<u8>, from: Addr, to: Addr) {
+        if !self.is_connected(from, to) {
+            return;
+        }
+        if let Some(binding) = self.bindings.get(&to) {
+            drop(binding.tx.send((data, from)));
+        }
+    }
+}
+
+fn send<Addr>(network: &Weak<Mutex<InnerFakeNetwork<Addr>>>, data: &[u8], from: Addr, to: Addr)
+where
+    Addr: AddrBound,
+{
+    let network = match network.upgrade() {
+        Some(network) => network,
+        None => return,
+    };
+    // Decide the packet's fate under the lock, but release it before spawning the deliveries.
+    let delays = {
+        let mut inner = network.lock().unwrap();
+        if !inner.is_connected(from, to) {
+            return;
+        }
+        let delays = inner
+            .links
+            .get_mut(&(from, to))
+            .map(|conditioner| conditioner.schedule(data.len(), Instant::now()));
+        if delays.is_none() {
+            inner.deliver(data.to_vec(), from, to);
+        }
+        delays
+    };
+    let delays = match delays {
+        Some(delays) => delays,
+        None => return,
+    };
+
+    for delay in delays {
+        let network = Arc::downgrade(&network);
+        let data = data.to_vec();
+        tokio::spawn(async move {
+            sleep(delay).await;
+            if let Some(network) = network.upgrade() {
+                network.lock().unwrap().deliver(data, from, to);
+            }
+        });
+    }
 }
 
 pub struct FakeBoundSocket<Addr> {
     network: Weak<Mutex<InnerFakeNetwork<Addr>>>,
-    local_addr: Addr,
-    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<(Vec<u8>, Addr)>>,
+    local_addr: Arc<Mutex<Addr>>,
+    rx: tokio::sync::Mutex<PacketReceiver<Addr>>,
 }
 
 #[async_trait]
@@ -82,25 +256,17 @@ where
     }
 
     async fn send_to(&'_ self, buf: &'_ [u8], addr: Addr) -> io::Result<()> {
-        let network = match self.network.upgrade() {
-            Some(network) => network,
-            None => return Ok(()),
-        };
-        let tx = match network.lock().unwrap().bindings.get(&addr) {
-            Some(tx) => tx.clone(),
-            None => return Ok(()),
-        };
-        drop(network);
-        drop(tx.send((buf.to_vec(), self.local_addr)));
+        let local_addr = *self.local_addr.lock().unwrap();
+        send(&self.network, buf, local_addr, addr);
         Ok(())
     }
 }
 
 pub struct FakeConnectedSocket<Addr> {
     network: Weak<Mutex<InnerFakeNetwork<Addr>>>,
-    local_addr: Addr,
+    local_addr: Arc<Mutex<Addr>>,
     remote_addr: Addr,
-    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<(Vec<u8>, Addr)>>,
+    rx: tokio::sync::Mutex<PacketReceiver<Addr>>,
 }
 
 #[async_trait]
@@ -120,16 +286,127 @@ where
     }
 
     async fn send(&'_ self, buf: &'_ [u8]) -> io::Result<()> {
-        let network = match self.network.upgrade() {
-            Some(network) => network,
-            None => return Ok(()),
-        };
-        let tx = match network.lock().unwrap().bindings.get(&self.remote_addr) {
-            Some(tx) => tx.clone(),
-            None => return Ok(()),
-        };
-        drop(network);
-        drop(tx.send((buf.to_vec(), self.local_addr)));
+        let local_addr = *self.local_addr.lock().unwrap();
+        send(&self.network, buf, local_addr, self.remote_addr);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::{timeout, Instant};
+
+    use super::FakeNetwork;
+    use crate::fakelag::{DelayDistribution, FakeLagConfig, Loss};
+    use crate::{BoundSocket, ConnectedSocket};
+
+    async fn recv_from(socket: &impl BoundSocket<&'static str>) -> Option<(Vec<u8>, &'static str)> {
+        let mut buf = [0; 1500];
+        let (len, addr) = timeout(Duration::from_secs(10), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some((buf[..len].to_vec(), addr))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn links_have_their_own_profiles() {
+        let network = FakeNetwork::new();
+        let server = network.bind("server");
+        let near = network.connect("near", "server");
+        let far = network.connect("far", "server");
+        let lossy = network.connect("lossy", "server");
+        network.set_link(
+            "far",
+            "server",
+            FakeLagConfig {
+                delay: DelayDistribution::Constant(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+        network.set_link(
+            "lossy",
+            "server",
+            FakeLagConfig {
+                loss: Loss::Random { probability: 1.0 },
+                ..Default::default()
+            },
+        );
+
+        let start = Instant::now();
+        far.send(b"far").await.unwrap();
+        lossy.send(b"lossy").await.unwrap();
+        near.send(b"near").await.unwrap();
+        assert_eq!(recv_from(&server).await, Some((b"near".to_vec(), "near")));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(recv_from(&server).await, Some((b"far".to_vec(), "far")));
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert_eq!(recv_from(&server).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partitions_drop_packets_until_healed() {
+        let network = FakeNetwork::new();
+        let server = network.bind("server");
+        let client = network.connect("client", "server");
+        network.set_link(
+            "client",
+            "server",
+            FakeLagConfig {
+                delay: DelayDistribution::Constant(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+
+        client.send(b"in flight").await.unwrap();
+        network.partition("client", "server");
+        client.send(b"sent during the partition").await.unwrap();
+        assert_eq!(recv_from(&server).await, None);
+
+        network.heal("client", "server");
+        client.send(b"healed").await.unwrap();
+        assert_eq!(
+            recv_from(&server).await,
+            Some((b"healed".to_vec(), "client"))
+        );
+
+        network.isolate("server");
+        client.send(b"isolated").await.unwrap();
+        assert_eq!(recv_from(&server).await, None);
+        network.heal_all();
+        client.send(b"healed again").await.unwrap();
+        assert_eq!(
+            recv_from(&server).await,
+            Some((b"healed again".to_vec(), "client")),
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rebinding_changes_the_source_address() {
+        let network = FakeNetwork::new();
+        let server = network.bind("server");
+        let client = network.bind("client");
+
+        network.rebind("client", "rebound client");
+        client.send_to(b"hello", "server").await.unwrap();
+        assert_eq!(
+            recv_from(&server).await,
+            Some((b"hello".to_vec(), "rebound client")),
+        );
+
+        server
+            .send_to(b"to the old address", "client")
+            .await
+            .unwrap();
+        server
+            .send_to(b"to the new address", "rebound client")
+            .await
+            .unwrap();
+        assert_eq!(
+            recv_from(&client).await,
+            Some((b"to the new address".to_vec(), "server")),
+        );
+    }
+}
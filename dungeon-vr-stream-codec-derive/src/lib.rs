@@ -0,0 +1,385 @@
+//! `#[derive(StreamCodec)]` for structs and enums. This is re-exported by
+//! `dungeon-vr-stream-codec`, so depend on that crate rather than this one.
+//!
+//! Fields are encoded in declaration order with no framing. Enums are encoded as a tag followed by
+//! the fields of the selected variant.
+//!
+//! Container attributes:
+//!
+//! - `#[stream_codec(read_error = "Type")]` sets `StreamCodec::ReadError`. Every field's read
+//!   error must convert into it with `From`. Defaults to `dungeon_vr_stream_codec::ReadError`.
+//! - `#[stream_codec(write_error = "Type")]` sets `StreamCodec::WriteError`. Defaults to
+//!   `std::convert::Infallible`.
+//! - `#[stream_codec(tag = "Type")]` sets the integer type used to encode enum tags. Defaults to
+//!   the enum's `#[repr]` type if it has one, otherwise `u8`.
+//! - `#[stream_codec(invalid_tag = "path")]` is required for enums. It is called with any
+//!   unrecognized tag value and must return something that converts into the read error, such as
+//!   a tuple variant constructor.
+//!
+//! Variant attributes:
+//!
+//! - `#[stream_codec(tag = 7)]` sets the tag for a variant. Untagged variants follow the previous
+//!   variant, starting at zero, the same as implicit discriminants. Fieldless enums use their
+//!   discriminants as tags.
+//!
+//! Field attributes:
+//!
+//! - `#[stream_codec(with = "Type")]` encodes the field with an `ExternalStreamCodec` instead of
+//!   the field type's own `StreamCodec` impl.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DataEnum, DataStruct, DeriveInput, Fields,
+    Ident, Lit, LitInt, Meta, MetaNameValue, NestedMeta, Path, Type,
+};
+
+#[proc_macro_derive(StreamCodec, attributes(stream_codec))]
+pub fn derive_stream_codec(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct ContainerAttrs {
+    read_error: Option<Type>,
+    write_error: Option<Type>,
+    tag: Option<Type>,
+    invalid_tag: Option<Path>,
+}
+
+impl ContainerAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut result = Self::default();
+        for_each_name_value(attrs, |nv| {
+            if nv.path.is_ident("read_error") {
+                result.read_error = Some(parse_lit_str(&nv.lit)?);
+            } else if nv.path.is_ident("write_error") {
+                result.write_error = Some(parse_lit_str(&nv.lit)?);
+            } else if nv.path.is_ident("tag") {
+                result.tag = Some(parse_lit_str(&nv.lit)?);
+            } else if nv.path.is_ident("invalid_tag") {
+                result.invalid_tag = Some(parse_lit_str(&nv.lit)?);
+            } else {
+                return Err(syn::Error::new_spanned(
+                    &nv.path,
+                    "unknown stream_codec container attribute",
+                ));
+            }
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+#[derive(Default)]
+struct VariantAttrs {
+    tag: Option<LitInt>,
+}
+
+impl VariantAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut result = Self::default();
+        for_each_name_value(attrs, |nv| {
+            if nv.path.is_ident("tag") {
+                match &nv.lit {
+                    Lit::Int(lit) => result.tag = Some(lit.clone()),
+                    lit => return Err(syn::Error::new_spanned(lit, "expected integer literal")),
+                }
+            } else {
+                return Err(syn::Error::new_spanned(
+                    &nv.path,
+                    "unknown stream_codec variant attribute",
+                ));
+            }
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+#[derive(Default)]
+struct FieldAttrs {
+    with: Option<Type>,
+}
+
+impl FieldAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut result = Self::default();
+        for_each_name_value(attrs, |nv| {
+            if nv.path.is_ident("with") {
+                result.with = Some(parse_lit_str(&nv.lit)?);
+            } else {
+                return Err(syn::Error::new_spanned(
+                    &nv.path,
+                    "unknown stream_codec field attribute",
+                ));
+            }
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+fn for_each_name_value(
+    attrs: &[Attribute],
+    mut f: impl FnMut(&MetaNameValue) -> syn::Result<()>,
+) -> syn::Result<()> {
+    for attr in attrs {
+        if !attr.path.is_ident("stream_codec") {
+            continue;
+        }
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => {
+                return Err(syn::Error::new_spanned(
+                    meta,
+                    "expected #[stream_codec(key = value, ...)]",
+                ))
+            }
+        };
+        for nested in &list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) => f(nv)?,
+                nested => return Err(syn::Error::new_spanned(nested, "expected key = value")),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_lit_str<T: syn::parse::Parse>(lit: &Lit) -> syn::Result<T> {
+    match lit {
+        Lit::Str(lit) => lit.parse(),
+        lit => Err(syn::Error::new_spanned(lit, "expected string literal")),
+    }
+}
+
+/// Finds an integer type in a `#[repr(...)]` attribute, if any.
+fn repr_int_type(attrs: &[Attribute]) -> Option<Type> {
+    const INT_TYPES: &[&str] = &[
+        "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize",
+    ];
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("repr"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => Some(list.nested),
+            _ => None,
+        })
+        .flatten()
+        .find_map(|nested| match nested {
+            NestedMeta::Meta(Meta::Path(path))
+                if INT_TYPES.iter().any(|int_type| path.is_ident(int_type)) =>
+            {
+                Some(parse_quote!(#path))
+            }
+            _ => None,
+        })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let attrs = ContainerAttrs::parse(&input.attrs)?;
+    let read_error = attrs
+        .read_error
+        .clone()
+        .unwrap_or_else(|| parse_quote!(::dungeon_vr_stream_codec::ReadError));
+    let write_error = attrs
+        .write_error
+        .clone()
+        .unwrap_or_else(|| parse_quote!(::std::convert::Infallible));
+
+    let (read_body, write_body) = match &input.data {
+        Data::Struct(data) => expand_struct(data)?,
+        Data::Enum(data) => expand_enum(&input, attrs, data)?,
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "StreamCodec cannot be derived for unions",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::dungeon_vr_stream_codec::StreamCodec for #name #ty_generics
+        #where_clause
+        {
+            type ReadError = #read_error;
+            type WriteError = #write_error;
+
+            #[allow(unused_variables)]
+            fn read_from(r: &mut &[u8]) -> ::std::result::Result<Self, #read_error> {
+                #read_body
+            }
+
+            #[allow(unused_variables)]
+            fn write_to(
+                &self,
+                w: &mut ::std::vec::Vec<u8>,
+            ) -> ::std::result::Result<(), #write_error> {
+                #write_body
+            }
+        }
+    })
+}
+
+fn expand_struct(data: &DataStruct) -> syn::Result<(TokenStream, TokenStream)> {
+    let construct = read_fields(quote!(Self), &data.fields)?;
+    let (pattern, writes) = write_fields(quote!(Self), &data.fields)?;
+    Ok((
+        quote! {
+            ::std::result::Result::Ok(#construct)
+        },
+        quote! {
+            let #pattern = self;
+            #writes
+            ::std::result::Result::Ok(())
+        },
+    ))
+}
+
+fn expand_enum(
+    input: &DeriveInput,
+    attrs: ContainerAttrs,
+    data: &DataEnum,
+) -> syn::Result<(TokenStream, TokenStream)> {
+    if data.variants.is_empty() {
+        return Err(syn::Error::new(
+            Span::call_site(),
+            "StreamCodec cannot be derived for enums with no variants",
+        ));
+    }
+    let tag_type = attrs
+        .tag
+        .or_else(|| repr_int_type(&input.attrs))
+        .unwrap_or_else(|| parse_quote!(u8));
+    let invalid_tag = attrs.invalid_tag.ok_or_else(|| {
+        syn::Error::new(
+            Span::call_site(),
+            "deriving StreamCodec for an enum requires #[stream_codec(invalid_tag = \"...\")]",
+        )
+    })?;
+    let fieldless = data
+        .variants
+        .iter()
+        .all(|variant| matches!(variant.fields, Fields::Unit));
+
+    let mut next_tag = 0u128;
+    let mut read_arms = Vec::new();
+    let mut write_arms = Vec::new();
+    for variant in &data.variants {
+        let variant_attrs = VariantAttrs::parse(&variant.attrs)?;
+        let ident = &variant.ident;
+        let tag = match variant_attrs.tag {
+            Some(lit) => {
+                next_tag = lit.base10_parse::<u128>()? + 1;
+                quote!(#lit)
+            }
+            None if fieldless => quote!(Self::#ident as #tag_type),
+            None => {
+                if let Some((_, discriminant)) = &variant.discriminant {
+                    return Err(syn::Error::new_spanned(
+                        discriminant,
+                        "use #[stream_codec(tag = ...)] to set tags on enums with fields",
+                    ));
+                }
+                let tag = LitInt::new(&next_tag.to_string(), variant.span());
+                next_tag += 1;
+                quote!(#tag)
+            }
+        };
+
+        let construct = read_fields(quote!(Self::#ident), &variant.fields)?;
+        read_arms.push(quote! {
+            tag if tag == #tag => ::std::result::Result::Ok(#construct),
+        });
+
+        let (pattern, writes) = write_fields(quote!(Self::#ident), &variant.fields)?;
+        write_arms.push(quote! {
+            #pattern => {
+                let tag: #tag_type = #tag;
+                ::dungeon_vr_stream_codec::StreamCodec::write_to(&tag, w)?;
+                #writes
+            }
+        });
+    }
+
+    Ok((
+        quote! {
+            match <#tag_type as ::dungeon_vr_stream_codec::StreamCodec>::read_from(r)? {
+                #(#read_arms)*
+                tag => ::std::result::Result::Err(::std::convert::From::from(#invalid_tag(tag))),
+            }
+        },
+        quote! {
+            match self {
+                #(#write_arms)*
+            }
+            ::std::result::Result::Ok(())
+        },
+    ))
+}
+
+/// Builds an expression that reads each field in order and constructs `path` from them.
+fn read_fields(path: TokenStream, fields: &Fields) -> syn::Result<TokenStream> {
+    let reads = fields
+        .iter()
+        .map(|field| {
+            let ty = &field.ty;
+            Ok(match FieldAttrs::parse(&field.attrs)?.with {
+                Some(with) => quote! {
+                    <#with as ::dungeon_vr_stream_codec::ExternalStreamCodec>::read_from_ext(r)?
+                },
+                None => quote! {
+                    <#ty as ::dungeon_vr_stream_codec::StreamCodec>::read_from(r)?
+                },
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+    Ok(match fields {
+        Fields::Named(_) => {
+            let idents = fields.iter().map(|field| &field.ident);
+            quote!(#path { #(#idents: #reads,)* })
+        }
+        Fields::Unnamed(_) => quote!(#path(#(#reads,)*)),
+        Fields::Unit => path,
+    })
+}
+
+/// Builds a pattern that binds each field by reference, and statements that write the bound
+/// fields in order.
+fn write_fields(path: TokenStream, fields: &Fields) -> syn::Result<(TokenStream, TokenStream)> {
+    let bindings = (0..fields.len())
+        .map(|index| format_ident!("field{index}"))
+        .collect::<Vec<Ident>>();
+    let writes = fields
+        .iter()
+        .zip(&bindings)
+        .map(|(field, binding)| {
+            Ok(match FieldAttrs::parse(&field.attrs)?.with {
+                Some(with) => quote! {
+                    <#with as ::dungeon_vr_stream_codec::ExternalStreamCodec>::write_to_ext(
+                        w, #binding,
+                    )?;
+                },
+                None => quote! {
+                    ::dungeon_vr_stream_codec::StreamCodec::write_to(#binding, w)?;
+                },
+            })
+        })
+        .collect::<syn::Result<TokenStream>>()?;
+    let pattern = match fields {
+        Fields::Named(_) => {
+            let idents = fields.iter().map(|field| &field.ident);
+            quote!(#path { #(#idents: #bindings,)* })
+        }
+        Fields::Unnamed(_) => quote!(#path(#(#bindings,)*)),
+        Fields::Unit => path,
+    };
+    Ok((pattern, writes))
+}
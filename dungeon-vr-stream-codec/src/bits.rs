@@ -0,0 +1,296 @@
+//! Bit-granular encoding, for values that don't need whole bytes.
+//!
+//! Bits are packed most significant first. A bit-packed section always occupies whole bytes in the
+//! surrounding byte stream; see [`BitPacked`].
+
+use std::convert::Infallible;
+use std::marker::PhantomData;
+
+use crate::{ExternalStreamCodec, ReadError};
+
+fn low_mask(bits: u32) -> u64 {
+    if bits == 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bit_len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes the low `bits` bits of `value`, most significant first. Higher bits of `value` are
+    /// ignored.
+    pub fn write_bits(&mut self, value: u64, bits: u32) {
+        assert!(bits <= 64);
+        let mut remaining = bits;
+        while remaining > 0 {
+            let offset = (self.bit_len % 8) as u32;
+            if offset == 0 {
+                self.bytes.push(0);
+            }
+            let take = remaining.min(8 - offset);
+            let chunk = (value >> (remaining - take)) & low_mask(take);
+            *self.bytes.last_mut().unwrap() |= (chunk << (8 - offset - take)) as u8;
+            remaining -= take;
+            self.bit_len += take as usize;
+        }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) {
+        self.write_bits(bit as u64, 1);
+    }
+
+    /// The number of bits written so far.
+    pub fn bit_len(&self) -> usize {
+        self.bit_len
+    }
+
+    /// Returns the written bits, padded with zeros to a whole number of bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit_pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, bit_pos: 0 }
+    }
+
+    /// Reads `bits` bits, most significant first, into the low bits of the result.
+    pub fn read_bits(&mut self, bits: u32) -> Result<u64, ReadError> {
+        assert!(bits <= 64);
+        if bits as usize > self.remaining_bits() {
+            return Err(ReadError::UnexpectedEof);
+        }
+        let mut value = 0u64;
+        let mut remaining = bits;
+        while remaining > 0 {
+            let offset = (self.bit_pos % 8) as u32;
+            let take = remaining.min(8 - offset);
+            let byte = self.bytes[self.bit_pos / 8] as u64;
+            let chunk = (byte >> (8 - offset - take)) & low_mask(take);
+            value = (value << take) | chunk;
+            remaining -= take;
+            self.bit_pos += take as usize;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, ReadError> {
+        Ok(self.read_bits(1)? != 0)
+    }
+
+    pub fn remaining_bits(&self) -> usize {
+        self.bytes.len() * 8 - self.bit_pos
+    }
+
+    /// The number of bytes touched so far, including a partially read final byte.
+    pub fn byte_len(&self) -> usize {
+        (self.bit_pos + 7) >> 3
+    }
+}
+
+/// The bit-packed counterpart to [`StreamCodec`](crate::StreamCodec).
+pub trait BitCodec: Sized {
+    type ReadError;
+    type WriteError;
+
+    fn read_from_bits(r: &mut BitReader) -> Result<Self, Self::ReadError>;
+    fn write_to_bits(&self, w: &mut BitWriter) -> Result<(), Self::WriteError>;
+}
+
+/// The bit-packed counterpart to [`ExternalStreamCodec`].
+pub trait ExternalBitCodec {
+    type Item;
+    type ReadError;
+    type WriteError;
+
+    fn read_from_bits_ext(r: &mut BitReader) -> Result<Self::Item, Self::ReadError>;
+    fn write_to_bits_ext(w: &mut BitWriter, value: &Self::Item) -> Result<(), Self::WriteError>;
+}
+
+impl<C> ExternalBitCodec for C
+where
+    C: BitCodec,
+{
+    type Item = C;
+    type ReadError = C::ReadError;
+    type WriteError = C::WriteError;
+
+    fn read_from_bits_ext(r: &mut BitReader) -> Result<C, Self::ReadError> {
+        C::read_from_bits(r)
+    }
+
+    fn write_to_bits_ext(w: &mut BitWriter, value: &C) -> Result<(), Self::WriteError> {
+        value.write_to_bits(w)
+    }
+}
+
+/// Embeds a bit-packed value in a byte stream, padded with zero bits to a whole number of bytes.
+pub struct BitPacked<C>(PhantomData<C>);
+
+impl<C: ExternalBitCodec> ExternalStreamCodec for BitPacked<C> {
+    type Item = C::Item;
+    type ReadError = C::ReadError;
+    type WriteError = C::WriteError;
+
+    fn read_from_ext(r: &mut &[u8]) -> Result<C::Item, C::ReadError> {
+        let mut bits = BitReader::new(r);
+        let value = C::read_from_bits_ext(&mut bits)?;
+        *r = &r[bits.byte_len()..];
+        Ok(value)
+    }
+
+    fn write_to_ext(w: &mut Vec<u8>, value: &C::Item) -> Result<(), C::WriteError> {
+        let mut bits = BitWriter::new();
+        C::write_to_bits_ext(&mut bits, value)?;
+        w.extend_from_slice(&bits.into_bytes());
+        Ok(())
+    }
+}
+
+impl BitCodec for () {
+    type ReadError = Infallible;
+    type WriteError = Infallible;
+
+    fn read_from_bits(_r: &mut BitReader) -> Result<Self, Infallible> {
+        Ok(())
+    }
+
+    fn write_to_bits(&self, _w: &mut BitWriter) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl BitCodec for bool {
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from_bits(r: &mut BitReader) -> Result<Self, ReadError> {
+        r.read_bit()
+    }
+
+    fn write_to_bits(&self, w: &mut BitWriter) -> Result<(), Infallible> {
+        w.write_bit(*self);
+        Ok(())
+    }
+}
+
+macro_rules! impl_bit_codec_for_int {
+    ($t:ident, $u:ident) => {
+        impl BitCodec for $t {
+            type ReadError = ReadError;
+            type WriteError = Infallible;
+
+            fn read_from_bits(r: &mut BitReader) -> Result<Self, ReadError> {
+                Ok(r.read_bits($t::BITS)? as $u as $t)
+            }
+
+            fn write_to_bits(&self, w: &mut BitWriter) -> Result<(), Infallible> {
+                w.write_bits(*self as $u as u64, $t::BITS);
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_bit_codec_for_int!(u8, u8);
+impl_bit_codec_for_int!(u16, u16);
+impl_bit_codec_for_int!(u32, u32);
+impl_bit_codec_for_int!(u64, u64);
+impl_bit_codec_for_int!(i8, u8);
+impl_bit_codec_for_int!(i16, u16);
+impl_bit_codec_for_int!(i32, u32);
+impl_bit_codec_for_int!(i64, u64);
+
+impl<const N: usize> BitCodec for [u8; N] {
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from_bits(r: &mut BitReader) -> Result<Self, ReadError> {
+        let mut value = [0; N];
+        for byte in &mut value {
+            *byte = u8::read_from_bits(r)?;
+        }
+        Ok(value)
+    }
+
+    fn write_to_bits(&self, w: &mut BitWriter) -> Result<(), Infallible> {
+        for byte in self {
+            byte.write_to_bits(w)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ExternalStreamCodec, ReadError};
+
+    use super::{BitCodec, BitPacked, BitReader, BitWriter};
+
+    #[test]
+    fn write_bits() {
+        let mut w = BitWriter::new();
+        w.write_bit(true);
+        w.write_bits(0b010, 3);
+        w.write_bits(0xabc, 12);
+        w.write_bit(true);
+        assert_eq!(w.bit_len(), 17);
+        assert_eq!(w.into_bytes(), [0b1010_1010, 0b1011_1100, 0b1000_0000]);
+    }
+
+    #[test]
+    fn read_bits() {
+        let mut r = BitReader::new(&[0b1010_1010, 0b1011_1100, 0b1000_0000]);
+        assert!(r.read_bit().unwrap());
+        assert_eq!(r.read_bits(3).unwrap(), 0b010);
+        assert_eq!(r.read_bits(12).unwrap(), 0xabc);
+        assert!(r.read_bit().unwrap());
+        assert_eq!(r.byte_len(), 3);
+        assert_eq!(r.remaining_bits(), 7);
+        assert!(matches!(r.read_bits(8), Err(ReadError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn full_width() {
+        let mut w = BitWriter::new();
+        w.write_bit(false);
+        u64::MAX.write_to_bits(&mut w).unwrap();
+        (-2i16).write_to_bits(&mut w).unwrap();
+        let bytes = w.into_bytes();
+        assert_eq!(bytes.len(), 11);
+
+        let mut r = BitReader::new(&bytes);
+        assert!(!bool::read_from_bits(&mut r).unwrap());
+        assert_eq!(u64::read_from_bits(&mut r).unwrap(), u64::MAX);
+        assert_eq!(i16::read_from_bits(&mut r).unwrap(), -2);
+    }
+
+    #[test]
+    fn bit_packed() {
+        let mut w = Vec::new();
+        BitPacked::<bool>::write_to_ext(&mut w, &true).unwrap();
+        BitPacked::<[u8; 2]>::write_to_ext(&mut w, &[1, 2]).unwrap();
+        assert_eq!(w, [0x80, 1, 2]);
+
+        let mut r = &w[..];
+        assert!(BitPacked::<bool>::read_from_ext(&mut r).unwrap());
+        assert_eq!(BitPacked::<[u8; 2]>::read_from_ext(&mut r).unwrap(), [1, 2]);
+        assert!(r.is_empty());
+    }
+}
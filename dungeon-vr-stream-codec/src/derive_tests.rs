@@ -0,0 +1,160 @@
+use std::convert::Infallible;
+use std::fmt::Debug;
+
+use thiserror::Error;
+
+use crate::{ReadBoolError, ReadError, StreamCodec, UnframedByteVec};
+
+#[derive(Error, Debug)]
+enum TestReadError {
+    #[error("{0}")]
+    ReadError(#[from] ReadError),
+
+    #[error("{0}")]
+    ReadBoolError(#[from] ReadBoolError),
+
+    #[error("invalid tag: {0}")]
+    InvalidTag(u64),
+}
+
+impl From<Infallible> for TestReadError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
+
+#[derive(StreamCodec, Debug, PartialEq)]
+struct Unit;
+
+#[derive(StreamCodec, Debug, PartialEq)]
+#[stream_codec(read_error = "TestReadError")]
+struct Named {
+    a: u16,
+    b: bool,
+    #[stream_codec(with = "UnframedByteVec")]
+    rest: Vec<u8>,
+}
+
+#[derive(StreamCodec, Debug, PartialEq)]
+struct Tuple(u8, i32);
+
+#[derive(StreamCodec, Clone, Copy, Debug, PartialEq)]
+#[stream_codec(read_error = "TestReadError", invalid_tag = "invalid_u8_tag")]
+#[repr(u8)]
+enum Fieldless {
+    A,
+    B = 5,
+    C,
+}
+
+fn invalid_u8_tag(tag: u8) -> TestReadError {
+    TestReadError::InvalidTag(tag as u64)
+}
+
+#[derive(StreamCodec, Debug, PartialEq)]
+#[stream_codec(
+    read_error = "TestReadError",
+    tag = "u16",
+    invalid_tag = "invalid_u16_tag"
+)]
+enum WithData {
+    Empty,
+    Tuple(u8, Fieldless),
+    #[stream_codec(tag = 0x100)]
+    Named {
+        flag: bool,
+        tuple: Tuple,
+    },
+    AfterNamed(Unit),
+}
+
+fn invalid_u16_tag(tag: u16) -> TestReadError {
+    TestReadError::InvalidTag(tag as u64)
+}
+
+fn round_trip<T>(value: T, expected: &[u8])
+where
+    T: StreamCodec + Debug + PartialEq,
+    T::ReadError: Debug,
+    T::WriteError: Debug,
+{
+    let mut w = Vec::new();
+    value.write_to(&mut w).unwrap();
+    assert_eq!(w, expected);
+
+    let mut r = &w[..];
+    assert_eq!(T::read_from(&mut r).unwrap(), value);
+    assert!(r.is_empty());
+}
+
+#[test]
+fn unit_struct() {
+    round_trip(Unit, &[]);
+}
+
+#[test]
+fn named_struct() {
+    round_trip(
+        Named {
+            a: 0x1234,
+            b: true,
+            rest: vec![7, 8, 9],
+        },
+        &[0x12, 0x34, 1, 7, 8, 9],
+    );
+}
+
+#[test]
+fn tuple_struct() {
+    round_trip(Tuple(3, -2), &[3, 0xff, 0xff, 0xff, 0xfe]);
+}
+
+#[test]
+fn fieldless_enum_uses_discriminants() {
+    round_trip(Fieldless::A, &[0]);
+    round_trip(Fieldless::B, &[5]);
+    round_trip(Fieldless::C, &[6]);
+}
+
+#[test]
+fn fieldless_enum_rejects_invalid_tag() {
+    let mut r = &[1][..];
+    assert!(matches!(
+        Fieldless::read_from(&mut r),
+        Err(TestReadError::InvalidTag(1)),
+    ));
+}
+
+#[test]
+fn enum_with_data() {
+    round_trip(WithData::Empty, &[0, 0]);
+    round_trip(WithData::Tuple(9, Fieldless::C), &[0, 1, 9, 6]);
+    round_trip(
+        WithData::Named {
+            flag: false,
+            tuple: Tuple(1, 2),
+        },
+        &[1, 0, 0, 1, 0, 0, 0, 2],
+    );
+    round_trip(WithData::AfterNamed(Unit), &[1, 1]);
+}
+
+#[test]
+fn enum_with_data_rejects_invalid_tag() {
+    let mut r = &[0, 2][..];
+    assert!(matches!(
+        WithData::read_from(&mut r),
+        Err(TestReadError::InvalidTag(2)),
+    ));
+}
+
+#[test]
+fn field_errors_propagate() {
+    let mut r = &[0x12, 0x34, 2][..];
+    assert!(matches!(
+        Named::read_from(&mut r),
+        Err(TestReadError::ReadBoolError(
+            ReadBoolError::InvalidEncoding(2)
+        )),
+    ));
+}
@@ -2,12 +2,27 @@ use std::io::{self};
 
 use thiserror::Error;
 
+// Lets derived impls inside this crate refer to it by name, the same as they would elsewhere.
+extern crate self as dungeon_vr_stream_codec;
+
+mod bits;
+#[cfg(test)]
+mod derive_tests;
 mod nalgebra_impls;
+mod quantize;
 mod std_impls;
+mod varint;
 
 type O = byteorder::BigEndian;
 
+pub use crate::bits::{BitCodec, BitPacked, BitReader, BitWriter, ExternalBitCodec};
+pub use crate::quantize::{BoundedVector3, PositionBounds, QuantizedIsometry3, SmallestThree};
 pub use crate::std_impls::{ReadBoolError, ReadStringError, UnframedByteVec};
+pub use crate::varint::{
+    ReadVarIntError, ReadVarintFramedError, VarI16, VarI32, VarI64, VarU16, VarU32, VarU64, Varint,
+    VarintFramedByteVec, VarintFramedString,
+};
+pub use dungeon_vr_stream_codec_derive::StreamCodec;
 
 #[derive(Error, Debug)]
 pub enum ReadError {
@@ -0,0 +1,334 @@
+//! Lossy fixed-point encodings for transforms.
+//!
+//! Each quantized value maps its range onto `2^BITS - 1` evenly spaced codes, leaving the top code
+//! unused so that the midpoint of the range is exactly representable. For symmetric ranges this
+//! means zero survives a round trip unchanged, which keeps stationary and axis-aligned objects
+//! from picking up jitter.
+
+use std::convert::Infallible;
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::marker::PhantomData;
+
+use rapier3d::na::{Isometry3, Quaternion, Translation3, UnitQuaternion, Vector3};
+
+use crate::bits::{BitPacked, BitReader, BitWriter, ExternalBitCodec};
+use crate::{ExternalStreamCodec, ReadError};
+
+fn quantize(value: f32, min: f32, max: f32, bits: u32) -> u64 {
+    let max_code = (1u64 << bits) - 2;
+    let t = ((value - min) / (max - min)).clamp(0.0, 1.0);
+    (t as f64 * max_code as f64).round() as u64
+}
+
+fn dequantize(code: u64, min: f32, max: f32, bits: u32) -> f32 {
+    let max_code = (1u64 << bits) - 2;
+    let t = (code.min(max_code) as f64 / max_code as f64) as f32;
+    min + t * (max - min)
+}
+
+/// The region positions are expected to fall within, and how finely to encode them. Positions
+/// outside the bounds are clamped.
+pub trait PositionBounds {
+    /// The low corner of the region. Must be less than [`Self::MAX`] on every axis.
+    const MIN: [f32; 3];
+    const MAX: [f32; 3];
+    /// Bits per axis, from 2 through 32.
+    const BITS: u32;
+}
+
+/// Encodes a [`Vector3<f32>`] as fixed-point coordinates within `B`. The round-trip error per axis
+/// is at most half of `(B::MAX - B::MIN) / (2^B::BITS - 2)` for positions within the bounds.
+pub struct BoundedVector3<B>(PhantomData<B>);
+
+impl<B: PositionBounds> BoundedVector3<B> {
+    /// Evaluating this fails the build for bounds that can't be encoded.
+    const VALID_BOUNDS: () = {
+        assert!(
+            B::BITS >= 2 && B::BITS <= 32,
+            "PositionBounds::BITS must be from 2 through 32",
+        );
+        let mut axis = 0;
+        while axis < 3 {
+            assert!(
+                B::MIN[axis] < B::MAX[axis],
+                "PositionBounds::MIN must be less than MAX on every axis",
+            );
+            axis += 1;
+        }
+    };
+}
+
+impl<B: PositionBounds> ExternalBitCodec for BoundedVector3<B> {
+    type Item = Vector3<f32>;
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from_bits_ext(r: &mut BitReader) -> Result<Vector3<f32>, ReadError> {
+        let () = Self::VALID_BOUNDS;
+        let mut value = Vector3::zeros();
+        for axis in 0..3 {
+            let code = r.read_bits(B::BITS)?;
+            value[axis] = dequantize(code, B::MIN[axis], B::MAX[axis], B::BITS);
+        }
+        Ok(value)
+    }
+
+    fn write_to_bits_ext(w: &mut BitWriter, value: &Vector3<f32>) -> Result<(), Infallible> {
+        let () = Self::VALID_BOUNDS;
+        for axis in 0..3 {
+            let code = quantize(value[axis], B::MIN[axis], B::MAX[axis], B::BITS);
+            w.write_bits(code, B::BITS);
+        }
+        Ok(())
+    }
+}
+
+impl<B: PositionBounds> ExternalStreamCodec for BoundedVector3<B> {
+    type Item = Vector3<f32>;
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from_ext(r: &mut &[u8]) -> Result<Vector3<f32>, ReadError> {
+        BitPacked::<Self>::read_from_ext(r)
+    }
+
+    fn write_to_ext(w: &mut Vec<u8>, value: &Vector3<f32>) -> Result<(), Infallible> {
+        BitPacked::<Self>::write_to_ext(w, value)
+    }
+}
+
+/// Encodes a [`UnitQuaternion<f32>`] with "smallest three" compression: the index of the
+/// component with the largest magnitude in two bits, followed by the other three components at
+/// `BITS` bits each. The largest component is reconstructed from the unit norm.
+///
+/// Because the omitted component is the largest, the others all lie within ±1/√2, so each is
+/// encoded to within `1/√2 / (2^(BITS-1) - 1)`. At 10 bits a rotation takes 4 bytes and is
+/// accurate to roughly a tenth of a degree. `BITS` must be from 2 through 32.
+pub struct SmallestThree<const BITS: u32>;
+
+impl<const BITS: u32> SmallestThree<BITS> {
+    /// Evaluating this fails the build for a `BITS` that can't be encoded.
+    const VALID_BITS: () = assert!(
+        BITS >= 2 && BITS <= 32,
+        "SmallestThree BITS must be from 2 through 32",
+    );
+}
+
+impl<const BITS: u32> ExternalBitCodec for SmallestThree<BITS> {
+    type Item = UnitQuaternion<f32>;
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from_bits_ext(r: &mut BitReader) -> Result<UnitQuaternion<f32>, ReadError> {
+        let () = Self::VALID_BITS;
+        let largest = r.read_bits(2)? as usize;
+        let mut coords = [0.0; 4];
+        let mut sum_of_squares = 0.0;
+        for (index, coord) in coords.iter_mut().enumerate() {
+            if index != largest {
+                let code = r.read_bits(BITS)?;
+                *coord = dequantize(code, -FRAC_1_SQRT_2, FRAC_1_SQRT_2, BITS);
+                sum_of_squares += *coord * *coord;
+            }
+        }
+        coords[largest] = (1.0 - sum_of_squares).max(0.0).sqrt();
+
+        let [i, j, k, w] = coords;
+        Ok(UnitQuaternion::new_normalize(Quaternion::new(w, i, j, k)))
+    }
+
+    fn write_to_bits_ext(w: &mut BitWriter, value: &UnitQuaternion<f32>) -> Result<(), Infallible> {
+        let () = Self::VALID_BITS;
+        let coords = value.coords;
+        let largest = (0..4)
+            .max_by(|&a, &b| coords[a].abs().total_cmp(&coords[b].abs()))
+            .unwrap();
+        // q and -q are the same rotation. Pick the sign that makes the omitted component positive.
+        let sign = if coords[largest] < 0.0 { -1.0 } else { 1.0 };
+
+        w.write_bits(largest as u64, 2);
+        for (index, &coord) in coords.iter().enumerate() {
+            if index != largest {
+                let code = quantize(sign * coord, -FRAC_1_SQRT_2, FRAC_1_SQRT_2, BITS);
+                w.write_bits(code, BITS);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const BITS: u32> ExternalStreamCodec for SmallestThree<BITS> {
+    type Item = UnitQuaternion<f32>;
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from_ext(r: &mut &[u8]) -> Result<UnitQuaternion<f32>, ReadError> {
+        BitPacked::<Self>::read_from_ext(r)
+    }
+
+    fn write_to_ext(w: &mut Vec<u8>, value: &UnitQuaternion<f32>) -> Result<(), Infallible> {
+        BitPacked::<Self>::write_to_ext(w, value)
+    }
+}
+
+/// Encodes an [`Isometry3<f32>`] as a [`BoundedVector3<B>`] translation followed by a
+/// [`SmallestThree<ROTATION_BITS>`] rotation.
+pub struct QuantizedIsometry3<B, const ROTATION_BITS: u32>(PhantomData<B>);
+
+impl<B: PositionBounds, const ROTATION_BITS: u32> ExternalBitCodec
+    for QuantizedIsometry3<B, ROTATION_BITS>
+{
+    type Item = Isometry3<f32>;
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from_bits_ext(r: &mut BitReader) -> Result<Isometry3<f32>, ReadError> {
+        let translation = BoundedVector3::<B>::read_from_bits_ext(r)?;
+        let rotation = SmallestThree::<ROTATION_BITS>::read_from_bits_ext(r)?;
+        Ok(Isometry3::from_parts(
+            Translation3::from(translation),
+            rotation,
+        ))
+    }
+
+    fn write_to_bits_ext(w: &mut BitWriter, value: &Isometry3<f32>) -> Result<(), Infallible> {
+        BoundedVector3::<B>::write_to_bits_ext(w, &value.translation.vector)?;
+        SmallestThree::<ROTATION_BITS>::write_to_bits_ext(w, &value.rotation)?;
+        Ok(())
+    }
+}
+
+impl<B: PositionBounds, const ROTATION_BITS: u32> ExternalStreamCodec
+    for QuantizedIsometry3<B, ROTATION_BITS>
+{
+    type Item = Isometry3<f32>;
+    type ReadError = ReadError;
+    type WriteError = Infallible;
+
+    fn read_from_ext(r: &mut &[u8]) -> Result<Isometry3<f32>, ReadError> {
+        BitPacked::<Self>::read_from_ext(r)
+    }
+
+    fn write_to_ext(w: &mut Vec<u8>, value: &Isometry3<f32>) -> Result<(), Infallible> {
+        BitPacked::<Self>::write_to_ext(w, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+    use rapier3d::na::{self as nalgebra, vector, Isometry3, UnitQuaternion, Vector3};
+
+    use crate::ExternalStreamCodec;
+
+    use super::{BoundedVector3, PositionBounds, QuantizedIsometry3, SmallestThree};
+
+    enum TestBounds {}
+
+    impl PositionBounds for TestBounds {
+        const MIN: [f32; 3] = [-16.0, -4.0, -16.0];
+        const MAX: [f32; 3] = [16.0, 12.0, 16.0];
+        const BITS: u32 = 16;
+    }
+
+    fn round_trip<C: ExternalStreamCodec>(value: &C::Item) -> (C::Item, usize)
+    where
+        C::ReadError: std::fmt::Debug,
+        C::WriteError: std::fmt::Debug,
+    {
+        let mut w = Vec::new();
+        C::write_to_ext(&mut w, value).unwrap();
+        let mut r = &w[..];
+        let result = C::read_from_ext(&mut r).unwrap();
+        assert!(r.is_empty());
+        (result, w.len())
+    }
+
+    /// Rotations spread over the whole sphere, including axis-aligned and negative-w cases.
+    fn sample_rotations() -> impl Iterator<Item = UnitQuaternion<f32>> {
+        let steps = 12;
+        (0..steps).flat_map(move |a| {
+            (0..steps).flat_map(move |b| {
+                (0..steps).map(move |c| {
+                    let angle = |n: i32| (n as f32 / steps as f32) * 2.0 * PI - PI;
+                    UnitQuaternion::from_euler_angles(angle(a), angle(b) / 2.0, angle(c))
+                })
+            })
+        })
+    }
+
+    fn check_rotation_error_bound<const BITS: u32>() {
+        // Each encoded component is within half a step. The reconstructed largest component can
+        // be off by at most as much as the other three combined, and renormalizing can at most
+        // double the distance. The rotation angle is about twice the quaternion distance.
+        let step = 2.0 * FRAC_1_SQRT_2 / ((1u64 << BITS) - 2) as f32;
+        let component_error = step / 2.0;
+        let max_distance = 2.0 * 2.0 * 3.0f32.sqrt() * component_error;
+        let max_angle = 2.0 * max_distance + 1e-5;
+
+        for rotation in sample_rotations() {
+            let (result, len) = round_trip::<SmallestThree<BITS>>(&rotation);
+            assert_eq!(len, ((2 + 3 * BITS + 7) >> 3) as usize);
+            let angle = rotation.angle_to(&result);
+            assert!(
+                angle <= max_angle,
+                "{BITS} bits: {rotation:?} came back as {result:?}, off by {angle} > {max_angle}",
+            );
+        }
+    }
+
+    #[test]
+    fn smallest_three_error_bound() {
+        check_rotation_error_bound::<2>();
+        check_rotation_error_bound::<7>();
+        check_rotation_error_bound::<10>();
+        check_rotation_error_bound::<15>();
+        check_rotation_error_bound::<32>();
+    }
+
+    #[test]
+    fn smallest_three_identity_is_exact() {
+        let (result, _) = round_trip::<SmallestThree<10>>(&UnitQuaternion::identity());
+        assert_eq!(result, UnitQuaternion::identity());
+    }
+
+    #[test]
+    fn bounded_vector_error_bound() {
+        let max_error = Vector3::from_fn(|axis, _| {
+            (TestBounds::MAX[axis] - TestBounds::MIN[axis])
+                / ((1 << TestBounds::BITS) - 2) as f32
+                / 2.0
+                + 1e-6
+        });
+        for x in -16..=16 {
+            for y in -4..=12 {
+                let position = vector![x as f32 * 0.987, y as f32 * 0.993, x as f32 * -0.731];
+                let (result, len) = round_trip::<BoundedVector3<TestBounds>>(&position);
+                assert_eq!(len, 6);
+                for axis in 0..3 {
+                    let error = (result[axis] - position[axis]).abs();
+                    assert!(
+                        error <= max_error[axis],
+                        "{position:?} came back as {result:?}",
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bounded_vector_clamps_and_keeps_center() {
+        let (result, _) = round_trip::<BoundedVector3<TestBounds>>(&vector![100.0, -100.0, 0.0]);
+        assert_eq!(result, vector![16.0, -4.0, 0.0]);
+    }
+
+    #[test]
+    fn isometry() {
+        let isometry = Isometry3::new(vector![1.0, 2.0, 3.0], vector![0.3, -0.2, 0.1]);
+        let (result, len) = round_trip::<QuantizedIsometry3<TestBounds, 10>>(&isometry);
+        assert_eq!(len, 10);
+        assert!((result.translation.vector - isometry.translation.vector).norm() < 1e-3);
+        assert!(result.rotation.angle_to(&isometry.rotation) < 5e-3);
+    }
+}
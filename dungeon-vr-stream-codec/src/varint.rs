@@ -0,0 +1,285 @@
+//! Variable-length integer encodings.
+//!
+//! Unsigned integers use LEB128: seven bits per byte, least significant group first, with the high
+//! bit of each byte set if more bytes follow. Signed integers are zigzag encoded first so that
+//! values near zero stay short regardless of sign. Values below 128 take a single byte.
+
+use std::convert::Infallible;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use paste::paste;
+use thiserror::Error;
+
+use crate::{ExternalStreamCodec, ReadError, StreamCodec};
+
+#[derive(Error, Debug)]
+pub enum ReadVarIntError {
+    #[error("{0}")]
+    ReadError(#[from] ReadError),
+
+    #[error("varint overflows {0} bits")]
+    Overflow(u32),
+}
+
+fn write_varint(w: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        w.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    w.push(value as u8);
+}
+
+fn read_varint(r: &mut &[u8], bits: u32) -> Result<u64, ReadVarIntError> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = u8::read_from(r)?;
+        let group = (byte & 0x7f) as u64;
+        if shift >= bits || (bits - shift < 7 && group >> (bits - shift) != 0) {
+            return Err(ReadVarIntError::Overflow(bits));
+        }
+        value |= group << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Marks an integer type to be encoded as a varint. Use it as an [`ExternalStreamCodec`] for
+/// fields that should stay their plain integer type, e.g. `Varint::<u32>::read_from_ext(r)`.
+pub struct Varint<T>(PhantomData<T>);
+
+macro_rules! impl_varint_unsigned {
+    ($t:ident) => {
+        paste! {
+            #[doc = "A `" $t "` encoded as an LEB128 varint."]
+            #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+            pub struct [<Var $t:upper>](pub $t);
+
+            impl StreamCodec for [<Var $t:upper>] {
+                type ReadError = ReadVarIntError;
+                type WriteError = Infallible;
+
+                fn read_from(r: &mut &[u8]) -> Result<Self, ReadVarIntError> {
+                    Ok(Self(read_varint(r, $t::BITS)? as $t))
+                }
+
+                fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+                    write_varint(w, self.0 as u64);
+                    Ok(())
+                }
+            }
+
+            impl_varint_ext!($t, [<Var $t:upper>]);
+        }
+    };
+}
+
+macro_rules! impl_varint_signed {
+    ($t:ident, $u:ident) => {
+        paste! {
+            #[doc = "An `" $t "` encoded as a zigzag LEB128 varint."]
+            #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+            pub struct [<Var $t:upper>](pub $t);
+
+            impl StreamCodec for [<Var $t:upper>] {
+                type ReadError = ReadVarIntError;
+                type WriteError = Infallible;
+
+                fn read_from(r: &mut &[u8]) -> Result<Self, ReadVarIntError> {
+                    let zigzag = read_varint(r, $t::BITS)? as $u;
+                    Ok(Self((zigzag >> 1) as $t ^ -((zigzag & 1) as $t)))
+                }
+
+                fn write_to(&self, w: &mut Vec<u8>) -> Result<(), Infallible> {
+                    let zigzag = ((self.0 << 1) ^ (self.0 >> ($t::BITS - 1))) as $u;
+                    write_varint(w, zigzag as u64);
+                    Ok(())
+                }
+            }
+
+            impl_varint_ext!($t, [<Var $t:upper>]);
+        }
+    };
+}
+
+macro_rules! impl_varint_ext {
+    ($t:ty, $var:ident) => {
+        impl ExternalStreamCodec for Varint<$t> {
+            type Item = $t;
+            type ReadError = ReadVarIntError;
+            type WriteError = Infallible;
+
+            fn read_from_ext(r: &mut &[u8]) -> Result<$t, ReadVarIntError> {
+                Ok($var::read_from(r)?.0)
+            }
+
+            fn write_to_ext(w: &mut Vec<u8>, value: &$t) -> Result<(), Infallible> {
+                $var(*value).write_to(w)
+            }
+        }
+    };
+}
+
+impl_varint_unsigned!(u16);
+impl_varint_unsigned!(u32);
+impl_varint_unsigned!(u64);
+impl_varint_signed!(i16, u16);
+impl_varint_signed!(i32, u32);
+impl_varint_signed!(i64, u64);
+
+#[derive(Error, Debug)]
+pub enum ReadVarintFramedError {
+    #[error("{0}")]
+    ReadVarIntError(#[from] ReadVarIntError),
+
+    #[error("invalid UTF-8")]
+    InvalidUtf8,
+}
+
+impl From<ReadError> for ReadVarintFramedError {
+    fn from(e: ReadError) -> Self {
+        Self::ReadVarIntError(e.into())
+    }
+}
+
+/// A byte vector prefixed with its length as a varint.
+pub enum VarintFramedByteVec {}
+
+impl ExternalStreamCodec for VarintFramedByteVec {
+    type Item = Vec<u8>;
+    type ReadError = ReadVarIntError;
+    type WriteError = Infallible;
+
+    fn read_from_ext(r: &mut &[u8]) -> Result<Vec<u8>, ReadVarIntError> {
+        let len = VarU32::read_from(r)?.0 as usize;
+        if len > r.len() {
+            return Err(ReadError::UnexpectedEof.into());
+        }
+        let mut value = vec![0; len];
+        r.read_exact(&mut value).unwrap();
+        Ok(value)
+    }
+
+    fn write_to_ext(w: &mut Vec<u8>, value: &Vec<u8>) -> Result<(), Infallible> {
+        VarU32(u32::try_from(value.len()).unwrap()).write_to(w)?;
+        w.write_all(value).unwrap();
+        Ok(())
+    }
+}
+
+/// A string prefixed with its length in bytes as a varint.
+pub enum VarintFramedString {}
+
+impl ExternalStreamCodec for VarintFramedString {
+    type Item = String;
+    type ReadError = ReadVarintFramedError;
+    type WriteError = Infallible;
+
+    fn read_from_ext(r: &mut &[u8]) -> Result<String, ReadVarintFramedError> {
+        let buf = VarintFramedByteVec::read_from_ext(r)?;
+        String::from_utf8(buf).map_err(|_| ReadVarintFramedError::InvalidUtf8)
+    }
+
+    fn write_to_ext(w: &mut Vec<u8>, value: &String) -> Result<(), Infallible> {
+        VarU32(u32::try_from(value.len()).unwrap()).write_to(w)?;
+        w.write_all(value.as_bytes()).unwrap();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use crate::{ExternalStreamCodec, StreamCodec};
+
+    use super::{
+        ReadVarIntError, VarI32, VarI64, VarU16, VarU32, VarU64, Varint, VarintFramedString,
+    };
+
+    fn round_trip<T>(value: T, expected: &[u8])
+    where
+        T: StreamCodec<ReadError = ReadVarIntError> + Debug + PartialEq,
+        T::WriteError: Debug,
+    {
+        let mut w = Vec::new();
+        value.write_to(&mut w).unwrap();
+        assert_eq!(w, expected);
+
+        let mut r = &w[..];
+        assert_eq!(T::read_from(&mut r).unwrap(), value);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn unsigned() {
+        round_trip(VarU32(0), &[0]);
+        round_trip(VarU32(1), &[1]);
+        round_trip(VarU32(127), &[0x7f]);
+        round_trip(VarU32(128), &[0x80, 0x01]);
+        round_trip(VarU32(300), &[0xac, 0x02]);
+        round_trip(VarU32(u32::MAX), &[0xff, 0xff, 0xff, 0xff, 0x0f]);
+        round_trip(VarU16(u16::MAX), &[0xff, 0xff, 0x03]);
+        round_trip(
+            VarU64(u64::MAX),
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        );
+    }
+
+    #[test]
+    fn signed() {
+        round_trip(VarI32(0), &[0]);
+        round_trip(VarI32(-1), &[1]);
+        round_trip(VarI32(1), &[2]);
+        round_trip(VarI32(-64), &[0x7f]);
+        round_trip(VarI32(64), &[0x80, 0x01]);
+        round_trip(VarI32(i32::MIN), &[0xff, 0xff, 0xff, 0xff, 0x0f]);
+        round_trip(
+            VarI64(i64::MAX),
+            &[0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        );
+        round_trip(
+            VarI64(i64::MIN),
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        );
+    }
+
+    #[test]
+    fn overflow() {
+        let mut r = &[0xff, 0xff, 0xff, 0xff, 0x1f][..];
+        assert!(matches!(
+            VarU32::read_from(&mut r),
+            Err(ReadVarIntError::Overflow(32)),
+        ));
+        let mut r = &[0x80, 0x80, 0x80, 0x80, 0x80, 0x00][..];
+        assert!(matches!(
+            VarU32::read_from(&mut r),
+            Err(ReadVarIntError::Overflow(32)),
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut r = &[0x80][..];
+        assert!(matches!(
+            VarU32::read_from(&mut r),
+            Err(ReadVarIntError::ReadError(_)),
+        ));
+    }
+
+    #[test]
+    fn external() {
+        let mut w = Vec::new();
+        Varint::<u32>::write_to_ext(&mut w, &300).unwrap();
+        VarintFramedString::write_to_ext(&mut w, &"abc".to_string()).unwrap();
+        assert_eq!(w, [0xac, 0x02, 3, 97, 98, 99]);
+
+        let mut r = &w[..];
+        assert_eq!(Varint::<u32>::read_from_ext(&mut r).unwrap(), 300);
+        assert_eq!(VarintFramedString::read_from_ext(&mut r).unwrap(), "abc");
+        assert!(r.is_empty());
+    }
+}
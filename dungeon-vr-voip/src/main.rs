@@ -6,7 +6,7 @@ use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{BufferSize, SampleFormat, SampleRate, StreamConfig};
-use dungeon_vr_connection_client::ConnectionClient;
+use dungeon_vr_connection_client::{ConnectionClient, ConnectionConfig};
 use dungeon_vr_session_client::{Event as SessionEvent, Request as SessionRequest, SessionClient};
 use tokio::net::UdpSocket;
 use tokio::select;
@@ -32,7 +32,7 @@ pub async fn main() -> Result<()> {
     let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
     socket.connect(server_addr).await?;
     let (_connection_cancel_guard, connection_requests, connection_events) =
-        ConnectionClient::spawn(Box::new(socket));
+        ConnectionClient::spawn(Box::new(socket), ConnectionConfig::default(), None, None);
     let mut session_client = SessionClient::new(connection_requests, connection_events);
 
     let mut audio_ctx = AudioContext::new()?;
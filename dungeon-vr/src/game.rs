@@ -18,7 +18,7 @@ use dungeon_vr_session_shared::physics::{
 };
 use dungeon_vr_session_shared::render::{ModelHandle, RenderComponent};
 use dungeon_vr_session_shared::resources::{AllActionsResource, EntitiesByNetIdResource};
-use dungeon_vr_session_shared::snapshot::apply_snapshot;
+use dungeon_vr_session_shared::snapshot::{apply_snapshot, Snapshot};
 use dungeon_vr_session_shared::time::{NanoDuration, NanoTime};
 use dungeon_vr_session_shared::{PlayerId, TickId, TICK_INTERVAL};
 use itertools::{merge_join_by, EitherOrBoth};
@@ -79,7 +79,7 @@ struct GameNet {
 
 struct AuthoritativeState {
     tick_id: TickId,
-    snapshot: Vec<u8>,
+    snapshot: Snapshot,
 }
 
 struct GameTick {
@@ -295,8 +295,12 @@ impl Game {
         local_player_id: PlayerId,
         tick_id: TickId,
     ) {
-        log::info!("Configuring game for newly started session");
-        assert!(self.net.is_none());
+        // A session that reconnects starts over from the server's latest state.
+        if self.net.is_some() {
+            log::info!("Reconfiguring game for resumed session");
+        } else {
+            log::info!("Configuring game for newly started session");
+        }
         self.net = Some(GameNet {
             local_player_id,
             latest: None,
@@ -326,7 +330,7 @@ impl Game {
         &mut self,
         snapshot_tick_id: TickId,
         tick_interval: NanoDuration,
-        snapshot_data: Vec<u8>,
+        snapshot: Snapshot,
     ) {
         let net = self.net.as_mut().unwrap();
 
@@ -341,12 +345,10 @@ impl Game {
             tick_interval.clamp(TICK_INTERVAL * 9 / 10, TICK_INTERVAL * 11 / 10);
 
         // Go directly to the new snapshot.
-        let mut r = snapshot_data.as_slice();
-        apply_snapshot(&mut r, &mut self.ecs.world).unwrap();
-        assert!(r.is_empty());
+        apply_snapshot(&snapshot, &mut self.ecs.world);
         net.latest = Some(AuthoritativeState {
             tick_id: snapshot_tick_id,
-            snapshot: snapshot_data,
+            snapshot,
         });
         let goal_tick_id = replace(&mut self.tick.last_completed_tick_id, snapshot_tick_id);
 
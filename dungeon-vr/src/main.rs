@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::mem::forget;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -10,10 +11,11 @@ use std::time::Duration;
 use anyhow::Result;
 use ash::vk;
 use bytemuck::{Pod, Zeroable};
-use clap::Parser;
-use dungeon_vr_connection_client::ConnectionClient;
+use clap::{Parser, ValueEnum};
+use dungeon_vr_connection_client::{ConnectionClient, ConnectionConfig, VerifyingKey};
 use dungeon_vr_session_client::{Event as SessionEvent, Request as SessionRequest, SessionClient};
-use dungeon_vr_socket::fakelag::FakeLagConnectedSocket;
+use dungeon_vr_socket::capture::{CaptureWriter, RecordingConnectedSocket};
+use dungeon_vr_socket::fakelag::{DelayDistribution, FakeLagConfig, FakeLagConnectedSocket, Loss};
 use dungeon_vr_socket::ConnectedSocket;
 use openxr as xr;
 use rapier3d::na::{self, matrix, vector, Matrix4};
@@ -68,10 +70,135 @@ pub struct Args {
     #[clap(long)]
     connect: Option<String>,
 
-    /// Adds exponentially distributed delay with the given mean to all incoming and outgoing
-    /// packets.
+    /// Records every datagram sent to or received from the server to this pcap file.
+    #[clap(long)]
+    capture: Option<PathBuf>,
+
+    /// Adds delay with the given mean to all incoming and outgoing packets.
     #[clap(long)]
     fake_lag_ms: Option<u64>,
+
+    /// The distribution fake lag is drawn from.
+    #[clap(long, value_enum, default_value_t = FakeLagDistribution::Exponential)]
+    fake_lag_distribution: FakeLagDistribution,
+
+    /// The spread of uniform fake lag or the standard deviation of normal fake lag.
+    #[clap(long, default_value = "0")]
+    fake_jitter_ms: u64,
+
+    /// Drops this fraction of packets. With burst loss, this applies outside of bursts.
+    #[clap(long, default_value = "0", value_parser = parse_probability)]
+    fake_loss: f64,
+
+    /// Enables Gilbert-Elliott burst loss, entering a burst before each packet with this
+    /// probability.
+    #[clap(long, value_parser = parse_probability)]
+    fake_burst_enter: Option<f64>,
+
+    /// The probability of leaving a loss burst before each packet.
+    #[clap(long, default_value = "0.25", value_parser = parse_probability)]
+    fake_burst_exit: f64,
+
+    /// Drops this fraction of packets during a loss burst.
+    #[clap(long, default_value = "1", value_parser = parse_probability)]
+    fake_burst_loss: f64,
+
+    /// Delivers this fraction of packets twice.
+    #[clap(long, default_value = "0", value_parser = parse_probability)]
+    fake_duplicate: f64,
+
+    /// Delivers this fraction of packets without fake lag, ahead of packets sent before them.
+    #[clap(long, default_value = "0", value_parser = parse_probability)]
+    fake_reorder: f64,
+
+    /// Limits incoming and outgoing packets to this many kilobits per second each.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    fake_bandwidth_kbps: Option<u64>,
+
+    /// The most bytes waiting for fake bandwidth before further packets are dropped.
+    #[clap(long, default_value = "65536")]
+    fake_queue_bytes: usize,
+
+    /// Seeds the fake network conditions so that a run can be reproduced.
+    #[clap(long)]
+    fake_seed: Option<u64>,
+
+    /// Largest UDP payload to send, in bytes. Larger game data is split into fragments.
+    #[clap(long, default_value = "1200")]
+    mtu: usize,
+
+    /// Only completes a connection to a server that proves it holds the signing key matching this
+    /// hex-encoded public key.
+    #[clap(long)]
+    server_key: Option<VerifyingKey>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum FakeLagDistribution {
+    Constant,
+    Exponential,
+    Uniform,
+    Normal,
+}
+
+impl Args {
+    /// Returns the network conditions to simulate, if any were requested.
+    fn fake_lag_config(&self) -> Option<FakeLagConfig> {
+        let enabled = self.fake_lag_ms.is_some()
+            || self.fake_jitter_ms > 0
+            || self.fake_loss > 0.0
+            || self.fake_burst_enter.is_some()
+            || self.fake_duplicate > 0.0
+            || self.fake_reorder > 0.0
+            || self.fake_bandwidth_kbps.is_some();
+        if !enabled {
+            return None;
+        }
+
+        let mean = Duration::from_millis(self.fake_lag_ms.unwrap_or(0));
+        let jitter = Duration::from_millis(self.fake_jitter_ms);
+        Some(FakeLagConfig {
+            delay: match self.fake_lag_distribution {
+                FakeLagDistribution::Constant => DelayDistribution::Constant(mean),
+                FakeLagDistribution::Exponential => DelayDistribution::Exponential { mean },
+                FakeLagDistribution::Uniform => DelayDistribution::Uniform {
+                    mean,
+                    spread: jitter,
+                },
+                FakeLagDistribution::Normal => DelayDistribution::Normal {
+                    mean,
+                    std_dev: jitter,
+                },
+            },
+            loss: match self.fake_burst_enter {
+                Some(good_to_bad) => Loss::GilbertElliott {
+                    good_to_bad,
+                    bad_to_good: self.fake_burst_exit,
+                    good_loss: self.fake_loss,
+                    bad_loss: self.fake_burst_loss,
+                },
+                None => Loss::Random {
+                    probability: self.fake_loss,
+                },
+            },
+            duplicate_probability: self.fake_duplicate,
+            reorder_probability: self.fake_reorder,
+            bandwidth: self
+                .fake_bandwidth_kbps
+                .map(|kbps| kbps.saturating_mul(1000) / 8),
+            queue_limit: self.fake_queue_bytes,
+            seed: self.fake_seed,
+        })
+    }
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    let p: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if (0.0..=1.0).contains(&p) {
+        Ok(p)
+    } else {
+        Err(format!("{p} is not between 0 and 1"))
+    }
 }
 
 #[tokio::main]
@@ -87,17 +214,23 @@ pub async fn main() -> Result<()> {
             let udp_socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
             udp_socket.connect(server_addr).await?;
 
-            let socket: Box<dyn ConnectedSocket>;
-            if let Some(fake_lag_ms) = args.fake_lag_ms {
-                socket = Box::new(FakeLagConnectedSocket::new(
-                    udp_socket,
-                    Duration::from_millis(fake_lag_ms),
+            let mut socket: Box<dyn ConnectedSocket> = Box::new(udp_socket);
+            if let Some(path) = &args.capture {
+                socket = Box::new(RecordingConnectedSocket::new(
+                    socket,
+                    CaptureWriter::create(path)?,
                 ));
-            } else {
-                socket = Box::new(udp_socket);
+            }
+            if let Some(fake_lag_config) = args.fake_lag_config() {
+                socket = Box::new(FakeLagConnectedSocket::with_config(
+                    socket,
+                    fake_lag_config,
+                )?);
             }
 
-            let (cancel_guard, requests, events) = ConnectionClient::spawn(socket);
+            let config = ConnectionConfig::builder().mtu(args.mtu).build()?;
+            let (cancel_guard, requests, events) =
+                ConnectionClient::spawn(socket, config, args.server_key, None);
             let session = SessionClient::new(requests, events);
             forget(cancel_guard);
             Some(session)
@@ -422,6 +555,7 @@ fn main_loop<'a>(
                     SessionEvent::Start {
                         local_player_id,
                         tick_id,
+                        ..
                     } => game.start_net_session(
                         xr_frame_state.predicted_display_time,
                         local_player_id,
@@ -430,9 +564,9 @@ fn main_loop<'a>(
                     SessionEvent::Snapshot {
                         tick_id,
                         tick_interval,
-                        data,
-                    } => game.handle_snapshot(tick_id, tick_interval, data),
-                    SessionEvent::Voice(_) => (),
+                        snapshot,
+                    } => game.handle_snapshot(tick_id, tick_interval, snapshot),
+                    SessionEvent::Voice(_) | SessionEvent::ConnectionStats(_) => (),
                 }
             }
         }